@@ -0,0 +1,101 @@
+#ifndef CRABTRAP_H
+#define CRABTRAP_H
+
+/* Generated by cbindgen from src/capi.rs, so don't edit it by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// CrabtrapExitKind: which way a run ended, going by the variants of ChildExit
+typedef enum CrabtrapExitKind {
+  CRABTRAP_EXIT_KIND_EXITED,
+  CRABTRAP_EXIT_KIND_ILLEGAL_SYSCALL,
+  CRABTRAP_EXIT_KIND_WRITE_QUOTA_EXCEEDED,
+  CRABTRAP_EXIT_KIND_SYSCALL_STORM,
+  CRABTRAP_EXIT_KIND_DAEMONIZED,
+  CRABTRAP_EXIT_KIND_READ_ONLY_FILESYSTEM,
+  CRABTRAP_EXIT_KIND_SIGNALED,
+  CRABTRAP_EXIT_KIND_TIMED_OUT,
+  CRABTRAP_EXIT_KIND_SETUP_FAILED,
+  CRABTRAP_EXIT_KIND_DETACHED,
+} CrabtrapExitKind;
+
+// CrabtrapConfig: a config loaded for C callers, who only ever see a pointer to one
+typedef struct CrabtrapConfig CrabtrapConfig;
+
+// CrabtrapResult: how a run ended, filled in by crabtrap_execute. The strings belong to the result until it's passed
+// to crabtrap_result_free.
+typedef struct CrabtrapResult {
+  CrabtrapExitKind kind;
+  // The exit code for Exited, the signal for Signaled, the errno for SetupFailed and the pid for Daemonized.
+  // Otherwise 0.
+  int32_t code;
+  // The syscall's number for IllegalSyscall, SyscallStorm and ReadOnlyFilesystem. Otherwise -1.
+  int64_t syscall;
+  // The library that made the syscall for IllegalSyscall, the call site for SyscallStorm and the file for
+  // WriteQuotaExceeded and ReadOnlyFilesystem. Otherwise NULL.
+  char *subject;
+  // The id of the rule that was broken, if it has one. Otherwise NULL.
+  char *rule;
+  uint64_t processes;
+  uint64_t syscalls;
+  uint64_t wall_time_us;
+  // The exit and the run's stats as a JSON object with `exit` and `stats`, for everything the fields above leave
+  // out
+  char *json;
+} CrabtrapResult;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// crabtrap_last_error returns why the last call on this thread that failed did, or NULL if none has. The string
+// stays valid until the next call that fails.
+const char *crabtrap_last_error(void);
+
+// crabtrap_config_load reads a config file, along with any it extends. Returns NULL if it can't, see
+// crabtrap_last_error.
+//
+// # Safety
+//
+// `path` has to be a valid C string.
+CrabtrapConfig *crabtrap_config_load(const char *path);
+
+// crabtrap_config_free frees a config from crabtrap_config_load. Does nothing given NULL.
+//
+// # Safety
+//
+// `config` has to have come from crabtrap_config_load, and not have been freed already.
+void crabtrap_config_free(CrabtrapConfig *config);
+
+// crabtrap_execute runs `path` with `argv` and `envp` under `config`, or with nothing enforced if it's NULL, and
+// fills in `result` with how it went. `argv` and `envp` are NULL-terminated, like execve's. Returns 0 once the run
+// is over, whatever the target did, and -1 if it couldn't be run at all, see crabtrap_last_error.
+//
+// The target is traced on a thread of its own, which only waits for its own children, so this can be called from
+// several threads at once, and the caller's other children are left for it to wait for.
+//
+// # Safety
+//
+// The strings have to be valid C strings, `config` NULL or from crabtrap_config_load, and `result` somewhere a
+// CrabtrapResult can be written.
+int32_t crabtrap_execute(const char *path,
+                         const char *const *argv,
+                         const char *const *envp,
+                         const CrabtrapConfig *config,
+                         CrabtrapResult *result);
+
+// crabtrap_result_free frees the strings in a result crabtrap_execute filled in, and sets them to NULL.
+//
+// # Safety
+//
+// `result` has to have been filled in by crabtrap_execute, or be NULL.
+void crabtrap_result_free(CrabtrapResult *result);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CRABTRAP_H */
@@ -0,0 +1,37 @@
+#include <pthread.h>
+#include <stdio.h>
+#include <unistd.h>
+
+#define THREADS 8
+#define ROUNDS 50
+
+int printf_wrapper(const char *format, ...);
+
+static pthread_barrier_t barrier;
+
+void *worker(void *arg) {
+    // Start together, so the tracer sees syscalls from every thread at once
+    pthread_barrier_wait(&barrier);
+    for (int i = 0; i < ROUNDS; i++) {
+        getpid();
+        printf_wrapper("Thread %ld, round %d\n", (long) arg, i);
+    }
+    return NULL;
+}
+
+int main() {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    pthread_t threads[THREADS];
+    pthread_barrier_init(&barrier, NULL, THREADS);
+    for (long i = 0; i < THREADS; i++) {
+        if (pthread_create(&threads[i], NULL, worker, (void *) i) != 0) {
+            perror("pthread_create failed");
+            return 1;
+        }
+    }
+    for (int i = 0; i < THREADS; i++) {
+        pthread_join(threads[i], NULL);
+    }
+    return 0;
+}
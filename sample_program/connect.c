@@ -0,0 +1,37 @@
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/socket.h>
+#include <unistd.h>
+
+// Connects a UDP or TCP socket to an address and port, which for UDP works without anything listening. Exits 2 if
+// the socket can't be made, and 1 if it can't be connected.
+int main(int argc, char **argv) {
+    if (argc < 4) {
+        fprintf(stderr, "usage: connect udp|tcp address port\n");
+        return 3;
+    }
+    int type = strcmp(argv[1], "tcp") == 0 ? SOCK_STREAM : SOCK_DGRAM;
+    int fd = socket(AF_INET, type, 0);
+    if (fd < 0) {
+        perror("socket failed");
+        return 2;
+    }
+
+    struct sockaddr_in addr = {
+        .sin_family = AF_INET,
+        .sin_port = htons(atoi(argv[3])),
+    };
+    if (inet_pton(AF_INET, argv[2], &addr.sin_addr) != 1) {
+        fprintf(stderr, "bad address %s\n", argv[2]);
+        return 3;
+    }
+    if (connect(fd, (struct sockaddr *)&addr, sizeof(addr)) < 0) {
+        perror("connect failed");
+        return 1;
+    }
+    close(fd);
+    return 0;
+}
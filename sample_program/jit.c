@@ -0,0 +1,34 @@
+#define _GNU_SOURCE
+#include <stdio.h>
+#include <string.h>
+#include <sys/mman.h>
+#include <sys/syscall.h>
+#include <unistd.h>
+
+// A trampoline that calls the function it's given with the number it's given, keeping a frame record so that the
+// stack can be walked back through it:
+//   stp x29, x30, [sp, #-16]!
+//   mov x29, sp
+//   blr x1
+//   ldp x29, x30, [sp], #16
+//   ret
+static const unsigned int trampoline[] = {0xa9bf7bfd, 0x910003fd, 0xd63f0020, 0xa8c17bfd, 0xd65f03c0};
+
+// Makes a getpid through libc's syscall() from code it's copied into anonymous memory, like a JIT would, so that the
+// syscall itself is made from libc. Exits 1 if the code can't be mapped.
+int main(void) {
+    void *code = mmap(NULL, sizeof(trampoline), PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (code == MAP_FAILED) {
+        perror("mmap failed");
+        return 1;
+    }
+    memcpy(code, trampoline, sizeof(trampoline));
+    if (mprotect(code, sizeof(trampoline), PROT_READ | PROT_EXEC) < 0) {
+        perror("mprotect failed");
+        return 1;
+    }
+    __builtin___clear_cache(code, (char *)code + sizeof(trampoline));
+
+    long (*call)(long, long (*)(long, ...)) = code;
+    return call(SYS_getpid, syscall) == getpid() ? 0 : 2;
+}
@@ -0,0 +1,38 @@
+#include <spawn.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+extern char **environ;
+
+// Runs static with vfork or posix_spawn, whichever is given, and exits however it did
+int main(int argc, char **argv) {
+    char *args[] = {"static", NULL};
+    pid_t pid;
+
+    if (argc < 2) {
+        fprintf(stderr, "usage: spawn vfork|posix_spawn\n");
+        return 2;
+    }
+    if (strcmp(argv[1], "vfork") == 0) {
+        pid = vfork();
+        if (pid == 0) {
+            execve("/usr/local/bin/static", args, environ);
+            _exit(127);
+        }
+    } else if (posix_spawn(&pid, "/usr/local/bin/static", NULL, NULL, args, environ) != 0) {
+        pid = -1;
+    }
+    if (pid < 0) {
+        perror("spawn failed");
+        return 2;
+    }
+
+    int status;
+    if (waitpid(pid, &status, 0) < 0) {
+        perror("waitpid failed");
+        return 2;
+    }
+    return WIFEXITED(status) ? WEXITSTATUS(status) : 128 + WTERMSIG(status);
+}
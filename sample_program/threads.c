@@ -0,0 +1,28 @@
+#include <pthread.h>
+#include <stdio.h>
+
+int printf_wrapper(const char *format, ...);
+
+void *worker(void *arg) {
+    printf_wrapper("Hello from a thread!\n");
+    return NULL;
+}
+
+void *crasher(void *arg) {
+    *(volatile int *)NULL = 0;
+    return NULL;
+}
+
+// With an argument, the thread crashes instead of printing, while the main thread waits for it
+int main(int argc, char **argv) {
+    // Unbuffered, so the write happens inside printf_wrapper rather than whenever stdout is flushed
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    pthread_t thread;
+    if (pthread_create(&thread, NULL, argc > 1 ? crasher : worker, NULL) != 0) {
+        perror("pthread_create failed");
+        return 1;
+    }
+    pthread_join(thread, NULL);
+    printf("Goodbye from the main thread!\n");
+}
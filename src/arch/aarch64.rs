@@ -0,0 +1,111 @@
+use nix::{
+    errno::Errno,
+    libc::{self, c_void, iovec, user_regs_struct},
+    sys::ptrace::setregs,
+    unistd::Pid,
+};
+use std::mem;
+use syscalls::Sysno;
+
+/// The AUDIT_ARCH_* value for native processes, as seccomp filters see it
+pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+/// The AUDIT_ARCH_* value PTRACE_GET_SYSCALL_INFO reports for AArch32 processes
+pub const COMPAT_AUDIT_ARCH: u32 = 0x4000_0028;
+
+/// Where a frame record keeps the caller's frame pointer, relative to the frame pointer
+///
+/// Reference: https://github.com/ARM-software/abi-aa/blob/2a70c42d62e9c3eb5887fa50b71257f20daca6f9/aapcs64/aapcs64.rst#646the-frame-pointer
+pub const SAVED_FRAME_POINTER: i64 = 0;
+/// Where a frame record keeps the return address, relative to the frame pointer
+pub const SAVED_RETURN_ADDRESS: i64 = 8;
+
+/// The DWARF numbers of the stack pointer and frame pointer
+pub const DWARF_SP: u16 = 31;
+pub const DWARF_FP: u16 = 29;
+
+/// The bits of a user space address, above which pointer authentication keeps its signature
+const VA_BITS: u32 = 48;
+
+/// The regset holding the syscall number. Changing x8 at the entry stop doesn't change which syscall runs.
+const NT_ARM_SYSTEM_CALL: libc::c_int = 0x404;
+
+pub fn syscall_number(regs: &user_regs_struct) -> u64 {
+    regs.regs[8]
+}
+
+pub fn syscall_args(regs: &user_regs_struct) -> [u64; 6] {
+    regs.regs[0..6].try_into().unwrap()
+}
+
+/// return_value is only meaningful at a syscall exit stop. At the entry stop this is the first argument.
+pub fn return_value(regs: &user_regs_struct) -> i64 {
+    regs.regs[0] as i64
+}
+
+pub fn pc(regs: &user_regs_struct) -> u64 {
+    regs.pc
+}
+
+/// skip_syscall makes the syscall the tracee is at the entry stop of not run at all.
+pub fn skip_syscall(pid: Pid, _regs: &mut user_regs_struct) -> nix::Result<()> {
+    let mut syscall: i32 = -1;
+    let mut iov = iovec {
+        iov_base: &mut syscall as *mut i32 as *mut c_void,
+        iov_len: mem::size_of::<i32>(),
+    };
+    Errno::result(unsafe {
+        libc::ptrace(
+            libc::PTRACE_SETREGSET,
+            pid.as_raw(),
+            NT_ARM_SYSTEM_CALL,
+            &mut iov as *mut iovec,
+        )
+    })
+    .map(drop)
+}
+
+/// set_return_value changes what the syscall the tracee is at the exit stop of returns.
+pub fn set_return_value(pid: Pid, mut regs: user_regs_struct, value: i64) -> nix::Result<()> {
+    regs.regs[0] = value as u64;
+    setregs(pid, regs)
+}
+
+/// The length of the `svc` instruction
+const SYSCALL_INSTRUCTION_LEN: u64 = 4;
+
+/// restart_as_exit rewinds a tracee at a syscall exit stop to make its syscall again, as an `exit` with this code.
+/// `exit` only ends the calling thread, unlike `exit_group`.
+pub fn restart_as_exit(pid: Pid, mut regs: user_regs_struct, code: i32) -> nix::Result<()> {
+    regs.pc -= SYSCALL_INSTRUCTION_LEN;
+    regs.regs[8] = Sysno::exit as u64;
+    regs.regs[0] = code as u64;
+    setregs(pid, regs)
+}
+
+/// link_register holds the return address of a leaf function, which doesn't necessarily set up a frame record.
+pub fn link_register(regs: &user_regs_struct) -> u64 {
+    regs.regs[30]
+}
+
+pub fn frame_pointer(regs: &user_regs_struct) -> u64 {
+    regs.regs[29]
+}
+
+/// dwarf_registers lays out the integer registers by their DWARF numbers: x0 to x30, then sp.
+pub fn dwarf_registers(regs: &user_regs_struct) -> [u64; 32] {
+    let mut registers = [0; 32];
+    registers[..31].copy_from_slice(&regs.regs);
+    registers[31] = regs.sp;
+    registers
+}
+
+/// strip_return_address removes the signature pointer authentication may have added to a return address saved on the
+/// stack.
+pub fn strip_return_address(addr: u64) -> u64 {
+    addr & ((1 << VA_BITS) - 1)
+}
+
+/// compat_syscall translates a syscall number from a AArch32 process into the native syscall it corresponds to.
+pub fn compat_syscall(nr: u64) -> Option<Sysno> {
+    syscalls::arm::Sysno::new(nr as usize).and_then(|syscall| super::native(syscall.name()))
+}
@@ -0,0 +1,104 @@
+use nix::unistd::Pid;
+use std::{fs::File, io::Read, path::Path, str::FromStr};
+use syscalls::Sysno;
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::*;
+
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::*;
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+compile_error!("crabtrap only supports aarch64 and riscv64");
+
+/// Abi: which syscall table and register layout a traced process uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Abi {
+    /// The tracer's own architecture
+    Native,
+    /// The 32-bit architecture the kernel can also run, e.g. AArch32 on aarch64
+    Compat,
+}
+
+/// The offset and values of EI_CLASS in an ELF header
+const EI_CLASS: usize = 4;
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+
+/// elf_abi tells the ABI of a program from the start of its ELF header.
+fn elf_abi(header: &[u8]) -> Option<Abi> {
+    if !header.starts_with(b"\x7fELF") {
+        return None;
+    }
+    match header.get(EI_CLASS) {
+        Some(&ELFCLASS64) => Some(Abi::Native),
+        Some(&ELFCLASS32) => Some(Abi::Compat),
+        _ => None,
+    }
+}
+
+/// detect reads which ABI the program `pid` is running uses. Called when a process is first seen and after each exec,
+/// since a 64-bit process can exec a 32-bit one and vice versa.
+pub fn detect(proc_root: &Path, pid: Pid) -> Option<Abi> {
+    let mut header = [0; EI_CLASS + 1];
+    File::open(proc_root.join(pid.to_string()).join("exe"))
+        .and_then(|mut exe| exe.read_exact(&mut header))
+        .ok()?;
+    elf_abi(&header)
+}
+
+/// native finds the syscall on this architecture that a compat syscall's name corresponds to.
+///
+/// Most compat syscalls share a name with their native counterpart, or only differ by a suffix saying which
+/// variant of the arguments they take, like `fcntl64` or `clock_gettime64`. The rest are listed here.
+fn native(name: &str) -> Option<Sysno> {
+    let renamed = match name {
+        "mmap2" => "mmap",
+        "_llseek" | "llseek" => "lseek",
+        "_newselect" => "pselect6",
+        "stat64" | "lstat64" => "fstatat",
+        "sigreturn" => "rt_sigreturn",
+        "send" => "sendto",
+        "recv" => "recvfrom",
+        "ugetrlimit" => "getrlimit",
+        "arm_fadvise64_64" | "fadvise64_64" => "fadvise64",
+        "arm_sync_file_range" => "sync_file_range",
+        name => name,
+    };
+
+    Sysno::from_str(renamed).ok().or_else(|| {
+        ["_time64", "32", "64"]
+            .iter()
+            .find_map(|suffix| renamed.strip_suffix(suffix))
+            .and_then(|name| Sysno::from_str(name).ok())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_native() {
+        assert_eq!(native("openat"), Some(Sysno::openat));
+        assert_eq!(native("mmap2"), Some(Sysno::mmap));
+        assert_eq!(native("stat64"), Some(Sysno::fstatat));
+        assert_eq!(native("fcntl64"), Some(Sysno::fcntl));
+        assert_eq!(native("getuid32"), Some(Sysno::getuid));
+        assert_eq!(native("clock_gettime64"), Some(Sysno::clock_gettime));
+        assert_eq!(native("getdents64"), Some(Sysno::getdents64));
+        assert_eq!(native("not_a_syscall"), None);
+    }
+
+    #[test]
+    fn test_elf_abi() {
+        assert_eq!(elf_abi(b"\x7fELF\x02\x01\x01"), Some(Abi::Native));
+        assert_eq!(elf_abi(b"\x7fELF\x01\x01\x01"), Some(Abi::Compat));
+        assert_eq!(elf_abi(b"#!/bin/sh"), None);
+        assert_eq!(elf_abi(b"\x7fELF"), None);
+    }
+}
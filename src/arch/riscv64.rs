@@ -0,0 +1,89 @@
+use nix::{libc::user_regs_struct, sys::ptrace::setregs, unistd::Pid};
+use syscalls::Sysno;
+
+/// The AUDIT_ARCH_* value for native processes, as seccomp filters see it
+pub const AUDIT_ARCH: u32 = 0xc000_00f3;
+/// The AUDIT_ARCH_* value PTRACE_GET_SYSCALL_INFO reports for RV32 processes
+pub const COMPAT_AUDIT_ARCH: u32 = 0x4000_00f3;
+
+/// Where a frame keeps the caller's frame pointer, relative to the frame pointer. Unlike aarch64, s0 points to the
+/// top of the frame, and the saved registers are just below it.
+///
+/// Reference: https://github.com/riscv-non-isa/riscv-elf-psabi-doc/blob/master/riscv-cc.adoc#frame-pointer-convention
+pub const SAVED_FRAME_POINTER: i64 = -16;
+/// Where a frame keeps the return address, relative to the frame pointer
+pub const SAVED_RETURN_ADDRESS: i64 = -8;
+
+/// The DWARF numbers of the stack pointer and frame pointer
+pub const DWARF_SP: u16 = 2;
+pub const DWARF_FP: u16 = 8;
+
+pub fn syscall_number(regs: &user_regs_struct) -> u64 {
+    regs.a7
+}
+
+pub fn syscall_args(regs: &user_regs_struct) -> [u64; 6] {
+    [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5]
+}
+
+/// return_value is only meaningful at a syscall exit stop. At the entry stop this is the first argument.
+pub fn return_value(regs: &user_regs_struct) -> i64 {
+    regs.a0 as i64
+}
+
+pub fn pc(regs: &user_regs_struct) -> u64 {
+    regs.pc
+}
+
+/// skip_syscall makes the syscall the tracee is at the entry stop of not run at all.
+pub fn skip_syscall(pid: Pid, regs: &mut user_regs_struct) -> nix::Result<()> {
+    regs.a7 = u64::MAX;
+    setregs(pid, *regs)
+}
+
+/// set_return_value changes what the syscall the tracee is at the exit stop of returns.
+pub fn set_return_value(pid: Pid, mut regs: user_regs_struct, value: i64) -> nix::Result<()> {
+    regs.a0 = value as u64;
+    setregs(pid, regs)
+}
+
+/// The length of the `ecall` instruction
+const SYSCALL_INSTRUCTION_LEN: u64 = 4;
+
+/// restart_as_exit rewinds a tracee at a syscall exit stop to make its syscall again, as an `exit` with this code.
+/// `exit` only ends the calling thread, unlike `exit_group`.
+pub fn restart_as_exit(pid: Pid, mut regs: user_regs_struct, code: i32) -> nix::Result<()> {
+    regs.pc -= SYSCALL_INSTRUCTION_LEN;
+    regs.a7 = Sysno::exit as u64;
+    regs.a0 = code as u64;
+    setregs(pid, regs)
+}
+
+/// link_register holds the return address of a leaf function, which doesn't necessarily save it in its frame.
+pub fn link_register(regs: &user_regs_struct) -> u64 {
+    regs.ra
+}
+
+pub fn frame_pointer(regs: &user_regs_struct) -> u64 {
+    regs.s0
+}
+
+/// dwarf_registers lays out the integer registers by their DWARF numbers, which are the x numbers. x0 is always 0.
+pub fn dwarf_registers(regs: &user_regs_struct) -> [u64; 32] {
+    [
+        0, regs.ra, regs.sp, regs.gp, regs.tp, regs.t0, regs.t1, regs.t2, regs.s0, regs.s1,
+        regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5, regs.a6, regs.a7, regs.s2, regs.s3,
+        regs.s4, regs.s5, regs.s6, regs.s7, regs.s8, regs.s9, regs.s10, regs.s11, regs.t3, regs.t4,
+        regs.t5, regs.t6,
+    ]
+}
+
+/// strip_return_address returns a return address as is, as riscv64 doesn't sign them.
+pub fn strip_return_address(addr: u64) -> u64 {
+    addr
+}
+
+/// compat_syscall translates a syscall number from a RV32 process into the native syscall it corresponds to.
+pub fn compat_syscall(nr: u64) -> Option<Sysno> {
+    syscalls::riscv32::Sysno::new(nr as usize).and_then(|syscall| super::native(syscall.name()))
+}
@@ -0,0 +1,168 @@
+use crate::{
+    hooks::{Hooks, Judgement, Ruling},
+    log::warning,
+    ChildExit, Config,
+};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+};
+use syscalls::Sysno;
+
+/// Answer: what was said when asked about a syscall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    Yes,
+    No,
+    Always,
+    Never,
+}
+
+impl Answer {
+    fn parse(answer: &str) -> Option<Answer> {
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => Some(Answer::Yes),
+            "n" | "no" => Some(Answer::No),
+            "a" | "always" => Some(Answer::Always),
+            "never" => Some(Answer::Never),
+            _ => None,
+        }
+    }
+
+    fn allows(self) -> bool {
+        matches!(self, Answer::Yes | Answer::Always)
+    }
+}
+
+/// remember adds a rule for the syscall to the config, allowing it if `allow` and blocking it otherwise.
+fn remember(config: &mut Config, library: &str, syscall: Sysno, allow: bool) {
+    let entry = config
+        .shared_objects
+        .entry(library.to_string())
+        .or_default();
+    let (add, remove) = if allow {
+        (&mut entry.allow, &mut entry.block)
+    } else {
+        (&mut entry.block, &mut entry.allow)
+    };
+    add.get_or_insert_with(Default::default).insert(syscall);
+    if let Some(remove) = remove {
+        remove.remove(&syscall);
+    }
+}
+
+/// Asker: asks on the terminal whether to let syscalls through, for working out a config by hand
+///
+/// The tracer waits for each answer, so the whole traced tree is paused while it's asking. The prompts go to the
+/// controlling terminal rather than stdin and stdout, which the target may be using.
+pub struct Asker {
+    tty: BufReader<File>,
+    /// The answers that hold for the rest of the run
+    remembered: BTreeMap<(String, Sysno), bool>,
+    /// The config file to add the `always` and `never` answers to, and the config read from it
+    save: Option<(PathBuf, Config)>,
+}
+
+impl Asker {
+    /// new opens the terminal, and reads the config file to add answers to if there is one.
+    pub fn new(save: Option<PathBuf>) -> io::Result<Asker> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        Ok(Asker {
+            tty: BufReader::new(tty),
+            remembered: BTreeMap::new(),
+            // Read again rather than taking the caller's, which may have been changed by command line flags
+            save: save.map(|path| {
+                let config = Config::from_file(&path);
+                (path, config)
+            }),
+        })
+    }
+
+    /// ask returns whether to let the syscall from `library` through, asking if there's no answer for it yet.
+    pub fn ask(&mut self, library: &str, syscall: Sysno) -> bool {
+        if let Some(&allow) = self.remembered.get(&(library.to_string(), syscall)) {
+            return allow;
+        }
+
+        let answer = loop {
+            let mut line = String::new();
+            let read = write!(
+                self.tty.get_mut(),
+                "allow {syscall} from {library}? [y/n/always/never] "
+            )
+            .and_then(|_| self.tty.read_line(&mut line));
+            match read {
+                // Nobody to ask, so the config stands
+                Ok(0) | Err(_) => break Answer::No,
+                Ok(_) => {
+                    if let Some(answer) = Answer::parse(&line) {
+                        break answer;
+                    }
+                }
+            }
+        };
+
+        if matches!(answer, Answer::Always | Answer::Never) {
+            self.remembered
+                .insert((library.to_string(), syscall), answer.allows());
+            if let Some((path, config)) = self.save.as_mut() {
+                remember(config, library, syscall, answer.allows());
+                let saved = File::create(&*path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|file| {
+                        serde_yaml::to_writer(file, config).map_err(|e| e.to_string())
+                    });
+                if let Err(e) = saved {
+                    warning!("Failed to save answer to {}: {e}", path.display());
+                }
+            }
+        }
+        answer.allows()
+    }
+
+    /// hooks asks about each syscall the config blocks or has no rule for.
+    pub fn hooks(asker: &RefCell<Asker>) -> Hooks<'_> {
+        Hooks::default()
+            .on_violation(|_pid, exit| match exit {
+                ChildExit::IllegalSyscall {
+                    syscall, library, ..
+                } if asker.borrow_mut().ask(library, *syscall) => Ruling::Allow,
+                _ => Ruling::Enforce,
+            })
+            .on_unknown(|event| match event.stack.first() {
+                Some(library) if asker.borrow_mut().ask(library, event.syscall) => Judgement::Allow,
+                Some(_) => Judgement::Block,
+                // There's nothing to write a rule for
+                None => Judgement::Defer,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConfigEntry;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_remember() {
+        assert_eq!(Answer::parse("Always\n"), Some(Answer::Always));
+        assert_eq!(Answer::parse("maybe"), None);
+
+        let mut config = Config::default();
+        remember(&mut config, "/usr/lib/libfoo.so", Sysno::write, false);
+        remember(&mut config, "/usr/lib/libfoo.so", Sysno::read, true);
+        remember(&mut config, "/usr/lib/libfoo.so", Sysno::write, true);
+        assert_eq!(
+            config.shared_objects["/usr/lib/libfoo.so"],
+            ConfigEntry {
+                allow: Some(BTreeSet::from([Sysno::read, Sysno::write])),
+                block: Some(BTreeSet::new()),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use crate::coalesce::Coalescer;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+use syscalls::Sysno;
+
+/// SyscallRecord: an audit log line for a syscall, as written here and read back by `crabtrap aggregate`
+///
+/// Each line also has `"event": "syscall"`. Syscalls are written by name, and read by name or number.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyscallRecord {
+    #[serde(default)]
+    pub pid: i32,
+    #[serde(deserialize_with = "crate::names::deserialize")]
+    pub syscall: Sysno,
+    /// The library the syscall was attributed to, if any
+    pub library: Option<String>,
+    /// The id of the rule that decided it, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// The arguments captured for it, see Capture
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub args: Map<String, Value>,
+    /// How many identical syscalls in a row the line stands for
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub count: u64,
+}
+
+fn one() -> u64 {
+    1
+}
+
+fn is_one(count: &u64) -> bool {
+    *count == 1
+}
+
+/// AuditLog: one JSON object per line for each syscall the traced tree makes, see SyscallRecord
+///
+/// Runs of identical consecutive syscalls are written once, with a `count` of how many there were.
+pub struct AuditLog {
+    writer: BufWriter<File>,
+    runs: Coalescer<SyscallRecord>,
+}
+
+impl AuditLog {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<AuditLog> {
+        Ok(AuditLog {
+            writer: BufWriter::new(File::create(path)?),
+            runs: Coalescer::default(),
+        })
+    }
+
+    /// syscall logs a syscall entry, along with the library it was attributed to, the id of the rule that decided it
+    /// and whichever arguments were captured.
+    pub fn syscall(
+        &mut self,
+        pid: Pid,
+        syscall: Sysno,
+        library: Option<&str>,
+        rule: Option<&str>,
+        args: Map<String, Value>,
+    ) {
+        let record = SyscallRecord {
+            pid: pid.as_raw(),
+            syscall,
+            library: library.map(String::from),
+            rule: rule.map(String::from),
+            args,
+            count: 1,
+        };
+        if let Some(run) = self.runs.push(record) {
+            self.write(run).expect("failed to write audit log");
+        }
+    }
+
+    fn write(&mut self, (record, count): (SyscallRecord, u64)) -> io::Result<()> {
+        let mut line = serde_json::to_value(SyscallRecord { count, ..record })?;
+        line["event"] = "syscall".into();
+        writeln!(self.writer, "{line}")
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        if let Some(run) = self.runs.finish() {
+            // There's nobody left to report a failure to
+            let _ = self.write(run);
+        }
+    }
+}
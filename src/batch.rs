@@ -0,0 +1,154 @@
+use crate::{c_path, execute_with_stats, resolve, ChildExit, Config, RunStats};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{CStr, CString},
+    path::PathBuf,
+};
+
+/// Job: one line of input to `crabtrap batch`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    /// Copied to the result as-is, so results can be matched up with jobs when they run concurrently
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    /// The target executable, looked up in the target's PATH if it's just a name
+    pub target: String,
+    /// The arguments after argv[0]
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// argv[0], if not the target's path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub argv0: Option<String>,
+    /// Start the target as a login shell, see argv
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub login: bool,
+    /// The target's environment. Defaults to our own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<BTreeMap<String, String>>,
+    /// The path to the config file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<PathBuf>,
+}
+
+/// argv builds the argument list for a target from its path and the arguments after argv[0]. argv[0] is `argv0` if
+/// given and the path otherwise, with a `-` in front if `login`, which is how shells tell they're login shells.
+pub fn argv(target: &str, argv0: Option<&str>, login: bool, args: &[String]) -> Vec<CString> {
+    let argv0 = argv0.unwrap_or(target);
+    let argv0 = if login {
+        format!("-{argv0}")
+    } else {
+        argv0.to_string()
+    };
+    [argv0]
+        .iter()
+        .chain(args)
+        .map(|arg| CString::new(arg.as_str()).expect("argument contains a nul byte"))
+        .collect()
+}
+
+/// JobResult: one line of output from `crabtrap batch`, either the outcome of the run or why there wasn't one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JobResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit: Option<ChildExit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<RunStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl JobResult {
+    pub fn failed(id: Option<serde_json::Value>, error: String) -> JobResult {
+        JobResult {
+            id,
+            exit: None,
+            stats: None,
+            error: Some(error),
+        }
+    }
+}
+
+impl Job {
+    /// run traces the job's target to completion in this process.
+    pub fn run(&self) -> JobResult {
+        let config = self
+            .config
+            .as_ref()
+            .map_or_else(Config::new, Config::from_file);
+        let args = argv(&self.target, self.argv0.as_deref(), self.login, &self.args);
+        let env = match &self.env {
+            Some(env) => env
+                .iter()
+                .map(|(key, val)| CString::new(format!("{key}={val}")).unwrap())
+                .collect::<Vec<_>>(),
+            None => env::vars()
+                .map(|(key, val)| CString::new(format!("{key}={val}")).unwrap())
+                .collect::<Vec<_>>(),
+        };
+        let env: Vec<&CStr> = env.iter().map(|s| s.as_c_str()).collect();
+        let target = match resolve(&self.target, &env, &config).and_then(c_path) {
+            Ok(target) => target,
+            Err(e) => return JobResult::failed(self.id.clone(), format!("{}: {e}", self.target)),
+        };
+
+        let (exit, stats) = execute_with_stats(
+            &target,
+            &args.iter().map(|s| s.as_c_str()).collect::<Vec<_>>(),
+            &env,
+            &config,
+        );
+        JobResult {
+            id: self.id.clone(),
+            exit: Some(exit),
+            stats: Some(stats),
+            error: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job() {
+        assert_eq!(
+            serde_json::from_str::<Job>(r#"{"id": 3, "target": "/bin/true"}"#).unwrap(),
+            Job {
+                id: Some(serde_json::Value::from(3)),
+                target: String::from("/bin/true"),
+                args: Vec::new(),
+                argv0: None,
+                login: false,
+                env: None,
+                config: None,
+            }
+        );
+
+        assert_eq!(
+            argv("/bin/sh", None, false, &[String::from("-c")]),
+            [c"/bin/sh", c"-c"]
+        );
+        assert_eq!(argv("/bin/busybox", Some("ls"), false, &[]), [c"ls"]);
+        assert_eq!(argv("/bin/bash", Some("bash"), true, &[]), [c"-bash"]);
+
+        assert_eq!(
+            serde_json::to_string(&JobResult::failed(None, String::from("bad job"))).unwrap(),
+            r#"{"error":"bad job"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&JobResult {
+                id: Some(serde_json::Value::from("a")),
+                exit: Some(ChildExit::Exited(0)),
+                stats: None,
+                error: None,
+            })
+            .unwrap(),
+            r#"{"id":"a","exit":{"Exited":0}}"#
+        );
+    }
+}
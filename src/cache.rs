@@ -0,0 +1,277 @@
+use crate::{
+    config::{Check, Config},
+    log::warning,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::Path,
+};
+use syscalls::Sysno;
+
+/// CachedDecision: one rule decision, as saved in a snapshot
+#[derive(Serialize, Deserialize)]
+struct CachedDecision {
+    /// The `binaries` section the decision was made under, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    binary: Option<String>,
+    library: String,
+    syscall: Sysno,
+    check: Check,
+}
+
+/// Snapshot: the decision cache as written to disk
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// The fingerprint of the rules the decisions were made with
+    fingerprint: u64,
+    decisions: Vec<CachedDecision>,
+}
+
+/// RegionDecisions: what's been worked out about a file's region of a memory map
+#[derive(Debug)]
+struct RegionDecisions {
+    /// The `binaries` section it was worked out under
+    binary: Option<String>,
+    /// The name the region's rules are looked up by, see Config::library_name
+    library: String,
+    checks: HashMap<Sysno, Check>,
+}
+
+/// DecisionCache: the rule decisions made so far, by binary, library and syscall
+///
+/// Checking the rules only depends on the binary's section, the library and the syscall, so each is only checked
+/// once. The cache can be saved at the end of a run and loaded at the start of the next, as long as the rules haven't
+/// changed.
+///
+/// Naming the library at an address takes longer than checking its rules, so the names and decisions are also kept by
+/// the region they were made for, for as long as the map's regions stay the same. See ProcessMap::generation.
+#[derive(Debug, Default)]
+pub struct DecisionCache {
+    fingerprint: u64,
+    decisions: BTreeMap<(Option<String>, String, Sysno), Check>,
+    /// By map generation and the index of the region in the map's files
+    regions: HashMap<(u64, usize), RegionDecisions>,
+    /// Checks answered from the cache this run
+    pub hits: u64,
+    /// Checks that had to go to the rules this run
+    pub misses: u64,
+}
+
+/// fingerprint identifies the rules in a config, so a cache made with other rules isn't used.
+///
+/// DefaultHasher isn't guaranteed to be stable across Rust releases, which at worst means starting with a cold cache.
+fn fingerprint(config: &Config) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(&(&config.shared_objects, &config.binaries))
+        .expect("failed to serialize rules")
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+impl DecisionCache {
+    pub fn new(config: &Config) -> DecisionCache {
+        DecisionCache {
+            fingerprint: fingerprint(config),
+            ..Default::default()
+        }
+    }
+
+    /// load reads a cache saved by an earlier run, or starts a cold one if there isn't one for these rules.
+    pub fn load(config: &Config, path: &Path) -> DecisionCache {
+        let mut cache = DecisionCache::new(config);
+        let snapshot = match fs::read(path) {
+            Ok(contents) => serde_json::from_slice::<Snapshot>(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return cache,
+            Err(e) => {
+                warning!("Ignoring decision cache {}: {e}", path.display());
+                return cache;
+            }
+        };
+        match snapshot {
+            Ok(snapshot) if snapshot.fingerprint == cache.fingerprint => {
+                cache.decisions = snapshot
+                    .decisions
+                    .into_iter()
+                    .map(|decision| {
+                        (
+                            (decision.binary, decision.library, decision.syscall),
+                            decision.check,
+                        )
+                    })
+                    .collect();
+            }
+            Ok(_) => warning!(
+                "Ignoring decision cache {}, the rules have changed since it was saved",
+                path.display()
+            ),
+            Err(e) => warning!("Ignoring decision cache {}: {e}", path.display()),
+        }
+        cache
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let snapshot = Snapshot {
+            fingerprint: self.fingerprint,
+            decisions: self
+                .decisions
+                .iter()
+                .map(|((binary, library, syscall), check)| CachedDecision {
+                    binary: binary.clone(),
+                    library: library.clone(),
+                    syscall: *syscall,
+                    check: check.clone(),
+                })
+                .collect(),
+        };
+        fs::write(path, serde_json::to_vec(&snapshot)?)
+    }
+
+    /// check is Config::check_in, remembering the result.
+    pub fn check(
+        &mut self,
+        config: &Config,
+        binary: Option<&str>,
+        library: &str,
+        syscall: Sysno,
+    ) -> Check {
+        let key = (binary.map(String::from), library.to_string(), syscall);
+        if let Some(check) = self.decisions.get(&key) {
+            self.hits += 1;
+            return check.clone();
+        }
+        self.misses += 1;
+        let check = config.check_in(binary, library, syscall);
+        self.decisions.insert(key, check.clone());
+        check
+    }
+
+    /// check_region is check for the code in a file's region of a memory map, given by the map's generation and the
+    /// region's index in it. `name` names the library, see library_name, and is only called the first time the
+    /// region's seen.
+    pub fn check_region(
+        &mut self,
+        config: &Config,
+        binary: Option<&str>,
+        region: (u64, usize),
+        syscall: Sysno,
+        name: impl FnOnce() -> String,
+    ) -> Check {
+        // The name depends on the binary's section too, which an exec changes a stop before the map's reread
+        if self
+            .regions
+            .get(&region)
+            .is_some_and(|decisions| decisions.binary.as_deref() != binary)
+        {
+            self.regions.remove(&region);
+        }
+        let decisions = self
+            .regions
+            .entry(region)
+            .or_insert_with(|| RegionDecisions {
+                binary: binary.map(String::from),
+                library: name(),
+                checks: HashMap::new(),
+            });
+        if let Some(check) = decisions.checks.get(&syscall) {
+            self.hits += 1;
+            return check.clone();
+        }
+        let library = decisions.library.clone();
+        let check = self.check(config, binary, &library, syscall);
+        if let Some(decisions) = self.regions.get_mut(&region) {
+            decisions.checks.insert(syscall, check.clone());
+        }
+        check
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigEntry, Rule, RuleList};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_snapshot() {
+        let mut config = Config {
+            shared_objects: BTreeMap::from([(
+                String::from("/usr/lib/libfoo.so"),
+                ConfigEntry {
+                    allow: Some(BTreeSet::from([Sysno::read])),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("crabtrap_cache_{}", std::process::id()));
+
+        let mut cache = DecisionCache::new(&config);
+        assert_eq!(
+            cache.check(&config, None, "/usr/lib/libfoo.so", Sysno::read),
+            Check::Allowed(Rule {
+                id: None,
+                list: RuleList::Allow
+            })
+        );
+        cache.check(&config, None, "/usr/lib/libfoo.so", Sysno::read);
+        assert_eq!((cache.hits, cache.misses), (1, 1));
+        // A binary's decisions are kept apart from everyone else's, even where its rules are the same
+        cache.check(
+            &config,
+            Some("/usr/bin/foo"),
+            "/usr/lib/libfoo.so",
+            Sysno::read,
+        );
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+        cache.save(&path).unwrap();
+        assert_eq!(
+            DecisionCache::load(&config, &path).decisions,
+            cache.decisions
+        );
+
+        config.shared_objects.clear();
+        assert!(DecisionCache::load(&config, &path).decisions.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_regions() {
+        let config = Config {
+            shared_objects: BTreeMap::from([(
+                String::from("/usr/lib/libfoo.so"),
+                ConfigEntry {
+                    allow: Some(BTreeSet::from([Sysno::read])),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let mut cache = DecisionCache::new(&config);
+        let mut named = 0;
+        let mut check = |cache: &mut DecisionCache, binary, region, syscall| {
+            cache.check_region(&config, binary, region, syscall, || {
+                named += 1;
+                String::from("/usr/lib/libfoo.so")
+            })
+        };
+
+        assert!(matches!(
+            check(&mut cache, None, (0, 3), Sysno::read),
+            Check::Allowed(_)
+        ));
+        check(&mut cache, None, (0, 3), Sysno::read);
+        assert_eq!(
+            check(&mut cache, None, (0, 3), Sysno::write),
+            Check::Unknown
+        );
+        // The same region of a new generation of the map, or under another binary, is named again
+        check(&mut cache, None, (1, 3), Sysno::read);
+        check(&mut cache, Some("/usr/bin/foo"), (1, 3), Sysno::read);
+        assert_eq!(named, 3);
+        assert_eq!((cache.hits, cache.misses), (2, 3));
+    }
+}
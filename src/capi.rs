@@ -0,0 +1,232 @@
+use crate::{execute_with_stats, ChildExit, Config};
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+/// CrabtrapConfig: a config loaded for C callers, who only ever see a pointer to one
+pub struct CrabtrapConfig {
+    config: Config,
+}
+
+/// CrabtrapExitKind: which way a run ended, going by the variants of ChildExit
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrabtrapExitKind {
+    Exited,
+    IllegalSyscall,
+    WriteQuotaExceeded,
+    SyscallStorm,
+    Daemonized,
+    ReadOnlyFilesystem,
+    Signaled,
+    TimedOut,
+    SetupFailed,
+    Detached,
+}
+
+/// CrabtrapResult: how a run ended, filled in by crabtrap_execute. The strings belong to the result until it's passed
+/// to crabtrap_result_free.
+#[repr(C)]
+pub struct CrabtrapResult {
+    pub kind: CrabtrapExitKind,
+    /// The exit code for Exited, the signal for Signaled, the errno for SetupFailed and the pid for Daemonized.
+    /// Otherwise 0.
+    pub code: i32,
+    /// The syscall's number for IllegalSyscall, SyscallStorm and ReadOnlyFilesystem. Otherwise -1.
+    pub syscall: i64,
+    /// The library that made the syscall for IllegalSyscall, the call site for SyscallStorm and the file for
+    /// WriteQuotaExceeded and ReadOnlyFilesystem. Otherwise NULL.
+    pub subject: *mut c_char,
+    /// The id of the rule that was broken, if it has one. Otherwise NULL.
+    pub rule: *mut c_char,
+    pub processes: u64,
+    pub syscalls: u64,
+    pub wall_time_us: u64,
+    /// The exit and the run's stats as a JSON object with `exit` and `stats`, for everything the fields above leave
+    /// out
+    pub json: *mut c_char,
+}
+
+thread_local! {
+    /// Why the last call on this thread that failed did
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// set_error records why a call failed, for crabtrap_last_error.
+fn set_error(error: impl ToString) {
+    let error = CString::new(error.to_string().replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+}
+
+/// string copies `s` into a string the caller frees through crabtrap_result_free.
+fn string(s: &str) -> *mut c_char {
+    CString::new(s.replace('\0', "")).unwrap().into_raw()
+}
+
+/// strings collects a NULL-terminated array of strings, which may itself be NULL for none.
+unsafe fn strings<'a>(mut array: *const *const c_char) -> Vec<&'a CStr> {
+    let mut strings = Vec::new();
+    while !array.is_null() && !(*array).is_null() {
+        strings.push(CStr::from_ptr(*array));
+        array = array.add(1);
+    }
+    strings
+}
+
+/// crabtrap_last_error returns why the last call on this thread that failed did, or NULL if none has. The string
+/// stays valid until the next call that fails.
+#[no_mangle]
+pub extern "C" fn crabtrap_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// crabtrap_config_load reads a config file, along with any it extends. Returns NULL if it can't, see
+/// crabtrap_last_error.
+///
+/// # Safety
+///
+/// `path` has to be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn crabtrap_config_load(path: *const c_char) -> *mut CrabtrapConfig {
+    if path.is_null() {
+        set_error("path is NULL");
+        return ptr::null_mut();
+    }
+    let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+    match Config::load(&path) {
+        Ok(config) => Box::into_raw(Box::new(CrabtrapConfig { config })),
+        Err(e) => {
+            set_error(format!("failed to load config {path}: {e}"));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// crabtrap_config_free frees a config from crabtrap_config_load. Does nothing given NULL.
+///
+/// # Safety
+///
+/// `config` has to have come from crabtrap_config_load, and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn crabtrap_config_free(config: *mut CrabtrapConfig) {
+    if !config.is_null() {
+        drop(Box::from_raw(config));
+    }
+}
+
+/// crabtrap_execute runs `path` with `argv` and `envp` under `config`, or with nothing enforced if it's NULL, and
+/// fills in `result` with how it went. `argv` and `envp` are NULL-terminated, like execve's. Returns 0 once the run
+/// is over, whatever the target did, and -1 if it couldn't be run at all, see crabtrap_last_error.
+///
+/// The target is traced on a thread of its own, which only waits for its own children, so this can be called from
+/// several threads at once, and the caller's other children are left for it to wait for.
+///
+/// # Safety
+///
+/// The strings have to be valid C strings, `config` NULL or from crabtrap_config_load, and `result` somewhere a
+/// CrabtrapResult can be written.
+#[no_mangle]
+pub unsafe extern "C" fn crabtrap_execute(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    config: *const CrabtrapConfig,
+    result: *mut CrabtrapResult,
+) -> i32 {
+    if path.is_null() || result.is_null() {
+        set_error("path and result can't be NULL");
+        return -1;
+    }
+    let path = CStr::from_ptr(path);
+    let (args, env) = (strings(argv), strings(envp));
+    let default = Config::default();
+    let config = config.as_ref().map_or(&default, |config| &config.config);
+
+    // Unwinding into C is undefined, and the tracer panics when it can't carry on
+    let run = panic::catch_unwind(AssertUnwindSafe(|| {
+        execute_with_stats(path, &args, &env, config)
+    }));
+    let (exit, stats) = match run {
+        Ok(run) => run,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            set_error(format!("the tracer failed: {message}"));
+            return -1;
+        }
+    };
+
+    let (kind, code, syscall, subject) = match &exit {
+        ChildExit::Exited(code) => (CrabtrapExitKind::Exited, *code, None, None),
+        ChildExit::IllegalSyscall {
+            syscall, library, ..
+        } => (
+            CrabtrapExitKind::IllegalSyscall,
+            0,
+            Some(*syscall),
+            Some(library.as_str()),
+        ),
+        ChildExit::WriteQuotaExceeded(_, file) => (
+            CrabtrapExitKind::WriteQuotaExceeded,
+            0,
+            None,
+            Some(file.as_str()),
+        ),
+        ChildExit::SyscallStorm(syscall, site) => (
+            CrabtrapExitKind::SyscallStorm,
+            0,
+            Some(*syscall),
+            Some(site.as_str()),
+        ),
+        ChildExit::Daemonized(pid) => (CrabtrapExitKind::Daemonized, *pid, None, None),
+        ChildExit::ReadOnlyFilesystem(syscall, file) => (
+            CrabtrapExitKind::ReadOnlyFilesystem,
+            0,
+            Some(*syscall),
+            Some(file.as_str()),
+        ),
+        ChildExit::Signaled(signal) => (CrabtrapExitKind::Signaled, *signal, None, None),
+        ChildExit::TimedOut(_) => (CrabtrapExitKind::TimedOut, 0, None, None),
+        ChildExit::SetupFailed { errno, .. } => {
+            (CrabtrapExitKind::SetupFailed, *errno as i32, None, None)
+        }
+        ChildExit::Detached => (CrabtrapExitKind::Detached, 0, None, None),
+    };
+    let json = serde_json::json!({ "exit": exit, "stats": stats }).to_string();
+    result.write(CrabtrapResult {
+        kind,
+        code,
+        syscall: syscall.map_or(-1, |syscall| syscall.id() as i64),
+        subject: subject.map_or(ptr::null_mut(), string),
+        rule: exit.rule().map_or(ptr::null_mut(), string),
+        processes: stats.processes,
+        syscalls: stats.syscalls,
+        wall_time_us: stats.usage.wall_time.as_micros() as u64,
+        json: string(&json),
+    });
+    0
+}
+
+/// crabtrap_result_free frees the strings in a result crabtrap_execute filled in, and sets them to NULL.
+///
+/// # Safety
+///
+/// `result` has to have been filled in by crabtrap_execute, or be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn crabtrap_result_free(result: *mut CrabtrapResult) {
+    let Some(result) = result.as_mut() else {
+        return;
+    };
+    for string in [&mut result.subject, &mut result.rule, &mut result.json] {
+        if !string.is_null() {
+            drop(CString::from_raw(*string));
+        }
+        *string = ptr::null_mut();
+    }
+}
@@ -0,0 +1,290 @@
+use crate::{fd::FdTable, memory::read_string};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{collections::BTreeMap, str::FromStr};
+use syscalls::Sysno;
+
+/// ArgKind: how to decode an argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArgKind {
+    /// Logged as a number
+    Int,
+    /// Logged as the number along with what it refers to
+    Fd,
+    /// A pointer to a nul-terminated string, which has to be read out of the tracee
+    Str,
+}
+
+use ArgKind::{Fd, Int, Str};
+
+/// signature returns the names and kinds of a syscall's arguments, for the syscalls we know how to decode.
+fn signature(syscall: Sysno) -> &'static [(&'static str, ArgKind)] {
+    match syscall {
+        Sysno::read | Sysno::write => &[("fd", Fd), ("buf", Int), ("count", Int)],
+        Sysno::pread64 | Sysno::pwrite64 => {
+            &[("fd", Fd), ("buf", Int), ("count", Int), ("offset", Int)]
+        }
+        Sysno::readv | Sysno::writev => &[("fd", Fd), ("iov", Int), ("iovcnt", Int)],
+        Sysno::openat => &[("dirfd", Fd), ("path", Str), ("flags", Int), ("mode", Int)],
+        Sysno::openat2 => &[("dirfd", Fd), ("path", Str), ("how", Int), ("size", Int)],
+        Sysno::close | Sysno::fchdir | Sysno::fsync => &[("fd", Fd)],
+        Sysno::execve => &[("path", Str), ("argv", Int), ("envp", Int)],
+        Sysno::execveat => &[
+            ("dirfd", Fd),
+            ("path", Str),
+            ("argv", Int),
+            ("envp", Int),
+            ("flags", Int),
+        ],
+        Sysno::unlinkat => &[("dirfd", Fd), ("path", Str), ("flags", Int)],
+        Sysno::mkdirat | Sysno::faccessat | Sysno::fchmodat => {
+            &[("dirfd", Fd), ("path", Str), ("mode", Int)]
+        }
+        Sysno::fstatat => &[
+            ("dirfd", Fd),
+            ("path", Str),
+            ("statbuf", Int),
+            ("flags", Int),
+        ],
+        Sysno::readlinkat => &[("dirfd", Fd), ("path", Str), ("buf", Int), ("bufsiz", Int)],
+        Sysno::renameat | Sysno::renameat2 => &[
+            ("olddirfd", Fd),
+            ("oldpath", Str),
+            ("newdirfd", Fd),
+            ("newpath", Str),
+            ("flags", Int),
+        ],
+        Sysno::chdir => &[("path", Str)],
+        Sysno::truncate => &[("path", Str), ("length", Int)],
+        Sysno::socket => &[("domain", Int), ("type", Int), ("protocol", Int)],
+        Sysno::connect | Sysno::bind => &[("fd", Fd), ("addr", Int), ("addrlen", Int)],
+        Sysno::sendto | Sysno::recvfrom => &[
+            ("fd", Fd),
+            ("buf", Int),
+            ("len", Int),
+            ("flags", Int),
+            ("addr", Int),
+            ("addrlen", Int),
+        ],
+        Sysno::mmap => &[
+            ("addr", Int),
+            ("length", Int),
+            ("prot", Int),
+            ("flags", Int),
+            ("fd", Fd),
+            ("offset", Int),
+        ],
+        Sysno::munmap => &[("addr", Int), ("length", Int)],
+        Sysno::mprotect => &[("addr", Int), ("length", Int), ("prot", Int)],
+        Sysno::ioctl => &[("fd", Fd), ("request", Int), ("arg", Int)],
+        Sysno::lseek => &[("fd", Fd), ("offset", Int), ("whence", Int)],
+        Sysno::dup => &[("oldfd", Fd)],
+        Sysno::dup3 => &[("oldfd", Fd), ("newfd", Int), ("flags", Int)],
+        Sysno::fcntl => &[("fd", Fd), ("cmd", Int), ("arg", Int)],
+        Sysno::getrandom => &[("buf", Int), ("buflen", Int), ("flags", Int)],
+        Sysno::kill => &[("pid", Int), ("sig", Int)],
+        Sysno::exit | Sysno::exit_group => &[("status", Int)],
+        _ => &[],
+    }
+}
+
+/// arg finds a named argument of `syscall`. Any argument can also be captured raw as `arg0` to `arg5`.
+fn arg(syscall: Sysno, name: &str) -> Option<(usize, ArgKind)> {
+    if let Some(index) = name
+        .strip_prefix("arg")
+        .and_then(|i| usize::from_str(i).ok())
+    {
+        return (index < 6).then_some((index, Int));
+    }
+    signature(syscall)
+        .iter()
+        .position(|(arg, _)| *arg == name)
+        .map(|index| (index, signature(syscall)[index].1))
+}
+
+/// Capture: which arguments of which syscalls to decode into the audit log, e.g.
+///
+/// ```yaml
+/// capture:
+///   openat: [path, flags]
+///   write: [fd, count]
+/// ```
+///
+/// Syscalls that aren't listed are logged without their arguments.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(
+    try_from = "BTreeMap<String, Vec<String>>",
+    into = "BTreeMap<String, Vec<String>>"
+)]
+pub struct Capture {
+    syscalls: BTreeMap<Sysno, Vec<String>>,
+}
+
+impl TryFrom<BTreeMap<String, Vec<String>>> for Capture {
+    type Error = String;
+
+    fn try_from(capture: BTreeMap<String, Vec<String>>) -> Result<Capture, String> {
+        let mut syscalls = BTreeMap::new();
+        for (name, args) in capture {
+            let syscall = Sysno::from_str(&name).map_err(|_| format!("unknown syscall {name}"))?;
+            if let Some(unknown) = args.iter().find(|arg| self::arg(syscall, arg).is_none()) {
+                return Err(format!("{name} has no argument called {unknown}"));
+            }
+            syscalls.insert(syscall, args);
+        }
+        Ok(Capture { syscalls })
+    }
+}
+
+impl From<Capture> for BTreeMap<String, Vec<String>> {
+    fn from(capture: Capture) -> BTreeMap<String, Vec<String>> {
+        capture
+            .syscalls
+            .into_iter()
+            .map(|(syscall, args)| (syscall.name().to_string(), args))
+            .collect()
+    }
+}
+
+impl Capture {
+    pub fn is_empty(&self) -> bool {
+        self.syscalls.is_empty()
+    }
+
+    /// decode reads the configured arguments of a syscall at its entry stop.
+    pub fn decode(
+        &self,
+        pid: Pid,
+        syscall: Sysno,
+        args: &[u64; 6],
+        fds: &mut FdTable,
+    ) -> Map<String, Value> {
+        let Some(names) = self.syscalls.get(&syscall) else {
+            return Map::new();
+        };
+
+        names
+            .iter()
+            .filter_map(|name| {
+                let (index, kind) = arg(syscall, name)?;
+                let value = args[index];
+                let decoded = match kind {
+                    Int => json!(value),
+                    Fd => json!({
+                        "fd": value as i32,
+                        "target": fds.lookup(pid, value as i32),
+                    }),
+                    Str => json!(read_string(pid, value)),
+                };
+                Some((name.clone(), decoded))
+            })
+            .collect()
+    }
+}
+
+/// The arguments that are addresses, which read better in hex
+const POINTERS: &[&str] = &["buf", "iov", "argv", "envp", "statbuf", "how", "addr"];
+
+/// describe formats a syscall at its entry stop the way strace would, with each argument decoded as far as we
+/// know how and descriptors followed by what they refer to, e.g. `openat(AT_FDCWD, "/etc/hosts", 524288, 0)`.
+pub fn describe(pid: Pid, syscall: Sysno, args: &[u64; 6], fds: &mut FdTable) -> String {
+    render(
+        syscall,
+        args,
+        |fd| fds.lookup(pid, fd).map(str::to_string),
+        |addr| read_string(pid, addr),
+    )
+}
+
+/// render is describe with the reads from the tracee passed in.
+fn render(
+    syscall: Sysno,
+    args: &[u64; 6],
+    mut target: impl FnMut(i32) -> Option<String>,
+    string: impl Fn(u64) -> Option<String>,
+) -> String {
+    let signature = signature(syscall);
+    let args: Vec<String> = if signature.is_empty() {
+        // Without a signature we don't know how many arguments there are, so all of them go in
+        args.iter().map(|&arg| format!("{arg:#x}")).collect()
+    } else {
+        signature
+            .iter()
+            .zip(args)
+            .map(|(&(name, kind), &value)| match kind {
+                Int if POINTERS.contains(&name) => format!("{value:#x}"),
+                Int => (value as i64).to_string(),
+                Fd if value as i32 == nix::libc::AT_FDCWD => "AT_FDCWD".to_string(),
+                Fd => match target(value as i32) {
+                    Some(target) => format!("{}<{target}>", value as i32),
+                    None => (value as i32).to_string(),
+                },
+                Str => match string(value) {
+                    Some(string) => format!("{string:?}"),
+                    None => format!("{value:#x}"),
+                },
+            })
+            .collect()
+    };
+    format!("{}({})", syscall.name(), args.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let capture: Capture = serde_yaml::from_str(
+            "openat: [path, flags]\nwrite: [fd, count]\ngettimeofday: []\nbrk: [arg0]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            capture.syscalls,
+            BTreeMap::from([
+                (
+                    Sysno::openat,
+                    vec![String::from("path"), String::from("flags")]
+                ),
+                (
+                    Sysno::write,
+                    vec![String::from("fd"), String::from("count")]
+                ),
+                (Sysno::gettimeofday, Vec::new()),
+                (Sysno::brk, vec![String::from("arg0")]),
+            ])
+        );
+
+        assert!(serde_yaml::from_str::<Capture>("openat: [nope]").is_err());
+        assert!(serde_yaml::from_str::<Capture>("brk: [arg6]").is_err());
+        assert!(serde_yaml::from_str::<Capture>("not_a_syscall: []").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let target = |fd| (fd == 3).then(|| String::from("/etc/hosts"));
+        let string = |addr| (addr == 0x1000).then(|| String::from("a \"file\""));
+        assert_eq!(
+            render(
+                Sysno::openat,
+                &[-100i64 as u64, 0x1000, 524288, 0, 0, 0],
+                target,
+                string
+            ),
+            r#"openat(AT_FDCWD, "a \"file\"", 524288, 0)"#
+        );
+        assert_eq!(
+            render(Sysno::write, &[3, 0x2000, 5, 0, 0, 0], target, string),
+            "write(3</etc/hosts>, 0x2000, 5)"
+        );
+        assert_eq!(
+            render(Sysno::execve, &[0, 0, 0, 0, 0, 0], target, string),
+            "execve(0x0, 0x0, 0x0)"
+        );
+        assert_eq!(
+            render(Sysno::getpid, &[0, 1, 2, 3, 4, 0xff], target, string),
+            "getpid(0x0, 0x1, 0x2, 0x3, 0x4, 0xff)"
+        );
+    }
+}
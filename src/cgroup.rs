@@ -0,0 +1,187 @@
+use crate::{config::CgroupConfig, log::warning};
+use nix::{libc, unistd};
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    os::fd::{AsRawFd, BorrowedFd, RawFd},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The period cpu.max's quota is out of, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+/// How long to wait for whatever's left in the cgroup to die before giving up on removing it
+const REMOVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Cgroup: a cgroup made for one run, which is removed, along with anything left in it, when it's dropped
+pub struct Cgroup {
+    path: PathBuf,
+    /// Its cgroup.procs, opened ahead of time so that the child can join without allocating
+    procs: File,
+    /// The controllers that weren't enabled in the parent until we enabled them, to be disabled again afterwards
+    enabled: Enabled,
+}
+
+/// Enabled: controllers enabled in a cgroup's cgroup.subtree_control for a run
+struct Enabled {
+    parent: PathBuf,
+    controllers: Vec<&'static str>,
+}
+
+impl Enabled {
+    /// disable disables the controllers again, unless there are other cgroups under the parent by now, which may be
+    /// using them.
+    fn disable(&self) {
+        if self.controllers.is_empty() {
+            return;
+        }
+        let children = fs::read_dir(&self.parent).map(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        });
+        if children.unwrap_or(true) {
+            return;
+        }
+        let disabled: Vec<String> = self
+            .controllers
+            .iter()
+            .map(|controller| format!("-{controller}"))
+            .collect();
+        if let Err(e) = fs::write(
+            self.parent.join("cgroup.subtree_control"),
+            disabled.join(" "),
+        ) {
+            warning!(
+                "Failed to disable {} in cgroup {}: {e}",
+                self.controllers.join(", "),
+                self.parent.display()
+            );
+        }
+    }
+}
+
+impl Cgroup {
+    /// create makes a cgroup with the configured limits under the configured parent, enabling the controllers it
+    /// needs there. Nothing's in it until a process joins.
+    pub fn create(config: &CgroupConfig) -> io::Result<Cgroup> {
+        let parent = config.parent.clone();
+        let limits = [
+            (
+                "memory",
+                "memory.max",
+                config.memory_max.map(|max| max.to_string()),
+            ),
+            (
+                "pids",
+                "pids.max",
+                config.pids_max.map(|max| max.to_string()),
+            ),
+            (
+                "cpu",
+                "cpu.max",
+                config.cpu_percent.map(|percent| {
+                    format!(
+                        "{} {CPU_PERIOD}",
+                        percent.get().saturating_mul(CPU_PERIOD / 100)
+                    )
+                }),
+            ),
+        ];
+        let subtree_control = fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+        let controllers: Vec<&'static str> = limits
+            .iter()
+            .filter(|(_, _, value)| value.is_some())
+            .map(|(controller, _, _)| *controller)
+            .filter(|controller| !subtree_control.split_whitespace().any(|c| c == *controller))
+            .collect();
+        if !controllers.is_empty() {
+            let enabling: Vec<String> = controllers
+                .iter()
+                .map(|controller| format!("+{controller}"))
+                .collect();
+            fs::write(parent.join("cgroup.subtree_control"), enabling.join(" "))?;
+        }
+        let enabled = Enabled {
+            parent,
+            controllers,
+        };
+
+        // Runs can happen on several threads at once
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = enabled.parent.join(format!(
+            "crabtrap-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Err(e) = fs::create_dir(&path) {
+            enabled.disable();
+            return Err(e);
+        }
+        let procs = match OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+        {
+            Ok(procs) => procs,
+            Err(e) => {
+                let _ = fs::remove_dir(&path);
+                enabled.disable();
+                return Err(e);
+            }
+        };
+        // From here on dropping it cleans up
+        let cgroup = Cgroup {
+            path,
+            procs,
+            enabled,
+        };
+        for (_, file, value) in limits {
+            if let Some(value) = value {
+                fs::write(cgroup.path.join(file), value)?;
+            }
+        }
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// procs returns the descriptor `join` takes.
+    pub fn procs(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Whatever's still running, e.g. if the run ended at a violation before everything had been reaped. Nothing
+        // in it can have been let go of to carry on by itself, since runs with a cgroup never detach. The kernel might
+        // be too old for cgroup.kill, but then there's nothing better to do than wait and see.
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        let deadline = Instant::now() + REMOVE_TIMEOUT;
+        loop {
+            match fs::remove_dir(&self.path) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) && Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => {
+                    warning!("Failed to remove cgroup {}: {e}", self.path.display());
+                    break;
+                }
+                Ok(()) => break,
+            }
+        }
+        self.enabled.disable();
+    }
+}
+
+/// join moves the calling process into the cgroup whose cgroup.procs is open as `procs`. Processes it starts after
+/// that are in it too. Safe to call between fork and exec.
+pub fn join(procs: RawFd) -> nix::Result<()> {
+    // Writing 0 means whoever's writing
+    unistd::write(unsafe { BorrowedFd::borrow_raw(procs) }, b"0").map(drop)
+}
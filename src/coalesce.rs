@@ -0,0 +1,46 @@
+/// Coalescer: collapses runs of identical consecutive items into one item and a count
+#[derive(Debug)]
+pub struct Coalescer<T> {
+    run: Option<(T, u64)>,
+}
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Coalescer<T> {
+        Coalescer { run: None }
+    }
+}
+
+impl<T: PartialEq> Coalescer<T> {
+    /// push adds an item, and returns the run it ended, if any.
+    pub fn push(&mut self, item: T) -> Option<(T, u64)> {
+        match self.run.as_mut() {
+            Some((last, count)) if *last == item => {
+                *count += 1;
+                None
+            }
+            _ => self.run.replace((item, 1)),
+        }
+    }
+
+    /// finish returns the run in progress, if any.
+    pub fn finish(&mut self) -> Option<(T, u64)> {
+        self.run.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce() {
+        let mut coalescer = Coalescer::default();
+        assert_eq!(coalescer.push("write"), None);
+        assert_eq!(coalescer.push("write"), None);
+        assert_eq!(coalescer.push("write"), None);
+        assert_eq!(coalescer.push("read"), Some(("write", 3)));
+        assert_eq!(coalescer.push("write"), Some(("read", 1)));
+        assert_eq!(coalescer.finish(), Some(("write", 1)));
+        assert_eq!(coalescer.finish(), None);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    cgroup::{self, Cgroup},
+    credentials, deterministic,
+    hooks::Hooks,
+    inherit,
+    landlock::Ruleset,
+    limits, namespace, parent, ptrace_options, scrub, set_subreaper, ChildExit, Config, Output,
+    RunStats,
+};
+use nix::{
+    sys::{
+        ptrace::{detach, seize, traceme},
+        signal::Signal,
+        wait::waitpid,
+    },
+    unistd::{self, Pid},
+};
+use std::{
+    env,
+    ffi::OsString,
+    fs::File,
+    io,
+    os::unix::{ffi::OsStrExt, process::CommandExt as _},
+    process::{ChildStderr, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+/// CommandExt: runs a `std::process::Command` under crabtrap, e.g.
+///
+/// ```no_run
+/// use crabtrap::{CommandExt, Config};
+/// use std::{os::unix::process::CommandExt as _, process::Command};
+///
+/// let exit = Command::new("ls").arg("/").sandbox(&Config::default()).status_traced();
+/// // Programs like busybox go by argv[0], which std's `arg0` sets
+/// let exit = Command::new("/bin/busybox").arg0("ls").sandbox(&Config::default()).status_traced();
+/// ```
+pub trait CommandExt {
+    fn sandbox<'a>(&'a mut self, config: &'a Config) -> SandboxedCommand<'a>;
+}
+
+impl CommandExt for Command {
+    fn sandbox<'a>(&'a mut self, config: &'a Config) -> SandboxedCommand<'a> {
+        SandboxedCommand {
+            command: self,
+            config,
+        }
+    }
+}
+
+/// SandboxedCommand: a command that'll be traced once it's started
+pub struct SandboxedCommand<'a> {
+    command: &'a mut Command,
+    config: &'a Config,
+}
+
+impl<'a> SandboxedCommand<'a> {
+    /// spawn_traced starts the command stopped at its first instruction. Any pipes set up on the command are available
+    /// on the returned TracedChild, and nothing runs until it's waited on.
+    pub fn spawn_traced(&mut self) -> io::Result<TracedChild<'a>> {
+        // std forks for us, and there's no making a PID namespace's init after that
+        if self.config.namespaces.pid {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "PID namespaces need crabtrap to start the target itself",
+            ));
+        }
+        if self.config.subreaper {
+            set_subreaper();
+        }
+        let cgroup = self
+            .config
+            .cgroup
+            .as_ref()
+            .map(Cgroup::create)
+            .transpose()?;
+        if let Some(procs) = cgroup.as_ref().map(Cgroup::procs) {
+            unsafe {
+                self.command
+                    .pre_exec(move || cgroup::join(procs).map_err(io::Error::from));
+            }
+        }
+        if let Some(path) = &self.config.stdio.stdin {
+            self.command.stdin(File::open(path)?);
+        }
+        if let Some(stdout) = stdio(&self.config.stdio.stdout)? {
+            self.command.stdout(stdout);
+        }
+        if let Some(stderr) = stdio(&self.config.stdio.stderr)? {
+            self.command.stderr(stderr);
+        }
+        if self.config.environment.scrub {
+            for var in scrub::UNSECURE_VARIABLES {
+                self.command.env_remove(var);
+            }
+        }
+        self.command.envs(&self.config.environment.pin);
+        if let Some(deterministic) = self.config.deterministic.clone() {
+            let locale: Vec<OsString> = env::vars_os()
+                .map(|(key, _)| key)
+                .chain(self.command.get_envs().map(|(key, _)| key.to_owned()))
+                .filter(|key| deterministic::is_locale(key.as_bytes()))
+                .collect();
+            for key in locale {
+                self.command.env_remove(key);
+            }
+            self.command
+                .env("LC_ALL", &deterministic.locale)
+                .env("LANG", &deterministic.locale);
+            unsafe {
+                self.command.pre_exec(move || {
+                    deterministic::setup(&deterministic).map_err(io::Error::from)
+                });
+            }
+        }
+        let namespaces = namespace::Setup::new(&self.config.namespaces)?;
+        unsafe {
+            self.command
+                .pre_exec(move || namespaces.apply().map_err(io::Error::from));
+        }
+        let (run_as, no_new_privs) = (self.config.run_as.clone(), self.config.no_new_privs);
+        unsafe {
+            self.command.pre_exec(move || {
+                credentials::drop_privileges(run_as.as_ref(), no_new_privs).map_err(io::Error::from)
+            });
+        }
+        // Not current_dir, since std changes directory before running any of these, and a new root would undo it
+        if let Some(dir) = self.config.working_dir.clone() {
+            unsafe {
+                self.command
+                    .pre_exec(move || unistd::chdir(&dir).map_err(io::Error::from));
+            }
+        }
+        let inherited_fds = self.config.inherited_fds.clone();
+        unsafe {
+            self.command
+                .pre_exec(move || inherit::apply(&inherited_fds).map_err(io::Error::from));
+        }
+        if let Some(seconds) = self.config.time_limits.cpu_seconds {
+            unsafe {
+                self.command
+                    .pre_exec(move || limits::set_cpu_limit(seconds).map_err(io::Error::from));
+            }
+        }
+        let resource_limits = self.config.resource_limits;
+        unsafe {
+            self.command.pre_exec(move || {
+                limits::set_resource_limits(&resource_limits).map_err(io::Error::from)
+            });
+        }
+        if let Some(landlock) = &self.config.landlock {
+            let ruleset = Ruleset::new(landlock);
+            unsafe {
+                self.command
+                    .pre_exec(move || ruleset.apply().map_err(io::Error::from));
+            }
+        }
+        unsafe {
+            self.command.pre_exec(|| traceme().map_err(io::Error::from));
+        }
+
+        let child = self.command.spawn()?;
+        let pid = Pid::from_raw(child.id() as i32);
+        Ok(TracedChild {
+            stdin: child.stdin,
+            stdout: child.stdout,
+            stderr: child.stderr,
+            pid,
+            config: self.config,
+            _cgroup: cgroup,
+        })
+    }
+
+    /// status_traced runs the command to completion, like `Command::status`, and returns how it exited.
+    pub fn status_traced(&mut self) -> io::Result<ChildExit> {
+        Ok(self.spawn_traced()?.wait().0)
+    }
+}
+
+/// TracedChild: a command started by `spawn_traced`, which is traced under the config it was sandboxed with as soon
+/// as it's waited on
+///
+/// Unlike `std::process::Child` this has to be waited on, since the child won't run until it is. That also means
+/// anything reading from or writing to its pipes needs to be on another thread.
+pub struct TracedChild<'a> {
+    pub stdin: Option<ChildStdin>,
+    pub stdout: Option<ChildStdout>,
+    pub stderr: Option<ChildStderr>,
+    pid: Pid,
+    config: &'a Config,
+    /// Removed once the child's been waited on
+    _cgroup: Option<Cgroup>,
+}
+
+impl TracedChild<'_> {
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw() as u32
+    }
+
+    /// wait traces the child until it exits, and returns how it exited along with counters describing the run.
+    pub fn wait(self) -> (ChildExit, RunStats) {
+        reseize(self.pid).unwrap_or_else(|e| panic!("failed to attach to child {}: {e}", self.pid));
+        // std's spawn waits for the exec, so there's no stopping the child before it to install the prefilter
+        parent(self.pid, self.config, Hooks::default(), None)
+    }
+}
+
+/// stdio returns what to set one of the command's output streams to, if the config redirects it. Captured streams
+/// are piped, and can be read from the TracedChild.
+fn stdio(output: &Output) -> io::Result<Option<Stdio>> {
+    Ok(match output {
+        Output::Inherit => None,
+        Output::Null => Some(Stdio::null()),
+        Output::Capture => Some(Stdio::piped()),
+        Output::File(path) => Some(File::create(path)?.into()),
+    })
+}
+
+/// reseize swaps the PTRACE_TRACEME the child was started with for PTRACE_SEIZE, which the tracer needs to tell
+/// group-stops apart from other stops. Detaching with SIGSTOP keeps the child from running in between, and seizing
+/// it then traps it again.
+fn reseize(pid: Pid) -> nix::Result<()> {
+    // The stop from the exec
+    waitpid(pid, None)?;
+    detach(pid, Signal::SIGSTOP)?;
+    seize(pid, ptrace_options(false))?;
+    // Either the SIGSTOP being delivered or the group-stop it caused, depending on which came first. Resuming it
+    // without a signal suppresses the SIGSTOP either way.
+    waitpid(pid, None)?;
+    Ok(())
+}
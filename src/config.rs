@@ -1,66 +1,2590 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fs::File,
-    io::Read,
-    path::Path,
+    fs,
+    num::NonZeroU64,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use serde::{Deserialize, Serialize};
+use crate::{
+    capture::Capture,
+    fd::{self, FdKind, FdRule},
+    glob::{self, Globs},
+    identity, map,
+    network::{Destination, DestinationRule, SocketDomain, SocketType},
+    scenario::Scenario,
+    seccomp,
+};
+use nix::{errno::Errno, libc};
+use serde::{de, Deserialize, Serialize};
+use serde_yaml::Value;
 use syscalls::Sysno;
+use thiserror::Error;
+
+/// RuleAction: what to do about a syscall a rule blocks. Written as `kill`, `log`, `trap`, `deny` for EPERM, or
+/// e.g. `deny: EACCES` for another error.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "RuleActionSetting", into = "RuleActionSetting")]
+pub enum RuleAction {
+    /// Kill the tracee and report it
+    #[default]
+    Kill,
+    /// Skip the syscall and have it fail with this error
+    Deny(Errno),
+    /// Let the syscall run, and print that it happened
+    Log,
+    /// Skip the syscall and send the thread SIGSYS, like a seccomp filter returning SECCOMP_RET_TRAP. Unlike with
+    /// seccomp, the signal doesn't say which syscall it was.
+    Trap,
+}
+
+impl RuleAction {
+    pub fn is_default(&self) -> bool {
+        *self == RuleAction::default()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SimpleRuleAction {
+    Kill,
+    Deny,
+    Log,
+    Trap,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RuleActionSetting {
+    Simple(SimpleRuleAction),
+    Deny {
+        #[serde(with = "crate::names::errno")]
+        deny: Errno,
+    },
+}
+
+impl From<RuleActionSetting> for RuleAction {
+    fn from(setting: RuleActionSetting) -> RuleAction {
+        match setting {
+            RuleActionSetting::Simple(SimpleRuleAction::Kill) => RuleAction::Kill,
+            RuleActionSetting::Simple(SimpleRuleAction::Deny) => RuleAction::Deny(Errno::EPERM),
+            RuleActionSetting::Simple(SimpleRuleAction::Log) => RuleAction::Log,
+            RuleActionSetting::Simple(SimpleRuleAction::Trap) => RuleAction::Trap,
+            RuleActionSetting::Deny { deny } => RuleAction::Deny(deny),
+        }
+    }
+}
+
+impl From<RuleAction> for RuleActionSetting {
+    fn from(action: RuleAction) -> RuleActionSetting {
+        match action {
+            RuleAction::Kill => RuleActionSetting::Simple(SimpleRuleAction::Kill),
+            RuleAction::Deny(Errno::EPERM) => RuleActionSetting::Simple(SimpleRuleAction::Deny),
+            RuleAction::Deny(deny) => RuleActionSetting::Deny { deny },
+            RuleAction::Log => RuleActionSetting::Simple(SimpleRuleAction::Log),
+            RuleAction::Trap => RuleActionSetting::Simple(SimpleRuleAction::Trap),
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+/// ConfigEntry: the rules for one library. A key that isn't one of these is an error rather than ignored, since a
+/// misspelled list would otherwise let through everything in it.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigEntry {
+    /// A stable name for the library's rules, reported with their decisions so they can be followed across changes
+    /// to the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(
+        default,
+        with = "crate::names::set",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub allow: Option<BTreeSet<Sysno>>,
+    #[serde(
+        default,
+        with = "crate::names::set",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub block: Option<BTreeSet<Sysno>>,
+    /// What to do about the syscalls in `block`, e.g. `deny` to skip them and have them fail with EPERM, so the
+    /// program can handle the error and carry on
+    #[serde(default, skip_serializing_if = "RuleAction::is_default")]
+    pub action: RuleAction,
+    /// What to do about the library's syscalls that aren't in any of the lists. Without one, they're left to the
+    /// libraries further up the stack.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<DefaultPolicy>,
+    /// Syscalls only allowed for some paths, e.g. `openat: ["/etc/ssl/", "/usr/share/**/*.pem"]`. Each path is
+    /// allowed if it's under one of the prefixes or matches one of the globs, once it's been made absolute and had its
+    /// symlinks resolved. Anything else, including a path that can't be read, gets `action`. These take the place of
+    /// the lists for their syscalls.
+    #[serde(
+        default,
+        with = "crate::names::map",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub paths: BTreeMap<Sysno, Vec<String>>,
+    /// Limits on the sockets the library makes and where it sends to. Like `paths`, anything they don't allow gets
+    /// `action`, and they take the place of the lists for socket, connect, sendto, sendmsg and sendmmsg.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkRules>,
+    /// Syscalls only allowed on some descriptors, e.g. `write: [stdout, stderr]` or `write: ["!/etc/"]`, see
+    /// FdRule. Like `paths`, anything they don't allow gets `action`.
+    #[serde(
+        default,
+        with = "crate::names::map",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub fds: BTreeMap<Sysno, Vec<FdRule>>,
+}
+
+impl ConfigEntry {
+    /// patterns returns the paths, descriptor targets and unix sockets the rules name, which can be globs.
+    fn patterns(&self) -> impl Iterator<Item = &str> {
+        let fds = self
+            .fds
+            .values()
+            .flatten()
+            .filter_map(|rule| match &rule.kind {
+                FdKind::File(pattern) => Some(pattern.as_str()),
+                _ => None,
+            });
+        let destinations = self
+            .network
+            .iter()
+            .flat_map(|network| network.destinations.iter().flatten())
+            .filter_map(|rule| match rule {
+                DestinationRule::Unix(pattern) => Some(pattern.as_str()),
+                DestinationRule::Ip { .. } => None,
+            });
+        self.paths
+            .values()
+            .flatten()
+            .map(String::as_str)
+            .chain(fds)
+            .chain(destinations)
+    }
+
+    /// has_argument_rules returns whether the entry has rules for what `syscall`'s arguments refer to.
+    fn has_argument_rules(&self, syscall: Sysno) -> bool {
+        self.paths.contains_key(&syscall)
+            || self.fds.contains_key(&syscall)
+            || self
+                .network
+                .as_ref()
+                .is_some_and(|network| network.covers(syscall))
+    }
+
+    /// check_arguments checks what a syscall's arguments refer to against the entry's rules for them, if it has any
+    /// for the syscall, with the globs among the rules compiled in `globs`.
+    fn check_arguments(
+        &self,
+        syscall: Sysno,
+        arguments: &Arguments,
+        globs: &Globs,
+    ) -> Option<Check> {
+        let (list, allowed) = match arguments {
+            Arguments::Paths(paths) => {
+                let patterns = self.paths.get(&syscall)?;
+                let allowed = |path: &PathBuf| {
+                    patterns.iter().any(|pattern| {
+                        if glob::is_pattern(pattern) {
+                            globs.matches(pattern, &path.to_string_lossy())
+                        } else {
+                            path.starts_with(pattern)
+                        }
+                    })
+                };
+                (
+                    RuleList::Paths,
+                    !paths.is_empty() && paths.iter().all(allowed),
+                )
+            }
+            Arguments::Socket { domain, kind } => {
+                let network = self
+                    .network
+                    .as_ref()
+                    .filter(|network| network.covers(syscall))?;
+                let domain_allowed = network.domains.as_ref().is_none_or(|domains| {
+                    SocketDomain::from_raw(*domain).is_some_and(|domain| domains.contains(&domain))
+                });
+                let type_allowed = network.types.as_ref().is_none_or(|types| {
+                    SocketType::from_raw(*kind).is_some_and(|kind| types.contains(&kind))
+                });
+                (RuleList::Network, domain_allowed && type_allowed)
+            }
+            Arguments::Fd { fd, target, family } => {
+                let rules = self.fds.get(&syscall)?;
+                (
+                    RuleList::Fds,
+                    fd::allows(rules, *fd, target.as_deref(), *family, globs),
+                )
+            }
+            Arguments::Destination(destination) => {
+                let destinations = self.network.as_ref()?.destinations.as_ref()?;
+                let allowed = destination.as_ref().is_some_and(|destination| {
+                    destinations
+                        .iter()
+                        .any(|rule| rule.matches(destination, globs))
+                });
+                (RuleList::Network, allowed)
+            }
+        };
+        let rule = Rule {
+            id: self.id.clone(),
+            list,
+        };
+        Some(if allowed {
+            Check::Allowed(rule)
+        } else {
+            Check::Blocked(self.action, rule)
+        })
+    }
+}
+
+/// NetworkRules: limits on the sockets a library makes and where it sends to, e.g. only connecting to
+/// `10.0.0.0/8:443`. Each kind of limit only applies if it's given.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct NetworkRules {
+    /// The socket domains it may use, like `inet` or `unix`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domains: Option<BTreeSet<SocketDomain>>,
+    /// The socket types it may use, like `stream` or `dgram`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub types: Option<BTreeSet<SocketType>>,
+    /// Where it may connect or send to. Sending on a connected socket goes by where it was connected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destinations: Option<Vec<DestinationRule>>,
+}
+
+impl NetworkRules {
+    /// covers returns whether the rules have a say in `syscall`.
+    fn covers(&self, syscall: Sysno) -> bool {
+        match syscall {
+            Sysno::socket => self.domains.is_some() || self.types.is_some(),
+            Sysno::connect | Sysno::sendto | Sysno::sendmsg | Sysno::sendmmsg => {
+                self.destinations.is_some()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Arguments: what a syscall's arguments refer to, for the rules that look past which syscall it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Arguments {
+    /// The paths it names, see filesystem::path_arguments
+    Paths(Vec<PathBuf>),
+    /// The socket it's making, as passed to socket(2)
+    Socket { domain: i32, kind: i32 },
+    /// Where it's connecting or sending to, or None if that couldn't be read
+    Destination(Option<Destination>),
+    /// The descriptor it acts on, with what it refers to and its address family as far as FdTable knows
+    Fd {
+        fd: i32,
+        target: Option<String>,
+        family: Option<i32>,
+    },
+}
+
+/// DefaultPolicy: what to do about a syscall no rule covers
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultPolicy {
+    #[default]
+    Allow,
+    /// Skip the syscall and have it fail with EPERM, like `action: deny`. Unlike a `block` list, it doesn't kill.
+    Deny,
+    /// Kill the tracee and report it
+    Kill,
+}
+
+impl DefaultPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == DefaultPolicy::default()
+    }
+
+    /// check returns what the policy makes of a syscall, as decided by `rule`.
+    pub fn check(self, rule: Rule) -> Check {
+        match self {
+            DefaultPolicy::Allow => Check::Allowed(rule),
+            DefaultPolicy::Deny => Check::Blocked(RuleAction::Deny(Errno::EPERM), rule),
+            DefaultPolicy::Kill => Check::Blocked(RuleAction::Kill, rule),
+        }
+    }
+}
+
+fn default_max_depth() -> usize {
+    256
+}
+
+/// StackWalkConfig: how the stack is walked to see where a syscall came from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StackWalkConfig {
+    /// The most frames to walk, so that a corrupted stack can't keep the walk going
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    /// What to do about a syscall no rule covers when the walk was cut short, by max_depth or by a frame that doesn't
+    /// look right, since a rule further up may have covered it. It's only fallen back on once the hooks and any remote
+    /// policy have had their say, as `default` is. Without it, it's treated like any other syscall no rule covers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_failure: Option<DefaultPolicy>,
+}
+
+impl Default for StackWalkConfig {
+    fn default() -> StackWalkConfig {
+        StackWalkConfig {
+            max_depth: default_max_depth(),
+            on_failure: None,
+        }
+    }
+}
+
+impl StackWalkConfig {
+    pub fn is_default(&self) -> bool {
+        *self == StackWalkConfig::default()
+    }
+}
+
+/// WriteQuota: limits on how many bytes the sandbox may write to files. Growing a file counts as much as writing to
+/// it, whether that's by writing past its end, truncating it to be longer or setting space aside with fallocate, as
+/// does copying into it with the likes of copy_file_range and sendfile. Syscalls that would go over are stopped
+/// before they run, going by the most they could write.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct WriteQuota {
+    /// Maximum number of bytes written to any one file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_file: Option<u64>,
+    /// Maximum number of bytes written across all files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+impl WriteQuota {
+    pub fn is_unlimited(&self) -> bool {
+        self.per_file.is_none() && self.total.is_none()
+    }
+}
+
+/// TimeLimits: how long the traced tree may run before it's killed
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimeLimits {
+    /// Wall-clock time for the whole run, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// CPU time for each process, in seconds, enforced by the kernel with RLIMIT_CPU
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+}
+
+impl TimeLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.timeout_ms.is_none() && self.cpu_seconds.is_none()
+    }
+}
+
+/// ResourceLimits: rlimits to set on the target before it starts, which each process in the tree inherits. Each is
+/// both the soft and the hard limit.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// The most virtual memory each process may map, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address_space: Option<u64>,
+    /// The most each process's heap and data segment may grow to, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<u64>,
+    /// The most each process's main stack may grow to, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<u64>,
+    /// One more than the highest file descriptor each process may open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
+    /// The largest file any process may write, in bytes. Writing past it raises SIGXFSZ.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<u64>,
+    /// The most processes and threads the user may have at once. The kernel counts every one the user running the
+    /// target has, not just the traced tree.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processes: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// resources pairs each of the limits with the resource it's for.
+    pub fn resources(&self) -> [(libc::__rlimit_resource_t, Option<u64>); 6] {
+        [
+            (libc::RLIMIT_AS, self.address_space),
+            (libc::RLIMIT_DATA, self.data),
+            (libc::RLIMIT_STACK, self.stack),
+            (libc::RLIMIT_NOFILE, self.open_files),
+            (libc::RLIMIT_FSIZE, self.file_size),
+            (libc::RLIMIT_NPROC, self.processes),
+        ]
+    }
+}
+
+/// CgroupConfig: limits on the traced tree as a whole, enforced by a cgroup v2 group made for the run and removed
+/// once it's over. Each limit needs its controller enabled in the parent, which is done if it isn't already, and
+/// undone afterwards if nothing else has been put under the parent since.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CgroupConfig {
+    /// The cgroup to make the run's under, which has to be one we're allowed to manage. It can't have any processes
+    /// in it, since the kernel won't enable controllers for the cgroups under one that has, so it can't be the one
+    /// we're in.
+    pub parent: PathBuf,
+    /// memory.max: the most memory the tree may use at once, in bytes, past which the kernel's OOM killer steps in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_max: Option<u64>,
+    /// pids.max: the most processes and threads the tree may have at once, past which fork and clone fail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pids_max: Option<u64>,
+    /// cpu.max, as a percentage of one CPU, e.g. 50 for half of one or 200 for two. 1% is the least the kernel allows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_percent: Option<NonZeroU64>,
+}
+
+/// Namespaces: namespaces of its own to start the target in, split off from ours just before it execs. Making them
+/// takes CAP_SYS_ADMIN.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Namespaces {
+    /// A network namespace, which has no way out to any network, only a loopback interface
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub network: bool,
+    /// Bring the new network namespace's loopback interface up, so the target can still talk to itself
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub loopback: bool,
+    /// A user namespace, which lets the other namespaces be made without CAP_SYS_ADMIN. The target keeps its uid and
+    /// gid inside it, and has no privileges over anything outside.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub user: bool,
+    /// A PID namespace, which the target starts off as init of. Nothing in the tree can signal processes outside it,
+    /// and when the target exits everything left in it is killed. /proc isn't mounted again for it, so the host's
+    /// processes can still be listed there, unless `root` leaves /proc out.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pid: bool,
+    /// A mount namespace whose root is this directory, so that the target can't see anything outside of it. The
+    /// target's own path is looked up inside it. Library paths in the config are still ours unless
+    /// `container_paths` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// Directories to bind read-only into the new root at the same paths, like /usr and /lib. Each one's mount point
+    /// is made if it isn't there, as long as its parent is.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read_only_binds: Vec<PathBuf>,
+}
+
+impl Namespaces {
+    pub fn is_default(&self) -> bool {
+        *self == Namespaces::default()
+    }
+}
+
+/// RunAs: the user and groups to switch the target to before it execs, which takes root
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunAs {
+    pub uid: u32,
+    pub gid: u32,
+    /// Its supplementary groups, of which it has none unless they're given
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<u32>,
+}
+
+/// EnvironmentConfig: what to do about the environment variables glibc loads libraries and reads files by, which
+/// would otherwise let whoever starts the target put code of their choosing in it
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct EnvironmentConfig {
+    /// Drop the variables glibc ignores for setuid programs, like LD_PRELOAD and GCONV_PATH, from the target's
+    /// environment
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub scrub: bool,
+    /// Variables to set to these values, whatever the target was started with, e.g. `LD_LIBRARY_PATH: /opt/app/lib`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pin: BTreeMap<String, String>,
+}
+
+impl EnvironmentConfig {
+    pub fn is_default(&self) -> bool {
+        *self == EnvironmentConfig::default()
+    }
+}
+
+/// StdioConfig: where the target's standard streams go, when it shouldn't share ours
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct StdioConfig {
+    /// A file to read stdin from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Output::is_default")]
+    pub stdout: Output,
+    #[serde(default, skip_serializing_if = "Output::is_default")]
+    pub stderr: Output,
+}
+
+impl StdioConfig {
+    pub fn is_default(&self) -> bool {
+        *self == StdioConfig::default()
+    }
+}
+
+/// Output: where one of the target's output streams goes
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// Wherever ours goes
+    #[default]
+    Inherit,
+    /// Nowhere
+    Null,
+    /// Into the run's stats, once it's over
+    Capture,
+    /// Into this file, which is truncated first
+    File(PathBuf),
+}
+
+impl Output {
+    pub fn is_default(&self) -> bool {
+        *self == Output::default()
+    }
+}
+
+/// InheritedFds: which of our file descriptors the target is started with, besides stdin, stdout and stderr. Anything
+/// it inherits it can use whatever the rules for opening files or sockets say.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct InheritedFds {
+    /// Close every descriptor above 2 that isn't in `keep`, rather than passing on whatever isn't close-on-exec
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub close: bool,
+    /// Descriptors to pass on even so, including ones that are close-on-exec
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub keep: BTreeSet<i32>,
+}
+
+impl InheritedFds {
+    pub fn is_default(&self) -> bool {
+        *self == InheritedFds::default()
+    }
+}
+
+/// StormAction: what to do about a call site that keeps failing
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StormAction {
+    /// Print a warning, once per second the storm goes on
+    #[default]
+    Log,
+    /// Slow the tracee down by sleeping before each further failing call returns
+    Throttle,
+    /// Kill the tracee
+    Kill,
+}
+
+/// StormConfig: settings for detecting busy loops of failing syscalls
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StormConfig {
+    /// How many times one call site may fail in a second before it counts as a storm
+    pub failures_per_second: u64,
+    #[serde(default)]
+    pub action: StormAction,
+}
+
+/// Fallback: what to do when the remote policy service can't be reached in time
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallback {
+    #[default]
+    Allow,
+    Block,
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+/// RemotePolicyConfig: a service to ask about syscalls the config has no rule for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RemotePolicyConfig {
+    /// The endpoint to POST each question to
+    pub url: String,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub fallback: Fallback,
+}
+
+/// DaemonPolicy: what to do when a traced process daemonizes, either by calling setsid or by being orphaned when
+/// the process that forked it exits
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DaemonPolicy {
+    /// Stop tracing the daemon and let it run on its own. With the prefilter, a cgroup or captured output, it's traced
+    /// on as with `allow-but-keep-tracing` instead, since none of them would outlive the run safely.
+    Allow,
+    /// Kill the daemon and report it
+    Deny,
+    /// Let it daemonize, but keep tracing it. The run isn't over until the daemon exits too.
+    #[default]
+    AllowButKeepTracing,
+}
+
+impl DaemonPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == DaemonPolicy::default()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+/// Enforcement: what happens when the traced tree breaks the config
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Enforcement {
+    /// Stop the run, or whatever else the broken rule says to do
+    #[default]
+    Enforce,
+    /// Let everything run, and report what would have been stopped in the run stats. For trying a config out on a
+    /// real workload before enforcing it.
+    Audit,
+}
+
+impl Enforcement {
+    pub fn is_default(&self) -> bool {
+        *self == Enforcement::default()
+    }
+}
+
+/// ViolationScope: what gets stopped when a traced process breaks the config
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ViolationScope {
+    /// End just the offending thread, and let the rest of the tree run on. The run still reports the first
+    /// violation once everything has exited.
+    Thread,
+    /// Kill the offending process and end the run
+    #[default]
+    Process,
+    /// Kill every traced process and end the run
+    Tree,
+}
+
+impl ViolationScope {
+    pub fn is_default(&self) -> bool {
+        *self == ViolationScope::default()
+    }
+}
+
+/// UnattributedPolicy: what to do about a syscall that couldn't be attributed to any mapped file, say because the stack
+/// walk got nowhere in a binary built without frame pointers, or the code was generated at runtime
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnattributedPolicy {
+    /// Treat it like any other syscall with no rule. It's still counted in the run stats.
+    #[default]
+    Allow,
+    /// Like allow, but also print each one
+    Log,
+    /// Treat it as a violation
+    Block,
+}
+
+impl UnattributedPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == UnattributedPolicy::default()
+    }
+}
+
+/// Virtualization: a source of nondeterminism that can be replaced with repeatable results at syscall exit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Virtualization {
+    /// getrandom fills its buffer from a generator seeded with `seed`
+    Getrandom,
+    /// gettimeofday and clock_gettime report a clock that starts at `epoch` and advances a microsecond per call.
+    /// Time read through the vDSO never makes a syscall, so it isn't affected.
+    Time,
+}
+
+/// Deterministic: settings that take some of the nondeterminism out of a run, e.g. for judging output. The directory
+/// the target starts in is `working_dir`, which applies whether or not the run is deterministic.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Deterministic {
+    /// Pin the traced tree to this CPU
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<usize>,
+    /// Set as LC_ALL and LANG
+    pub locale: String,
+    pub umask: u32,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub virtualize: BTreeSet<Virtualization>,
+    pub seed: u64,
+    /// Where the virtual clock starts, in seconds since the Unix epoch
+    pub epoch: u64,
+}
+
+impl Default for Deterministic {
+    fn default() -> Deterministic {
+        Deterministic {
+            cpu: None,
+            locale: String::from("C"),
+            umask: 0o022,
+            virtualize: BTreeSet::new(),
+            seed: 0,
+            epoch: 0,
+        }
+    }
+}
+
+/// FilesystemMode: whether the traced tree may modify files
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilesystemMode {
+    #[default]
+    ReadWrite,
+    /// Nothing may modify files outside the writable prefixes, no matter which library it comes from
+    ReadOnly,
+}
+
+/// ReadOnlyAction: what happens to a syscall that would modify a read-only filesystem
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadOnlyAction {
+    /// Kill the tracee and report it
+    #[default]
+    Kill,
+    /// Skip the syscall and have it fail with EROFS, as if the filesystem was mounted read-only
+    Erofs,
+}
+
+/// FilesystemConfig: restrictions on modifying files. Can be written as just the mode, e.g. `filesystem: read-only`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(from = "FilesystemSetting")]
+pub struct FilesystemConfig {
+    pub mode: FilesystemMode,
+    /// Paths under these prefixes stay writable
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub writable: Vec<PathBuf>,
+    pub action: ReadOnlyAction,
+}
+
+impl FilesystemConfig {
+    pub fn is_default(&self) -> bool {
+        *self == FilesystemConfig::default()
+    }
+
+    /// is_writable returns whether the config lets `path`, which should be absolute and normalized, be modified.
+    pub fn is_writable(&self, path: &Path) -> bool {
+        self.mode == FilesystemMode::ReadWrite
+            || self.writable.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FilesystemSetting {
+    Mode(FilesystemMode),
+    Full {
+        #[serde(default)]
+        mode: FilesystemMode,
+        #[serde(default)]
+        writable: Vec<PathBuf>,
+        #[serde(default)]
+        action: ReadOnlyAction,
+    },
+}
+
+impl From<FilesystemSetting> for FilesystemConfig {
+    fn from(setting: FilesystemSetting) -> FilesystemConfig {
+        match setting {
+            FilesystemSetting::Mode(mode) => FilesystemConfig {
+                mode,
+                ..Default::default()
+            },
+            FilesystemSetting::Full {
+                mode,
+                writable,
+                action,
+            } => FilesystemConfig {
+                mode,
+                writable,
+                action,
+            },
+        }
+    }
+}
+
+/// LandlockConfig: the only paths the traced tree may access, enforced by the kernel with Landlock. Each rule covers
+/// everything beneath its path, and the target's own executable and libraries need to be among them.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct LandlockConfig {
+    /// Paths that may be read and executed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read_only: Vec<PathBuf>,
+    /// Paths that may also be written to, created in and removed from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub writable: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct Config {
+    /// Rules for each library, by path. Keys can also be globs like `**/libc.so.*`, where `*` stays within a
+    /// directory and `**` doesn't, or name the library by what it says it is, as `soname:libssl.so.3` or
+    /// `build-id:` and the build ID in hex. An exact path always wins, then a build ID, then a soname, then a glob,
+    /// and among globs the one with the most literal characters wins.
+    ///
+    /// A library's soname and build ID are read from the file itself, so they're whatever the file says they are:
+    /// any library the tracee can get loaded can declare the soname `libssl.so.3`, or copy libssl's build ID, and be
+    /// given libssl's rules. Only allow more by those names than you would allow any library the tracee may load.
+    #[serde(default)]
     pub shared_objects: BTreeMap<String, ConfigEntry>,
+    /// Rules for particular executables, by path or glob, picked by what each process is running each time it
+    /// execs. A binary's rules for a library take the place of the ones above, which still cover the libraries it
+    /// doesn't list.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub binaries: BTreeMap<String, BinaryConfig>,
+    /// Named sets of syscalls, which the rules can refer to as e.g. `@db` alongside the built-in groups. Groups can
+    /// include other groups. They're expanded when the config is loaded with `from_file` or `from_str`.
+    #[serde(
+        default,
+        deserialize_with = "crate::names::groups::deserialize",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// What to do about syscalls that no library on the stack has a rule for, once the hooks and any remote policy
+    /// have had their say
+    #[serde(default, skip_serializing_if = "DefaultPolicy::is_default")]
+    pub default: DefaultPolicy,
+    #[serde(default, skip_serializing_if = "WriteQuota::is_unlimited")]
+    pub write_quota: WriteQuota,
+    #[serde(default, skip_serializing_if = "TimeLimits::is_unlimited")]
+    pub time_limits: TimeLimits,
+    #[serde(default, skip_serializing_if = "ResourceLimits::is_unlimited")]
+    pub resource_limits: ResourceLimits,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storm: Option<StormConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_policy: Option<RemotePolicyConfig>,
+    #[serde(default, skip_serializing_if = "DaemonPolicy::is_default")]
+    pub daemonize: DaemonPolicy,
+    /// Make the tracer the subreaper for the tree, so that processes orphaned by a double fork are reparented to it
+    /// rather than to init, and are reaped by it. It stays that way for the rest of the tracer's life, since other
+    /// runs might be going on. The run isn't over until any daemon it let go of with `daemonize: allow` exits too.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub subreaper: bool,
+    /// Where procfs is mounted, if not /proc
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proc_root: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deterministic: Option<Deterministic>,
+    #[serde(default, skip_serializing_if = "EnvironmentConfig::is_default")]
+    pub environment: EnvironmentConfig,
+    #[serde(default, skip_serializing_if = "StdioConfig::is_default")]
+    pub stdio: StdioConfig,
+    #[serde(default, skip_serializing_if = "InheritedFds::is_default")]
+    pub inherited_fds: InheritedFds,
+    /// Failures to inject, for testing how the target copes with them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scenario: Option<Scenario>,
+    #[serde(default, skip_serializing_if = "FilesystemConfig::is_default")]
+    pub filesystem: FilesystemConfig,
+    /// Filesystem access to apply with Landlock before starting the target, which holds whatever library the access
+    /// comes from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub landlock: Option<LandlockConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cgroup: Option<CgroupConfig>,
+    #[serde(default, skip_serializing_if = "Namespaces::is_default")]
+    pub namespaces: Namespaces,
+    /// The directory to start the target in, after it's moved into any new root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_as: Option<RunAs>,
+    /// Keep the target from gaining privileges through setuid executables or file capabilities
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_new_privs: bool,
+    /// Where to write a JSON line for each syscall
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<PathBuf>,
+    /// Where to write a JSON line for each event, like syscalls, forks, execs, violations and exits, with a timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_log: Option<PathBuf>,
+    /// Where to record what the target prints in asciinema's format, with a marker at each policy decision
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_recording: Option<PathBuf>,
+    /// Where to record each syscall the rules are checked at, with the registers, memory map and stack it was made
+    /// with, for `crabtrap replay` to check configs against later. Turns off the prefilter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_recording: Option<PathBuf>,
+    /// Where to listen for requests to pause, resume, reload the config, report stats or detach while the run goes
+    /// on, see `crabtrap ctl`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_socket: Option<PathBuf>,
+    /// Which syscall arguments to decode into the audit log
+    #[serde(default, skip_serializing_if = "Capture::is_empty")]
+    pub capture: Capture,
+    /// Print each syscall to stderr as it's made, strace-style, with the library it came from and its decoded
+    /// arguments
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub trace: bool,
+    #[serde(default, skip_serializing_if = "Enforcement::is_default")]
+    pub enforcement: Enforcement,
+    #[serde(default, skip_serializing_if = "ViolationScope::is_default")]
+    pub violation_scope: ViolationScope,
+    #[serde(default, skip_serializing_if = "UnattributedPolicy::is_default")]
+    pub unattributed: UnattributedPolicy,
+    #[serde(default, skip_serializing_if = "StackWalkConfig::is_default")]
+    pub stack_walk: StackWalkConfig,
+    /// Treat any syscall made from code outside of a mapped file, like a JIT's or shellcode, or called through it, as
+    /// a violation, whatever the rules for the rest of the stack say
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forbid_anonymous_code: bool,
+    /// Where to keep rule decisions between runs, so later runs with the same rules start with them cached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decision_cache: Option<PathBuf>,
+    /// Only stop at the syscalls the config has rules for, using a seccomp filter, and let the rest run at full
+    /// speed. Ignored if something else in the config needs to see every syscall, like the audit log. Hooks only
+    /// hear about the syscalls the filter stops at. Installing the filter sets NO_NEW_PRIVS, whatever `no_new_privs`
+    /// says, and the filter can't be taken off again, so nothing is ever detached from while it's on.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prefilter: bool,
+    /// Match libraries by their paths inside the tracee's root directory rather than ours, so that a config
+    /// written for a container or chroot works when tracing into it from outside. Keys that are symlinks then only
+    /// match by their own paths, since what they lead to here needn't be what they lead to in there.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub container_paths: bool,
+    /// What the keys that are paths lead to, see compile
+    #[serde(skip)]
+    pub resolved_keys: ResolvedKeys,
+    /// The globs among the keys and rules, see compile
+    #[serde(skip)]
+    pub globs: Globs,
 }
 
-#[derive(Debug)]
+/// ResolvedKeys: what the keys of a config that are paths lead to, resolved when it's loaded rather than at every
+/// lookup, see Config::compile
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedKeys(BTreeMap<String, Option<PathBuf>>);
+
+impl ResolvedKeys {
+    /// new resolves the symlinks in the keys that are paths. Keys name files the way the map does, as we see them, so
+    /// they're resolved in our mount namespace. A key that doesn't lead anywhere is kept as None.
+    fn new<'a>(keys: impl IntoIterator<Item = &'a str>) -> ResolvedKeys {
+        ResolvedKeys(
+            keys.into_iter()
+                .filter(|key| is_path_key(key))
+                .map(|key| (key.to_string(), fs::canonicalize(key).ok()))
+                .collect(),
+        )
+    }
+
+    /// covers returns whether all of `keys` that are paths have been resolved.
+    fn covers<'a>(&self, mut keys: impl Iterator<Item = &'a str>) -> bool {
+        keys.all(|key| !is_path_key(key) || self.0.contains_key(key))
+    }
+
+    /// links_to returns whether a key is a symlink to `path`. A key that wasn't resolved with the rest, like one added
+    /// in code, is resolved now.
+    fn links_to(&self, key: &str, path: &str) -> bool {
+        match self.0.get(key) {
+            Some(resolved) => resolved.as_deref() == Some(Path::new(path)),
+            None => {
+                is_path_key(key)
+                    && fs::canonicalize(key).is_ok_and(|resolved| resolved == Path::new(path))
+            }
+        }
+    }
+}
+
+/// Resolved keys don't make configs differ, since they're worked out from the rest of the config
+impl PartialEq for ResolvedKeys {
+    fn eq(&self, _: &ResolvedKeys) -> bool {
+        true
+    }
+}
+
+impl Eq for ResolvedKeys {}
+
+/// is_path_key returns whether a config key is a path, rather than a glob or a name like `soname:libssl.so.3`.
+fn is_path_key(key: &str) -> bool {
+    key.starts_with('/') && !glob::is_pattern(key)
+}
+
+/// BinaryConfig: the rules for one executable
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct BinaryConfig {
+    #[serde(default)]
+    pub shared_objects: BTreeMap<String, ConfigEntry>,
+}
+
+/// RuleList: which part of a library's rules decided a check
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleList {
+    Allow,
+    Block,
+    /// The syscall wasn't in any of the lists, so the library's default decided
+    Default,
+    /// The library's rules for the syscall's paths decided
+    Paths,
+    /// The library's network rules decided
+    Network,
+    /// The library's rules for the syscall's descriptor decided
+    Fds,
+}
+
+/// Rule: the rule that decided a check
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    /// The library's id, if the config gave it one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub list: RuleList,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum Check {
-    Allowed,
-    Blocked,
+    Allowed(Rule),
+    Blocked(RuleAction, Rule),
     Unknown,
 }
 
 impl Config {
+    /// follows_links returns whether keys that are symlinks match the paths they lead to, which they can't when the
+    /// map has paths inside the tracee's root.
+    fn follows_links(&self) -> bool {
+        !self.container_paths
+    }
+
+    /// keys returns every key in the config: the libraries, the binaries and the libraries in each binary's section.
+    fn keys(&self) -> impl Iterator<Item = &str> {
+        self.shared_objects
+            .keys()
+            .chain(self.binaries.keys())
+            .chain(
+                self.binaries
+                    .values()
+                    .flat_map(|section| section.shared_objects.keys()),
+            )
+            .map(String::as_str)
+    }
+
+    /// patterns returns the keys, along with the paths, descriptors and unix sockets the rules name, any of which can
+    /// be globs.
+    fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.keys()
+            .chain(self.entries().flat_map(ConfigEntry::patterns))
+    }
+
+    /// compile compiles the globs among the keys and rules and resolves the keys that are symlinks, which from_file
+    /// and from_str do as they load a config, and which picks up any links that have changed since if it's done again.
+    /// A config built or changed in code has its globs compiled and keys resolved one at a time as they're matched
+    /// instead, which is much slower, so a run compiles a copy of it.
+    pub fn compile(&mut self) {
+        self.resolved_keys = ResolvedKeys::new(self.keys());
+        self.globs = Globs::new(self.patterns());
+    }
+
+    /// is_compiled returns whether every key and rule was there when the config was compiled.
+    pub(crate) fn is_compiled(&self) -> bool {
+        self.resolved_keys.covers(self.keys()) && self.globs.covers(self.patterns())
+    }
+
+    /// lookup is the free lookup, with this config's globs and, if it follows links, its resolved keys.
+    fn lookup<'a, V>(
+        &self,
+        map: &'a BTreeMap<String, V>,
+        path: &str,
+    ) -> Option<(&'a String, &'a V)> {
+        lookup(
+            map,
+            path,
+            &self.globs,
+            self.follows_links().then_some(&self.resolved_keys),
+        )
+    }
+
+    /// entry returns the rules for a library, whether they're listed under its path or a glob matching it.
+    pub fn entry(&self, library: &str) -> Option<&ConfigEntry> {
+        self.lookup(&self.shared_objects, library)
+            .map(|(_, entry)| entry)
+    }
+
+    /// binary returns the key of the `binaries` section for an executable, if one covers it.
+    pub fn binary(&self, executable: &str) -> Option<&str> {
+        self.lookup(&self.binaries, executable)
+            .map(|(key, _)| key.as_str())
+    }
+
+    /// entry_in is entry for a process running the executable under `binary`, whose own rules come first.
+    pub fn entry_in(&self, binary: Option<&str>, library: &str) -> Option<&ConfigEntry> {
+        binary
+            .and_then(|binary| self.binaries.get(binary))
+            .and_then(|section| self.lookup(&section.shared_objects, library))
+            .map(|(_, entry)| entry)
+            .or_else(|| self.entry(library))
+    }
+
+    /// key_in returns the key entry_in finds a library's rules under, and whether it's in the binary's own section.
+    pub fn key_in(&self, binary: Option<&str>, library: &str) -> Option<(&str, bool)> {
+        binary
+            .and_then(|binary| self.binaries.get(binary))
+            .and_then(|section| self.lookup(&section.shared_objects, library))
+            .map(|(key, _)| (key.as_str(), true))
+            .or_else(|| {
+                self.lookup(&self.shared_objects, library)
+                    .map(|(key, _)| (key.as_str(), false))
+            })
+    }
+
+    /// library_name picks which of the names a library goes by to look up its rules by: its path, or one of `others`
+    /// like `soname:libssl.so.3`. The first with rules listed under exactly that name wins, and otherwise it's the
+    /// path, which globs are matched against.
+    pub fn library_name<'a>(
+        &self,
+        binary: Option<&str>,
+        path: &'a str,
+        others: &'a [String],
+    ) -> &'a str {
+        let section = binary.and_then(|binary| self.binaries.get(binary));
+        let listed = |name: &str| {
+            section.is_some_and(|section| section.shared_objects.contains_key(name))
+                || self.shared_objects.contains_key(name)
+        };
+        std::iter::once(path)
+            .chain(others.iter().map(String::as_str))
+            .find(|name| listed(name))
+            .unwrap_or(path)
+    }
+
+    /// uses_identities returns whether any rules name a library by its soname or build ID, which otherwise aren't
+    /// worth reading. It goes through every key, so a run works it out once rather than for each library.
+    pub fn uses_identities(&self) -> bool {
+        self.shared_objects
+            .keys()
+            .chain(
+                self.binaries
+                    .values()
+                    .flat_map(|section| section.shared_objects.keys()),
+            )
+            .any(|key| identity::is_identity(key))
+    }
+
     pub fn check(&self, loc: &str, syscall: Sysno) -> Check {
-        match self.shared_objects.get(loc) {
-            Some(entry) => {
-                if entry
-                    .allow
-                    .as_ref()
-                    .is_some_and(|allowed| allowed.contains(&syscall))
-                {
-                    Check::Allowed
-                } else if entry
-                    .block
-                    .as_ref()
-                    .is_some_and(|blocked| blocked.contains(&syscall))
-                {
-                    Check::Blocked
-                } else {
-                    Check::Unknown
+        self.check_in(None, loc, syscall)
+    }
+
+    /// check_in is check for a process running the executable under `binary`, see Config::binary.
+    pub fn check_in(&self, binary: Option<&str>, loc: &str, syscall: Sysno) -> Check {
+        let Some(entry) = self.entry_in(binary, loc) else {
+            return Check::Unknown;
+        };
+        let rule = |list| Rule {
+            id: entry.id.clone(),
+            list,
+        };
+        let listed =
+            |list: &Option<BTreeSet<Sysno>>| list.as_ref().is_some_and(|l| l.contains(&syscall));
+        if listed(&entry.allow) {
+            Check::Allowed(rule(RuleList::Allow))
+        } else if listed(&entry.block) {
+            Check::Blocked(entry.action, rule(RuleList::Block))
+        } else {
+            entry.default.map_or(Check::Unknown, |default| {
+                default.check(rule(RuleList::Default))
+            })
+        }
+    }
+
+    /// has_argument_rules returns whether any library's rules depend on what `syscall`'s arguments refer to, which
+    /// then need reading out of the tracee before checking it.
+    pub fn has_argument_rules(&self, syscall: Sysno) -> bool {
+        self.entries()
+            .any(|entry| entry.has_argument_rules(syscall))
+    }
+
+    /// check_arguments is check_in for a syscall whose arguments refer to `arguments`, see
+    /// Config::has_argument_rules. Where rules for different arguments disagree, the one that blocks wins. Returns
+    /// None if the library has no rules for them, in which case the other rules decide.
+    pub fn check_arguments(
+        &self,
+        binary: Option<&str>,
+        loc: &str,
+        syscall: Sysno,
+        arguments: &[Arguments],
+    ) -> Option<Check> {
+        let entry = self.entry_in(binary, loc)?;
+        let checks: Vec<Check> = arguments
+            .iter()
+            .filter_map(|arguments| entry.check_arguments(syscall, arguments, &self.globs))
+            .collect();
+        checks
+            .iter()
+            .find(|check| matches!(check, Check::Blocked(..)))
+            .or(checks.first())
+            .cloned()
+    }
+
+    /// prefilter_syscalls returns the syscalls the seccomp prefilter should stop at, or None if there's no prefilter,
+    /// either because it's off or because something else in the config needs to see every syscall.
+    pub fn prefilter_syscalls(&self) -> Option<BTreeSet<Sysno>> {
+        let sees_everything = self.audit_log.is_some()
+            || self.event_log.is_some()
+            || self.trace_recording.is_some()
+            || self.trace
+            || self.forbid_anonymous_code
+            || self.remote_policy.is_some()
+            || self.storm.is_some()
+            || self.deterministic.is_some()
+            || !self.write_quota.is_unlimited()
+            || !self.filesystem.is_default()
+            || !self.unattributed.is_default()
+            || !self.default.is_default()
+            || self.entries().any(|entry| entry.default.is_some());
+        if !self.prefilter || sees_everything {
+            return None;
+        }
+
+        // The syscalls that change what's mapped, so that the maps stay up to date, and setsid for daemonizing.
+        // mprotect is among them since lookups go by what's executable, but brk can be left out, since it only moves
+        // the heap, which never holds code unless an mprotect makes it.
+        let mut syscalls: BTreeSet<Sysno> = map::CHANGES_MAP
+            .iter()
+            .copied()
+            .filter(|&syscall| syscall != Sysno::brk)
+            .collect();
+        syscalls.insert(Sysno::setsid);
+        for entry in self.entries() {
+            for rules in [&entry.allow, &entry.block].into_iter().flatten() {
+                syscalls.extend(rules);
+            }
+            syscalls.extend(entry.paths.keys());
+            syscalls.extend(entry.fds.keys());
+            // Descriptor rules, relative paths and /dev/fd/N go by the descriptor table, which has to be kept up to
+            // date, or a number that's been closed and reused would be checked as what it used to be
+            if !entry.paths.is_empty() || !entry.fds.is_empty() {
+                syscalls.extend(fd::CHANGES_FDS);
+            }
+            if entry.network.is_some() {
+                syscalls.extend([
+                    Sysno::socket,
+                    Sysno::connect,
+                    Sysno::sendto,
+                    Sysno::sendmsg,
+                    Sysno::sendmmsg,
+                ]);
+            }
+        }
+        if let Some(scenario) = &self.scenario {
+            syscalls.extend(scenario.steps.iter().map(|step| step.syscall));
+        }
+        if self.session_recording.is_some() {
+            syscalls.extend([Sysno::write, Sysno::writev]);
+        }
+        Some(syscalls)
+    }
+
+    /// to_seccomp_bpf compiles the config into a seccomp filter that can be loaded without a tracer, for cheaper
+    /// enforcement where the tracer's overhead is too much.
+    ///
+    /// The filter can't tell which library made a syscall or which binary is running, so it blocks the syscalls any
+    /// library blocks, except those another library allows, which the target needs to run. Where
+    /// libraries' actions for a syscall differ the strictest wins. With a `default` other than allow, the syscalls
+    /// libraries allow are the only ones that aren't stopped. Nothing that looks at syscall arguments, like the
+    /// filesystem rules and write quota, is enforced.
+    pub fn to_seccomp_bpf(&self) -> Vec<libc::sock_filter> {
+        let allowed = self
+            .entries()
+            .filter_map(|entry| entry.allow.as_ref())
+            .flatten()
+            .collect::<BTreeSet<_>>();
+        let mut rules = BTreeMap::new();
+        for entry in self.entries() {
+            for (syscall, action) in entry.block.iter().flatten().map(|&s| (s, entry.action)) {
+                if allowed.contains(&syscall) {
+                    continue;
                 }
+                let ret = match action {
+                    RuleAction::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+                    RuleAction::Trap => libc::SECCOMP_RET_TRAP,
+                    RuleAction::Deny(errno) => libc::SECCOMP_RET_ERRNO | errno as u32,
+                    RuleAction::Log => libc::SECCOMP_RET_LOG,
+                };
+                rules
+                    .entry(syscall)
+                    .and_modify(|existing| *existing = strictest(*existing, ret))
+                    .or_insert(ret);
             }
-            None => Check::Unknown,
         }
+        let default = match self.default {
+            DefaultPolicy::Allow => libc::SECCOMP_RET_ALLOW,
+            DefaultPolicy::Deny => libc::SECCOMP_RET_ERRNO | Errno::EPERM as u32,
+            DefaultPolicy::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+        };
+        if !self.default.is_default() {
+            rules.extend(
+                allowed
+                    .into_iter()
+                    .map(|&syscall| (syscall, libc::SECCOMP_RET_ALLOW)),
+            );
+        }
+        seccomp::standalone(&rules, default)
     }
 
+    /// entries returns every library's rules, including those for particular binaries.
+    fn entries(&self) -> impl Iterator<Item = &ConfigEntry> {
+        self.shared_objects.values().chain(
+            self.binaries
+                .values()
+                .flat_map(|section| section.shared_objects.values()),
+        )
+    }
+
+    /// proc_root returns where procfs is mounted.
+    pub fn proc_root(&self) -> &Path {
+        self.proc_root.as_deref().unwrap_or(Path::new("/proc"))
+    }
+
+    /// from_file reads a config in whichever format its extension says it's in, along with any it extends.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Config {
-        let mut file = File::open(path).expect("failed to open file");
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .expect("failed to read file");
-        serde_yaml::from_str(&contents).expect("failed to parse config file")
+        let path = path.as_ref();
+        Config::load(path)
+            .unwrap_or_else(|e| panic!("failed to load config {}: {e}", path.display()))
+    }
+
+    /// load is from_file for callers that would rather have the error than a panic.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let config = read_layers(path.as_ref(), &mut Vec::new(), &mut Vec::new())?;
+        Ok(from_value(config)?)
+    }
+
+    /// from_files reads several configs, laying each over the ones before it as if it extended them.
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> Config {
+        Config::load_files(paths).unwrap_or_else(|e| panic!("failed to load configs: {e}"))
+    }
+
+    /// load_files is from_files for callers that would rather have the error than a panic.
+    pub fn load_files<P: AsRef<Path>>(paths: &[P]) -> Result<Config, ConfigError> {
+        let mut merged = Value::Mapping(Default::default());
+        for path in paths {
+            merge(
+                &mut merged,
+                read_layers(path.as_ref(), &mut Vec::new(), &mut Vec::new())?,
+            );
+        }
+        Ok(from_value(merged)?)
+    }
+
+    /// parse parses a config written in `format`, expanding the groups it defines in its rules. Any files it extends
+    /// are found relative to the working directory.
+    pub fn parse(s: &str, format: ConfigFormat) -> Result<Config, ConfigError> {
+        let config = with_layers(
+            parse_value(s, format)?,
+            Path::new("."),
+            &mut Vec::new(),
+            &mut Vec::new(),
+        )?;
+        Ok(from_value(config)?)
+    }
+
+    /// layers returns the files that make up a config, in the order they're laid over each other, so the file
+    /// itself comes last.
+    pub fn layers<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>, ConfigError> {
+        let mut layers = Vec::new();
+        read_layers(path.as_ref(), &mut Vec::new(), &mut layers)?;
+        Ok(layers)
     }
 
     pub fn new() -> Config {
-        Config {
-            shared_objects: BTreeMap::new(),
+        Config::default()
+    }
+}
+
+/// The built-in profiles, as the configs they stand for. Rules under `**` apply to every library.
+const PROFILES: &[(&str, &str)] = &[
+    (
+        "no-network",
+        r#"shared_objects:
+  "**":
+    id: no-network
+    block: ["@network"]
+    action: deny
+"#,
+    ),
+    (
+        "read-only-fs",
+        r#"filesystem:
+  mode: read-only
+  writable: [/dev]
+  action: erofs
+"#,
+    ),
+    (
+        "compute-only",
+        r#"shared_objects:
+  ld.so (startup):
+    id: compute-only-startup
+    default: allow
+  "**":
+    id: compute-only
+    allow: ["@memory", read, readv, pread64, write, writev, lseek, fstat, fstatat, close, futex, clone, clone3,
+      exit, exit_group, rt_sigaction, rt_sigprocmask, rt_sigreturn, sigaltstack, clock_gettime, clock_nanosleep,
+      nanosleep, gettimeofday, getrandom, sched_yield, getpid, gettid, getuid, geteuid, getgid, getegid, uname,
+      set_robust_list, set_tid_address, rseq, prlimit64]
+    default: deny
+"#,
+    ),
+];
+
+impl Config {
+    /// profile returns one of the built-in profiles, which are starting points for not writing a config at all:
+    ///
+    /// - `no-network`: every library gets EPERM from the network syscalls
+    /// - `read-only-fs`: nothing outside /dev can be modified, and trying fails with EROFS
+    /// - `compute-only`: once the program has started, nothing but memory, threads, time and reading and writing
+    ///   files that are already open is allowed, and anything else fails with EPERM
+    pub fn profile(name: &str) -> Option<Config> {
+        let (_, profile) = PROFILES.iter().find(|(profile, _)| *profile == name)?;
+        Some(profile.parse().expect("built-in profile is invalid"))
+    }
+
+    /// profile_names lists the built-in profiles.
+    pub fn profile_names() -> impl Iterator<Item = &'static str> {
+        PROFILES.iter().map(|(name, _)| *name)
+    }
+}
+
+impl FromStr for Config {
+    type Err = serde_yaml::Error;
+
+    /// from_str parses a YAML config, expanding the groups it defines in its rules.
+    fn from_str(s: &str) -> Result<Config, serde_yaml::Error> {
+        from_value(serde_yaml::from_str(s)?)
+    }
+}
+
+/// parse_value parses a config without interpreting it.
+pub(crate) fn parse_value(s: &str, format: ConfigFormat) -> Result<Value, ConfigError> {
+    // Everything goes through YAML's data model, which the others fit in, so the rest only needs doing once
+    Ok(match format {
+        ConfigFormat::Yaml => serde_yaml::from_str(s)?,
+        ConfigFormat::Json => serde_json::from_str(s)?,
+        ConfigFormat::Toml => toml::from_str(s)?,
+    })
+}
+
+/// read_layers reads a config file and lays it over the configs it extends, adding each file it reads to `layers`.
+/// `within` is the files it's being read as part of, to catch configs that extend themselves.
+pub(crate) fn read_layers(
+    path: &Path,
+    within: &mut Vec<PathBuf>,
+    layers: &mut Vec<PathBuf>,
+) -> Result<Value, ConfigError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| ConfigError::IoError(path.display().to_string(), e))?;
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if within.contains(&canonical) {
+        return Err(ConfigError::Cycle(path.display().to_string()));
+    }
+
+    within.push(canonical);
+    let config = parse_value(&contents, ConfigFormat::from_path(path))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let config = with_layers(config, dir, within, layers)?;
+    within.pop();
+    layers.push(path.to_path_buf());
+    Ok(config)
+}
+
+/// with_layers merges the configs listed in `config`'s `extends`, in order, and then `config` itself on top. Their
+/// paths are relative to `dir`.
+fn with_layers(
+    mut config: Value,
+    dir: &Path,
+    within: &mut Vec<PathBuf>,
+    layers: &mut Vec<PathBuf>,
+) -> Result<Value, ConfigError> {
+    let extends = match config.as_mapping_mut().and_then(|c| c.remove("extends")) {
+        Some(extends) => Vec::<PathBuf>::deserialize(extends)?,
+        None => return Ok(config),
+    };
+    let mut merged = Value::Mapping(Default::default());
+    for base in extends {
+        merge(&mut merged, read_layers(&dir.join(base), within, layers)?);
+    }
+    merge(&mut merged, config);
+    Ok(merged)
+}
+
+/// lookup finds the value for a path in a map keyed by paths and globs. An exact path wins, then the glob with the
+/// most literal characters, then the first of those. Failing those, if there are `links`, a key that's a symlink to
+/// the path matches it, like `/lib/libc.so.6` for the file it points to, since the memory map only has canonical
+/// paths.
+fn lookup<'a, V>(
+    map: &'a BTreeMap<String, V>,
+    path: &str,
+    globs: &Globs,
+    links: Option<&ResolvedKeys>,
+) -> Option<(&'a String, &'a V)> {
+    if let Some(found) = map.get_key_value(path) {
+        return Some(found);
+    }
+    map.iter()
+        .filter(|(key, _)| glob::is_pattern(key) && globs.matches(key, path))
+        // max_by_key takes the last of equals, so go backwards to break ties by the first key
+        .rev()
+        .max_by_key(|(key, _)| glob::specificity(key))
+        .or_else(|| {
+            let links = links?;
+            map.iter().find(|(key, _)| links.links_to(key, path))
+        })
+}
+
+/// merge lays `layer` over `base`. Rules for the same library are combined, with the syscalls in each list and the
+/// path rules added together and anything else in the layer's rules replacing the base's. A syscall the layer lists
+/// is taken out of the base's other lists for that library, so a layer can block what its base allows, even when the
+/// base lists it as part of a group. Groups with the same name, and every other setting, are replaced outright.
+/// Sections for the same binary are merged the same way.
+fn merge(base: &mut Value, layer: Value) {
+    let mut groups = config_groups(base);
+    groups.extend(config_groups(&layer));
+    merge_with(base, layer, &groups);
+}
+
+/// config_groups reads the groups a config defines, if they're well-formed.
+fn config_groups(config: &Value) -> BTreeMap<String, Vec<String>> {
+    config
+        .get("groups")
+        .and_then(|groups| crate::names::groups::deserialize(groups.clone()).ok())
+        .unwrap_or_default()
+}
+
+/// merge_with is merge, with `groups` the groups defined by the two configs together.
+fn merge_with(base: &mut Value, layer: Value, groups: &BTreeMap<String, Vec<String>>) {
+    let (Some(base), Value::Mapping(layer)) = (base.as_mapping_mut(), layer) else {
+        return;
+    };
+    for (key, value) in layer {
+        match (key.as_str(), base.get_mut(&key), value) {
+            (
+                Some("shared_objects" | "groups" | "binaries"),
+                Some(Value::Mapping(base)),
+                Value::Mapping(layer),
+            ) => {
+                for (name, value) in layer {
+                    match (key.as_str(), base.get_mut(&name)) {
+                        (Some("shared_objects"), Some(entry)) => merge_entry(entry, value, groups),
+                        (Some("binaries"), Some(section)) => merge_with(section, value, groups),
+                        _ => {
+                            base.insert(name, value);
+                        }
+                    }
+                }
+            }
+            (_, _, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// merge_entry lays one library's rules over another's, as described for merge.
+fn merge_entry(base: &mut Value, layer: Value, groups: &BTreeMap<String, Vec<String>>) {
+    const LISTS: [&str; 2] = ["allow", "block"];
+    let (Some(base), Value::Mapping(layer)) = (base.as_mapping_mut(), layer) else {
+        return;
+    };
+    for (key, value) in layer {
+        // Path rules for the same syscall are replaced, and the rest kept
+        if let (Some("paths"), Some(Value::Mapping(base)), Value::Mapping(layer)) =
+            (key.as_str(), base.get_mut(&key), &value)
+        {
+            base.extend(layer.clone());
+            continue;
+        }
+        let Some(list) = key.as_str().filter(|key| LISTS.contains(key)) else {
+            base.insert(key, value);
+            continue;
+        };
+        let Value::Sequence(names) = value else {
+            base.insert(key, value);
+            continue;
+        };
+        // A list with a name that isn't a syscall is left as it is, for deserializing to report
+        if let Some(listed) = expand_syscalls(groups, &names) {
+            for other in LISTS.iter().filter(|other| **other != list) {
+                let Some(Value::Sequence(others)) = base.get_mut(*other) else {
+                    continue;
+                };
+                // Groups are only written out as their syscalls where part of one is taken away
+                if let Some(remaining) = expand_syscalls(groups, others)
+                    .filter(|syscalls| !syscalls.is_disjoint(&listed))
+                {
+                    *others = remaining
+                        .difference(&listed)
+                        .map(|syscall| Value::from(syscall.name()))
+                        .collect();
+                }
+            }
+        }
+        match base.get_mut(list) {
+            Some(Value::Sequence(existing)) => {
+                for name in names {
+                    if !existing.contains(&name) {
+                        existing.push(name);
+                    }
+                }
+            }
+            _ => {
+                base.insert(key, Value::Sequence(names));
+            }
+        }
+    }
+}
+
+/// expand_syscalls returns the syscalls in a list of names, numbers and groups, the config's own and the built-in
+/// ones, or None if any of them isn't one.
+fn expand_syscalls(
+    groups: &BTreeMap<String, Vec<String>>,
+    names: &[Value],
+) -> Option<BTreeSet<Sysno>> {
+    let mut expanded = Vec::new();
+    for name in names {
+        expand_group(groups, name.clone(), &mut expanded, &mut Vec::new()).ok()?;
+    }
+    crate::names::set::deserialize(Value::Sequence(expanded))
+        .ok()
+        .flatten()
+}
+
+pub(crate) fn from_value(mut config: Value) -> Result<Config, serde_yaml::Error> {
+    expand_groups(&mut config)?;
+    let mut config: Config = serde_yaml::from_value(config)?;
+    // The paths are handed to mount as C strings
+    let namespaces = &config.namespaces;
+    if let Some(path) = namespaces
+        .root
+        .iter()
+        .chain(&namespaces.read_only_binds)
+        .find(|path| path.as_os_str().as_bytes().contains(&0))
+    {
+        return Err(de::Error::custom(format!(
+            "namespaces: {path:?} has a NUL byte in it"
+        )));
+    }
+    // And these go into the target's environment as KEY=VALUE
+    if let Some((key, _)) =
+        config.environment.pin.iter().find(|(key, value)| {
+            key.is_empty() || key.contains(['=', '\0']) || value.contains('\0')
+        })
+    {
+        return Err(de::Error::custom(format!("environment: can't pin {key:?}")));
+    }
+    config.compile();
+    Ok(config)
+}
+
+/// ConfigFormat: the languages a config file can be written in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    #[default]
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// from_path goes by a file's extension, taking anything that isn't `.json` or `.toml` to be YAML.
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Invalid YAML config: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Invalid JSON config: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid TOML config: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Failed to read {0}: {1}")]
+    IoError(String, std::io::Error),
+    #[error("Config {0} extends itself")]
+    Cycle(String),
+}
+
+/// expand_groups replaces references to the config's own groups in its rules with the syscalls in them. The built-in
+/// groups are left for deserializing the rules to expand.
+fn expand_groups(config: &mut Value) -> Result<(), serde_yaml::Error> {
+    let groups = match config.get("groups") {
+        Some(groups) => crate::names::groups::deserialize(groups.clone())?,
+        None => return Ok(()),
+    };
+    let Some(config) = config.as_mapping_mut() else {
+        return Ok(());
+    };
+    let mut sections = Vec::new();
+    for (key, value) in config.iter_mut() {
+        match key.as_str() {
+            Some("shared_objects") => sections.push(value),
+            Some("binaries") => sections.extend(
+                value
+                    .as_mapping_mut()
+                    .into_iter()
+                    .flat_map(|binaries| binaries.values_mut())
+                    .filter_map(|section| section.get_mut("shared_objects")),
+            ),
+            _ => {}
+        }
+    }
+    for entry in sections
+        .into_iter()
+        .filter_map(Value::as_mapping_mut)
+        .flat_map(|shared_objects| shared_objects.values_mut())
+    {
+        for list in ["allow", "block"] {
+            if let Some(Value::Sequence(names)) = entry.get_mut(list) {
+                let mut expanded = Vec::new();
+                for name in names.drain(..) {
+                    expand_group(&groups, name, &mut expanded, &mut Vec::new())?;
+                }
+                *names = expanded;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// expand_group adds `name` to `expanded`, or the syscalls in it if it's one of `groups`. `within` is the groups
+/// it's being expanded as part of, to catch groups that include themselves.
+fn expand_group(
+    groups: &BTreeMap<String, Vec<String>>,
+    name: Value,
+    expanded: &mut Vec<Value>,
+    within: &mut Vec<String>,
+) -> Result<(), serde_yaml::Error> {
+    let group = name
+        .as_str()
+        .and_then(|name| name.strip_prefix('@'))
+        .filter(|group| groups.contains_key(*group))
+        .map(String::from);
+    let Some(group) = group else {
+        expanded.push(name);
+        return Ok(());
+    };
+    if within.contains(&group) {
+        return Err(de::Error::custom(format!(
+            "syscall group @{group} includes itself"
+        )));
+    }
+
+    within.push(group.clone());
+    for member in &groups[&group] {
+        // Syscall numbers come back as strings, so they have to be told apart from names again
+        let member = match member.parse::<u32>() {
+            Ok(number) => Value::from(number),
+            Err(_) => Value::from(member.as_str()),
+        };
+        expand_group(groups, member, expanded, within)?;
+    }
+    within.pop();
+    Ok(())
+}
+
+/// strictest returns whichever of two seccomp return values stops the syscall hardest.
+fn strictest(a: u32, b: u32) -> u32 {
+    let rank = |ret: u32| match ret & libc::SECCOMP_RET_ACTION_FULL {
+        libc::SECCOMP_RET_KILL_PROCESS => 3,
+        libc::SECCOMP_RET_TRAP => 2,
+        libc::SECCOMP_RET_ERRNO => 1,
+        _ => 0,
+    };
+    if rank(b) > rank(a) {
+        b
+    } else {
+        a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::LOADER_STARTUP;
+
+    fn rule(list: RuleList) -> Rule {
+        Rule { id: None, list }
+    }
+
+    #[test]
+    fn test_rule_action() {
+        let config: Config = serde_yaml::from_str(
+            r#"shared_objects:
+  /usr/lib/libfoo.so:
+    block: [write]
+    action:
+      deny: EACCES
+  /usr/lib/libbar.so:
+    block: [write]
+    action: trap
+  /usr/lib/libbaz.so:
+    block: [write]
+    action: deny
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.check("/usr/lib/libfoo.so", Sysno::write),
+            Check::Blocked(RuleAction::Deny(Errno::EACCES), rule(RuleList::Block))
+        );
+        assert_eq!(
+            config.check("/usr/lib/libbar.so", Sysno::write),
+            Check::Blocked(RuleAction::Trap, rule(RuleList::Block))
+        );
+        assert_eq!(
+            config.check("/usr/lib/libbaz.so", Sysno::write),
+            Check::Blocked(RuleAction::Deny(Errno::EPERM), rule(RuleList::Block))
+        );
+        assert_eq!(
+            config.check("/usr/lib/libbar.so", Sysno::openat),
+            Check::Unknown
+        );
+
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert!(yaml.contains("deny: EACCES"));
+        assert_eq!(serde_yaml::from_str::<Config>(&yaml).unwrap(), config);
+    }
+
+    #[test]
+    fn test_glob() {
+        let config: Config = serde_yaml::from_str(
+            r#"shared_objects:
+  "**/libc.so.*":
+    id: libc
+    allow: [read]
+  "/usr/lib/**/libc.so.*":
+    id: usr-libc
+    allow: [read]
+  /usr/lib/aarch64-linux-gnu/libc.so.6:
+    id: exact
+    allow: [read]
+"#,
+        )
+        .unwrap();
+        let id = |library| config.entry(library).and_then(|entry| entry.id.as_deref());
+        assert_eq!(id("/usr/lib/aarch64-linux-gnu/libc.so.6"), Some("exact"));
+        assert_eq!(id("/usr/lib/riscv64-linux-gnu/libc.so.6"), Some("usr-libc"));
+        assert_eq!(id("/lib/libc.so.6"), Some("libc"));
+        assert_eq!(id("/lib/libcrypt.so.1"), None);
+        assert!(matches!(
+            config.check("/lib/libc.so.6", Sysno::read),
+            Check::Allowed(..)
+        ));
+        // A config that wasn't loaded matches its globs as it goes, the same as one that was compiled
+        assert!(!config.is_compiled());
+        let mut compiled = config.clone();
+        compiled.compile();
+        assert!(compiled.is_compiled());
+        for library in [
+            "/usr/lib/riscv64-linux-gnu/libc.so.6",
+            "/lib/libc.so.6",
+            "/lib/libcrypt.so.1",
+        ] {
+            assert_eq!(compiled.entry(library), config.entry(library));
         }
+        assert!(!config.uses_identities());
+
+        // A library named exactly by its soname or build ID wins over a glob matching its path
+        let config: Config = serde_yaml::from_str(
+            r#"shared_objects:
+  "**/libssl.so.*":
+    allow: [read]
+  soname:libssl.so.3:
+    allow: [write]
+  build-id:8d7b2c:
+    allow: [openat]
+"#,
+        )
+        .unwrap();
+        assert!(config.uses_identities());
+        let names = [
+            String::from("build-id:0000"),
+            String::from("soname:libssl.so.3"),
+        ];
+        assert_eq!(
+            config.library_name(None, "/usr/lib/libssl.so.3", &names),
+            "soname:libssl.so.3"
+        );
+        let names = [
+            String::from("build-id:8d7b2c"),
+            String::from("soname:libssl.so.3"),
+        ];
+        assert_eq!(
+            config.library_name(None, "/usr/lib/libssl.so.3", &names),
+            "build-id:8d7b2c"
+        );
+        assert_eq!(
+            config.library_name(None, "/usr/lib/libssl.so.1.1", &[]),
+            "/usr/lib/libssl.so.1.1"
+        );
+
+        // A key that's a symlink matches the file it leads to
+        let dir = std::env::temp_dir().join(format!("crabtrap_symlink_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("libfoo.so.1.2");
+        let link = dir.join("libfoo.so.1");
+        fs::write(&file, "").unwrap();
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+        let config: Config = serde_yaml::from_str(&format!(
+            "shared_objects:\n  {}:\n    id: foo\n    allow: [read]\n",
+            link.display()
+        ))
+        .unwrap();
+        let id = |library: &str| config.entry(library).and_then(|entry| entry.id.clone());
+        assert_eq!(id(&file.to_string_lossy()), Some("foo".into()));
+        assert_eq!(id(&link.to_string_lossy()), Some("foo".into()));
+        assert_eq!(id("/lib/libc.so.6"), None);
+
+        // Loading a config resolves its keys there and then, and compiling it again resolves them afresh
+        let yaml = format!(
+            "shared_objects:\n  {}:\n    id: foo\n    allow: [read]\n",
+            link.display()
+        );
+        let mut config: Config = yaml.parse().unwrap();
+        let upgraded = dir.join("libfoo.so.1.3");
+        fs::write(&upgraded, "").unwrap();
+        fs::remove_file(&link).unwrap();
+        std::os::unix::fs::symlink(&upgraded, &link).unwrap();
+        let id = |config: &Config, library: &Path| {
+            config
+                .entry(&library.to_string_lossy())
+                .and_then(|entry| entry.id.clone())
+        };
+        assert_eq!(id(&config, &file), Some("foo".into()));
+        assert_eq!(id(&config, &upgraded), None);
+        assert_eq!(id(&yaml.parse().unwrap(), &upgraded), Some("foo".into()));
+        config.compile();
+        assert_eq!(id(&config, &file), None);
+        assert_eq!(id(&config, &upgraded), Some("foo".into()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_binaries() {
+        let mut config: Config = r#"groups:
+  io: [read, write]
+shared_objects:
+  /usr/lib/libfoo.so:
+    id: everyone
+    allow: ["@io"]
+binaries:
+  /usr/bin/convert:
+    shared_objects:
+      /usr/lib/libfoo.so:
+        id: convert
+        block: ["@io"]
+  /usr/local/bin/*:
+    shared_objects:
+      /usr/lib/libbar.so:
+        allow: [read]
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(config.binary("/usr/bin/convert"), Some("/usr/bin/convert"));
+        assert_eq!(
+            config.binary("/usr/local/bin/static"),
+            Some("/usr/local/bin/*")
+        );
+        assert_eq!(config.binary("/bin/sh"), None);
+
+        let id = |binary, library| {
+            config
+                .entry_in(binary, library)
+                .and_then(|entry| entry.id.as_deref())
+        };
+        assert_eq!(id(None, "/usr/lib/libfoo.so"), Some("everyone"));
+        assert_eq!(
+            id(Some("/usr/bin/convert"), "/usr/lib/libfoo.so"),
+            Some("convert")
+        );
+        // Libraries a binary doesn't list fall back to the rules for everyone
+        assert_eq!(
+            id(Some("/usr/local/bin/*"), "/usr/lib/libfoo.so"),
+            Some("everyone")
+        );
+        assert!(matches!(
+            config.check_in(Some("/usr/bin/convert"), "/usr/lib/libfoo.so", Sysno::write),
+            Check::Blocked(RuleAction::Kill, _)
+        ));
+
+        // Sections for the same binary are merged like the rules for everyone
+        let mut base = parse_value(
+            "binaries:\n  /usr/bin/convert:\n    shared_objects:\n      /usr/lib/libfoo.so:\n        allow: [read]\n",
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+        merge(
+            &mut base,
+            parse_value(
+                "binaries:\n  /usr/bin/convert:\n    shared_objects:\n      /usr/lib/libfoo.so:\n        block: [write]\n",
+                ConfigFormat::Yaml,
+            )
+            .unwrap(),
+        );
+        config = from_value(base).unwrap();
+        let entry = &config.binaries["/usr/bin/convert"].shared_objects["/usr/lib/libfoo.so"];
+        assert_eq!(entry.allow, Some(BTreeSet::from([Sysno::read])));
+        assert_eq!(entry.block, Some(BTreeSet::from([Sysno::write])));
+    }
+
+    #[test]
+    fn test_paths() {
+        let config: Config = r#"shared_objects:
+  /usr/lib/libfoo.so:
+    allow: [openat]
+    action:
+      deny: EACCES
+    paths:
+      open: [/etc/ssl/, "/usr/share/**/*.pem"]
+      renameat: [/tmp]
+"#
+        .parse()
+        .unwrap();
+        assert!(config.has_argument_rules(Sysno::openat));
+        assert!(!config.has_argument_rules(Sysno::read));
+        let check = |syscall, paths: &[&str]| {
+            let paths = Arguments::Paths(paths.iter().map(PathBuf::from).collect());
+            config.check_arguments(None, "/usr/lib/libfoo.so", syscall, &[paths])
+        };
+        let allowed = Some(Check::Allowed(rule(RuleList::Paths)));
+        let denied = Some(Check::Blocked(
+            RuleAction::Deny(Errno::EACCES),
+            rule(RuleList::Paths),
+        ));
+        assert_eq!(check(Sysno::openat, &["/etc/ssl/certs/ca.pem"]), allowed);
+        assert_eq!(check(Sysno::openat, &["/usr/share/ca/root.pem"]), allowed);
+        // Prefixes go by whole components
+        assert_eq!(check(Sysno::openat, &["/etc/ssl.d/key"]), denied);
+        assert_eq!(check(Sysno::openat, &[]), denied);
+        assert_eq!(check(Sysno::renameat, &["/tmp/a", "/etc/passwd"]), denied);
+        assert_eq!(check(Sysno::renameat, &["/tmp/a", "/tmp/b"]), allowed);
+        assert_eq!(check(Sysno::read, &["/etc/ssl/certs/ca.pem"]), None);
+        let prefiltered = Config {
+            prefilter: true,
+            ..config.clone()
+        };
+        let syscalls = prefiltered.prefilter_syscalls().unwrap();
+        assert!(syscalls.contains(&Sysno::renameat));
+        // So that the map and the descriptor table are kept up to date
+        assert!(syscalls.contains(&Sysno::mprotect));
+        assert!(!syscalls.contains(&Sysno::brk));
+        assert!(syscalls.contains(&Sysno::close));
+        assert!(syscalls.contains(&Sysno::dup3));
+    }
+
+    #[test]
+    fn test_network() {
+        let config: Config = r#"shared_objects:
+  /usr/lib/libcurl.so.4:
+    network:
+      domains: [inet, inet6]
+      types: [stream]
+      destinations: ["10.0.0.0/8:443"]
+"#
+        .parse()
+        .unwrap();
+        assert!(config.has_argument_rules(Sysno::socket));
+        assert!(config.has_argument_rules(Sysno::sendmsg));
+        let check = |syscall, arguments| {
+            config.check_arguments(None, "/usr/lib/libcurl.so.4", syscall, &[arguments])
+        };
+        let allowed = Some(Check::Allowed(rule(RuleList::Network)));
+        let killed = Some(Check::Blocked(RuleAction::Kill, rule(RuleList::Network)));
+        let socket = |domain, kind| Arguments::Socket { domain, kind };
+        let destination =
+            |addr: &str| Arguments::Destination(Some(Destination::Ip(addr.parse().unwrap())));
+
+        assert_eq!(
+            check(
+                Sysno::socket,
+                socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC)
+            ),
+            allowed
+        );
+        assert_eq!(
+            check(Sysno::socket, socket(libc::AF_INET, libc::SOCK_DGRAM)),
+            killed
+        );
+        assert_eq!(
+            check(Sysno::socket, socket(libc::AF_UNIX, libc::SOCK_STREAM)),
+            killed
+        );
+        assert_eq!(check(Sysno::connect, destination("10.1.2.3:443")), allowed);
+        assert_eq!(check(Sysno::connect, destination("10.1.2.3:80")), killed);
+        assert_eq!(check(Sysno::sendto, Arguments::Destination(None)), killed);
+        // sendmmsg is blocked if any of its messages go somewhere they may not
+        assert!(config.has_argument_rules(Sysno::sendmmsg));
+        assert_eq!(
+            config.check_arguments(
+                None,
+                "/usr/lib/libcurl.so.4",
+                Sysno::sendmmsg,
+                &[destination("10.1.2.3:443"), destination("10.1.2.3:80")]
+            ),
+            killed
+        );
+        assert_eq!(
+            config.check_arguments(
+                None,
+                "/usr/lib/libc.so.6",
+                Sysno::connect,
+                &[destination("10.1.2.3:80")]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fds() {
+        let config: Config = r#"shared_objects:
+  /usr/lib/libfoo.so:
+    action: deny
+    fds:
+      write: [stdout, stderr]
+      sendto: ["socket:unix"]
+    network:
+      destinations: [/run/]
+"#
+        .parse()
+        .unwrap();
+        assert!(config.has_argument_rules(Sysno::write));
+        let check = |syscall, arguments: &[Arguments]| {
+            config.check_arguments(None, "/usr/lib/libfoo.so", syscall, arguments)
+        };
+        let fd = |fd, target: &str, family| Arguments::Fd {
+            fd,
+            target: Some(target.into()),
+            family,
+        };
+        let denied = |list| Some(Check::Blocked(RuleAction::Deny(Errno::EPERM), rule(list)));
+        assert_eq!(
+            check(Sysno::write, &[fd(2, "/dev/pts/0", None)]),
+            Some(Check::Allowed(rule(RuleList::Fds)))
+        );
+        assert_eq!(
+            check(Sysno::write, &[fd(3, "/etc/passwd", None)]),
+            denied(RuleList::Fds)
+        );
+        // Both the descriptor and the destination have to be allowed
+        let to = |path: &str| Arguments::Destination(Some(Destination::Unix(path.into())));
+        let unix = fd(3, "socket:[1]", Some(libc::AF_UNIX));
+        assert_eq!(
+            check(Sysno::sendto, &[unix.clone(), to("/run/foo")]),
+            Some(Check::Allowed(rule(RuleList::Fds)))
+        );
+        assert_eq!(
+            check(Sysno::sendto, &[unix, to("/tmp/foo")]),
+            denied(RuleList::Network)
+        );
+        let prefiltered = Config {
+            prefilter: true,
+            ..config.clone()
+        };
+        let syscalls = prefiltered.prefilter_syscalls().unwrap();
+        assert!(syscalls.contains(&Sysno::write));
+        assert!(syscalls.contains(&Sysno::close));
+        assert!(syscalls.contains(&Sysno::openat));
+    }
+
+    #[test]
+    fn test_formats() {
+        let yaml: Config = r#"groups:
+  db: [connect, sendto]
+shared_objects:
+  /usr/lib/libfoo.so:
+    allow: ["@db", read]
+    block: [write]
+    action:
+      deny: EACCES
+"#
+        .parse()
+        .unwrap();
+        let json = Config::parse(
+            r#"{
+  "groups": {"db": ["connect", "sendto"]},
+  "shared_objects": {
+    "/usr/lib/libfoo.so": {
+      "allow": ["@db", "read"],
+      "block": ["write"],
+      "action": {"deny": "EACCES"}
+    }
+  }
+}"#,
+            ConfigFormat::Json,
+        )
+        .unwrap();
+        let toml = Config::parse(
+            r#"[groups]
+db = ["connect", "sendto"]
+
+[shared_objects."/usr/lib/libfoo.so"]
+allow = ["@db", "read"]
+block = ["write"]
+action = { deny = "EACCES" }
+"#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        assert_eq!(json, yaml);
+        assert_eq!(toml, yaml);
+
+        assert!(matches!(
+            Config::parse("shared_objects = 1", ConfigFormat::Toml),
+            Err(ConfigError::Yaml(_))
+        ));
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("crabtrap.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("crabtrap.yml")),
+            ConfigFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn test_extends() {
+        let dir = std::env::temp_dir().join(format!("crabtrap_extends_{}", std::process::id()));
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::write(
+            dir.join("shared/base.yaml"),
+            r#"prefilter: true
+groups:
+  io: [read, write]
+shared_objects:
+  /usr/lib/libfoo.so:
+    allow: ["@io", openat]
+    action: log
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("shared/network-deny.toml"),
+            r#"[shared_objects."/usr/lib/libfoo.so"]
+block = ["connect"]
+action = "deny"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("project.yaml"),
+            r#"extends: [shared/base.yaml, shared/network-deny.toml]
+shared_objects:
+  /usr/lib/libfoo.so:
+    block: [write]
+    action: kill
+  /usr/lib/libbar.so:
+    allow: ["@io"]
+"#,
+        )
+        .unwrap();
+
+        let config = Config::from_file(dir.join("project.yaml"));
+        assert!(config.prefilter);
+        let foo = &config.shared_objects["/usr/lib/libfoo.so"];
+        assert_eq!(
+            foo.allow,
+            Some(BTreeSet::from([Sysno::read, Sysno::openat]))
+        );
+        assert_eq!(
+            foo.block,
+            Some(BTreeSet::from([Sysno::write, Sysno::connect]))
+        );
+        assert_eq!(foo.action, RuleAction::Kill);
+        // The base's groups are there for the layers above it
+        assert_eq!(
+            config.shared_objects["/usr/lib/libbar.so"].allow,
+            Some(BTreeSet::from([Sysno::read, Sysno::write]))
+        );
+
+        assert_eq!(
+            Config::layers(dir.join("project.yaml")).unwrap(),
+            vec![
+                dir.join("shared/base.yaml"),
+                dir.join("shared/network-deny.toml"),
+                dir.join("project.yaml")
+            ]
+        );
+
+        // Configs given together are layered the same way
+        let config = Config::from_files(&[
+            dir.join("shared/base.yaml"),
+            dir.join("shared/network-deny.toml"),
+        ]);
+        let foo = &config.shared_objects["/usr/lib/libfoo.so"];
+        assert_eq!(foo.block, Some(BTreeSet::from([Sysno::connect])));
+        assert_eq!(foo.action, RuleAction::Deny(Errno::EPERM));
+
+        fs::write(dir.join("shared/base.yaml"), "extends: [../project.yaml]\n").unwrap();
+        assert!(matches!(
+            read_layers(&dir.join("project.yaml"), &mut Vec::new(), &mut Vec::new()),
+            Err(ConfigError::Cycle(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_groups() {
+        let mut base = parse_value(
+            r#"groups:
+  io: [read, write]
+shared_objects:
+  /usr/lib/libfoo.so:
+    allow: ["@io", "@network", openat]
+"#,
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+        merge(
+            &mut base,
+            parse_value(
+                r#"shared_objects:
+  /usr/lib/libfoo.so:
+    block: [write, connect]
+"#,
+                ConfigFormat::Yaml,
+            )
+            .unwrap(),
+        );
+        let config = from_value(base).unwrap();
+        let foo = &config.shared_objects["/usr/lib/libfoo.so"];
+        let allow = foo.allow.as_ref().unwrap();
+        // Each group loses only what the layer blocks
+        assert!(allow.contains(&Sysno::read));
+        assert!(allow.contains(&Sysno::socket));
+        assert!(allow.contains(&Sysno::openat));
+        assert!(!allow.contains(&Sysno::write));
+        assert!(!allow.contains(&Sysno::connect));
+        assert_eq!(
+            foo.block,
+            Some(BTreeSet::from([Sysno::write, Sysno::connect]))
+        );
+
+        // A group the layer lists is taken out of the base's lists by its syscalls too
+        let mut base = parse_value(
+            "shared_objects:\n  /usr/lib/libfoo.so:\n    allow: [read, socket]\n",
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+        merge(
+            &mut base,
+            parse_value(
+                "shared_objects:\n  /usr/lib/libfoo.so:\n    block: [\"@network\"]\n",
+                ConfigFormat::Yaml,
+            )
+            .unwrap(),
+        );
+        let config = from_value(base).unwrap();
+        let foo = &config.shared_objects["/usr/lib/libfoo.so"];
+        assert_eq!(foo.allow, Some(BTreeSet::from([Sysno::read])));
+    }
+
+    #[test]
+    fn test_profiles() {
+        for name in Config::profile_names() {
+            assert!(Config::profile(name).is_some(), "{name}");
+        }
+        assert_eq!(Config::profile("nope"), None);
+
+        let blocked = |config: &Config, library, syscall| {
+            matches!(config.check(library, syscall), Check::Blocked(..))
+        };
+        let no_network = Config::profile("no-network").unwrap();
+        assert!(blocked(
+            &no_network,
+            "/usr/lib/libcurl.so.4",
+            Sysno::connect
+        ));
+        assert!(!blocked(
+            &no_network,
+            "/usr/lib/libcurl.so.4",
+            Sysno::openat
+        ));
+
+        let compute_only = Config::profile("compute-only").unwrap();
+        assert!(blocked(&compute_only, "/usr/bin/python3", Sysno::openat));
+        assert!(!blocked(&compute_only, "/usr/bin/python3", Sysno::mmap));
+        assert!(!blocked(&compute_only, LOADER_STARTUP, Sysno::openat));
+
+        assert_eq!(
+            Config::profile("read-only-fs").unwrap().filesystem.mode,
+            FilesystemMode::ReadOnly
+        );
+    }
+
+    #[test]
+    fn test_default() {
+        let config: Config = serde_yaml::from_str(
+            r#"default: kill
+shared_objects:
+  /usr/lib/libfoo.so:
+    id: foo
+    allow: [read]
+    default: deny
+  /usr/lib/libbar.so:
+    allow: [read]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.default, DefaultPolicy::Kill);
+        assert_eq!(
+            config.check("/usr/lib/libfoo.so", Sysno::read),
+            Check::Allowed(Rule {
+                id: Some(String::from("foo")),
+                list: RuleList::Allow
+            })
+        );
+        assert_eq!(
+            config.check("/usr/lib/libfoo.so", Sysno::write),
+            Check::Blocked(
+                RuleAction::Deny(Errno::EPERM),
+                Rule {
+                    id: Some(String::from("foo")),
+                    list: RuleList::Default
+                }
+            )
+        );
+        // Left to the top-level default once the whole stack has been walked
+        assert_eq!(
+            config.check("/usr/lib/libbar.so", Sysno::write),
+            Check::Unknown
+        );
+        assert_eq!(config.prefilter_syscalls(), None);
+        assert_eq!(config.stack_walk, StackWalkConfig::default());
+
+        let config: Config = serde_yaml::from_str(
+            r#"stack_walk:
+  on_failure: kill
+forbid_anonymous_code: true
+prefilter: true
+shared_objects:
+  "[anonymous-exec]":
+    allow: [mprotect]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.stack_walk.max_depth, 256);
+        assert_eq!(config.stack_walk.on_failure, Some(DefaultPolicy::Kill));
+        assert_eq!(
+            config.check("[anonymous-exec]", Sysno::mprotect),
+            Check::Allowed(Rule {
+                id: None,
+                list: RuleList::Allow
+            })
+        );
+        // Any syscall could come from anonymous code
+        assert_eq!(config.prefilter_syscalls(), None);
+    }
+
+    #[test]
+    fn test_groups() {
+        let config: Config = r#"groups:
+  db: [sendto, recvfrom, '@files']
+  files: [openat, 64]
+shared_objects:
+  /usr/lib/libfoo.so:
+    allow: ['@db', read]
+    block: ['@network']
+"#
+        .parse()
+        .unwrap();
+        let entry = &config.shared_objects["/usr/lib/libfoo.so"];
+        assert_eq!(
+            entry.allow,
+            Some(BTreeSet::from([
+                Sysno::sendto,
+                Sysno::recvfrom,
+                Sysno::openat,
+                Sysno::write,
+                Sysno::read
+            ]))
+        );
+        assert!(entry
+            .block
+            .as_ref()
+            .is_some_and(|block| block.contains(&Sysno::connect)));
+
+        assert!(r#"groups:
+  a: ['@b']
+  b: ['@a']
+shared_objects:
+  /usr/lib/libfoo.so:
+    allow: ['@a']
+"#
+        .parse::<Config>()
+        .is_err());
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let config: Config = "namespaces:\n  root: /tmp/root\n  read_only_binds: [/usr]\n"
+            .parse()
+            .unwrap();
+        assert_eq!(config.namespaces.root, Some("/tmp/root".into()));
+        assert!("namespaces:\n  read_only_binds: [\"/usr\\0\"]\n"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_environment() {
+        let config: Config = "environment:\n  pin:\n    TZ: UTC\n".parse().unwrap();
+        assert_eq!(config.environment.pin["TZ"], "UTC");
+        assert!("environment:\n  pin:\n    TZ: \"UTC\\0\"\n"
+            .parse::<Config>()
+            .is_err());
+        assert!("environment:\n  pin:\n    A=B: C\n"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_to_seccomp_bpf() {
+        let config: Config = serde_yaml::from_str(
+            r#"shared_objects:
+  /usr/lib/libfoo.so:
+    block: [socket, connect]
+  /usr/lib/libbaz.so:
+    block: [openat]
+    action: deny
+  /usr/lib/libbar.so:
+    allow: [connect]
+    block: [openat]
+    action: trap
+"#,
+        )
+        .unwrap();
+        let filter = config.to_seccomp_bpf();
+        // After checking the architecture and loading the syscall number, each rule is a comparison and a return
+        let rules = filter[4..filter.len() - 1]
+            .chunks_exact(2)
+            .map(|rule| (rule[0].k, rule[1].k))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            rules,
+            BTreeMap::from([
+                (Sysno::openat.id() as u32, libc::SECCOMP_RET_TRAP),
+                (Sysno::socket.id() as u32, libc::SECCOMP_RET_KILL_PROCESS),
+            ])
+        );
     }
 }
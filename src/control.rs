@@ -0,0 +1,232 @@
+use crate::{log::warning, wake::Wake, Config, RunStats};
+use nix::fcntl::{self, RenameFlags};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, DirBuilder},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+};
+
+/// Request: what a client can ask the supervisor over its control socket, as one JSON object per line like
+/// `{"command": "reload", "path": "/etc/crabtrap/web.yaml"}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    /// Stop checking syscalls until told to resume, and let the tree run at full speed. Forks, execs and exits are
+    /// still followed.
+    Pause,
+    /// Go back to checking syscalls after a pause
+    Resume,
+    /// Load the config at this path, along with any it extends, and enforce it in place of the current one. Refused
+    /// if it needs the tracer to see syscalls the seccomp prefilter lets through.
+    Reload { path: PathBuf },
+    /// Report the stats for the run so far
+    Stats,
+    /// Let go of the whole tree, leaving it to run untraced. Refused if the seccomp prefilter is installed, or the run
+    /// has a cgroup or captures the target's output.
+    Detach,
+}
+
+/// Response: the supervisor's answer to a request, one JSON object per line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+    Ok,
+    Stats(Box<RunStats>),
+    Error(String),
+}
+
+/// Command: a request as the supervisor acts on it, with any config to reload already loaded
+pub(crate) enum Command {
+    Pause,
+    Resume,
+    Reload(Box<Config>),
+    Stats,
+    Detach,
+}
+
+/// ControlSocket: listens for requests on a Unix socket while the run goes on. Clients are served on threads of their
+/// own, which hand each request to the supervisor and wait for it to answer, so a client can only hold up itself.
+/// The socket is removed once the run is over.
+pub(crate) struct ControlSocket {
+    path: PathBuf,
+    receiver: Receiver<(Command, Sender<Response>)>,
+}
+
+impl ControlSocket {
+    /// bind starts listening at `path`, which only we can connect to. A socket left there by an earlier run is
+    /// replaced, but anything else is an error. It has to be called on the tracer's thread, which is woken up for
+    /// each request.
+    pub fn bind(path: &Path) -> io::Result<ControlSocket> {
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        // Bound in a directory only we can get into, and locked down before it's moved out of it, the socket is
+        // never open to anyone else
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let private = path.with_file_name(format!(".{name}.{}", process::id()));
+        DirBuilder::new().mode(0o700).create(&private)?;
+        let bound = private.join("socket");
+        let listener = UnixListener::bind(&bound).and_then(|listener| {
+            fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+            fcntl::renameat2(None, &bound, None, path, RenameFlags::RENAME_NOREPLACE)?;
+            Ok(listener)
+        });
+        let _ = fs::remove_file(&bound);
+        fs::remove_dir(&private)?;
+        let listener = listener?;
+        let wake = Wake::current()?;
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        thread::spawn(move || serve(stream, sender, wake));
+                    }
+                    Err(e) => warning!("Failed to accept a control connection: {e}"),
+                }
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            receiver,
+        })
+    }
+
+    /// requests returns the requests that have come in since the last call, along with where to answer each.
+    pub fn requests(&self) -> Vec<(Command, Sender<Response>)> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// serve answers one client's requests until it hangs up.
+fn serve(stream: UnixStream, supervisor: Sender<(Command, Sender<Response>)>, wake: Wake) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle(request, &supervisor, wake),
+            Err(e) => Response::Error(format!("malformed request: {e}")),
+        };
+        let mut json = serde_json::to_string(&response).expect("failed to serialize response");
+        json.push('\n');
+        if writer.write_all(json.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// handle passes a request on to the supervisor, and wakes it until it answers.
+fn handle(
+    request: Request,
+    supervisor: &Sender<(Command, Sender<Response>)>,
+    wake: Wake,
+) -> Response {
+    let command = match request {
+        Request::Pause => Command::Pause,
+        Request::Resume => Command::Resume,
+        // Loading it here keeps a slow or broken file from holding up the tracer
+        Request::Reload { path } => match Config::load(&path) {
+            Ok(config) => Command::Reload(Box::new(config)),
+            Err(e) => {
+                return Response::Error(format!("failed to load config {}: {e}", path.display()))
+            }
+        },
+        Request::Stats => Command::Stats,
+        Request::Detach => Command::Detach,
+    };
+    let (sender, receiver) = channel();
+    if supervisor.send((command, sender)).is_err() {
+        return Response::Error(String::from("the run is over"));
+    }
+    loop {
+        wake.wake();
+        match receiver.recv_timeout(Wake::INTERVAL) {
+            Ok(response) => return response,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Response::Error(String::from("the run is over"))
+            }
+        }
+    }
+}
+
+/// request sends a request to the supervisor listening at `path`, and returns its answer.
+pub fn request(path: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut json = serde_json::to_string(request)?;
+    json.push('\n');
+    stream.write_all(json.as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_socket() {
+        let path =
+            std::env::temp_dir().join(format!("crabtrap_control_{}.sock", std::process::id()));
+        let socket = ControlSocket::bind(&path).unwrap();
+
+        // Stand in for the supervisor, answering whatever comes in
+        let client = thread::spawn({
+            let path = path.clone();
+            move || {
+                (
+                    request(&path, &Request::Stats).unwrap(),
+                    request(
+                        &path,
+                        &Request::Reload {
+                            path: PathBuf::from("/nonexistent.yaml"),
+                        },
+                    )
+                    .unwrap(),
+                    request(&path, &Request::Pause).unwrap(),
+                )
+            }
+        });
+        let mut answered = 0;
+        while answered < 2 {
+            for (command, reply) in socket.requests() {
+                let response = match command {
+                    Command::Stats => Response::Stats(Box::default()),
+                    Command::Pause => Response::Ok,
+                    _ => Response::Error(String::from("unexpected")),
+                };
+                reply.send(response).unwrap();
+                answered += 1;
+            }
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let (stats, reload, pause) = client.join().unwrap();
+        assert_eq!(stats, Response::Stats(Box::default()));
+        // A config that can't be loaded never reaches the supervisor
+        assert!(matches!(reload, Response::Error(_)));
+        assert_eq!(pause, Response::Ok);
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,18 @@
+use crate::config::RunAs;
+use nix::{errno::Errno, libc};
+
+/// drop_privileges switches the calling process to the configured user and groups, if there are any, and then keeps
+/// it from gaining privileges again through setuid executables or file capabilities if `no_new_privs` is set. Safe to
+/// call between fork and exec.
+pub fn drop_privileges(run_as: Option<&RunAs>, no_new_privs: bool) -> nix::Result<()> {
+    if let Some(run_as) = run_as {
+        // The groups first, since only root can change them
+        Errno::result(unsafe { libc::setgroups(run_as.groups.len(), run_as.groups.as_ptr()) })?;
+        Errno::result(unsafe { libc::setresgid(run_as.gid, run_as.gid, run_as.gid) })?;
+        Errno::result(unsafe { libc::setresuid(run_as.uid, run_as.uid, run_as.uid) })?;
+    }
+    if no_new_privs {
+        Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use crate::{
+    config::{Deterministic, Virtualization},
+    memory::write_bytes,
+};
+use nix::{
+    sched::{sched_setaffinity, CpuSet},
+    sys::stat::{umask, Mode},
+    unistd::Pid,
+};
+use std::{
+    ffi::{CStr, CString},
+    time::Duration,
+};
+use syscalls::Sysno;
+
+/// setup applies the settings that are inherited across exec. Called in the child, between fork and exec.
+pub fn setup(config: &Deterministic) -> nix::Result<()> {
+    if let Some(cpu) = config.cpu {
+        let mut cpus = CpuSet::new();
+        cpus.set(cpu)?;
+        sched_setaffinity(Pid::from_raw(0), &cpus)?;
+    }
+    umask(Mode::from_bits_truncate(config.umask));
+    Ok(())
+}
+
+/// is_locale returns whether an environment variable's name is one of those that pick the locale, all of which make
+/// way for the configured one.
+pub fn is_locale(key: &[u8]) -> bool {
+    key.starts_with(b"LC_") || key == b"LANG" || key == b"LANGUAGE"
+}
+
+/// environment returns `env` with the locale variables replaced by the configured locale.
+pub fn environment(config: &Deterministic, env: &[&CStr]) -> Vec<CString> {
+    env.iter()
+        .filter(|var| {
+            let var = var.to_bytes();
+            !is_locale(var.split(|&byte| byte == b'=').next().unwrap_or(var))
+        })
+        .map(|var| CString::from(*var))
+        .chain(["LC_ALL", "LANG"].map(|key| {
+            CString::new(format!("{key}={}", config.locale)).expect("locale contains a nul byte")
+        }))
+        .collect()
+}
+
+/// Virtualizer: replaces the results of virtualized syscalls as they return
+pub struct Virtualizer {
+    /// splitmix64 state for getrandom
+    state: u64,
+    /// The virtual clock, relative to the Unix epoch
+    clock: Duration,
+}
+
+impl Virtualizer {
+    pub fn new(config: &Deterministic) -> Virtualizer {
+        Virtualizer {
+            state: config.seed,
+            clock: Duration::from_secs(config.epoch),
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn tick(&mut self) -> Duration {
+        self.clock += Duration::from_micros(1);
+        self.clock
+    }
+
+    /// rewrite overwrites what a finished syscall put in the tracee's memory, and returns which virtualization that
+    /// was, if any.
+    pub fn rewrite(
+        &mut self,
+        pid: Pid,
+        config: &Deterministic,
+        syscall: Sysno,
+        args: &[u64; 6],
+        ret: i64,
+    ) -> Option<Virtualization> {
+        let virtualization = match syscall {
+            Sysno::getrandom => Virtualization::Getrandom,
+            Sysno::clock_gettime | Sysno::gettimeofday => Virtualization::Time,
+            _ => return None,
+        };
+        if !config.virtualize.contains(&virtualization) || ret < 0 {
+            return None;
+        }
+
+        match syscall {
+            Sysno::getrandom => {
+                let bytes: Vec<u8> = (0..(ret as usize).div_ceil(8))
+                    .flat_map(|_| self.next_random().to_ne_bytes())
+                    .take(ret as usize)
+                    .collect();
+                write_bytes(pid, args[0], &bytes);
+            }
+            Sysno::clock_gettime => {
+                let now = self.tick();
+                write_bytes(pid, args[1], &timeval(now.as_secs(), now.subsec_nanos()));
+            }
+            _ => {
+                let now = self.tick();
+                if args[0] != 0 {
+                    write_bytes(pid, args[0], &timeval(now.as_secs(), now.subsec_micros()));
+                }
+            }
+        }
+        Some(virtualization)
+    }
+}
+
+/// timeval lays out a struct timespec or struct timeval, which on 64-bit targets are both two 64-bit fields.
+fn timeval(secs: u64, fraction: u32) -> Vec<u8> {
+    [secs.to_ne_bytes(), (fraction as u64).to_ne_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeatable() {
+        let config = Deterministic {
+            seed: 42,
+            ..Default::default()
+        };
+        let mut first = Virtualizer::new(&config);
+        let mut second = Virtualizer::new(&config);
+        assert_eq!(
+            (0..4).map(|_| first.next_random()).collect::<Vec<_>>(),
+            (0..4).map(|_| second.next_random()).collect::<Vec<_>>()
+        );
+        assert_eq!(first.tick(), Duration::from_micros(1));
+        assert_eq!(first.tick(), Duration::from_micros(2));
+    }
+
+    #[test]
+    fn test_environment() {
+        let env = [
+            c"PATH=/bin",
+            c"LANG=de_DE.UTF-8",
+            c"LANGUAGE=de",
+            c"LANGS=de",
+            c"LC_TIME=fr_FR",
+        ];
+        assert_eq!(
+            environment(&Deterministic::default(), &env),
+            vec![
+                CString::from(c"PATH=/bin"),
+                CString::from(c"LANGS=de"),
+                CString::from(c"LC_ALL=C"),
+                CString::from(c"LANG=C")
+            ]
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use std::{fs, path::Path};
+use thiserror::Error;
+
+/// From elf.h
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_NOTE: u32 = 4;
+const DT_NULL: i64 = 0;
+const DT_NEEDED: i64 = 1;
+const DT_STRTAB: i64 = 5;
+const DT_SONAME: i64 = 14;
+const DT_RPATH: i64 = 15;
+const DT_RUNPATH: i64 = 29;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+const STT_FUNC: u8 = 2;
+
+const PROGRAM_HEADER_LEN: usize = 56;
+const DYNAMIC_ENTRY_LEN: usize = 16;
+const SECTION_HEADER_LEN: usize = 64;
+const SYMBOL_LEN: usize = 24;
+const NOTE_HEADER_LEN: usize = 12;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+#[derive(Debug, Error)]
+pub enum ElfError {
+    #[error("Failed to read {0}: {1}")]
+    IoError(String, std::io::Error),
+    #[error("Not a 64-bit little-endian ELF file")]
+    NotElf,
+    #[error("ELF file is truncated or malformed")]
+    Malformed,
+}
+
+/// Elf: what an executable or shared object says about how it's loaded
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Elf {
+    /// e_machine, for telling apart libraries built for other architectures
+    pub machine: u16,
+    /// The dynamic loader, from PT_INTERP. Only executables have one.
+    pub interpreter: Option<String>,
+    /// The DT_NEEDED libraries, in the order they're listed
+    pub needed: Vec<String>,
+    /// Where to look for them first, from DT_RUNPATH, or DT_RPATH if there isn't one. `$ORIGIN` isn't expanded.
+    pub search_path: Vec<String>,
+    /// The name a shared object is linked against by, from DT_SONAME, like `libssl.so.3`
+    pub soname: Option<String>,
+    /// The GNU build ID note, in hex, which tells apart builds of the same library
+    pub build_id: Option<String>,
+}
+
+/// add adds two offsets or sizes from the file, which can be anything at all, so the sum may not fit.
+fn add(a: usize, b: usize) -> Result<usize, ElfError> {
+    a.checked_add(b).ok_or(ElfError::Malformed)
+}
+
+/// nth returns where the entry at `index` in a table of `len`-byte entries starting at `table` is.
+fn nth(table: usize, index: usize, len: usize) -> Result<usize, ElfError> {
+    add(table, index.checked_mul(len).ok_or(ElfError::Malformed)?)
+}
+
+/// field returns the `len` bytes starting at `offset`.
+fn field(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], ElfError> {
+    bytes
+        .get(offset..add(offset, len)?)
+        .ok_or(ElfError::Malformed)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    Ok(u16::from_le_bytes(
+        field(bytes, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    Ok(u32::from_le_bytes(
+        field(bytes, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64, ElfError> {
+    Ok(u64::from_le_bytes(
+        field(bytes, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+/// string_at reads a nul-terminated string starting at `offset`.
+fn string_at(bytes: &[u8], offset: usize) -> Result<String, ElfError> {
+    let rest = bytes.get(offset..).ok_or(ElfError::Malformed)?;
+    let len = rest
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or(ElfError::Malformed)?;
+    Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+/// ProgramHeader: the fields of an Elf64_Phdr we use
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+/// program_headers reads the program headers of a 64-bit little-endian ELF file.
+fn program_headers(bytes: &[u8]) -> Result<Vec<ProgramHeader>, ElfError> {
+    // ELFCLASS64 and ELFDATA2LSB, which is all the architectures we trace
+    if bytes.get(..6) != Some(b"\x7fELF\x02\x01") {
+        return Err(ElfError::NotElf);
+    }
+    let phoff = u64_at(bytes, 0x20)? as usize;
+    let phnum = u16_at(bytes, 0x38)? as usize;
+    (0..phnum)
+        .map(|i| {
+            let header = field(
+                bytes,
+                nth(phoff, i, PROGRAM_HEADER_LEN)?,
+                PROGRAM_HEADER_LEN,
+            )?;
+            Ok(ProgramHeader {
+                kind: u32_at(header, 0)?,
+                offset: u64_at(header, 8)?,
+                vaddr: u64_at(header, 16)?,
+                filesz: u64_at(header, 32)?,
+            })
+        })
+        .collect()
+}
+
+/// build_id finds the GNU build ID among the notes in a PT_NOTE segment, and writes it out in hex.
+fn build_id(bytes: &[u8], note: &ProgramHeader) -> Result<Option<String>, ElfError> {
+    let align = |len: usize| len.checked_next_multiple_of(4).ok_or(ElfError::Malformed);
+    let mut offset = note.offset as usize;
+    let end = add(offset, note.filesz as usize)?;
+    while end.saturating_sub(offset) >= NOTE_HEADER_LEN {
+        let header = field(bytes, offset, NOTE_HEADER_LEN)?;
+        let name_len = u32_at(header, 0)? as usize;
+        let desc_len = u32_at(header, 4)? as usize;
+        let kind = u32_at(header, 8)?;
+        let name = offset + NOTE_HEADER_LEN;
+        let desc = add(name, align(name_len)?)?;
+        if kind == NT_GNU_BUILD_ID && field(bytes, name, name_len).ok() == Some(b"GNU\0") {
+            let id = field(bytes, desc, desc_len)?;
+            return Ok(Some(id.iter().map(|byte| format!("{byte:02x}")).collect()));
+        }
+        offset = add(desc, align(desc_len)?)?;
+    }
+    Ok(None)
+}
+
+/// Section: where a section is, in memory and in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Section {
+    kind: u32,
+    /// Where it's loaded, as the file lays out addresses
+    pub addr: u64,
+    pub offset: usize,
+    pub size: usize,
+    link: usize,
+    name: usize,
+}
+
+impl Section {
+    /// bytes returns the section's contents.
+    pub fn bytes<'b>(&self, file: &'b [u8]) -> Result<&'b [u8], ElfError> {
+        field(file, self.offset, self.size)
+    }
+}
+
+/// sections reads the section headers of a 64-bit little-endian ELF file.
+fn sections(bytes: &[u8]) -> Result<Vec<Section>, ElfError> {
+    let shoff = u64_at(bytes, 0x28)? as usize;
+    let shnum = u16_at(bytes, 0x3c)? as usize;
+    (0..shnum)
+        .map(|i| {
+            let header = field(
+                bytes,
+                nth(shoff, i, SECTION_HEADER_LEN)?,
+                SECTION_HEADER_LEN,
+            )?;
+            Ok(Section {
+                name: u32_at(header, 0)? as usize,
+                kind: u32_at(header, 4)?,
+                addr: u64_at(header, 16)?,
+                offset: u64_at(header, 24)? as usize,
+                size: u64_at(header, 32)? as usize,
+                link: u32_at(header, 40)? as usize,
+            })
+        })
+        .collect()
+}
+
+/// section finds a section by name, like `.eh_frame`.
+pub fn section(bytes: &[u8], name: &str) -> Result<Option<Section>, ElfError> {
+    let sections = sections(bytes)?;
+    let shstrndx = u16_at(bytes, 0x3e)? as usize;
+    let names = sections.get(shstrndx).ok_or(ElfError::Malformed)?.offset;
+    for section in &sections {
+        if string_at(bytes, add(names, section.name)?)? == name {
+            return Ok(Some(*section));
+        }
+    }
+    Ok(None)
+}
+
+/// load_bias returns what to add to how far an address is past the start of a file's first mapping to get the
+/// address as the file lays it out, which is what its symbols and unwind tables go by.
+pub fn load_bias(bytes: &[u8]) -> Result<u64, ElfError> {
+    let first = program_headers(bytes)?
+        .into_iter()
+        .find(|header| header.kind == PT_LOAD)
+        .ok_or(ElfError::Malformed)?;
+    first
+        .vaddr
+        .checked_sub(first.offset)
+        .ok_or(ElfError::Malformed)
+}
+
+/// symbolize finds the function at `offset` bytes past where a file's first segment is loaded, which is how far an
+/// address is past the start of the file's first mapping. Returns the function's name and how far into it the
+/// address is. The full symbol table is used if the file still has one, and the dynamic one otherwise.
+pub fn symbolize(bytes: &[u8], offset: u64) -> Result<Option<(String, u64)>, ElfError> {
+    let vaddr = offset
+        .checked_add(load_bias(bytes)?)
+        .ok_or(ElfError::Malformed)?;
+    let sections = sections(bytes)?;
+    let Some(symbols) = [SHT_SYMTAB, SHT_DYNSYM]
+        .iter()
+        .find_map(|&kind| sections.iter().find(|section| section.kind == kind))
+    else {
+        return Ok(None);
+    };
+    let strtab = sections
+        .get(symbols.link)
+        .ok_or(ElfError::Malformed)?
+        .offset;
+
+    for i in 0..symbols.size / SYMBOL_LEN {
+        let symbol = field(bytes, nth(symbols.offset, i, SYMBOL_LEN)?, SYMBOL_LEN)?;
+        let info = symbol[4];
+        let value = u64_at(symbol, 8)?;
+        let len = u64_at(symbol, 16)?;
+        if info & 0xf == STT_FUNC && vaddr.checked_sub(value).is_some_and(|offset| offset < len) {
+            let name = string_at(bytes, add(strtab, u32_at(symbol, 0)? as usize)?)?;
+            return Ok(Some((name, vaddr - value)));
+        }
+    }
+    Ok(None)
+}
+
+impl Elf {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Elf, ElfError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|e| ElfError::IoError(path.display().to_string(), e))?;
+        Elf::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Elf, ElfError> {
+        let headers = program_headers(bytes)?;
+
+        let mut elf = Elf {
+            machine: u16_at(bytes, 0x12)?,
+            ..Default::default()
+        };
+        if let Some(interp) = headers.iter().find(|header| header.kind == PT_INTERP) {
+            elf.interpreter = Some(string_at(bytes, interp.offset as usize)?);
+        }
+        for note in headers.iter().filter(|header| header.kind == PT_NOTE) {
+            if let Some(id) = build_id(bytes, note)? {
+                elf.build_id = Some(id);
+                break;
+            }
+        }
+        let Some(dynamic) = headers.iter().find(|header| header.kind == PT_DYNAMIC) else {
+            return Ok(elf);
+        };
+
+        let mut entries = Vec::new();
+        for i in 0..dynamic.filesz as usize / DYNAMIC_ENTRY_LEN {
+            let entry = field(
+                bytes,
+                nth(dynamic.offset as usize, i, DYNAMIC_ENTRY_LEN)?,
+                DYNAMIC_ENTRY_LEN,
+            )?;
+            let tag = u64_at(entry, 0)? as i64;
+            if tag == DT_NULL {
+                break;
+            }
+            entries.push((tag, u64_at(entry, 8)?));
+        }
+        // DT_STRTAB is an address, so it has to be found in the segment that loads it
+        let strtab = entries
+            .iter()
+            .find(|(tag, _)| *tag == DT_STRTAB)
+            .and_then(|&(_, addr)| {
+                headers
+                    .iter()
+                    .filter(|header| header.kind == PT_LOAD)
+                    .find_map(|header| {
+                        let offset = addr.checked_sub(header.vaddr)?;
+                        (offset < header.filesz).then(|| offset.checked_add(header.offset))?
+                    })
+            })
+            .ok_or(ElfError::Malformed)? as usize;
+
+        let mut rpath = Vec::new();
+        for &(tag, value) in &entries {
+            let string = || string_at(bytes, add(strtab, value as usize)?);
+            match tag {
+                DT_NEEDED => elf.needed.push(string()?),
+                DT_SONAME => elf.soname = Some(string()?),
+                DT_RUNPATH => elf.search_path = string()?.split(':').map(String::from).collect(),
+                DT_RPATH => rpath = string()?.split(':').map(String::from).collect(),
+                _ => {}
+            }
+        }
+        if elf.search_path.is_empty() {
+            elf.search_path = rpath;
+        }
+        Ok(elf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        // The test binary is as good an executable as any
+        let elf = Elf::read("/proc/self/exe").unwrap();
+        assert!(elf.interpreter.is_some());
+        assert!(elf
+            .needed
+            .iter()
+            .any(|library| library.starts_with("libc.so")));
+        assert_eq!(elf.soname, None);
+        // Linked with the default --build-id, the ID is a 160-bit SHA-1
+        assert_eq!(elf.build_id.map(|id| id.len()), Some(40));
+
+        assert!(matches!(Elf::parse(b"#!/bin/sh\n"), Err(ElfError::NotElf)));
+        assert!(matches!(
+            Elf::parse(b"\x7fELF\x02\x01\x01\x00"),
+            Err(ElfError::Malformed)
+        ));
+
+        // Offsets and sizes that run past the end of the address space are malformed too
+        let mut header = [0; 0x40 + PROGRAM_HEADER_LEN];
+        header[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        header[0x20..0x28].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        header[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(Elf::parse(&header), Err(ElfError::Malformed)));
+        header[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        header[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+        header[0x40..0x44].copy_from_slice(&PT_NOTE.to_le_bytes());
+        header[0x48..0x50].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        header[0x60..0x68].copy_from_slice(&16u64.to_le_bytes());
+        assert!(matches!(Elf::parse(&header), Err(ElfError::Malformed)));
+        header[0x40..0x44].copy_from_slice(&PT_DYNAMIC.to_le_bytes());
+        assert!(matches!(Elf::parse(&header), Err(ElfError::Malformed)));
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let bytes = fs::read("/proc/self/exe").unwrap();
+        let map = crate::map::MemoryMap::from_pid(nix::unistd::getpid()).unwrap();
+        let exe = fs::read_link("/proc/self/exe").unwrap();
+        let exe = exe.to_str().unwrap();
+        let base = map.base(exe).unwrap();
+
+        let addr = test_symbolize as *const () as u64;
+        let (name, offset) = symbolize(&bytes, addr - base).unwrap().unwrap();
+        assert!(name.contains("test_symbolize"), "{name}");
+        assert_eq!(offset, 0);
+        let (name, offset) = symbolize(&bytes, addr + 4 - base).unwrap().unwrap();
+        assert!(name.contains("test_symbolize"), "{name}");
+        assert_eq!(offset, 4);
+    }
+}
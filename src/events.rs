@@ -0,0 +1,124 @@
+use crate::{execute_with_events, ChildExit, Config, RunStats};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use syscalls::Sysno;
+
+/// Event: something that happened in the traced tree, for callers building their own reporting
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The target was started with this pid, and is about to be traced
+    Started { pid: i32 },
+    /// A traced process entered a syscall, attributed to the named library if any
+    Syscall {
+        pid: i32,
+        syscall: Sysno,
+        library: Option<String>,
+        /// The id of the rule that decided it, if it has one
+        rule: Option<String>,
+    },
+    /// A file was mapped into a traced process that wasn't mapped before, usually a shared library being loaded
+    LibraryLoaded { pid: i32, path: String },
+    /// A traced process forked or cloned a new one, which is traced too
+    Forked { parent: i32, child: i32 },
+    /// A traced process exec'd a new program
+    Exec { pid: i32 },
+    /// A traced process was stopped by job control, e.g. with ^Z or by a background read from the terminal
+    Stopped { pid: i32, signal: i32 },
+    /// A stopped process was continued with SIGCONT
+    Continued { pid: i32 },
+    /// A traced process broke the config, and the run is ending because of it. `rule` is the id of the rule it
+    /// broke, if it has one.
+    Violation {
+        pid: i32,
+        exit: ChildExit,
+        rule: Option<String>,
+    },
+    /// A traced process exited with this status
+    Exited { pid: i32, status: i32 },
+    /// A traced process was killed by this signal
+    Killed { pid: i32, signal: i32 },
+}
+
+/// Events: an iterator over the events of a run that's going on in the background
+///
+/// ```no_run
+/// use crabtrap::{events::Events, Config};
+///
+/// let mut events = Events::spawn(c"/bin/ls", &[c"ls"], &[], Config::default());
+/// for event in events.by_ref() {
+///     println!("{event:?}");
+/// }
+/// let (exit, stats) = events.wait();
+/// ```
+pub struct Events {
+    receiver: mpsc::Receiver<Event>,
+    tracer: JoinHandle<(ChildExit, RunStats)>,
+}
+
+impl Events {
+    /// spawn starts tracing the program on a thread of its own, since ptrace only lets the thread that started a
+    /// tracee wait on it. That thread only waits for its own children, so runs started this way don't interfere with
+    /// each other or with the caller's children.
+    pub fn spawn(path: &CStr, args: &[&CStr], env: &[&CStr], config: Config) -> Events {
+        let path = CString::from(path);
+        let args: Vec<CString> = args.iter().map(|&arg| arg.into()).collect();
+        let env: Vec<CString> = env.iter().map(|&var| var.into()).collect();
+        let (sender, receiver) = mpsc::channel();
+
+        let tracer = thread::spawn(move || {
+            let args: Vec<&CStr> = args.iter().map(CString::as_c_str).collect();
+            let env: Vec<&CStr> = env.iter().map(CString::as_c_str).collect();
+            execute_with_events(&path, &args, &env, &config, |event| {
+                // Nobody listening just means nobody cares about the events any more
+                let _ = sender.send(event);
+            })
+        });
+        Events { receiver, tracer }
+    }
+
+    /// wait waits for the run to finish, dropping any events that haven't been seen.
+    pub fn wait(self) -> (ChildExit, RunStats) {
+        self.tracer.join().expect("tracer thread panicked")
+    }
+}
+
+impl Iterator for Events {
+    type Item = Event;
+
+    /// next blocks until the next event, and returns None once the run is over and every event has been seen.
+    fn next(&mut self) -> Option<Event> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// EventLog: one JSON object per line for each event, with a `timestamp` in seconds since the epoch added to the
+/// event's own fields
+pub(crate) struct EventLog {
+    writer: BufWriter<File>,
+}
+
+impl EventLog {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<EventLog> {
+        Ok(EventLog {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn event(&mut self, event: &Event) -> io::Result<()> {
+        let mut record = serde_json::to_value(event)?;
+        record["timestamp"] = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_secs_f64())
+            .into();
+        writeln!(self.writer, "{record}")
+    }
+}
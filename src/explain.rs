@@ -0,0 +1,160 @@
+use crate::{identity::Identities, Check, Config, DefaultPolicy};
+use std::{fs, os::unix::fs::MetadataExt, path::Path};
+use syscalls::Sysno;
+
+/// Explanation: how a config decides a syscall made from a library, worked out without running anything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    /// The key of the `binaries` section for the executable, if one covers it
+    pub binary: Option<String>,
+    /// The name the library's rules are looked up by: its path, or its soname or build ID if there are rules for
+    /// those, see Config::library_name
+    pub name: String,
+    /// The key the library's rules are listed under, and whether it's in the binary's section rather than the top
+    /// level, if it has any
+    pub rules: Option<(String, bool)>,
+    /// What the library's rules make of the syscall, including the library's own default
+    pub check: Check,
+    /// What happens to the syscall if neither they nor the rules of the libraries further up the stack decide it
+    pub default: DefaultPolicy,
+    /// What happens to it instead of `default` if the walk up the stack is cut short, see StackWalkConfig
+    pub on_failure: Option<DefaultPolicy>,
+    /// Whether some library's rules depend on the syscall's arguments, which can decide it differently at runtime
+    pub argument_rules: bool,
+}
+
+/// explain works out how `config` decides `syscall` made from `library` by a process running `executable`. Hooks,
+/// remote policies and anything else that needs the run itself can still have their say. The library's soname and
+/// build ID are read from `library`, if there's a file there.
+pub fn explain(
+    config: &Config,
+    executable: Option<&str>,
+    library: &str,
+    syscall: Sysno,
+) -> Explanation {
+    let binary = executable.and_then(|executable| config.binary(executable));
+    let others = match fs::metadata(library) {
+        Ok(metadata) if config.uses_identities() => Identities::default()
+            .names((metadata.dev(), metadata.ino()), Path::new(library))
+            .to_vec(),
+        _ => Vec::new(),
+    };
+    let name = config.library_name(binary, library, &others);
+    Explanation {
+        binary: binary.map(String::from),
+        name: name.to_string(),
+        rules: config
+            .key_in(binary, name)
+            .map(|(key, in_binary)| (key.to_string(), in_binary)),
+        check: config.check_in(binary, name, syscall),
+        default: config.default,
+        on_failure: config.stack_walk.on_failure,
+        argument_rules: config.has_argument_rules(syscall),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConfigFormat, Rule, RuleAction, RuleList};
+
+    #[test]
+    fn test_explain() {
+        let config = Config::parse(
+            "
+shared_objects:
+  /usr/lib/**:
+    id: system
+    block: [ptrace]
+binaries:
+  /usr/bin/gdb:
+    shared_objects:
+      /usr/lib/libc.so.6:
+        allow: [ptrace]
+default: deny
+",
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+
+        let explanation = explain(&config, None, "/usr/lib/libc.so.6", Sysno::ptrace);
+        assert_eq!(explanation.binary, None);
+        assert_eq!(
+            explanation.rules,
+            Some((String::from("/usr/lib/**"), false))
+        );
+        assert_eq!(
+            explanation.check,
+            Check::Blocked(
+                RuleAction::Kill,
+                Rule {
+                    id: Some(String::from("system")),
+                    list: RuleList::Block,
+                }
+            )
+        );
+
+        let explanation = explain(
+            &config,
+            Some("/usr/bin/gdb"),
+            "/usr/lib/libc.so.6",
+            Sysno::ptrace,
+        );
+        assert_eq!(explanation.binary.as_deref(), Some("/usr/bin/gdb"));
+        assert_eq!(
+            explanation.rules,
+            Some((String::from("/usr/lib/libc.so.6"), true))
+        );
+        assert!(matches!(explanation.check, Check::Allowed(_)));
+
+        let explanation = explain(&config, None, "/opt/lib/libfoo.so", Sysno::read);
+        assert_eq!(explanation.rules, None);
+        assert_eq!(explanation.check, Check::Unknown);
+        assert_eq!(explanation.default, DefaultPolicy::Deny);
+        assert_eq!(explanation.on_failure, None);
+    }
+
+    #[test]
+    fn test_names_and_defaults() {
+        let map = crate::MemoryMap::from_pid(nix::unistd::getpid()).unwrap();
+        let libc = map
+            .files
+            .iter()
+            .map(|file| file.path())
+            .find(|path| path.contains("/libc.so"))
+            .unwrap();
+        let config = Config::parse(
+            "
+shared_objects:
+  soname:libc.so.6:
+    allow: [read]
+    default: kill
+stack_walk:
+  on_failure: deny
+",
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+
+        // The library goes by its soname, which has rules of its own
+        let explanation = explain(&config, None, libc, Sysno::read);
+        assert_eq!(explanation.name, "soname:libc.so.6");
+        assert_eq!(
+            explanation.rules,
+            Some((String::from("soname:libc.so.6"), false))
+        );
+        assert!(matches!(explanation.check, Check::Allowed(_)));
+        // What its lists don't cover, its own default decides
+        assert_eq!(
+            explain(&config, None, libc, Sysno::ptrace).check,
+            Check::Blocked(
+                RuleAction::Kill,
+                Rule {
+                    id: None,
+                    list: RuleList::Default,
+                }
+            )
+        );
+        assert_eq!(explanation.on_failure, Some(DefaultPolicy::Deny));
+    }
+}
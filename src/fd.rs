@@ -0,0 +1,387 @@
+use crate::{
+    glob::{self, Globs},
+    memory::read_bytes,
+    network::SocketDomain,
+};
+use nix::{libc, unistd::Pid};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use syscalls::Sysno;
+
+/// The syscalls that can change what a process's file descriptors refer to when they succeed, which FdTable::update
+/// keeps up with
+pub const CHANGES_FDS: &[Sysno] = &[
+    Sysno::openat,
+    Sysno::openat2,
+    Sysno::dup,
+    Sysno::dup3,
+    Sysno::fcntl,
+    Sysno::socket,
+    Sysno::accept,
+    Sysno::accept4,
+    Sysno::memfd_create,
+    Sysno::eventfd2,
+    Sysno::epoll_create1,
+    Sysno::pipe2,
+    Sysno::socketpair,
+    Sysno::close,
+    Sysno::close_range,
+    Sysno::execve,
+    Sysno::execveat,
+];
+
+/// FdTable: what each file descriptor in a tracee refers to, as reported by /proc/{pid}/fd
+///
+/// Entries are filled in when a syscall that creates a descriptor returns. Anything we didn't see
+/// being created (inherited descriptors, ones opened by another thread sharing the table) is
+/// looked up lazily the first time it's used. The address family of a socket is only known if we
+/// saw it being made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdTable {
+    proc_root: PathBuf,
+    fds: BTreeMap<i32, String>,
+    families: BTreeMap<i32, i32>,
+}
+
+impl FdTable {
+    /// new creates an empty table, which looks descriptors up under `proc_root` (normally /proc).
+    pub fn new<P: AsRef<Path>>(proc_root: P) -> FdTable {
+        FdTable {
+            proc_root: proc_root.as_ref().to_path_buf(),
+            fds: BTreeMap::new(),
+            families: BTreeMap::new(),
+        }
+    }
+
+    /// family returns the address family of the socket `fd` refers to, if we saw it being made.
+    pub fn family(&self, fd: i32) -> Option<i32> {
+        self.families.get(&fd).copied()
+    }
+
+    /// forget drops what we know about `fd`, so it's looked up again the next time it's used.
+    fn forget(&mut self, fd: i32) {
+        self.fds.remove(&fd);
+        self.families.remove(&fd);
+    }
+
+    /// lookup returns the target of `fd`, e.g. `/tmp/out.txt`, `pipe:[1234]` or `socket:[5678]`
+    pub fn lookup(&mut self, pid: Pid, fd: i32) -> Option<&str> {
+        match self.fds.entry(fd) {
+            Entry::Occupied(entry) => Some(entry.into_mut().as_str()),
+            Entry::Vacant(entry) => {
+                let target = fs::read_link(
+                    self.proc_root
+                        .join(pid.to_string())
+                        .join("fd")
+                        .join(fd.to_string()),
+                )
+                .ok()?;
+                Some(entry.insert(target.to_string_lossy().into_owned()).as_str())
+            }
+        }
+    }
+
+    /// update records the effect of a finished syscall, given its arguments and return value
+    pub fn update(&mut self, pid: Pid, syscall: Sysno, args: &[u64; 6], ret: i64) {
+        if ret < 0 {
+            return;
+        }
+
+        match syscall {
+            Sysno::openat
+            | Sysno::openat2
+            | Sysno::dup
+            | Sysno::dup3
+            | Sysno::fcntl
+            | Sysno::socket
+            | Sysno::accept
+            | Sysno::accept4
+            | Sysno::memfd_create
+            | Sysno::eventfd2
+            | Sysno::epoll_create1 => {
+                // fcntl only creates a descriptor for F_DUPFD(_CLOEXEC), otherwise ret isn't an fd
+                if syscall == Sysno::fcntl
+                    && !matches!(args[1] as i32, libc::F_DUPFD | libc::F_DUPFD_CLOEXEC)
+                {
+                    return;
+                }
+                let fd = ret as i32;
+                self.forget(fd);
+                self.lookup(pid, fd);
+                let family = match syscall {
+                    Sysno::socket => Some(args[0] as i32),
+                    // Accepted sockets are the listening socket's family, and copies the original's
+                    Sysno::accept | Sysno::accept4 | Sysno::dup | Sysno::dup3 | Sysno::fcntl => {
+                        self.family(args[0] as i32)
+                    }
+                    _ => None,
+                };
+                if let Some(family) = family {
+                    self.families.insert(fd, family);
+                }
+            }
+            // These return their pair of descriptors in an array
+            Sysno::pipe2 | Sysno::socketpair => {
+                let addr = if syscall == Sysno::pipe2 {
+                    args[0]
+                } else {
+                    args[3]
+                };
+                let Some(bytes) = read_bytes(pid, addr, 8) else {
+                    return;
+                };
+                for fd in [&bytes[..4], &bytes[4..]] {
+                    let fd = i32::from_ne_bytes(fd.try_into().unwrap());
+                    self.forget(fd);
+                    self.lookup(pid, fd);
+                    if syscall == Sysno::socketpair {
+                        self.families.insert(fd, args[0] as i32);
+                    }
+                }
+            }
+            Sysno::close => self.forget(args[0] as i32),
+            Sysno::close_range => {
+                let (first, last) = (args[0] as u32, args[1] as u32);
+                let kept = |&fd: &i32| fd < 0 || (fd as u32) < first || (fd as u32) > last;
+                self.fds.retain(|fd, _| kept(fd));
+                self.families.retain(|fd, _| kept(fd));
+            }
+            // Descriptors marked close-on-exec are gone after an exec. Rather than track the flag,
+            // start over and look everything up again.
+            Sysno::execve | Sysno::execveat => {
+                self.fds.clear();
+                self.families.clear();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// fd_argument returns the descriptor a syscall acts on, for the syscalls that act on one.
+pub fn fd_argument(syscall: Sysno, args: &[u64; 6]) -> Option<i32> {
+    match syscall {
+        Sysno::read
+        | Sysno::write
+        | Sysno::pread64
+        | Sysno::pwrite64
+        | Sysno::readv
+        | Sysno::writev
+        | Sysno::preadv
+        | Sysno::pwritev
+        | Sysno::preadv2
+        | Sysno::pwritev2
+        | Sysno::sendfile
+        | Sysno::ioctl
+        | Sysno::fstat
+        | Sysno::ftruncate
+        | Sysno::fallocate
+        | Sysno::fchmod
+        | Sysno::fchown
+        | Sysno::fsync
+        | Sysno::fdatasync
+        | Sysno::flock
+        | Sysno::lseek
+        | Sysno::getdents64
+        | Sysno::fsetxattr
+        | Sysno::fremovexattr
+        | Sysno::connect
+        | Sysno::bind
+        | Sysno::listen
+        | Sysno::accept
+        | Sysno::accept4
+        | Sysno::sendto
+        | Sysno::sendmsg
+        | Sysno::sendmmsg
+        | Sysno::recvfrom
+        | Sysno::recvmsg
+        | Sysno::shutdown => Some(args[0] as i32),
+        Sysno::mmap if args[3] as i32 & libc::MAP_ANONYMOUS == 0 => Some(args[4] as i32),
+        _ => None,
+    }
+}
+
+/// FdRule: a kind of descriptor a library may use. Written as `stdin`, `stdout` or `stderr` for those descriptors,
+/// `pipe`, `socket` or e.g. `socket:inet` for a socket in that domain, or a path prefix or glob for files. Starting
+/// one with `!` rules those descriptors out instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct FdRule {
+    pub negated: bool,
+    pub kind: FdKind,
+}
+
+/// FdKind: what an FdRule matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdKind {
+    Number(i32),
+    Pipe,
+    Socket(Option<SocketDomain>),
+    File(String),
+}
+
+impl FdKind {
+    /// matches checks a descriptor, with its target as FdTable::lookup gives it and its family if known, with the
+    /// config's `globs`.
+    pub fn matches(
+        &self,
+        fd: i32,
+        target: Option<&str>,
+        family: Option<i32>,
+        globs: &Globs,
+    ) -> bool {
+        match self {
+            FdKind::Number(number) => fd == *number,
+            FdKind::Pipe => target.is_some_and(|target| target.starts_with("pipe:")),
+            FdKind::Socket(domain) => {
+                target.is_some_and(|target| target.starts_with("socket:"))
+                    && domain.is_none_or(|domain| {
+                        family.and_then(SocketDomain::from_raw) == Some(domain)
+                    })
+            }
+            FdKind::File(pattern) => target.is_some_and(|target| {
+                if glob::is_pattern(pattern) {
+                    globs.matches(pattern, target)
+                } else {
+                    target.starts_with('/') && Path::new(target).starts_with(pattern)
+                }
+            }),
+        }
+    }
+}
+
+/// allows applies a list of rules to a descriptor: it mustn't match any that are negated, and must match one of the
+/// others if there are any.
+pub fn allows(
+    rules: &[FdRule],
+    fd: i32,
+    target: Option<&str>,
+    family: Option<i32>,
+    globs: &Globs,
+) -> bool {
+    let (negated, required): (Vec<_>, Vec<_>) = rules.iter().partition(|rule| rule.negated);
+    let matches = |rule: &&FdRule| rule.kind.matches(fd, target, family, globs);
+    !negated.iter().any(matches) && (required.is_empty() || required.iter().any(matches))
+}
+
+impl FromStr for FdRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FdRule, String> {
+        let (negated, written) = match s.strip_prefix('!') {
+            Some(written) => (true, written),
+            None => (false, s),
+        };
+        let kind = match written {
+            "stdin" => FdKind::Number(0),
+            "stdout" => FdKind::Number(1),
+            "stderr" => FdKind::Number(2),
+            "pipe" => FdKind::Pipe,
+            "socket" => FdKind::Socket(None),
+            path if path.starts_with('/') || glob::is_pattern(path) => FdKind::File(path.into()),
+            written => match written.strip_prefix("socket:") {
+                Some(domain) => FdKind::Socket(Some(domain.parse()?)),
+                None => return Err(format!("unknown kind of descriptor {written}")),
+            },
+        };
+        Ok(FdRule { negated, kind })
+    }
+}
+
+impl TryFrom<String> for FdRule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<FdRule, String> {
+        s.parse()
+    }
+}
+
+impl From<FdRule> for String {
+    fn from(rule: FdRule) -> String {
+        rule.to_string()
+    }
+}
+
+impl fmt::Display for FdRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negated {
+            write!(f, "!")?;
+        }
+        match &self.kind {
+            FdKind::Number(0) => write!(f, "stdin"),
+            FdKind::Number(1) => write!(f, "stdout"),
+            FdKind::Number(2) => write!(f, "stderr"),
+            FdKind::Number(fd) => write!(f, "{fd}"),
+            FdKind::Pipe => write!(f, "pipe"),
+            FdKind::Socket(None) => write!(f, "socket"),
+            FdKind::Socket(Some(domain)) => write!(f, "socket:{domain}"),
+            FdKind::File(path) => write!(f, "{path}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fd_rules() {
+        let rules = |written: &[&str]| -> Vec<FdRule> {
+            written.iter().map(|rule| rule.parse().unwrap()).collect()
+        };
+        let globs = Globs::new(["/tmp/**/*.log"]);
+        let allows =
+            |rules: &[FdRule], fd, target, family| allows(rules, fd, target, family, &globs);
+        let only_output = rules(&["stdout", "stderr"]);
+        assert!(allows(&only_output, 1, Some("/dev/pts/0"), None));
+        assert!(!allows(&only_output, 3, Some("/tmp/out"), None));
+
+        let not_etc = rules(&["!/etc/"]);
+        assert!(allows(&not_etc, 3, Some("/tmp/out"), None));
+        assert!(!allows(&not_etc, 3, Some("/etc/passwd"), None));
+        assert!(allows(&not_etc, 3, Some("/etcetera"), None));
+
+        let inet = rules(&["socket:inet", "pipe"]);
+        assert!(allows(&inet, 3, Some("socket:[1234]"), Some(libc::AF_INET)));
+        assert!(!allows(
+            &inet,
+            3,
+            Some("socket:[1234]"),
+            Some(libc::AF_UNIX)
+        ));
+        assert!(!allows(&inet, 3, Some("socket:[1234]"), None));
+        assert!(allows(&inet, 3, Some("pipe:[99]"), None));
+        assert!(!allows(&inet, 3, None, None));
+
+        let logs = rules(&["/tmp/**/*.log"]);
+        assert!(allows(&logs, 3, Some("/tmp/a/b.log"), None));
+        assert!(!allows(&logs, 3, Some("/tmp/a/b.txt"), None));
+
+        for written in ["stdout", "!/etc/", "socket:inet6", "pipe", "/tmp/**/*.log"] {
+            assert_eq!(written.parse::<FdRule>().unwrap().to_string(), written);
+        }
+        assert!("socket:carrier-pigeon".parse::<FdRule>().is_err());
+        assert!("terminal".parse::<FdRule>().is_err());
+    }
+
+    #[test]
+    fn test_fd_argument() {
+        let args = [3, 0, 0, libc::MAP_PRIVATE as u64, 7, 0];
+        assert_eq!(fd_argument(Sysno::write, &args), Some(3));
+        assert_eq!(fd_argument(Sysno::mmap, &args), Some(7));
+        let anonymous = [
+            0,
+            0,
+            0,
+            (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64,
+            -1i64 as u64,
+            0,
+        ];
+        assert_eq!(fd_argument(Sysno::mmap, &anonymous), None);
+        assert_eq!(fd_argument(Sysno::getpid, &args), None);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::{fd::FdTable, memory::read_string, quota};
+use nix::{
+    fcntl::{self, OFlag, OpenHow, ResolveFlag},
+    libc,
+    sys::{
+        ptrace::{read, AddressType},
+        stat::Mode,
+    },
+    unistd::Pid,
+};
+use std::{
+    fs,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Component, Path, PathBuf},
+};
+use syscalls::Sysno;
+
+/// normalize resolves `.` and `..` in an absolute path without touching the filesystem, so `/tmp/../etc` can't pass
+/// for something under `/tmp`. Symlinks aren't resolved, so it's only a fallback for paths resolve can't find.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// resolve finds the file an absolute path of a tracee's names the way the kernel will, under the tracee's root
+/// directory from `proc` (its /proc/<pid>), following symlinks and `..` as it goes, so `allowed/link/../..` ends up
+/// where the link leads rather than where it looks like it does. With `follow` false the last component is left
+/// alone, as it is by syscalls that act on a link rather than what it points to. A file that doesn't exist yet, like
+/// one about to be created, is put under its directory. None if the directory can't be found either.
+///
+/// The path is the one we see the file by, like the targets of /proc/<pid>/fd.
+pub fn resolve(proc: &Path, path: &Path, follow: bool) -> Option<PathBuf> {
+    let root = fcntl::open(
+        &proc.join("root"),
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )
+    .ok()?;
+    let root = unsafe { OwnedFd::from_raw_fd(root) };
+    let lookup = |path: &Path, flags: OFlag| {
+        let how = OpenHow::new()
+            .flags(OFlag::O_PATH | OFlag::O_CLOEXEC | flags)
+            .resolve(ResolveFlag::RESOLVE_IN_ROOT);
+        let fd = fcntl::openat2(root.as_raw_fd(), path, how).ok()?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).ok()
+    };
+
+    if follow {
+        if let Some(resolved) = lookup(path, OFlag::empty()) {
+            return Some(resolved);
+        }
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            lookup(parent, OFlag::O_DIRECTORY).map(|parent| parent.join(name))
+        }
+        // `/` or a path ending in `..`, neither of which can be a link
+        _ => lookup(path, OFlag::empty()),
+    }
+}
+
+/// Resolver: turns the path and descriptor arguments of one tracee's syscalls into absolute paths
+struct Resolver<'a> {
+    pid: Pid,
+    fds: &'a mut FdTable,
+    proc_root: &'a Path,
+}
+
+impl Resolver<'_> {
+    /// path reads the path argument at `addr` and resolves it in the tracee's root, relative to `dirfd` or its
+    /// working directory as in the *at syscalls. See resolve.
+    ///
+    /// If the directory can't be found the path is only normalized, and if it's relative to a directory that can't
+    /// be found it's returned as-is, which won't be under any writable prefix.
+    fn path(&mut self, dirfd: u64, addr: u64, follow: bool) -> PathBuf {
+        let path = PathBuf::from(read_string(self.pid, addr).unwrap_or_default());
+        let Some(path) = self.absolute(dirfd, &path) else {
+            return path;
+        };
+        if let Some(magic) = self.magic(&path, follow) {
+            return magic;
+        }
+        let proc = self.proc_root.join(self.pid.to_string());
+        resolve(&proc, &path, follow).unwrap_or_else(|| normalize(&path))
+    }
+
+    /// absolute makes `path` absolute in the tracee's root, relative to `dirfd` or its working directory as in the
+    /// *at syscalls. None if it's relative to a directory that can't be found.
+    fn absolute(&mut self, dirfd: u64, path: &Path) -> Option<PathBuf> {
+        if path.is_absolute() {
+            return Some(path.to_path_buf());
+        }
+        let proc = self.proc_root.join(self.pid.to_string());
+        let base = if dirfd as i32 == libc::AT_FDCWD {
+            fs::read_link(proc.join("cwd")).ok()
+        } else {
+            self.fds.lookup(self.pid, dirfd as i32).map(PathBuf::from)
+        };
+        // The base is a path we see, which in a chroot is under the tracee's root rather than in it
+        let root = fs::read_link(proc.join("root")).unwrap_or_else(|_| PathBuf::from("/"));
+        let base = base.filter(|base| base.is_absolute())?;
+        Some(match base.strip_prefix(&root) {
+            Ok(relative) => Path::new("/").join(relative).join(path),
+            Err(_) => base.join(path),
+        })
+    }
+
+    /// magic resolves the paths that lead somewhere different for each process that looks them up, like
+    /// `/proc/self` and `/dev/fd/N`, which resolve would take to be the tracer's own. Descriptors give the file they
+    /// refer to, and anything else under `/proc/self` is under the tracee's directory in our procfs.
+    fn magic(&mut self, path: &Path, follow: bool) -> Option<PathBuf> {
+        let path = normalize(path);
+        let proc = self.proc_root.join(self.pid.to_string());
+        let (dir, rest) = if let Ok(rest) = path.strip_prefix("/proc/self") {
+            (proc, rest.to_path_buf())
+        } else if let Ok(rest) = path.strip_prefix("/proc/thread-self") {
+            let task = proc.join("task").join(self.pid.to_string());
+            (task, rest.to_path_buf())
+        } else if let Ok(rest) = path.strip_prefix("/dev/fd") {
+            (proc, Path::new("fd").join(rest))
+        } else {
+            let fd = ["/dev/stdin", "/dev/stdout", "/dev/stderr"]
+                .iter()
+                .position(|stdio| path == Path::new(stdio))?;
+            (proc, Path::new("fd").join(fd.to_string()))
+        };
+        if rest.as_os_str().is_empty() && !follow {
+            return None;
+        }
+
+        let fd = rest
+            .strip_prefix("fd")
+            .ok()
+            .and_then(|number| number.to_str()?.parse().ok());
+        if let Some(fd) = fd.filter(|_| follow) {
+            return self.fds.lookup(self.pid, fd).map(PathBuf::from);
+        }
+        Some(dir.join(rest))
+    }
+
+    /// fd returns the file `fd` refers to, if it's a file on disk.
+    fn fd(&mut self, fd: u64) -> Vec<PathBuf> {
+        self.fds
+            .lookup(self.pid, fd as i32)
+            .filter(|target| quota::is_file(target))
+            .map(PathBuf::from)
+            .into_iter()
+            .collect()
+    }
+}
+
+/// follows returns whether an *at syscall with `flags` acts on what a symlink points to.
+fn follows(flags: u64) -> bool {
+    flags as i32 & libc::AT_SYMLINK_NOFOLLOW == 0
+}
+
+fn opens_for_writing(flags: u64) -> bool {
+    let flags = flags as i32;
+    flags & (libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC) != 0
+}
+
+/// modified_paths returns the files a syscall entry is about to modify, if any.
+pub fn modified_paths(
+    pid: Pid,
+    syscall: Sysno,
+    args: &[u64; 6],
+    fds: &mut FdTable,
+    proc_root: &Path,
+) -> Vec<PathBuf> {
+    let mut resolver = Resolver {
+        pid,
+        fds,
+        proc_root,
+    };
+    let cwd = libc::AT_FDCWD as u64;
+    match syscall {
+        Sysno::openat if opens_for_writing(args[2]) => {
+            let follow = args[2] as i32 & libc::O_NOFOLLOW == 0;
+            vec![resolver.path(args[0], args[1], follow)]
+        }
+        Sysno::openat2 => {
+            // The flags are the first field of struct open_how
+            let flags = read(pid, args[2] as AddressType).unwrap_or(0) as u64;
+            if opens_for_writing(flags) {
+                let follow = flags as i32 & libc::O_NOFOLLOW == 0;
+                vec![resolver.path(args[0], args[1], follow)]
+            } else {
+                Vec::new()
+            }
+        }
+        Sysno::unlinkat | Sysno::mkdirat | Sysno::mknodat => {
+            vec![resolver.path(args[0], args[1], false)]
+        }
+        Sysno::fchmodat => vec![resolver.path(args[0], args[1], true)],
+        Sysno::fchmodat2 | Sysno::utimensat if args[1] != 0 => {
+            vec![resolver.path(args[0], args[1], follows(args[3]))]
+        }
+        Sysno::fchownat => vec![resolver.path(args[0], args[1], follows(args[4]))],
+        // A null path means the file args[0] refers to
+        Sysno::utimensat => resolver.fd(args[0]),
+        Sysno::renameat | Sysno::renameat2 => vec![
+            resolver.path(args[0], args[1], false),
+            resolver.path(args[2], args[3], false),
+        ],
+        Sysno::linkat => vec![resolver.path(args[2], args[3], false)],
+        Sysno::symlinkat => vec![resolver.path(args[1], args[2], false)],
+        Sysno::truncate | Sysno::setxattr | Sysno::removexattr => {
+            vec![resolver.path(cwd, args[0], true)]
+        }
+        Sysno::lsetxattr | Sysno::lremovexattr => vec![resolver.path(cwd, args[0], false)],
+        Sysno::write
+        | Sysno::pwrite64
+        | Sysno::writev
+        | Sysno::pwritev
+        | Sysno::pwritev2
+        | Sysno::ftruncate
+        | Sysno::fallocate
+        | Sysno::fchmod
+        | Sysno::fchown
+        | Sysno::fsetxattr
+        | Sysno::fremovexattr => resolver.fd(args[0]),
+        _ => Vec::new(),
+    }
+}
+
+/// socket_path resolves the path of a unix socket a tracee is connecting or sending to, relative to its working
+/// directory, the way path_arguments does. Unlike there, None if it can't be found rather than the path as written,
+/// since the socket has to exist to be reached.
+pub fn socket_path(pid: Pid, path: &Path, fds: &mut FdTable, proc_root: &Path) -> Option<PathBuf> {
+    let mut resolver = Resolver {
+        pid,
+        fds,
+        proc_root,
+    };
+    let path = resolver.absolute(libc::AT_FDCWD as u64, path)?;
+    if let Some(magic) = resolver.magic(&path, true) {
+        return Some(magic);
+    }
+    resolve(&proc_root.join(pid.to_string()), &path, true)
+}
+
+/// path_arguments returns the paths a syscall entry names, resolved the way the kernel will find them, or None if
+/// the syscall doesn't take any. Syscalls given a descriptor in place of a path, like utimensat with a null path,
+/// name none.
+pub fn path_arguments(
+    pid: Pid,
+    syscall: Sysno,
+    args: &[u64; 6],
+    fds: &mut FdTable,
+    proc_root: &Path,
+) -> Option<Vec<PathBuf>> {
+    let mut resolver = Resolver {
+        pid,
+        fds,
+        proc_root,
+    };
+    let cwd = libc::AT_FDCWD as u64;
+    let paths = match syscall {
+        Sysno::openat
+        | Sysno::openat2
+        | Sysno::faccessat
+        | Sysno::faccessat2
+        | Sysno::fstatat
+        | Sysno::statx
+        | Sysno::fchmodat
+        | Sysno::fchmodat2
+        | Sysno::fchownat
+        | Sysno::execveat => vec![resolver.path(args[0], args[1], true)],
+        Sysno::utimensat if args[1] == 0 => Vec::new(),
+        Sysno::utimensat => vec![resolver.path(args[0], args[1], true)],
+        Sysno::unlinkat | Sysno::mkdirat | Sysno::mknodat | Sysno::readlinkat => {
+            vec![resolver.path(args[0], args[1], false)]
+        }
+        Sysno::renameat | Sysno::renameat2 | Sysno::linkat => vec![
+            resolver.path(args[0], args[1], false),
+            resolver.path(args[2], args[3], false),
+        ],
+        Sysno::symlinkat => vec![resolver.path(args[1], args[2], false)],
+        Sysno::execve
+        | Sysno::chdir
+        | Sysno::chroot
+        | Sysno::truncate
+        | Sysno::statfs
+        | Sysno::getxattr
+        | Sysno::setxattr
+        | Sysno::listxattr
+        | Sysno::removexattr => vec![resolver.path(cwd, args[0], true)],
+        Sysno::lgetxattr | Sysno::lsetxattr | Sysno::llistxattr | Sysno::lremovexattr => {
+            vec![resolver.path(cwd, args[0], false)]
+        }
+        Sysno::inotify_add_watch => vec![resolver.path(cwd, args[1], true)],
+        _ => return None,
+    };
+    Some(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(Path::new("/tmp/../etc/./passwd")),
+            PathBuf::from("/etc/passwd")
+        );
+        assert_eq!(normalize(Path::new("/../..")), PathBuf::from("/"));
+        assert!(!normalize(Path::new("/tmp/../etc")).starts_with("/tmp"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("crabtrap_resolve_{}", std::process::id()));
+        fs::create_dir_all(dir.join("allowed")).unwrap();
+        fs::create_dir_all(dir.join("elsewhere/deeper")).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        let link = dir.join("allowed/link");
+        std::os::unix::fs::symlink(dir.join("elsewhere/deeper"), &link).unwrap();
+        let proc = Path::new("/proc/self");
+
+        // Lexically this is under allowed, but the link takes the `..` somewhere else
+        let sneaky = link.join("../secret");
+        assert!(normalize(&sneaky).starts_with(dir.join("allowed")));
+        assert_eq!(
+            resolve(proc, &sneaky, true),
+            Some(dir.join("elsewhere/secret"))
+        );
+        assert_eq!(
+            resolve(proc, &link, true),
+            Some(dir.join("elsewhere/deeper"))
+        );
+        assert_eq!(resolve(proc, &link, false), Some(link.clone()));
+        assert_eq!(
+            resolve(proc, &dir.join("allowed/new"), true),
+            Some(dir.join("allowed/new"))
+        );
+        assert_eq!(resolve(proc, &dir.join("missing/new"), true), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_magic() {
+        let dir = std::env::temp_dir().join(format!("crabtrap_magic_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        let file = fs::File::create(dir.join("file")).unwrap();
+        let fd = file.as_raw_fd();
+
+        let pid = nix::unistd::getpid();
+        let mut fds = FdTable::new("/proc");
+        let mut resolver = Resolver {
+            pid,
+            fds: &mut fds,
+            proc_root: Path::new("/proc"),
+        };
+        let proc = PathBuf::from(format!("/proc/{pid}"));
+        assert_eq!(
+            resolver.magic(Path::new("/proc/self/status"), true),
+            Some(proc.join("status"))
+        );
+        assert_eq!(
+            resolver.magic(Path::new("/proc/thread-self/comm"), true),
+            Some(proc.join(format!("task/{pid}/comm")))
+        );
+        assert_eq!(
+            resolver.magic(Path::new(&format!("/dev/fd/{fd}")), true),
+            Some(dir.join("file"))
+        );
+        assert_eq!(
+            resolver.magic(Path::new(&format!("/proc/self/fd/{fd}")), false),
+            Some(proc.join(format!("fd/{fd}")))
+        );
+        assert_eq!(resolver.magic(Path::new("/proc/self"), false), None);
+        assert_eq!(resolver.magic(Path::new("/etc/passwd"), true), None);
+        drop(file);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_opens_for_writing() {
+        assert!(!opens_for_writing(libc::O_RDONLY as u64));
+        assert!(!opens_for_writing(
+            (libc::O_RDONLY | libc::O_CLOEXEC) as u64
+        ));
+        assert!(opens_for_writing(libc::O_WRONLY as u64));
+        assert!(opens_for_writing((libc::O_RDONLY | libc::O_CREAT) as u64));
+    }
+}
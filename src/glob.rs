@@ -0,0 +1,181 @@
+use regex::Regex;
+use std::collections::BTreeMap;
+
+/// is_pattern returns whether a shared object key is a glob rather than an exact path. Names in brackets, like
+/// `[anonymous-exec]`, aren't paths at all, so they're never globs.
+pub fn is_pattern(key: &str) -> bool {
+    key.contains(['*', '?', '[']) && !(key.starts_with('[') && key.ends_with(']'))
+}
+
+/// specificity ranks a pattern by how much of it has to match literally, so `/usr/lib/**/libc.so.*` beats
+/// `**/libc.so.*`.
+pub fn specificity(pattern: &str) -> usize {
+    let mut count = 0;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '?' => {}
+            '[' => {
+                // A class matches one character, which isn't literal
+                chars.by_ref().find(|&c| c == ']');
+            }
+            _ => count += 1,
+        }
+    }
+    count
+}
+
+/// Globs: the globs in a config, compiled when it's loaded so that matching against one is only a lookup away, see
+/// Config::compile
+#[derive(Debug, Clone, Default)]
+pub struct Globs(BTreeMap<String, Regex>);
+
+impl Globs {
+    /// new compiles those of `patterns` that are globs.
+    pub fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Globs {
+        Globs(
+            patterns
+                .into_iter()
+                .filter(|pattern| is_pattern(pattern))
+                .map(|pattern| (pattern.to_string(), compile(pattern)))
+                .collect(),
+        )
+    }
+
+    /// covers returns whether all of `patterns` that are globs have been compiled.
+    pub fn covers<'a>(&self, mut patterns: impl Iterator<Item = &'a str>) -> bool {
+        patterns.all(|pattern| !is_pattern(pattern) || self.0.contains_key(pattern))
+    }
+
+    /// matches checks a path against a glob, where `*` matches within a directory, `**` matches across directories,
+    /// `?` matches a single character other than `/`, and `[a-z]` or `[!a-z]` match a character in or out of a
+    /// class. Anything else only matches itself. A glob that wasn't compiled with the rest, like one added in code,
+    /// is compiled each time it's matched.
+    pub fn matches(&self, pattern: &str, path: &str) -> bool {
+        match self.0.get(pattern) {
+            Some(regex) => regex.is_match(path),
+            None if is_pattern(pattern) => compile(pattern).is_match(path),
+            None => pattern == path,
+        }
+    }
+}
+
+/// Compiled globs don't make configs differ, since they're worked out from the rest of the config
+impl PartialEq for Globs {
+    fn eq(&self, _: &Globs) -> bool {
+        true
+    }
+}
+
+impl Eq for Globs {}
+
+/// compile turns a glob into a regex that matches the same paths.
+fn compile(pattern: &str) -> Regex {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut regex = String::from("(?s)^");
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            '*' if pattern.get(i + 1) == Some(&'*') => {
+                regex.push_str(".*");
+                i += 1;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            // A `]` straight after the `[` is part of the class
+            '[' => match pattern[i + 1..].iter().skip(1).position(|&c| c == ']') {
+                Some(len) => {
+                    let end = i + 2 + len;
+                    regex.push_str(&class(&pattern[i + 1..end]));
+                    i = end;
+                }
+                // No closing bracket, so it's just a bracket
+                None => regex.push_str(r"\["),
+            },
+            c => regex.push_str(&escape(c)),
+        }
+        i += 1;
+    }
+    regex.push('$');
+    Regex::new(&regex).expect("a glob always makes a valid regex")
+}
+
+/// class turns what's between a glob's brackets into a regex class, which like the other wildcards never matches `/`.
+/// A range that runs backwards matches nothing.
+fn class(class: &[char]) -> String {
+    let (negated, class) = match class {
+        ['!', class @ ..] => (true, class),
+        class => (false, class),
+    };
+    let mut items = String::new();
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            if class[i] <= class[i + 2] {
+                items.push_str(&format!("{}-{}", escape(class[i]), escape(class[i + 2])));
+            }
+            i += 3;
+        } else {
+            items.push_str(&escape(class[i]));
+            i += 1;
+        }
+    }
+    match (negated, items.is_empty()) {
+        (true, _) => format!("[^/{items}]"),
+        // A class with nothing in it
+        (false, true) => String::from("[a&&b]"),
+        (false, false) => format!("[[{items}]&&[^/]]"),
+    }
+}
+
+fn escape(c: char) -> String {
+    regex::escape(c.encode_utf8(&mut [0; 4]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        // Globs match the same whether they were compiled ahead of time or not
+        let matches = |pattern: &str, path: &str| {
+            let compiled = Globs::new([pattern]).matches(pattern, path);
+            assert_eq!(Globs::default().matches(pattern, path), compiled);
+            compiled
+        };
+        assert!(matches("**/libc.so.*", "/lib/libc.so.6"));
+        assert!(matches(
+            "**/libc.so.*",
+            "/usr/lib/aarch64-linux-gnu/libc.so.6"
+        ));
+        assert!(!matches("**/libc.so.*", "/lib/libcrypt.so.1"));
+        assert!(matches("/usr/lib/*.so", "/usr/lib/libfoo.so"));
+        assert!(!matches("/usr/lib/*.so", "/usr/lib/foo/libfoo.so"));
+        assert!(matches("/lib/lib?.so", "/lib/libm.so"));
+        assert!(!matches("/lib/lib?.so", "/lib/lib/.so"));
+        assert!(matches("/lib/libssl.so.[0-9]", "/lib/libssl.so.3"));
+        assert!(!matches("/lib/libssl.so.[!0-9]", "/lib/libssl.so.3"));
+        assert!(matches("/lib/[x", "/lib/[x"));
+        assert!(matches("/lib/[]x]", "/lib/]"));
+        assert!(!matches("/lib/[z-a]", "/lib/b"));
+        assert!(!matches("/lib/[!a]", "/lib//"));
+        assert!(!matches("/lib[/]x", "/lib/x"));
+        assert!(matches("/lib/lib.so.[^.]", "/lib/lib.so.^"));
+        assert!(matches("/lib/lib(.)+.so", "/lib/lib(.)+.so"));
+        assert!(!matches("/lib/lib(.)+.so", "/lib/lib(x)+.so"));
+        assert!(matches("/opt/***", "/opt/a/b"));
+        assert!(matches("/lib/libc.so.6", "/lib/libc.so.6"));
+        assert!(!matches("[vdso]", "v"));
+
+        let globs = Globs::new(["**/libc.so.*", "/lib/libc.so.6"]);
+        assert!(globs.covers(["**/libc.so.*", "/lib/libm.so.6"].into_iter()));
+        assert!(!globs.covers(["/lib/*.so"].into_iter()));
+
+        assert!(is_pattern("**/libc.so.*"));
+        assert!(!is_pattern("/lib/libc.so.6"));
+        assert!(!is_pattern("[anonymous-exec]"));
+        assert!(specificity("/usr/lib/**/libc.so.*") > specificity("**/libc.so.*"));
+        assert_eq!(specificity("/lib/libssl.so.[0-9]"), 15);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{events::Event, log::info, ChildExit, Config, ConfigError};
+use syscalls::Sysno;
+
+/// Ruling: what a violation handler decided to do about a would-be violation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ruling {
+    /// Enforce the config, ending the run
+    Enforce,
+    /// Let the process carry on as though the config had allowed it
+    Allow,
+}
+
+/// Judgement: what a syscall hook decided about a syscall the config has no rule for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Judgement {
+    Allow,
+    Block,
+    /// Leave it to the remote policy service if there is one, or let it through if not
+    Defer,
+}
+
+/// SyscallEvent: a syscall the config has no rule for, as handed to a syscall hook
+#[derive(Debug)]
+pub struct SyscallEvent<'s> {
+    pub pid: i32,
+    pub syscall: Sysno,
+    pub args: [u64; 6],
+    /// The mapped files seen on the stack, innermost first
+    pub stack: &'s [String],
+}
+
+/// ViolationHandler: decides what to do about a would-be violation by the process with the given pid
+type ViolationHandler<'a> = Box<dyn FnMut(i32, &ChildExit) -> Ruling + 'a>;
+/// SyscallHook: judges a syscall the config has no rule for
+type SyscallHook<'a> = Box<dyn FnMut(&SyscallEvent) -> Judgement + 'a>;
+/// ConfigLoader: reads the config again when it's to be reloaded
+type ConfigLoader<'a> = Box<dyn FnMut() -> Result<Config, ConfigError> + 'a>;
+
+/// Hooks: callbacks for embedders to watch a run, and to overrule the config
+///
+/// ```no_run
+/// use crabtrap::{hooks::{Hooks, Judgement, Ruling}, ChildExit, Config};
+///
+/// let hooks = Hooks::default()
+///     .on_event(|event| println!("{event:?}"))
+///     .on_violation(|_pid, exit| match exit {
+///         ChildExit::WriteQuotaExceeded(..) => Ruling::Allow,
+///         _ => Ruling::Enforce,
+///     })
+///     .on_unknown(|event| match event.stack.first() {
+///         Some(library) if library.starts_with("/opt/") => Judgement::Block,
+///         _ => Judgement::Defer,
+///     });
+/// crabtrap::execute_with_hooks(c"/bin/ls", &[c"ls"], &[], &Config::default(), hooks);
+/// ```
+#[derive(Default)]
+pub struct Hooks<'a> {
+    on_event: Option<Box<dyn FnMut(Event) + 'a>>,
+    on_violation: Option<ViolationHandler<'a>>,
+    on_unknown: Option<SyscallHook<'a>>,
+    on_reload: Option<ConfigLoader<'a>>,
+}
+
+impl<'a> Hooks<'a> {
+    /// on_event is called with each event as it happens.
+    pub fn on_event<F: FnMut(Event) + 'a>(mut self, on_event: F) -> Hooks<'a> {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    /// on_violation is called with the pid and the exit it would cause whenever a process is about to be stopped
+    /// for breaking the config, before anything is done about it.
+    pub fn on_violation<F: FnMut(i32, &ChildExit) -> Ruling + 'a>(
+        mut self,
+        on_violation: F,
+    ) -> Hooks<'a> {
+        self.on_violation = Some(Box::new(on_violation));
+        self
+    }
+
+    /// on_unknown is called at the entry of each syscall the config has no rule for, including those whose stack walk
+    /// was cut short, before the remote policy service is asked about it.
+    pub fn on_unknown<F: FnMut(&SyscallEvent) -> Judgement + 'a>(
+        mut self,
+        on_unknown: F,
+    ) -> Hooks<'a> {
+        self.on_unknown = Some(Box::new(on_unknown));
+        self
+    }
+
+    /// on_reload is called when the tracer gets SIGHUP, to read the config again. What it returns is enforced from
+    /// then on, as with a reload over the control socket, and if it fails, or the prefilter wouldn't stop at the
+    /// syscalls it has rules for, the config stays as it was. SIGHUP is only caught while a run with this hook is
+    /// going on, so there should only be one such run in a process at a time.
+    pub fn on_reload<F: FnMut() -> Result<Config, ConfigError> + 'a>(
+        mut self,
+        on_reload: F,
+    ) -> Hooks<'a> {
+        self.on_reload = Some(Box::new(on_reload));
+        self
+    }
+
+    pub(crate) fn reloads(&self) -> bool {
+        self.on_reload.is_some()
+    }
+
+    /// reload reads the config again, if there's a hook for it.
+    pub(crate) fn reload(&mut self) -> Option<Result<Config, ConfigError>> {
+        self.on_reload.as_mut().map(|on_reload| on_reload())
+    }
+
+    pub(crate) fn judge(&mut self, event: &SyscallEvent) -> Judgement {
+        self.on_unknown
+            .as_mut()
+            .map_or(Judgement::Defer, |on_unknown| on_unknown(event))
+    }
+
+    pub(crate) fn event(&mut self, event: Event) {
+        if let Some(on_event) = self.on_event.as_mut() {
+            on_event(event);
+        }
+    }
+
+    /// enforce asks the violation handler about a would-be violation, and returns it if it should be enforced.
+    pub(crate) fn enforce(&mut self, pid: i32, exit: ChildExit) -> Option<ChildExit> {
+        let ruling = self
+            .on_violation
+            .as_mut()
+            .map_or(Ruling::Enforce, |on_violation| on_violation(pid, &exit));
+        match ruling {
+            Ruling::Enforce => Some(exit),
+            Ruling::Allow => {
+                info!("Violation {exit:?} by child {pid} was allowed by the violation handler");
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use crate::{elf::Elf, map::FileId};
+use std::{collections::HashMap, fs, os::unix::fs::MetadataExt, path::Path, rc::Rc};
+
+/// The prefixes of the config keys that name a library by what its ELF headers say rather than by its path, like
+/// `soname:libssl.so.3` or `build-id:8d7b...`, which stay the same across distros and containers. They're only what
+/// the file claims, so any library the tracee loads can claim them, see Config::shared_objects.
+pub const SONAME: &str = "soname:";
+pub const BUILD_ID: &str = "build-id:";
+
+/// is_identity returns whether a config key names a library by its soname or build ID.
+pub fn is_identity(key: &str) -> bool {
+    key.starts_with(SONAME) || key.starts_with(BUILD_ID)
+}
+
+/// Changed: when a file's inode last changed, in seconds and nanoseconds
+type Changed = (i64, i64);
+
+/// Identities: the names each mapped file goes by besides its path, read from its ELF headers once per file
+#[derive(Default)]
+pub struct Identities {
+    /// By device and inode, along with when the inode last changed, since it can be reused for another file once the
+    /// one it was is deleted
+    names: HashMap<FileId, (Changed, Rc<[String]>)>,
+}
+
+impl Identities {
+    /// names returns the file's `build-id:` and `soname:` names, for whichever it has, reading them from `path` the
+    /// first time the file is seen. Files are told apart by device and inode, so a library mapped into many processes
+    /// or by several paths is only read once.
+    pub fn names(&mut self, file: FileId, path: &Path) -> Rc<[String]> {
+        let Ok(metadata) = fs::metadata(path) else {
+            return Rc::new([]);
+        };
+        let changed = (metadata.ctime(), metadata.ctime_nsec());
+        match self.names.get(&file) {
+            Some((seen, names)) if *seen == changed => names.clone(),
+            _ => {
+                let names: Rc<[String]> = match Elf::read(path) {
+                    Ok(elf) => {
+                        let build_id = elf.build_id.map(|id| format!("{BUILD_ID}{id}"));
+                        let soname = elf.soname.map(|name| format!("{SONAME}{name}"));
+                        build_id.into_iter().chain(soname).collect()
+                    }
+                    Err(_) => Rc::new([]),
+                };
+                self.names.insert(file, (changed, names.clone()));
+                names
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::MemoryMap;
+
+    #[test]
+    fn test_names() {
+        let map = MemoryMap::from_pid(nix::unistd::getpid()).unwrap();
+        let libc = map
+            .files
+            .iter()
+            .map(|file| file.path())
+            .find(|path| path.contains("/libc.so"))
+            .unwrap();
+        let file = map.file_id(libc).unwrap();
+
+        let mut identities = Identities::default();
+        let names = identities.names(file, Path::new(libc));
+        assert!(names[0].starts_with(BUILD_ID));
+        assert_eq!(names[1], "soname:libc.so.6");
+        // Once it's been read, it isn't read again
+        let again = identities.names(file, Path::new(libc));
+        assert!(Rc::ptr_eq(&names, &again));
+        // Unless the inode has been changed since, as it would be if it was reused for another file
+        let other = std::env::temp_dir().join(format!("crabtrap_identity_{}", std::process::id()));
+        fs::write(&other, "not an ELF file").unwrap();
+        assert!(identities.names(file, &other).is_empty());
+        fs::remove_file(&other).unwrap();
+
+        assert!(is_identity("soname:libc.so.6"));
+        assert!(!is_identity("/usr/lib/libc.so.6"));
+    }
+}
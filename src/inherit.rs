@@ -0,0 +1,56 @@
+use crate::config::InheritedFds;
+use nix::{errno::Errno, libc};
+
+/// The first descriptor past stdin, stdout and stderr
+const FIRST: u32 = 3;
+
+/// apply marks each descriptor above 2 that isn't to be kept close-on-exec, if the config says to close them, and
+/// clears close-on-exec on the ones that are. Marking rather than closing them leaves whatever the rest of the setup
+/// still needs open until the exec. Safe to call between fork and exec.
+pub fn apply(config: &InheritedFds) -> nix::Result<()> {
+    if config.close {
+        let mut first = FIRST;
+        for &keep in &config.keep {
+            let Ok(keep) = u32::try_from(keep) else {
+                continue;
+            };
+            if keep > first {
+                close_on_exec(first, keep - 1)?;
+            }
+            first = first.max(keep.saturating_add(1));
+        }
+        close_on_exec(first, u32::MAX)?;
+    }
+    for &keep in &config.keep {
+        match Errno::result(unsafe { libc::fcntl(keep, libc::F_SETFD, 0) }) {
+            // There's nothing to pass on
+            Ok(_) | Err(Errno::EBADF) => {}
+            Err(errno) => return Err(errno),
+        }
+    }
+    Ok(())
+}
+
+/// close_on_exec marks the descriptors from `first` to `last` inclusive close-on-exec.
+fn close_on_exec(first: u32, last: u32) -> nix::Result<()> {
+    match Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_close_range,
+            first,
+            last,
+            libc::CLOSE_RANGE_CLOEXEC,
+        )
+    }) {
+        Ok(_) => Ok(()),
+        // Kernels before 5.11 don't have the flag, so it takes one descriptor at a time
+        Err(Errno::ENOSYS | Errno::EINVAL) => {
+            let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+            let last = last.min(max.clamp(0, u32::MAX as libc::c_long) as u32);
+            for fd in first..last.saturating_add(1) {
+                unsafe { libc::fcntl(fd as i32, libc::F_SETFD, libc::FD_CLOEXEC) };
+            }
+            Ok(())
+        }
+        Err(errno) => Err(errno),
+    }
+}
@@ -0,0 +1,137 @@
+use crate::config::LandlockConfig;
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    libc,
+    sys::stat::{fstat, Mode, SFlag},
+};
+use std::{
+    ffi::CString,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    ptr,
+};
+
+/// From linux/landlock.h
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Everything up to ACCESS_FS_MAKE_SYM, which is what the first version of the ABI handles
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// The rights that make sense for a file rather than a directory
+const ACCESS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Ruleset: the landlock paths from the config, converted ahead of time so they can be applied between fork and exec
+pub struct Ruleset {
+    /// Each path, and whether it's writable
+    paths: Vec<(CString, bool)>,
+}
+
+impl Ruleset {
+    pub fn new(config: &LandlockConfig) -> Ruleset {
+        let path = |path: &std::path::PathBuf| {
+            CString::new(path.as_os_str().as_bytes()).expect("landlock path contains a nul byte")
+        };
+        Ruleset {
+            paths: config
+                .read_only
+                .iter()
+                .map(|p| (path(p), false))
+                .chain(config.writable.iter().map(|p| (path(p), true)))
+                .collect(),
+        }
+    }
+
+    /// apply restricts the calling process, and everything it goes on to start, to the ruleset's paths. Handles
+    /// whatever the kernel's version of landlock can, so older kernels leave renames across directories and
+    /// truncation unrestricted. Called in the forked child, so it mustn't allocate.
+    pub fn apply(&self) -> nix::Result<()> {
+        let abi = Errno::result(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        })?;
+        let mut handled = ACCESS_FS_V1;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let ruleset = Errno::result(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                size_of::<RulesetAttr>(),
+                0,
+            )
+        })?;
+        let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+
+        for (path, writable) in &self.paths {
+            let fd = open(
+                path.as_c_str(),
+                OFlag::O_PATH | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )?;
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let mut allowed = if *writable { handled } else { ACCESS_READ };
+            // The kernel refuses directory rights on anything else
+            if SFlag::from_bits_truncate(fstat(fd.as_raw_fd())?.st_mode) & SFlag::S_IFMT
+                != SFlag::S_IFDIR
+            {
+                allowed &= ACCESS_FILE;
+            }
+            let rule = PathBeneathAttr {
+                allowed_access: allowed & handled,
+                parent_fd: fd.as_raw_fd(),
+            };
+            Errno::result(unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0,
+                )
+            })?;
+        }
+
+        // Without this only root could restrict itself
+        Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        Errno::result(unsafe {
+            libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0)
+        })
+        .map(drop)
+    }
+}
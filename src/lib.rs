@@ -1,26 +1,137 @@
-pub use config::{Check, Config, ConfigEntry};
-pub use map::MemoryMap;
+use arch::Abi;
+pub use ask::Asker;
+use audit::AuditLog;
+use cache::DecisionCache;
+pub use capture::Capture;
+use cgroup::Cgroup;
+pub use command::{CommandExt, SandboxedCommand, TracedChild};
+pub use config::{
+    Arguments, BinaryConfig, CgroupConfig, Check, Config, ConfigEntry, ConfigError, ConfigFormat,
+    DaemonPolicy, DefaultPolicy, Deterministic, Enforcement, EnvironmentConfig, Fallback,
+    FilesystemConfig, FilesystemMode, InheritedFds, LandlockConfig, Namespaces, NetworkRules,
+    Output, ReadOnlyAction, RemotePolicyConfig, ResourceLimits, Rule, RuleAction, RuleList, RunAs,
+    StackWalkConfig, StdioConfig, StormAction, StormConfig, TimeLimits, UnattributedPolicy,
+    ViolationScope, Virtualization, WriteQuota,
+};
+use control::{Command, ControlSocket, Response};
+use deterministic::Virtualizer;
+use events::EventLog;
+pub use fd::{FdKind, FdRule, FdTable};
+use hooks::{Hooks, Judgement, SyscallEvent};
+use identity::Identities;
+use landlock::Ruleset;
+use limits::Watchdog;
+use log::{debug, info, warning};
+pub use map::{MemoryMap, Permissions, Region};
+use map::{MemoryMapError, ProcessMap};
+use memory::MemoryReader;
 use nix::{
     errno::Errno,
-    libc::c_int,
+    fcntl::OFlag,
+    libc::{self, ptrace_syscall_info, sock_filter, user_regs_struct},
     sys::{
         ptrace::{
-            getevent, getregs, kill, read, setoptions, syscall, traceme, AddressType, Event,
-            Options,
+            cont, detach, getevent, getregs, interrupt, kill, seize, syscall, Event, Options,
         },
-        signal::Signal,
-        wait::{waitpid, WaitStatus},
+        signal::{self, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
     },
-    unistd::{execve, fork, ForkResult, Pid},
+    unistd::{self, execve, ForkResult, Pid},
 };
+use profile::{ANONYMOUS_EXEC, LOADER_STARTUP, UNATTRIBUTED};
+pub use quota::Quota;
+use quota::WriteTracker;
+use reload::Hangups;
+use remote::{Query, RemotePolicy};
+use replay::TraceRecording;
+pub use resolve::{c_path, resolve};
+use restart::RestartQueue;
+use scenario::{Action, ScenarioRunner};
 use serde::{Deserialize, Serialize};
+use session::SessionRecording;
+pub use stats::{LibraryStats, ProcessExit, RunStats, Usage};
 use std::{
+    borrow::Cow,
+    cell::{Ref, RefCell},
     collections::{BTreeMap, BTreeSet},
-    ffi::CStr,
+    ffi::{CStr, CString},
+    fs, io,
+    mem::{self, MaybeUninit},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::fs::MetadataExt,
+    },
+    panic,
+    path::{Path, PathBuf},
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
 };
+use stdio::Redirections;
+use storm::StormDetector;
 use syscalls::Sysno;
+use unwind::Unwinder;
+mod arch;
+mod ask;
+mod audit;
+pub mod batch;
+mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod capture;
+mod cgroup;
+mod coalesce;
+mod command;
 mod config;
+pub mod control;
+mod credentials;
+mod deterministic;
+mod elf;
+pub mod events;
+pub mod explain;
+mod fd;
+mod filesystem;
+mod glob;
+pub mod hooks;
+mod identity;
+mod inherit;
+mod landlock;
+mod limits;
+pub mod lint;
+mod log;
 mod map;
+mod memory;
+mod names;
+mod namespace;
+pub mod network;
+pub mod oci;
+pub mod plugins;
+pub mod profile;
+#[cfg(feature = "python")]
+mod python;
+mod quota;
+#[cfg(feature = "receipts")]
+pub mod receipt;
+pub mod reload;
+mod remote;
+pub mod replay;
+mod resolve;
+mod restart;
+#[cfg(feature = "async")]
+pub mod sandbox;
+pub mod scenario;
+mod scrub;
+pub mod seccomp;
+mod session;
+mod stats;
+mod stdio;
+mod storm;
+mod unwind;
+pub mod validate;
+mod wake;
+
+/// How often to check for new stops while a tracee is being held back
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
 
 fn event_from_int(event: i32) -> Event {
     match event {
@@ -36,185 +147,2439 @@ fn event_from_int(event: i32) -> Event {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum ChildExit {
     Exited(i32),
-    IllegalSyscall(Sysno, String),
+    /// The named library made a syscall the config doesn't allow it.
+    IllegalSyscall {
+        syscall: Sysno,
+        library: String,
+        /// The id of the library's rules that blocked it, if they have one. Syscalls killed by the top-level default,
+        /// the unattributed policy or the remote policy service weren't blocked by any library's rules, so have none.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rule: Option<String>,
+        /// Where in the library the call came from, as `libfoo.so!function+0x1c`, if it was found on the stack and
+        /// violations are being enforced.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        call_site: Option<String>,
+        /// The whole stack, as far as it could be walked.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        backtrace: Vec<Frame>,
+    },
+    /// A write would have taken the named file, or all files together, over the configured quota.
+    WriteQuotaExceeded(Quota, String),
+    /// A syscall kept failing from the same call site, named by its library or address.
+    SyscallStorm(Sysno, String),
+    /// The process with this pid daemonized, and the config doesn't allow it.
+    Daemonized(i32),
+    /// A syscall would have modified the named file, but the filesystem is read-only.
+    ReadOnlyFilesystem(Sysno, String),
+    /// The target was killed by this signal.
+    Signaled(i32),
+    /// The run went over one of its time limits, and the whole tree was killed.
+    TimedOut(TimeLimit),
+    /// The target never started, because this step of setting it up failed.
+    SetupFailed {
+        stage: SetupStage,
+        #[serde(with = "names::errno")]
+        errno: Errno,
+    },
+    /// The tracer was asked to detach, and left the target running untraced before it could see how it exited.
+    Detached,
+}
+
+impl ChildExit {
+    /// rule returns the id of the rules that blocked the syscall, if it was an IllegalSyscall blocked by a library's
+    /// rules and they have one.
+    pub fn rule(&self) -> Option<&str> {
+        match self {
+            ChildExit::IllegalSyscall { rule, .. } => rule.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Frame: one address on a walked stack, innermost first, starting with the pc
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Frame {
+    pub address: u64,
+    /// The mapped file the address is in, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub library: Option<String>,
+    /// How far the address is past where the file was loaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+}
+
+/// TimeLimit: which of the config's time limits a run went over
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeLimit {
+    /// The wall-clock timeout for the whole run
+    Wall,
+    /// The CPU time limit, which one of the processes used up
+    Cpu,
+}
+
+/// SetupStage: the steps the forked child takes before it's running the target
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum SetupStage {
+    /// Applying the `deterministic` settings
+    Deterministic,
+    /// Waiting for the tracer to attach
+    Attach,
+    /// Opening the files the standard streams are redirected to, or redirecting them
+    Stdio,
+    /// Making the cgroup for the run, or joining it
+    Cgroup,
+    /// Moving into the new namespaces
+    Namespaces,
+    /// Switching to the configured user and groups, or setting no_new_privs
+    Credentials,
+    /// Changing to the configured working directory
+    Chdir,
+    /// Closing the inherited file descriptors
+    Fds,
+    /// Setting the CPU time and resource limits
+    Limits,
+    /// Applying the `landlock` rules
+    Landlock,
+    /// Installing the seccomp prefilter
+    Seccomp,
+    /// Executing the target
+    Exec,
+}
+
+impl SetupStage {
+    fn from_u8(stage: u8) -> Option<SetupStage> {
+        [
+            SetupStage::Deterministic,
+            SetupStage::Attach,
+            SetupStage::Stdio,
+            SetupStage::Cgroup,
+            SetupStage::Namespaces,
+            SetupStage::Credentials,
+            SetupStage::Chdir,
+            SetupStage::Fds,
+            SetupStage::Limits,
+            SetupStage::Landlock,
+            SetupStage::Seccomp,
+            SetupStage::Exec,
+        ]
+        .into_iter()
+        .find(|&known| known as u8 == stage)
+    }
+}
+
+/// SyscallEntry: a syscall as seen at its entry stop, kept around until the matching exit stop
+struct SyscallEntry {
+    syscall: Sysno,
+    args: [u64; 6],
+    pc: u64,
+    /// The library the syscall is attributed to, if any
+    library: Option<String>,
+    /// The id of the rule that decided the syscall, if it has one
+    rule: Option<String>,
+    entered: Instant,
+    /// The error the failure scenario made this syscall fail with, instead of running it
+    injected: Option<Errno>,
+    /// What it's about to write to a file, for the write quota
+    write: Option<quota::Write>,
+}
+
+/// Tracee: what we keep track of for each traced process
+struct Tracee {
+    /// None if we couldn't tell, in which case it's checked at every stop
+    abi: Option<Abi>,
+    /// The process it's a thread of
+    tgid: Pid,
+    /// Shared with the other threads of the process
+    map: Rc<RefCell<ProcessMap>>,
+    /// Shared with the other threads of the process too, since they share their descriptors
+    fds: Rc<RefCell<FdTable>>,
+    /// The syscall the tracee is currently stopped inside of, if any
+    pending: Option<SyscallEntry>,
+    /// Whether the dynamic loader is still starting the program up, which it is until the first syscall from
+    /// outside of it
+    starting: bool,
+    /// Whether the thread is being made to exit after a violation, see end_thread
+    ending: bool,
+    /// The `binaries` section for the executable it's running, if there is one
+    binary: Option<String>,
+    /// The last signal delivered to the thread, which if it's the one that kills the process makes this the thread
+    /// whose stack the crash is reported with
+    signal: Option<Signal>,
+}
+
+impl Tracee {
+    /// new starts tracking a thread, which shares its map and descriptors with any of the `others` in the same
+    /// process.
+    fn new(pid: Pid, config: &Config, others: &BTreeMap<Pid, Tracee>) -> Tracee {
+        let tgid = thread_group(pid, config).unwrap_or(pid);
+        let (map, fds) = match others.values().find(|other| other.tgid == tgid) {
+            Some(other) => (other.map.clone(), other.fds.clone()),
+            None => (
+                Rc::new(RefCell::new(ProcessMap::new(
+                    read_map(pid, config)
+                        .unwrap_or_else(|e| panic!("Couldn't build map for {}: {}", pid, e)),
+                ))),
+                Rc::new(RefCell::new(FdTable::new(config.proc_root()))),
+            ),
+        };
+        Tracee {
+            abi: arch::detect(config.proc_root(), pid),
+            tgid,
+            map,
+            fds,
+            pending: None,
+            starting: true,
+            ending: false,
+            binary: binary(pid, config),
+            signal: None,
+        }
+    }
+
+    fn map(&self) -> Ref<'_, MemoryMap> {
+        Ref::map(self.map.borrow(), |shared| &shared.map)
+    }
+}
+
+/// Decision: what to do with a tracee once one of its stops has been dealt with
+enum Decision {
+    /// Resume it right away
+    Continue,
+    /// Resume it once the delay has passed, leaving the rest of the tree running in the meantime
+    Delay(Duration),
+    /// Leave it stopped until whatever it's waiting on schedules the restart
+    Hold,
+    /// Stop tracing it and let it run on its own
+    Detach,
+    /// Kill it and report the exit
+    Exit(ChildExit),
+}
+
+/// Trackers: accounting shared by the whole traced tree
+#[derive(Default)]
+struct Trackers<'a> {
+    writes: WriteTracker,
+    storms: StormDetector,
+    remote: Option<RemotePolicy>,
+    virtualizer: Option<Virtualizer>,
+    scenario: Option<ScenarioRunner>,
+    audit: Option<AuditLog>,
+    events: Option<EventLog>,
+    session: Option<SessionRecording>,
+    trace: Option<TraceRecording>,
+    hooks: Hooks<'a>,
+    enforcement: Enforcement,
+    decisions: DecisionCache,
+    stats: RunStats,
+    unwinder: Unwinder,
+    /// The names files go by besides their paths, if any rules use them
+    identities: Option<Identities>,
+    /// Why the tracees mustn't be let go of, if they mustn't
+    pinned: Option<&'static str>,
 }
 
-/// child sets up ptrace and then calls execve.
-fn child(path: &CStr, args: &[&CStr], env: &[&CStr]) -> ! {
+impl Trackers<'_> {
+    fn emit(&mut self, event: events::Event) {
+        if let Some(log) = self.events.as_mut() {
+            log.event(&event).expect("failed to write event log");
+        }
+        self.hooks.event(event);
+    }
+
+    /// enforce returns a would-be violation if it should be acted on, which it isn't if the violation handler
+    /// overrules it or we're only auditing.
+    fn enforce(&mut self, pid: Pid, exit: ChildExit) -> Option<ChildExit> {
+        let enforced = self
+            .hooks
+            .enforce(pid.as_raw(), exit.clone())
+            .and_then(|exit| match self.enforcement {
+                Enforcement::Enforce => Some(exit),
+                Enforcement::Audit => {
+                    if self.stats.record_violation(exit.clone()) {
+                        info!("Audit mode, letting child {pid} carry on after {exit:?}");
+                    }
+                    None
+                }
+            });
+        if let Some(session) = self.session.as_mut() {
+            let outcome = if enforced.is_some() {
+                "enforced"
+            } else {
+                "let through"
+            };
+            session.marker(&format!("{exit:?} in child {pid}, {outcome}"));
+        }
+        enforced
+    }
+
+    /// overrule turns a decision to end the run into one to carry on, if the violation isn't to be enforced.
+    fn overrule(&mut self, pid: Pid, decision: Decision) -> Decision {
+        match decision {
+            Decision::Exit(exit) => match self.enforce(pid, exit) {
+                Some(exit) => Decision::Exit(exit),
+                None => Decision::Continue,
+            },
+            decision => decision,
+        }
+    }
+}
+
+/// Restrictions: what the child applies to itself once it's attached, built before forking since the child mustn't
+/// allocate
+struct Restrictions {
+    stdio: Redirections,
+    cgroup: Option<Cgroup>,
+    namespaces: namespace::Setup,
+    landlock: Option<Ruleset>,
+    /// The seccomp prefilter
+    filter: Option<Vec<sock_filter>>,
+}
+
+/// child waits for the parent to attach, applies the restrictions, and then calls execve. If any of that fails, it
+/// writes the stage and errno to `report` and exits, and otherwise `report` is closed by the exec. The parent closes
+/// its end of `attached` once it's attached.
+fn child(
+    path: &CStr,
+    args: &[&CStr],
+    env: &[&CStr],
+    config: &Config,
+    report: OwnedFd,
+    attached: OwnedFd,
+    restrictions: &Restrictions,
+) -> ! {
     // Unsafe to use `println!` (or `unwrap`) here. See https://docs.rs/nix/latest/nix/unistd/fn.fork.html#safety
-    // Since we're not handling errors anyway, panics should be fine for now.
+    let fail = |stage: SetupStage, errno: Errno| -> ! {
+        let mut message = [0; 5];
+        message[0] = stage as u8;
+        message[1..].copy_from_slice(&(errno as i32).to_ne_bytes());
+        // If the parent can't hear about it there's nobody left to tell
+        let _ = unistd::write(&report, &message);
+        unsafe { libc::_exit(127) }
+    };
 
-    traceme().expect("error calling traceme");
-    execve(path, args, env).expect("error calling execve");
-    unreachable!();
+    if let Some(deterministic) = &config.deterministic {
+        if let Err(errno) = deterministic::setup(deterministic) {
+            fail(SetupStage::Deterministic, errno);
+        }
+    }
+    loop {
+        match unistd::read(attached.as_raw_fd(), &mut [0]) {
+            Ok(_) => break,
+            Err(Errno::EINTR) => continue,
+            Err(errno) => fail(SetupStage::Attach, errno),
+        }
+    }
+    if let Err(errno) = restrictions.stdio.apply() {
+        fail(SetupStage::Stdio, errno);
+    }
+    if let Some(cgroup) = &restrictions.cgroup {
+        if let Err(errno) = cgroup::join(cgroup.procs()) {
+            fail(SetupStage::Cgroup, errno);
+        }
+    }
+    if let Err(errno) = restrictions.namespaces.apply() {
+        fail(SetupStage::Namespaces, errno);
+    }
+    if let Err(errno) = credentials::drop_privileges(config.run_as.as_ref(), config.no_new_privs) {
+        fail(SetupStage::Credentials, errno);
+    }
+    if let Some(dir) = &config.working_dir {
+        if let Err(errno) = unistd::chdir(dir) {
+            fail(SetupStage::Chdir, errno);
+        }
+    }
+    if let Err(errno) = inherit::apply(&config.inherited_fds) {
+        fail(SetupStage::Fds, errno);
+    }
+    if let Some(seconds) = config.time_limits.cpu_seconds {
+        if let Err(errno) = limits::set_cpu_limit(seconds) {
+            fail(SetupStage::Limits, errno);
+        }
+    }
+    if let Err(errno) = limits::set_resource_limits(&config.resource_limits) {
+        fail(SetupStage::Limits, errno);
+    }
+    if let Some(landlock) = &restrictions.landlock {
+        if let Err(errno) = landlock.apply() {
+            fail(SetupStage::Landlock, errno);
+        }
+    }
+    if let Some(filter) = &restrictions.filter {
+        if let Err(errno) = seccomp::install(filter) {
+            fail(SetupStage::Seccomp, errno);
+        }
+    }
+    let Err(errno) = execve(path, args, env);
+    fail(SetupStage::Exec, errno);
 }
 
-/// handle_syscall walks up the stack to see where a syscall came from, and returns an IllegalSyscall if it should be blocked.
+/// read_report waits for the child to either exec or report why it couldn't.
+fn read_report(child: Pid, report: &OwnedFd) -> Option<ChildExit> {
+    let mut message = [0; 5];
+    let mut len = 0;
+    while len < message.len() {
+        match unistd::read(report.as_raw_fd(), &mut message[len..]) {
+            // Closed by the exec
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(Errno::EINTR) => continue,
+            Err(errno) => panic!("failed to read from child {child}: {errno}"),
+        }
+    }
+    if len == 0 {
+        return None;
+    }
+
+    let stage = SetupStage::from_u8(message[0])
+        .filter(|_| len == message.len())
+        .unwrap_or_else(|| panic!("garbled setup report from child {child}"));
+    let errno = Errno::from_raw(i32::from_ne_bytes(message[1..].try_into().unwrap()));
+    Some(ChildExit::SetupFailed { stage, errno })
+}
+
+/// start lets the attached child run up to its exec, and returns why it couldn't exec if it couldn't. Otherwise it's
+/// left at the stop from the exec.
+fn start(child: Pid, report: OwnedFd) -> Option<ChildExit> {
+    loop {
+        let restarted = match waitpid(child, None) {
+            Ok(WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_EXEC)) => return None,
+            Ok(WaitStatus::Exited(..) | WaitStatus::Signaled(..)) => {
+                return Some(read_report(child, &report).unwrap_or_else(|| {
+                    panic!("child {child} exited without starting or saying why")
+                }))
+            }
+            // The exec itself may well be one of the syscalls the prefilter stops at, and a child that fails to exec
+            // stops once more on its way out
+            Ok(WaitStatus::PtraceEvent(
+                _,
+                _,
+                libc::PTRACE_EVENT_SECCOMP | libc::PTRACE_EVENT_EXIT,
+            )) => cont(child, None),
+            Ok(WaitStatus::Stopped(_, signal)) => cont(child, signal),
+            status => panic!("unexpected status {status:?} from child {child} while starting"),
+        };
+        restarted.unwrap_or_else(|e| panic!("failed to start child {child}: {e}"));
+    }
+}
+
+/// ptrace_options returns the options every tracee is traced with.
+fn ptrace_options(prefilter: bool) -> Options {
+    let options = Options::PTRACE_O_EXITKILL
+        .union(Options::PTRACE_O_TRACESYSGOOD)
+        .union(Options::PTRACE_O_TRACEFORK)
+        .union(Options::PTRACE_O_TRACECLONE)
+        .union(Options::PTRACE_O_TRACEVFORK)
+        .union(Options::PTRACE_O_TRACEEXEC)
+        .union(Options::PTRACE_O_TRACEEXIT);
+    if prefilter {
+        options.union(Options::PTRACE_O_TRACESECCOMP)
+    } else {
+        options
+    }
+}
+
+/// resume restarts a tracee. With the seccomp prefilter it runs until the filter stops it, unless it's in the middle
+/// of a syscall, in which case it's stopped again at the syscall's exit.
+fn resume(
+    pid: Pid,
+    signal: Option<Signal>,
+    prefilter: bool,
+    tracee: Option<&Tracee>,
+) -> nix::Result<()> {
+    let mid_syscall = tracee.is_some_and(|tracee| tracee.pending.is_some() || tracee.ending);
+    if prefilter && !mid_syscall {
+        cont(pid, signal)
+    } else {
+        syscall(pid, signal)
+    }
+}
+
+/// listen leaves a tracee in its group-stop, like a stopped process that isn't being traced, until something like
+/// SIGCONT wakes it up, which is reported as another PTRACE_EVENT_STOP.
+fn listen(pid: Pid) -> nix::Result<()> {
+    Errno::result(unsafe {
+        libc::ptrace(
+            libc::PTRACE_LISTEN,
+            pid.as_raw(),
+            std::ptr::null_mut::<libc::c_void>(),
+            std::ptr::null_mut::<libc::c_void>(),
+        )
+    })
+    .map(drop)
+}
+
+/// Verdict: the outcome of checking a syscall against the config
+enum Verdict {
+    /// Allowed by the rules for the named file
+    Allowed(String, Rule),
+    /// Blocked by the rules for the named file, which say what to do about it. The address is where on the stack the
+    /// file was found.
+    Blocked(String, RuleAction, Rule, u64),
+    /// None of the mapped files on the stack had a rule for the syscall. These are the ones we saw, innermost first.
+    Unknown(Vec<String>),
+    /// Like Unknown, but the walk was cut short before the end of the stack, and the config says what to do about that
+    Truncated(Vec<String>),
+}
+
+/// syscall_info reads the tracee's current syscall stop with PTRACE_GET_SYSCALL_INFO, if the kernel supports it.
+fn syscall_info(pid: Pid) -> Option<ptrace_syscall_info> {
+    let mut info = MaybeUninit::<ptrace_syscall_info>::zeroed();
+    let written = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GET_SYSCALL_INFO,
+            pid.as_raw(),
+            mem::size_of::<ptrace_syscall_info>(),
+            info.as_mut_ptr(),
+        )
+    };
+    (written > 0).then(|| unsafe { info.assume_init() })
+}
+
+/// Stop: what we read out of a tracee at a syscall stop
+struct Stop {
+    /// Whether this is an entry stop rather than an exit stop
+    entry: bool,
+    /// None for a compat syscall with no native equivalent, which no rule can refer to
+    syscall: Option<Sysno>,
+    args: [u64; 6],
+    pc: u64,
+    /// Only meaningful at an exit stop
+    ret: i64,
+    /// The full register set, for walking the stack. Only available for native processes, since the frame
+    /// layout of compat processes depends on how they were compiled.
+    regs: Option<user_regs_struct>,
+}
+
+impl Stop {
+    /// read gets the syscall the tracee is stopped in. `pending` is its entry if this is an exit stop.
+    ///
+    /// A 32-bit process's registers don't fit in user_regs_struct, and its syscall numbers come from another table,
+    /// so those are read with PTRACE_GET_SYSCALL_INFO and translated to the matching native syscall.
+    fn read(pid: Pid, abi: Option<Abi>, pending: Option<&SyscallEntry>) -> Stop {
+        let compat = match abi {
+            Some(Abi::Native) => None,
+            Some(Abi::Compat) => Some(syscall_info(pid).unwrap_or_else(|| {
+                panic!(
+                    "can't read the syscalls of 32-bit child {pid} without PTRACE_GET_SYSCALL_INFO"
+                )
+            })),
+            None => syscall_info(pid).filter(|info| info.arch == arch::COMPAT_AUDIT_ARCH),
+        };
+        if let Some(info) = compat {
+            return match info.op {
+                libc::PTRACE_SYSCALL_INFO_ENTRY => {
+                    let entry = unsafe { info.u.entry };
+                    Stop {
+                        entry: true,
+                        syscall: arch::compat_syscall(entry.nr),
+                        args: entry.args,
+                        pc: info.instruction_pointer,
+                        ret: 0,
+                        regs: None,
+                    }
+                }
+                // A seccomp stop comes before the syscall runs, like an entry stop
+                libc::PTRACE_SYSCALL_INFO_SECCOMP => {
+                    let entry = unsafe { info.u.seccomp };
+                    Stop {
+                        entry: true,
+                        syscall: arch::compat_syscall(entry.nr),
+                        args: entry.args,
+                        pc: info.instruction_pointer,
+                        ret: 0,
+                        regs: None,
+                    }
+                }
+                _ => Stop {
+                    entry: false,
+                    syscall: pending.map(|entry| entry.syscall),
+                    args: pending.map_or([0; 6], |entry| entry.args),
+                    pc: info.instruction_pointer,
+                    ret: unsafe { info.u.exit.sval },
+                    regs: None,
+                },
+            };
+        }
+
+        // By the exit stop the first argument's register holds the return value, so what the syscall was made with
+        // comes from its entry
+        let regs = getregs(pid).expect("failed to get registers");
+        let (syscall, args) = match pending {
+            None => (
+                Sysno::from(arch::syscall_number(&regs) as u32),
+                arch::syscall_args(&regs),
+            ),
+            Some(entry) => (entry.syscall, entry.args),
+        };
+        Stop {
+            entry: pending.is_none(),
+            syscall: Some(syscall),
+            args,
+            pc: arch::pc(&regs),
+            ret: arch::return_value(&regs),
+            regs: Some(regs),
+        }
+    }
+}
+
+/// changes_map returns whether a finished syscall might have changed the tracee's memory map. A new thread or process
+/// doesn't need this, since it reads its map when it's first seen.
+fn changes_map(entry: &SyscallEntry, ret: i64) -> bool {
+    ret >= 0 && entry.injected.is_none() && map::CHANGES_MAP.contains(&entry.syscall)
+}
+
+/// patch_map applies a finished mmap or munmap to the tracee's map from its arguments, which saves rereading the
+/// whole map. Returns false if it can't, in which case the map has to be reread.
+fn patch_map(
+    pid: Pid,
+    config: &Config,
+    entry: &SyscallEntry,
+    ret: i64,
+    tracee: &Tracee,
+    trackers: &mut Trackers,
+) -> bool {
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let [addr, len, prot, flags, _, _] = entry.args;
+    let len = len.div_ceil(page) * page;
+    let mut shared = tracee.map.borrow_mut();
+    let changed = match entry.syscall {
+        Sysno::munmap => shared.map.unmap(addr, addr + len),
+        // With container_paths the map has paths inside the tracee's root, which the fd's link doesn't
+        Sysno::mmap if !config.container_paths => {
+            let (path, file) = if flags as i32 & libc::MAP_ANONYMOUS != 0 {
+                (String::new(), None)
+            } else {
+                // What's mapped, rather than whatever the fd is by now, which another thread may have closed and
+                // reopened as something else. Reading it takes CAP_SYS_ADMIN or CAP_CHECKPOINT_RESTORE, and without
+                // them, or if the mapping was merged with a neighbour, the map is reread instead.
+                let link = config
+                    .proc_root()
+                    .join(pid.to_string())
+                    .join("map_files")
+                    .join(format!("{:x}-{:x}", ret as u64, ret as u64 + len));
+                let (Ok(path), Ok(metadata)) = (fs::read_link(&link), fs::metadata(&link)) else {
+                    return false;
+                };
+                (
+                    path.to_string_lossy().into_owned(),
+                    Some((metadata.dev(), metadata.ino())),
+                )
+            };
+            if path.starts_with('/') && shared.map.base(&path).is_none() {
+                trackers.emit(events::Event::LibraryLoaded {
+                    pid: pid.as_raw(),
+                    path: path.clone(),
+                });
+            }
+            let permissions = Permissions {
+                read: prot as i32 & libc::PROT_READ != 0,
+                write: prot as i32 & libc::PROT_WRITE != 0,
+                execute: prot as i32 & libc::PROT_EXEC != 0,
+                shared: flags as i32 & libc::MAP_SHARED != 0,
+            };
+            shared
+                .map
+                .insert(ret as u64, ret as u64 + len, permissions, path, file)
+        }
+        _ => return false,
+    };
+    if changed {
+        shared.changed();
+    }
+    true
+}
+
+/// thread_group reads which process a thread belongs to, from the Tgid line of its status.
+fn thread_group(pid: Pid, config: &Config) -> Option<Pid> {
+    let status =
+        fs::read_to_string(config.proc_root().join(pid.to_string()).join("status")).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|tgid| tgid.trim().parse().ok())
+        .map(Pid::from_raw)
+}
+
+/// read_map reads the tracee's memory map, with the paths the config expects.
+fn read_map(pid: Pid, config: &Config) -> Result<MemoryMap, MemoryMapError> {
+    if config.container_paths {
+        MemoryMap::from_proc_in_root(config.proc_root(), pid)
+    } else {
+        MemoryMap::from_proc(config.proc_root(), pid)
+    }
+}
+
+/// binary picks the `binaries` section for the executable a tracee is running, going by /proc/pid/exe.
+fn binary(pid: Pid, config: &Config) -> Option<String> {
+    if config.binaries.is_empty() {
+        return None;
+    }
+    config
+        .binary(&executable(pid, config)?.to_string_lossy())
+        .map(String::from)
+}
+
+/// executable returns the path of the executable a tracee is running. Like the map, with container_paths it's the
+/// path inside the tracee's root.
+fn executable(pid: Pid, config: &Config) -> Option<PathBuf> {
+    let proc = config.proc_root().join(pid.to_string());
+    let executable = fs::read_link(proc.join("exe")).ok()?;
+    if config.container_paths {
+        if let Ok(root) = fs::read_link(proc.join("root")) {
+            if let Ok(relative) = executable.strip_prefix(&root) {
+                return Some(Path::new("/").join(relative));
+            }
+        }
+    }
+    Some(executable)
+}
+
+/// mapped_file gives the path to read a file the tracee has mapped from. With container_paths the map has the path
+/// inside the tracee's root. A file that's been deleted or replaced is read through map_files, which still has the
+/// one the tracee mapped. Reading that takes CAP_SYS_ADMIN or CAP_CHECKPOINT_RESTORE, and without them the file
+/// can't be read at all, so it has no build ID, soname or symbols rather than those of whatever replaced it.
+fn mapped_file(pid: Pid, config: &Config, map: &MemoryMap, path: &str) -> PathBuf {
+    let proc = config.proc_root().join(pid.to_string());
+    let first = map.base(path).and_then(|base| map.region(base));
+    if let Some(region) = first.filter(|_| map.is_deleted(path)) {
+        proc.join("map_files")
+            .join(format!("{:x}-{:x}", region.start, region.end))
+    } else if config.container_paths {
+        proc.join("root").join(path.trim_start_matches('/'))
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+/// locate names what the code at an address belongs to: the mapped file it's in, the region of the kernel's code like
+/// `[vdso]`, or ANONYMOUS_EXEC if it's in other executable memory that isn't backed by a file.
+fn locate(map: &MemoryMap, addr: u64) -> Option<&str> {
+    map.lookup(addr)
+        .or_else(|| map.kernel_code(addr))
+        .or_else(|| map.is_anonymous_code(addr).then_some(ANONYMOUS_EXEC))
+}
+
+/// library_name picks the name to look up the rules for the code at `loc` by, see Config::library_name. Besides its
+/// path, a file goes by its build ID and soname, which are read from `path`. `identities` is None if no rules use
+/// them.
+fn library_name(
+    config: &Config,
+    binary: Option<&str>,
+    map: &MemoryMap,
+    identities: Option<&mut Identities>,
+    loc: &str,
+    path: impl FnOnce() -> PathBuf,
+) -> String {
+    let others = identities
+        .zip(map.file_id(loc))
+        .map(|(identities, file)| identities.names(file, &path()));
+    let others = others.as_deref().unwrap_or_default();
+    config.library_name(binary, loc, others).to_string()
+}
+
+/// call_site names the function in a mapped file that a syscall was called from, as `libfoo.so!function+0x1c`, or
+/// just as `libfoo.so+0x1234` if the file has no symbol for it. `addr` is a return address, so it's the byte before
+/// it that's looked up, in case the call was the last thing in its function. It means reading the whole file, which
+/// isn't worth doing for violations audit mode lets through, so those get None.
+fn call_site(
+    pid: Pid,
+    config: &Config,
+    enforcement: Enforcement,
+    map: &MemoryMap,
+    addr: u64,
+) -> Option<String> {
+    if enforcement == Enforcement::Audit {
+        return None;
+    }
+    let call = addr.checked_sub(1)?;
+    let path = map.lookup(call)?;
+    let offset = call - map.base(path)?;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let symbol = fs::read(mapped_file(pid, config, map, path))
+        .ok()
+        .and_then(|bytes| elf::symbolize(&bytes, offset).ok().flatten());
+    Some(match symbol {
+        Some((function, offset)) => format!("{name}!{function}+{:#x}", offset + 1),
+        None => format!("{name}+{:#x}", offset + 1),
+    })
+}
+
+/// walk_stack calls `visit` with each address on the stack of a tracee stopped at a syscall, and returns whether the
+/// walk wasn't cut short, see Unwinder::walk.
+fn walk_stack(
+    pid: Pid,
+    config: &Config,
+    stop: &Stop,
+    map: &MemoryMap,
+    unwinder: &mut Unwinder,
+    visit: impl FnMut(u64) -> bool,
+) -> bool {
+    let mut memory = MemoryReader::new(pid);
+    unwinder.walk(
+        stop.pc,
+        stop.regs.as_ref(),
+        map,
+        config.stack_walk.max_depth,
+        |path| mapped_file(pid, config, map, path),
+        |addr| memory.word(addr),
+        visit,
+    )
+}
+
+/// backtrace walks the whole stack of a tracee stopped at a syscall, the way handle_syscall does but without stopping
+/// at the first file with a rule, for reporting a violation.
+fn backtrace(
+    pid: Pid,
+    config: &Config,
+    stop: &Stop,
+    map: &MemoryMap,
+    unwinder: &mut Unwinder,
+) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    walk_stack(pid, config, stop, map, unwinder, |address| {
+        let library = locate(map, address);
+        frames.push(Frame {
+            address,
+            offset: library.and_then(|library| Some(address - map.base(library)?)),
+            library: library.map(String::from),
+        });
+        true
+    });
+    frames
+}
+
+/// anonymous_frame returns the first address on the stack of a tracee stopped at a syscall that's in code outside of
+/// a mapped file, starting with where the syscall was made. JIT code can just as well make its syscalls through libc,
+/// so the whole stack is walked. Past the pc it only counts if it's in executable memory, so that a walk that's gone
+/// astray isn't taken for one.
+fn anonymous_frame(
+    pid: Pid,
+    config: &Config,
+    stop: &Stop,
+    map: &MemoryMap,
+    unwinder: &mut Unwinder,
+) -> Option<u64> {
+    if map.lookup(stop.pc).is_none() && map.kernel_code(stop.pc).is_none() {
+        return Some(stop.pc);
+    }
+    let mut found = None;
+    walk_stack(pid, config, stop, map, unwinder, |address| {
+        if map.is_anonymous_code(address) {
+            found = Some(address);
+        }
+        found.is_none()
+    });
+    found
+}
+
+/// crash_stack walks the stack of a tracee at its exit stop, where a signal left it. Compat processes' stacks can't be
+/// walked.
+fn crash_stack(pid: Pid, config: &Config, tracee: &Tracee, trackers: &mut Trackers) -> Vec<Frame> {
+    if tracee.abi == Some(Abi::Compat) {
+        return Vec::new();
+    }
+    let Ok(regs) = getregs(pid) else {
+        return Vec::new();
+    };
+    if tracee.map.borrow().dirty {
+        refresh_map(pid, config, tracee, trackers);
+    }
+    let stop = Stop {
+        entry: false,
+        syscall: None,
+        args: [0; 6],
+        pc: arch::pc(&regs),
+        ret: 0,
+        regs: Some(regs),
+    };
+    backtrace(pid, config, &stop, &tracee.map(), &mut trackers.unwinder)
+}
+
+/// refresh_map rereads the tracee's memory map for the whole process, and reports the files that weren't mapped
+/// before.
+fn refresh_map(pid: Pid, config: &Config, tracee: &Tracee, trackers: &mut Trackers) {
+    trackers.stats.map_rebuilds += 1;
+    let refreshed = read_map(pid, config).unwrap();
+    let mut shared = tracee.map.borrow_mut();
+    for path in refreshed.added(&shared.map) {
+        trackers.emit(events::Event::LibraryLoaded {
+            pid: pid.as_raw(),
+            path: path.to_string(),
+        });
+    }
+    shared.map = refreshed;
+    shared.dirty = false;
+    shared.changed();
+}
+
+/// handle_syscall walks up the stack to see where a syscall came from, and checks it against the config.
 ///
-/// Reference: https://github.com/ARM-software/abi-aa/blob/2a70c42d62e9c3eb5887fa50b71257f20daca6f9/aapcs64/aapcs64.rst#646the-frame-pointer
-fn handle_syscall(pid: Pid, config: &Config, map: &mut MemoryMap) -> Option<ChildExit> {
-    let regs = getregs(pid).expect("failed to get registers");
-    let syscall = Sysno::from(regs.regs[8] as u32);
-
-    // I don't have an exhaustive knowledge of which syscalls might affect memory.
-    // For a real project I'd do more research or set up some tests to see if I'd missed any.
-    if BTreeSet::from([
-        Sysno::execve,
-        Sysno::execveat,
-        Sysno::clone,
-        Sysno::mmap,
-        Sysno::munmap,
-        Sysno::mremap,
-    ])
-    .contains(&syscall)
+/// `walk` walks the stack like walk_stack, see Unwinder::walk, and locate says what each address is attributed to.
+/// While the loader is `starting`, it goes by LOADER_STARTUP. `check` checks a library and syscall against the rules,
+/// and is also given the index of the file's region in the map, unless the address was attributed to anything else.
+/// Also returns whether any of the addresses walked weren't in the map.
+fn handle_syscall(
+    config: &Config,
+    syscall: Sysno,
+    starting: bool,
+    map: &MemoryMap,
+    walk: impl FnOnce(&mut dyn FnMut(u64) -> bool) -> bool,
+    mut check: impl FnMut(Option<usize>, &str, Sysno) -> Check,
+) -> (Verdict, bool) {
+    let mut stack: Vec<String> = Vec::new();
+    let mut verdict = None;
+    let mut missed = false;
+    let complete = walk(&mut |addr| {
+        let Some(loc) = locate(map, addr).map(|loc| {
+            if starting && map::is_loader(loc) {
+                LOADER_STARTUP
+            } else {
+                loc
+            }
+        }) else {
+            missed = true;
+            return true;
+        };
+        let region = map
+            .region_index(addr)
+            .filter(|&index| map.files[index].path() == loc);
+        match check(region, loc, syscall) {
+            Check::Allowed(rule) => verdict = Some(Verdict::Allowed(loc.to_string(), rule)),
+            Check::Blocked(action, rule) => {
+                verdict = Some(Verdict::Blocked(loc.to_string(), action, rule, addr))
+            }
+            // Without rules of its own, the kernel's code is left out, so the syscall goes to whatever called it
+            Check::Unknown if map::KERNEL_CODE.contains(&loc) => {}
+            Check::Unknown => {
+                if stack.last().map(String::as_str) != Some(loc) {
+                    stack.push(loc.to_string());
+                }
+            }
+        }
+        verdict.is_none()
+    });
+
+    let verdict = match verdict {
+        Some(verdict) => verdict,
+        None if !complete && config.stack_walk.on_failure.is_some() => Verdict::Truncated(stack),
+        None => Verdict::Unknown(stack),
+    };
+    (verdict, missed)
+}
+
+/// check_write returns a WriteQuotaExceeded if a syscall that writes to or grows a file could go over quota, counting
+/// the most it could write (see quota::write).
+///
+/// This way writes are stopped before anything hits the disk.
+fn check_write(entry: &SyscallEntry, config: &Config, writes: &WriteTracker) -> Option<ChildExit> {
+    let write = entry.write.as_ref()?;
+    writes
+        .check(&config.write_quota, &write.path, write.len)
+        .map(|exceeded| ChildExit::WriteQuotaExceeded(exceeded, write.path.clone()))
+}
+
+/// record_write adds what a finished syscall wrote to the quota, and returns a WriteQuotaExceeded if it went over.
+fn record_write(
+    entry: &SyscallEntry,
+    ret: i64,
+    config: &Config,
+    writes: &mut WriteTracker,
+) -> Option<ChildExit> {
+    let write = entry.write.as_ref().filter(|_| ret >= 0)?;
+    writes.record(&write.path, write.written(ret));
+    writes
+        .check(&config.write_quota, &write.path, 0)
+        .map(|exceeded| ChildExit::WriteQuotaExceeded(exceeded, write.path.clone()))
+}
+
+/// check_filesystem returns the file a syscall is about to modify, if the config doesn't allow modifying it.
+fn check_filesystem(
+    pid: Pid,
+    syscall: Sysno,
+    args: &[u64; 6],
+    config: &Config,
+    tracee: &mut Tracee,
+) -> Option<String> {
+    if config.filesystem.mode == FilesystemMode::ReadWrite {
+        return None;
+    }
+
+    filesystem::modified_paths(
+        pid,
+        syscall,
+        args,
+        &mut tracee.fds.borrow_mut(),
+        config.proc_root(),
+    )
+    .into_iter()
+    .find(|path| !config.filesystem.is_writable(path))
+    .map(|path| path.display().to_string())
+}
+
+/// read_arguments reads what a syscall entry's arguments refer to, for the rules that depend on them.
+fn read_arguments(
+    pid: Pid,
+    syscall: Sysno,
+    args: &[u64; 6],
+    config: &Config,
+    fds: &mut FdTable,
+) -> Vec<Arguments> {
+    let mut arguments = Vec::new();
+    if let Some(fd) = fd::fd_argument(syscall, args) {
+        arguments.push(Arguments::Fd {
+            fd,
+            target: fds.lookup(pid, fd).map(String::from),
+            family: fds.family(fd),
+        });
+    }
+    match syscall {
+        Sysno::socket => arguments.push(Arguments::Socket {
+            domain: args[0] as i32,
+            kind: args[1] as i32,
+        }),
+        Sysno::connect | Sysno::sendto | Sysno::sendmsg | Sysno::sendmmsg => arguments.extend(
+            network::destinations(pid, syscall, args, fds, config.proc_root())
+                .into_iter()
+                .map(Arguments::Destination),
+        ),
+        _ => arguments.extend(
+            filesystem::path_arguments(pid, syscall, args, fds, config.proc_root())
+                .map(Arguments::Paths),
+        ),
+    }
+    arguments
+}
+
+/// check_storm counts failed syscalls per call site, and applies the configured action once a site fails too often.
+fn check_storm(
+    entry: &SyscallEntry,
+    ret: i64,
+    config: &Config,
+    map: &MemoryMap,
+    storms: &mut StormDetector,
+) -> Decision {
+    let Some(storm) = config.storm.as_ref() else {
+        return Decision::Continue;
+    };
+    if !(-4095..0).contains(&ret) {
+        return Decision::Continue;
+    }
+
+    let failures = storms.record_failure(entry.syscall, entry.pc, Instant::now());
+    if failures <= storm.failures_per_second {
+        return Decision::Continue;
+    }
+
+    let site = map
+        .lookup(entry.pc)
+        .map_or_else(|| format!("{:#x}", entry.pc), String::from);
+    match storm.action {
+        StormAction::Log => {
+            if failures == storm.failures_per_second + 1 {
+                warning!(
+                    "Syscall storm: {} from {site} failed more than {} times in one second",
+                    entry.syscall,
+                    storm.failures_per_second
+                );
+            }
+            Decision::Continue
+        }
+        StormAction::Throttle => Decision::Delay(storm::THROTTLE),
+        StormAction::Kill => Decision::Exit(ChildExit::SyscallStorm(entry.syscall, site)),
+    }
+}
+
+/// check_remote asks the remote policy service, if there is one, about a syscall the config has no rule for.
+fn check_remote(
+    pid: Pid,
+    syscall: Sysno,
+    stack: Vec<String>,
+    remote: Option<&mut RemotePolicy>,
+) -> Decision {
+    let Some(remote) = remote else {
+        return Decision::Continue;
+    };
+
+    let query = Query { syscall, stack };
+    let location = query
+        .stack
+        .first()
+        .cloned()
+        .unwrap_or_else(|| UNATTRIBUTED.to_string());
+    match remote.ask(pid, query) {
+        Some(true) => Decision::Continue,
+        Some(false) => Decision::Exit(ChildExit::IllegalSyscall {
+            syscall,
+            library: location,
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new(),
+        }),
+        None => Decision::Hold,
+    }
+}
+
+/// inject applies the next step of the failure scenario, if there is one and this syscall entry triggers it.
+fn inject(
+    pid: Pid,
+    stop: &Stop,
+    tracee: &mut Tracee,
+    scenario: Option<&mut ScenarioRunner>,
+) -> Decision {
+    let (Some(syscall), Some(scenario)) = (stop.syscall, scenario) else {
+        return Decision::Continue;
+    };
+
+    match scenario.next(syscall) {
+        None => Decision::Continue,
+        Some(Action::DelayMs(ms)) => Decision::Delay(Duration::from_millis(ms)),
+        Some(Action::Fail(errno)) => {
+            let Some(mut regs) = stop.regs else {
+                warning!("Can't inject {errno:?} into {syscall} in 32-bit child {pid}");
+                return Decision::Continue;
+            };
+            arch::skip_syscall(pid, &mut regs).expect("failed to skip syscall");
+            if let Some(entry) = tracee.pending.as_mut() {
+                entry.injected = Some(errno);
+            }
+            info!("Injected {errno:?} into {syscall} in child {pid}");
+            Decision::Continue
+        }
+    }
+}
+
+/// tkill sends a signal to one thread, rather than whichever thread of its process the kernel picks.
+fn tkill(tid: Pid, signal: Signal) -> nix::Result<()> {
+    Errno::result(unsafe { libc::syscall(libc::SYS_tkill, tid.as_raw(), signal as libc::c_int) })
+        .map(drop)
+}
+
+/// daemonized applies the config's daemon policy to a process that's been seen daemonizing. A daemon that's allowed
+/// to go is traced on anyway if it mustn't be let go of.
+fn daemonized(pid: Pid, config: &Config, trackers: &mut Trackers) -> Decision {
+    trackers.stats.daemonized += 1;
+    match config.daemonize {
+        DaemonPolicy::Allow => match trackers.pinned {
+            Some(reason) => {
+                warning!("Not detaching from daemon {pid} since {reason}, tracing it instead");
+                Decision::Continue
+            }
+            None => Decision::Detach,
+        },
+        DaemonPolicy::Deny => Decision::Exit(ChildExit::Daemonized(pid.as_raw())),
+        DaemonPolicy::AllowButKeepTracing => Decision::Continue,
+    }
+}
+
+/// handle_syscall_stop does the bookkeeping for a syscall entry or exit stop, and decides what happens to the tracee
+/// next.
+fn handle_syscall_stop(
+    pid: Pid,
+    config: &Config,
+    tracee: &mut Tracee,
+    trackers: &mut Trackers,
+) -> Decision {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("tracee", pid = pid.as_raw()).entered();
+    let now = Instant::now();
+    // Which stop this is comes from the kernel where it can say. Guessing from `pending` would stay out of step for
+    // good after a stop we never see, like the entry of the execve a tracee is first seen at.
+    if let Some(info) = syscall_info(pid) {
+        let exit = info.op == libc::PTRACE_SYSCALL_INFO_EXIT;
+        if exit && tracee.pending.is_none() {
+            return Decision::Continue;
+        }
+        if !exit && tracee.pending.take().is_some() {
+            debug!("Child {pid} started a syscall without finishing the last one");
+        }
+    }
+    let stop = Stop::read(pid, tracee.abi, tracee.pending.as_ref());
+    let Some(syscall) = stop.syscall else {
+        if stop.entry {
+            warning!(
+                "Child {pid} made a 32-bit syscall with no native equivalent, which no rule can cover"
+            );
+        }
+        return Decision::Continue;
+    };
+
+    // A syscall that changes the map only has once it's done, so this is the exit stop. An exec replaces the whole
+    // map, and anything else that can't be patched in that might have changed the code is reread right away too.
+    // The rest leave it to be reread when it's missing an address.
+    if let Some(entry) = tracee
+        .pending
+        .as_ref()
+        .filter(|entry| changes_map(entry, stop.ret))
     {
-        *map = MemoryMap::from_pid(pid).unwrap();
+        if matches!(entry.syscall, Sysno::execve | Sysno::execveat) {
+            refresh_map(pid, config, tracee, trackers);
+        } else if !patch_map(pid, config, entry, stop.ret, tracee, trackers) {
+            if map::changes_code(entry.syscall, &entry.args) {
+                refresh_map(pid, config, tracee, trackers);
+            } else {
+                tracee.map.borrow_mut().dirty = true;
+            }
+        }
+    }
+    if tracee.map.borrow().dirty && tracee.map().lookup(stop.pc).is_none() {
+        refresh_map(pid, config, tracee, trackers);
+    }
+    // The rules are only checked at the entry, before the syscall runs. By the exit its arguments may have been
+    // overwritten with what it returned, which the rest of the bookkeeping depends on.
+    if let Some(entry) = tracee.pending.take() {
+        return handle_exit_stop(pid, config, entry, &stop, now, tracee, trackers);
+    }
+    // Checked before the rules, so that nothing further up the stack can vouch for it. Code that's been mapped since
+    // the map was last read would be missed, so it's read again first if it's fallen behind.
+    if config.forbid_anonymous_code && tracee.map.borrow().dirty {
+        refresh_map(pid, config, tracee, trackers);
+    }
+    if let Some(address) = config
+        .forbid_anonymous_code
+        .then(|| anonymous_frame(pid, config, &stop, &tracee.map(), &mut trackers.unwinder))
+        .flatten()
+    {
+        let location = locate(&tracee.map(), address)
+            .unwrap_or(UNATTRIBUTED)
+            .to_string();
+        let exit = ChildExit::IllegalSyscall {
+            syscall,
+            library: location,
+            rule: None,
+            call_site: None,
+            backtrace: backtrace(pid, config, &stop, &tracee.map(), &mut trackers.unwinder),
+        };
+        if let Some(exit) = trackers.enforce(pid, exit) {
+            return Decision::Exit(exit);
+        }
+    }
+    if tracee.starting && !tracee.map().lookup(stop.pc).is_some_and(map::is_loader) {
+        tracee.starting = false;
+    }
+    let arguments = config.has_argument_rules(syscall).then(|| {
+        read_arguments(
+            pid,
+            syscall,
+            &stop.args,
+            config,
+            &mut tracee.fds.borrow_mut(),
+        )
+    });
+    let verdict = loop {
+        let binary = tracee.binary.as_deref();
+        let shared = tracee.map.borrow();
+        let map = &shared.map;
+        let (verdict, missed) = handle_syscall(
+            config,
+            syscall,
+            tracee.starting,
+            map,
+            |visit| walk_stack(pid, config, &stop, map, &mut trackers.unwinder, visit),
+            |region, loc, syscall| {
+                let mut name = || {
+                    library_name(
+                        config,
+                        binary,
+                        map,
+                        trackers.identities.as_mut(),
+                        loc,
+                        || mapped_file(pid, config, map, loc),
+                    )
+                };
+                match (arguments.as_deref(), region) {
+                    (None, Some(region)) => trackers.decisions.check_region(
+                        config,
+                        binary,
+                        (shared.generation, region),
+                        syscall,
+                        name,
+                    ),
+                    (arguments, _) => {
+                        let name = name();
+                        arguments
+                            .and_then(|arguments| {
+                                config.check_arguments(binary, &name, syscall, arguments)
+                            })
+                            .unwrap_or_else(|| {
+                                trackers.decisions.check(config, binary, &name, syscall)
+                            })
+                    }
+                }
+            },
+        );
+        drop(shared);
+        // Something on the stack may be in a mapping the map hasn't caught up with
+        if !missed || !tracee.map.borrow().dirty {
+            break verdict;
+        }
+        refresh_map(pid, config, tracee, trackers);
+    };
+    if let Some(trace) = trackers.trace.as_mut() {
+        trace
+            .syscall(pid, config, syscall, &stop, tracee, &mut trackers.unwinder)
+            .expect("failed to write trace recording");
+    }
+    if let Verdict::Blocked(loc, RuleAction::Kill, rule, addr) = &verdict {
+        let exit = ChildExit::IllegalSyscall {
+            syscall,
+            library: loc.clone(),
+            rule: rule.id.clone(),
+            call_site: call_site(pid, config, trackers.enforcement, &tracee.map(), *addr),
+            backtrace: backtrace(pid, config, &stop, &tracee.map(), &mut trackers.unwinder),
+        };
+        if let Some(exit) = trackers.enforce(pid, exit) {
+            return Decision::Exit(exit);
+        }
     }
 
-    for addr in [regs.pc, regs.regs[30]] {
-        if let Some(loc) = map.lookup(addr) {
-            match config.check(loc, syscall) {
-                Check::Allowed => return None,
-                Check::Blocked => return Some(ChildExit::IllegalSyscall(syscall, loc.to_string())),
-                Check::Unknown => {}
+    trackers.stats.syscalls += 1;
+    let entry = SyscallEntry {
+        syscall,
+        args: stop.args,
+        pc: stop.pc,
+        library: match &verdict {
+            Verdict::Allowed(loc, _) | Verdict::Blocked(loc, _, _, _) => Some(loc.clone()),
+            Verdict::Unknown(stack) | Verdict::Truncated(stack) => stack.first().cloned(),
+        },
+        rule: match &verdict {
+            Verdict::Allowed(_, rule) | Verdict::Blocked(_, _, rule, _) => rule.id.clone(),
+            Verdict::Unknown(_) | Verdict::Truncated(_) => None,
+        },
+        entered: now,
+        injected: None,
+        write: quota::write(
+            pid,
+            syscall,
+            &stop.args,
+            &mut tracee.fds.borrow_mut(),
+            config.proc_root(),
+        ),
+    };
+    if let Some(audit) = trackers.audit.as_mut() {
+        let args = config
+            .capture
+            .decode(pid, syscall, &entry.args, &mut tracee.fds.borrow_mut());
+        audit.syscall(
+            pid,
+            syscall,
+            entry.library.as_deref(),
+            entry.rule.as_deref(),
+            args,
+        );
+    }
+    if config.trace {
+        eprintln!(
+            "[{pid}] {}: {}",
+            entry.library.as_deref().unwrap_or(UNATTRIBUTED),
+            capture::describe(pid, syscall, &entry.args, &mut tracee.fds.borrow_mut())
+        );
+    }
+    if let (Some(session), Some(_)) = (trackers.session.as_mut(), stop.regs) {
+        session.syscall(pid, syscall, &entry.args);
+    }
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        syscall = syscall.name(),
+        library = entry.library.as_deref(),
+        rule = entry.rule.as_deref(),
+        "syscall"
+    );
+    trackers.emit(events::Event::Syscall {
+        pid: pid.as_raw(),
+        syscall,
+        library: entry.library.clone(),
+        rule: entry.rule.clone(),
+    });
+    if let Some(rule) = &entry.rule {
+        *trackers.stats.rules.entry(rule.clone()).or_insert(0) += 1;
+    }
+    let exit = check_write(&entry, config, &trackers.writes);
+    tracee.pending = Some(entry);
+    if let Some(exit) = exit.and_then(|exit| trackers.enforce(pid, exit)) {
+        return Decision::Exit(exit);
+    }
+
+    if let Some(exit) = check_filesystem(pid, syscall, &stop.args, config, tracee)
+        .and_then(|path| trackers.enforce(pid, ChildExit::ReadOnlyFilesystem(syscall, path)))
+    {
+        match (config.filesystem.action, stop.regs) {
+            (ReadOnlyAction::Erofs, Some(mut regs)) => {
+                arch::skip_syscall(pid, &mut regs).expect("failed to skip syscall");
+                if let Some(entry) = tracee.pending.as_mut() {
+                    entry.injected = Some(Errno::EROFS);
+                }
+                return Decision::Continue;
             }
+            _ => return Decision::Exit(exit),
         }
     }
 
-    let mut frame_pointer: u64 = regs.regs[29];
-    let mut saved_lr;
-    loop {
-        if frame_pointer == 0 {
-            break;
+    match &verdict {
+        Verdict::Blocked(loc, RuleAction::Log, rule, _) => {
+            warning!(
+                "Child {pid} made {syscall} from {loc}, which the config only logs{}",
+                describe_rule(rule)
+            );
         }
+        Verdict::Blocked(loc, action @ (RuleAction::Deny(_) | RuleAction::Trap), rule, addr) => {
+            let call_site = call_site(pid, config, trackers.enforcement, &tracee.map(), *addr);
+            if let Some(decision) = deny(
+                pid,
+                config,
+                loc.clone(),
+                call_site,
+                *action,
+                Some(rule),
+                &stop,
+                tracee,
+                trackers,
+            ) {
+                return decision;
+            }
+        }
+        _ => {}
+    }
 
-        saved_lr =
-            read(pid, (frame_pointer + 8) as AddressType).expect("failed to read saved lr") as u64;
+    if syscall == Sysno::setsid {
+        info!("Child {pid} is starting a new session");
+        let decision = daemonized(pid, config, trackers);
+        match trackers.overrule(pid, decision) {
+            Decision::Continue => {}
+            decision => return decision,
+        }
+    }
 
-        if let Some(loc) = map.lookup(saved_lr) {
-            match config.check(loc, syscall) {
-                Check::Allowed => return None,
-                Check::Blocked => return Some(ChildExit::IllegalSyscall(syscall, loc.to_string())),
-                Check::Unknown => {}
+    if matches!(&verdict, Verdict::Unknown(stack) if stack.is_empty()) {
+        *trackers.stats.unattributed.entry(syscall).or_insert(0) += 1;
+        if config.unattributed == UnattributedPolicy::Log {
+            debug!("Couldn't attribute {syscall} from child {pid} to any mapped file");
+        }
+    }
+    // A walk that was cut short goes through the hooks and any remote policy like any other syscall no rule covers,
+    // and only then falls back on on_failure instead of the default
+    let fallback = match &verdict {
+        Verdict::Truncated(_) => {
+            debug!("Couldn't walk the whole stack of child {pid} at {syscall}");
+            config.stack_walk.on_failure.unwrap_or_default()
+        }
+        _ => config.default,
+    };
+    let decision = match verdict {
+        Verdict::Unknown(stack)
+            if stack.is_empty() && config.unattributed == UnattributedPolicy::Block =>
+        {
+            Decision::Exit(ChildExit::IllegalSyscall {
+                syscall,
+                library: UNATTRIBUTED.to_string(),
+                rule: None,
+                call_site: None,
+                backtrace: backtrace(pid, config, &stop, &tracee.map(), &mut trackers.unwinder),
+            })
+        }
+        Verdict::Unknown(stack) | Verdict::Truncated(stack) => {
+            let event = SyscallEvent {
+                pid: pid.as_raw(),
+                syscall,
+                args: stop.args,
+                stack: &stack,
+            };
+            match trackers.hooks.judge(&event) {
+                Judgement::Allow => Decision::Continue,
+                Judgement::Block => Decision::Exit(ChildExit::IllegalSyscall {
+                    syscall,
+                    library: stack
+                        .first()
+                        .cloned()
+                        .unwrap_or_else(|| UNATTRIBUTED.to_string()),
+                    rule: None,
+                    call_site: None,
+                    backtrace: backtrace(pid, config, &stop, &tracee.map(), &mut trackers.unwinder),
+                }),
+                Judgement::Defer if trackers.remote.is_some() => {
+                    check_remote(pid, syscall, stack, trackers.remote.as_mut())
+                }
+                Judgement::Defer => {
+                    let location = stack
+                        .first()
+                        .cloned()
+                        .unwrap_or_else(|| UNATTRIBUTED.to_string());
+                    match fallback {
+                        DefaultPolicy::Allow => Decision::Continue,
+                        DefaultPolicy::Kill => Decision::Exit(ChildExit::IllegalSyscall {
+                            syscall,
+                            library: location,
+                            rule: None,
+                            call_site: None,
+                            backtrace: backtrace(
+                                pid,
+                                config,
+                                &stop,
+                                &tracee.map(),
+                                &mut trackers.unwinder,
+                            ),
+                        }),
+                        DefaultPolicy::Deny => {
+                            let denied = RuleAction::Deny(Errno::EPERM);
+                            match deny(
+                                pid, config, location, None, denied, None, &stop, tracee, trackers,
+                            ) {
+                                Some(decision) => return decision,
+                                None => Decision::Continue,
+                            }
+                        }
+                    }
+                }
             }
         }
+        _ => Decision::Continue,
+    };
+    match trackers.overrule(pid, decision) {
+        Decision::Continue => inject(pid, &stop, tracee, trackers.scenario.as_mut()),
+        decision => decision,
+    }
+}
 
-        frame_pointer =
-            read(pid, frame_pointer as AddressType).expect("failed to read frame pointer") as u64;
+/// handle_exit_stop does the bookkeeping for a syscall that's finished running, which depends on what it returned.
+fn handle_exit_stop(
+    pid: Pid,
+    config: &Config,
+    entry: SyscallEntry,
+    stop: &Stop,
+    now: Instant,
+    tracee: &mut Tracee,
+    trackers: &mut Trackers,
+) -> Decision {
+    let mut ret = stop.ret;
+    if let (Some(errno), Some(regs)) = (entry.injected, stop.regs) {
+        ret = -(errno as i64);
+        arch::set_return_value(pid, regs, ret).expect("failed to set return value");
+    }
+    trackers.stats.record(
+        entry.library.as_deref().unwrap_or(UNATTRIBUTED),
+        entry.syscall,
+        now.duration_since(entry.entered),
+    );
+    tracee
+        .fds
+        .borrow_mut()
+        .update(pid, entry.syscall, &entry.args, ret);
+    // Compat processes lay out their time structs differently, so they're left alone
+    if let (Some(virtualizer), Some(deterministic), Some(_)) = (
+        trackers.virtualizer.as_mut(),
+        config.deterministic.as_ref(),
+        stop.regs,
+    ) {
+        if let Some(virtualization) =
+            virtualizer.rewrite(pid, deterministic, entry.syscall, &entry.args, ret)
+        {
+            *trackers
+                .stats
+                .virtualized
+                .entry(virtualization)
+                .or_insert(0) += 1;
+        }
     }
+    if let Some(exit) = record_write(&entry, ret, config, &mut trackers.writes)
+        .and_then(|exit| trackers.enforce(pid, exit))
+    {
+        return Decision::Exit(exit);
+    }
+    let decision = check_storm(&entry, ret, config, &tracee.map(), &mut trackers.storms);
+    trackers.overrule(pid, decision)
+}
 
-    None
+/// deny skips a syscall at its entry stop and has it fail, for the Deny and Trap actions. `rule` is the rule that
+/// blocked it, if it was a library's rule, and `call_site` is where in the library the call came from. Returns None
+/// if the violation isn't being enforced, in which case the syscall carries on as if it was allowed.
+#[allow(clippy::too_many_arguments)]
+fn deny(
+    pid: Pid,
+    config: &Config,
+    loc: String,
+    call_site: Option<String>,
+    action: RuleAction,
+    rule: Option<&Rule>,
+    stop: &Stop,
+    tracee: &mut Tracee,
+    trackers: &mut Trackers,
+) -> Option<Decision> {
+    let syscall = stop.syscall?;
+    let frames = backtrace(pid, config, stop, &tracee.map(), &mut trackers.unwinder);
+    let exit = trackers.enforce(
+        pid,
+        ChildExit::IllegalSyscall {
+            syscall,
+            library: loc.clone(),
+            rule: rule.and_then(|rule| rule.id.clone()),
+            call_site,
+            backtrace: frames,
+        },
+    )?;
+    // Without the registers there's no skipping it, so the best we can do is stop it
+    let Some(mut regs) = stop.regs else {
+        return Some(Decision::Exit(exit));
+    };
+    arch::skip_syscall(pid, &mut regs).expect("failed to skip syscall");
+    let errno = match action {
+        RuleAction::Deny(errno) => errno,
+        _ => Errno::ENOSYS,
+    };
+    if let Some(entry) = tracee.pending.as_mut() {
+        entry.injected = Some(errno);
+    }
+    if action == RuleAction::Trap {
+        // Sent now, it's delivered as the skipped syscall returns
+        tkill(pid, Signal::SIGSYS).unwrap_or_else(|e| panic!("failed to signal child {pid}: {e}"));
+    }
+    info!(
+        "Denied {syscall} from {loc} in child {pid} with {action:?}{}",
+        rule.map(describe_rule).unwrap_or_default()
+    );
+    Some(Decision::Continue)
 }
 
-/// parent attaches to the child with ptrace and then watches for syscalls in a loop
-fn parent(child: Pid, config: &Config) -> ChildExit {
-    println!("Continuing execution in parent process, new child has pid: {child}");
+/// describe_rule names the rule that decided a syscall, for the end of a log line.
+fn describe_rule(rule: &Rule) -> String {
+    match &rule.id {
+        Some(id) => format!(" (rule {id}, {:?} list)", rule.list),
+        None => format!(" ({:?} list)", rule.list),
+    }
+}
 
-    // Wait for the stop from the first exec
-    waitpid(child, None).expect("failed to waitpid");
+/// The code a thread ended by end_thread exits with, as if it had been killed by SIGKILL
+const ENDED_THREAD_EXIT_CODE: i32 = 128 + libc::SIGKILL;
 
-    setoptions(
-        child,
-        Options::PTRACE_O_EXITKILL
-            .union(Options::PTRACE_O_TRACESYSGOOD)
-            .union(Options::PTRACE_O_TRACEFORK)
-            .union(Options::PTRACE_O_TRACECLONE)
-            .union(Options::PTRACE_O_TRACEVFORK)
-            .union(Options::PTRACE_O_TRACEEXEC),
-    )
-    .expect("failed to set ptrace options");
+/// end_thread makes a tracee at a syscall stop exit its own thread, and leave the rest of its process be. At an entry
+/// stop the syscall is skipped, so that it's rewound into an `exit` at the following exit stop. Returns false if
+/// that isn't possible, since a compat process's registers can't be rewritten.
+fn end_thread(pid: Pid, tracee: &mut Tracee) -> bool {
+    if tracee.abi != Some(Abi::Native) {
+        return false;
+    }
+    let Ok(mut regs) = getregs(pid) else {
+        return false;
+    };
+    // Violations found at an entry stop don't always get as far as recording the entry, so ask the kernel
+    let entry = syscall_info(pid).map_or(tracee.pending.is_none(), |info| {
+        info.op != libc::PTRACE_SYSCALL_INFO_EXIT
+    });
+    match (entry, tracee.ending) {
+        // This is the `exit` it was rewound into
+        (true, true) => {}
+        (true, false) => arch::skip_syscall(pid, &mut regs).expect("failed to skip syscall"),
+        (false, _) => arch::restart_as_exit(pid, regs, ENDED_THREAD_EXIT_CODE)
+            .expect("failed to rewind syscall"),
+    }
+    tracee.ending = true;
+    tracee.pending = None;
+    true
+}
+
+/// stop_violator stops what the violation scope says to after `pid` broke the config, and returns whether that's the
+/// end of the run. `stopped` is whether `pid` is at a syscall stop, without which there's no ending just its thread.
+fn stop_violator(
+    pid: Pid,
+    stopped: bool,
+    config: &Config,
+    children: &mut BTreeMap<Pid, Tracee>,
+) -> bool {
+    match config.violation_scope {
+        ViolationScope::Thread
+            if stopped
+                && children
+                    .get_mut(&pid)
+                    .is_some_and(|tracee| end_thread(pid, tracee)) =>
+        {
+            info!("Ending thread {pid}, and letting the rest of its process carry on");
+            return false;
+        }
+        ViolationScope::Tree => {
+            for &tracee in children.keys().chain([&pid]) {
+                // Some of them may well have exited already
+                let _ = signal::kill(tracee, Signal::SIGKILL);
+            }
+        }
+        _ if stopped => kill(pid).unwrap_or_else(|e| panic!("failed to kill child {pid}: {e}")),
+        // It isn't necessarily stopped, so it has to be an actual signal
+        _ => signal::kill(pid, Signal::SIGKILL)
+            .unwrap_or_else(|e| panic!("failed to kill child {pid}: {e}")),
+    }
+    true
+}
+
+/// time_out kills the whole tree once `pid` has taken it over a time limit, and returns the exit the run ends with
+/// when they've all been reaped, whatever else happened before.
+fn time_out(
+    pid: Pid,
+    limit: TimeLimit,
+    children: &BTreeMap<Pid, Tracee>,
+    trackers: &mut Trackers,
+) -> ChildExit {
+    info!("Child {pid} went over the {limit:?} time limit, killing everything");
+    for &tracee in children.keys() {
+        // Some of them may well have exited already
+        let _ = signal::kill(tracee, Signal::SIGKILL);
+    }
+    let exit = ChildExit::TimedOut(limit);
+    trackers.emit(events::Event::Violation {
+        pid: pid.as_raw(),
+        rule: None,
+        exit: exit.clone(),
+    });
+    exit
+}
+
+/// reload swaps in a config read again partway through a run, and logs what changed. Its rules and the rest of the
+/// policy apply from the next syscall on, but whatever was set up when the run started, like the logs, limits and any
+/// prefilter, stays as it was. That makes it an error for the new config to need stops at syscalls the prefilter,
+/// which is `filtered`, lets through.
+fn reload(
+    config: &mut Cow<Config>,
+    new: Config,
+    filtered: Option<&BTreeSet<Sysno>>,
+    children: &mut BTreeMap<Pid, Tracee>,
+    trackers: &mut Trackers,
+) -> Result<(), String> {
+    let mut new = Config {
+        shared_objects: new.shared_objects,
+        binaries: new.binaries,
+        groups: new.groups,
+        default: new.default,
+        write_quota: new.write_quota,
+        storm: new.storm,
+        daemonize: new.daemonize,
+        filesystem: new.filesystem,
+        enforcement: new.enforcement,
+        violation_scope: new.violation_scope,
+        unattributed: new.unattributed,
+        forbid_anonymous_code: new.forbid_anonymous_code,
+        ..config.as_ref().clone()
+    };
+    // Whatever the keys lead to now, in case a library has been upgraded since
+    new.compile();
+    if let Some(filtered) = filtered {
+        let Some(syscalls) = new.prefilter_syscalls() else {
+            return Err(String::from(
+                "the new config has to see every syscall, but the seccomp prefilter is installed",
+            ));
+        };
+        let missing: Vec<_> = syscalls.difference(filtered).map(Sysno::name).collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "the seccomp prefilter doesn't stop at {}",
+                missing.join(", ")
+            ));
+        }
+    }
+    let changes = reload::diff(config, &new);
+    if changes.is_empty() {
+        info!("Reloaded the config, which hasn't changed");
+    }
+    for change in changes {
+        info!("Reloaded the config, changing {change}");
+    }
+    trackers.enforcement = new.enforcement;
+    trackers.identities = new
+        .uses_identities()
+        .then(|| trackers.identities.take().unwrap_or_default());
+    let (hits, misses) = (trackers.decisions.hits, trackers.decisions.misses);
+    trackers.decisions = DecisionCache::new(&new);
+    trackers.decisions.hits = hits;
+    trackers.decisions.misses = misses;
+    for (&pid, tracee) in children.iter_mut() {
+        tracee.binary = binary(pid, &new);
+    }
+    *config = Cow::Owned(new);
+    Ok(())
+}
+
+/// pinned returns why the traced tree mustn't be let go of before it's exited, if it mustn't.
+fn pinned(config: &Config, prefilter: bool) -> Option<&'static str> {
+    if prefilter {
+        // The filter stays when we go, and with no tracer, the syscalls it stops at fail with ENOSYS
+        Some("the seccomp prefilter is installed")
+    } else if config.cgroup.is_some() {
+        // Whatever's left in the cgroup is killed once the run is over
+        Some("the run has a cgroup")
+    } else if [&config.stdio.stdout, &config.stdio.stderr].contains(&&Output::Capture) {
+        // The run isn't over until everything writing to the captured output has closed it
+        Some("the target's output is captured")
+    } else {
+        None
+    }
+}
+
+/// parent watches for syscalls from a child that's been seized with ptrace_options and is at a ptrace stop, until
+/// everything it starts has exited. `filtered` is the syscalls the seccomp prefilter stops at, if the child has it
+/// installed.
+fn parent(
+    child: Pid,
+    config: &Config,
+    hooks: Hooks,
+    filtered: Option<&BTreeSet<Sysno>>,
+) -> (ChildExit, RunStats) {
+    info!("Continuing execution in parent process, new child has pid: {child}");
+    let prefilter = filtered.is_some();
+    let tracing_since = Instant::now();
+    // Replaced when a new config comes in over the control socket. One built in code is compiled first, so that its
+    // lookups don't have to work out what loading it would have.
+    let mut config = if config.is_compiled() {
+        Cow::Borrowed(config)
+    } else {
+        let mut config = config.clone();
+        config.compile();
+        Cow::Owned(config)
+    };
 
-    let mut children: BTreeMap<Pid, Box<MemoryMap>> =
-        BTreeMap::from([(child, Box::new(MemoryMap::from_pid(child).unwrap()))]);
-    let mut ignore_next_stop: BTreeSet<Pid> = BTreeSet::new();
+    let mut children: BTreeMap<Pid, Tracee> =
+        BTreeMap::from([(child, Tracee::new(child, &config, &BTreeMap::new()))]);
+    let mut trackers = Trackers {
+        remote: config.remote_policy.as_ref().map(RemotePolicy::new),
+        virtualizer: config.deterministic.as_ref().map(Virtualizer::new),
+        scenario: config.scenario.as_ref().map(ScenarioRunner::new),
+        audit: config.audit_log.as_ref().map(|path| {
+            AuditLog::create(path)
+                .unwrap_or_else(|e| panic!("failed to create {}: {e}", path.display()))
+        }),
+        events: config.event_log.as_ref().map(|path| {
+            EventLog::create(path)
+                .unwrap_or_else(|e| panic!("failed to create {}: {e}", path.display()))
+        }),
+        session: config.session_recording.as_ref().map(|path| {
+            SessionRecording::create(path)
+                .unwrap_or_else(|e| panic!("failed to create {}: {e}", path.display()))
+        }),
+        trace: config.trace_recording.as_ref().map(|path| {
+            TraceRecording::create(path)
+                .unwrap_or_else(|e| panic!("failed to create {}: {e}", path.display()))
+        }),
+        hooks,
+        enforcement: config.enforcement,
+        decisions: match &config.decision_cache {
+            Some(path) => DecisionCache::load(&config, path),
+            None => DecisionCache::new(&config),
+        },
+        pinned: pinned(&config, prefilter),
+        identities: config.uses_identities().then(Identities::default),
+        ..Default::default()
+    };
+    // Caught before anyone hears the run has started, and might send one
+    let hangups = trackers
+        .hooks
+        .reloads()
+        .then(|| Hangups::catch().unwrap_or_else(|e| panic!("failed to catch SIGHUP: {e}")));
+    trackers.emit(events::Event::Started {
+        pid: child.as_raw(),
+    });
+    let mut watchdog = config.time_limits.timeout_ms.map(|timeout| {
+        Watchdog::start(child, Duration::from_millis(timeout))
+            .unwrap_or_else(|e| panic!("failed to start the timer for child {child}: {e}"))
+    });
+    let mut timed_out = false;
+    let mut restarts = RestartQueue::default();
+    // Tracees whose first stop has been seen. Each new tracee starts off with a PTRACE_EVENT_STOP, which shouldn't be
+    // mistaken for a group-stop.
+    let mut started: BTreeSet<Pid> = BTreeSet::from([child]);
+    // Tracees to detach from rather than restart at their next stop
+    let mut detaching: BTreeSet<Pid> = BTreeSet::new();
+    // Tracees sitting in a job-control stop, e.g. a job a shell has put in the background
+    let mut stopped: BTreeSet<Pid> = BTreeSet::new();
+    // The parent of each traced process that was forked while we were watching
+    let mut parents: BTreeMap<Pid, Pid> = BTreeMap::new();
+    // The stack of the thread each process was killed by a signal in, read at its exit stop while it could still be
+    // walked, for when the whole process is reported as gone
+    let mut crashes: BTreeMap<Pid, Vec<Frame>> = BTreeMap::new();
     let mut child_exit = None;
+    // The first violation, if the run carried on past it
+    let mut violation = None;
+    let control = config.control_socket.as_ref().map(|path| {
+        ControlSocket::bind(path)
+            .unwrap_or_else(|e| panic!("failed to listen on {}: {e}", path.display()))
+    });
+    // Whether syscalls go unchecked for now, as asked over the control socket
+    let mut paused = false;
+    // Whether we've been asked to let go of the whole tree
+    let mut detached = false;
 
-    println!("Starting to watch child...");
-    syscall(child, None).expect("failed to start child");
+    info!("Starting to watch child...");
+    resume(child, None, prefilter, None).expect("failed to start child");
 
-    loop {
-        match waitpid(None, None) {
-            Err(Errno::ECHILD) => {
-                return ChildExit::Exited(
-                    child_exit.unwrap_or_else(|| panic!("unknown exit status for child {child}")),
-                )
+    let exit = 'supervise: loop {
+        if !timed_out && watchdog.as_ref().is_some_and(Watchdog::fired) {
+            timed_out = true;
+            violation = Some(time_out(child, TimeLimit::Wall, &children, &mut trackers));
+        }
+
+        if hangups.as_ref().is_some_and(Hangups::take) {
+            match trackers.hooks.reload() {
+                Some(Ok(new)) => {
+                    if let Err(e) = reload(&mut config, new, filtered, &mut children, &mut trackers)
+                    {
+                        warning!("Refusing to reload the config, keeping the one we have: {e}")
+                    }
+                }
+                Some(Err(e)) => {
+                    warning!("Failed to reload the config, keeping the one we have: {e}")
+                }
+                None => {}
             }
-            Ok(WaitStatus::Exited(pid, code)) => {
-                if pid == child {
-                    child_exit = Some(code);
+        }
+
+        for (command, reply) in control.iter().flat_map(ControlSocket::requests) {
+            let response = match command {
+                Command::Pause => {
+                    info!("Pausing, letting syscalls through unchecked");
+                    paused = true;
+                    Response::Ok
+                }
+                Command::Resume => {
+                    info!("Resuming, checking syscalls again");
+                    paused = false;
+                    // Anything let run in the meantime has to be stopped to be set to stop at syscalls again
+                    for &pid in children.keys() {
+                        let _ = interrupt(pid);
+                    }
+                    Response::Ok
+                }
+                Command::Reload(new) => {
+                    match reload(&mut config, *new, filtered, &mut children, &mut trackers) {
+                        Ok(()) => Response::Ok,
+                        Err(e) => Response::Error(format!("can't reload: {e}")),
+                    }
                 }
+                Command::Stats => {
+                    let mut stats = trackers.stats.clone();
+                    stats.processes = children.len() as u64;
+                    stats.cache_hits = trackers.decisions.hits;
+                    stats.cache_misses = trackers.decisions.misses;
+                    stats.peak_tracer_rss = peak_rss();
+                    if child_exit.is_none() {
+                        stats.usage.wall_time = tracing_since.elapsed();
+                    }
+                    Response::Stats(Box::new(stats))
+                }
+                Command::Detach => match trackers.pinned {
+                    Some(reason) => Response::Error(format!("can't detach since {reason}")),
+                    None => {
+                        info!("Detaching from everything, and leaving it to run untraced");
+                        detached = true;
+                        // Without us, none of them are daemons, and nothing times them out
+                        parents.clear();
+                        watchdog = None;
+                        // Each of them is let go at its next stop, which the interrupt brings on if need be
+                        for &pid in children.keys() {
+                            if stopped.contains(&pid) {
+                                let _ = detach(pid, None);
+                            } else {
+                                let _ = interrupt(pid);
+                            }
+                        }
+                        Response::Ok
+                    }
+                },
+            };
+            // The client may have hung up already
+            let _ = reply.send(response);
+        }
+
+        for restart in restarts.take_due(Instant::now()) {
+            if detaching.remove(&restart.pid) || detached {
+                match detach(restart.pid, restart.signal) {
+                    // It may have been gone by the time we got around to it
+                    Ok(()) | Err(Errno::ESRCH) => {}
+                    Err(e) => panic!("failed to detach from child {}: {e}", restart.pid),
+                }
+                continue;
+            }
+            match resume(
+                restart.pid,
+                restart.signal,
+                prefilter || paused,
+                children.get(&restart.pid),
+            ) {
+                // Likewise, as everything is once it's been killed for going over the time limit
+                Ok(()) | Err(Errno::ESRCH) => {}
+                Err(e) => panic!("failed to restart child {}: {e}", restart.pid),
             }
-            Ok(WaitStatus::PtraceSyscall(pid)) => {
-                let child_mem: &mut MemoryMap = children
-                    .entry(pid)
-                    .or_insert(Box::new(MemoryMap::from_pid(pid).unwrap_or_else(|e| {
-                        panic!("Couldn't build map for {}: {}", pid, e)
-                    })));
+        }
 
-                if let Some(exit) = handle_syscall(pid, config, child_mem) {
-                    kill(pid).unwrap_or_else(|e| panic!("failed to kill child {pid}: {e}"));
-                    return exit;
+        if let Some(remote) = trackers.remote.as_mut() {
+            for (query, allow, pids) in remote.answers() {
+                for pid in pids {
+                    let denied = (!allow).then(|| ChildExit::IllegalSyscall {
+                        syscall: query.syscall,
+                        library: query
+                            .stack
+                            .first()
+                            .cloned()
+                            .unwrap_or_else(|| UNATTRIBUTED.to_string()),
+                        rule: None,
+                        call_site: None,
+                        backtrace: Vec::new(),
+                    });
+                    if let Some(exit) = denied.and_then(|exit| trackers.enforce(pid, exit)) {
+                        let over = stop_violator(pid, true, &config, &mut children);
+                        trackers.emit(events::Event::Violation {
+                            pid: pid.as_raw(),
+                            rule: exit.rule().map(String::from),
+                            exit: exit.clone(),
+                        });
+                        if over {
+                            break 'supervise exit;
+                        }
+                        violation.get_or_insert(exit);
+                    }
+                    restarts.schedule(pid, None, Instant::now());
                 }
-                syscall(pid, None)
-                    .unwrap_or_else(|e| panic!("failed to restart child {pid} after syscall: {e}"));
             }
-            Ok(WaitStatus::Stopped(pid, signal)) => {
-                if signal == Signal::SIGSTOP && ignore_next_stop.contains(&pid) {
-                    ignore_next_stop.remove(&pid);
-                    syscall(pid, None).unwrap_or_else(|e| {
-                        panic!("failed to restart child {pid} after suppressing SIGSTOP: {e}")
+        }
+
+        // If anyone is being held back we can't block in waitpid, or we'd never get around to resuming them.
+        let waiting = trackers
+            .remote
+            .as_ref()
+            .is_some_and(RemotePolicy::is_waiting);
+        // The timer can only kill the child we started, so once that's gone whatever it left behind has to be polled
+        // for the timeout to reach it.
+        let orphaned = watchdog.is_some() && child_exit.is_some() && !timed_out;
+        let mut flags = WaitPidFlag::WCONTINUED;
+        if waiting || orphaned || !restarts.is_empty() {
+            flags |= WaitPidFlag::WNOHANG;
+        }
+
+        let (status, rusage) = wait_with_usage(flags);
+        match status {
+            Err(Errno::ECHILD) => {
+                break violation
+                    .or(child_exit)
+                    .or(detached.then_some(ChildExit::Detached))
+                    .unwrap_or_else(|| panic!("unknown exit status for child {child}"))
+            }
+            Ok(WaitStatus::StillAlive) => {
+                let next_due = restarts
+                    .next_due()
+                    .unwrap_or_else(|| Instant::now() + POLL_INTERVAL);
+                thread::sleep(
+                    next_due
+                        .saturating_duration_since(Instant::now())
+                        .min(POLL_INTERVAL),
+                );
+            }
+            Ok(status @ (WaitStatus::Exited(..) | WaitStatus::Signaled(..))) => {
+                let (pid, exit) = match status {
+                    WaitStatus::Exited(pid, code) => {
+                        trackers.emit(events::Event::Exited {
+                            pid: pid.as_raw(),
+                            status: code,
+                        });
+                        (pid, ChildExit::Exited(code))
+                    }
+                    WaitStatus::Signaled(pid, signal, _) => {
+                        trackers.emit(events::Event::Killed {
+                            pid: pid.as_raw(),
+                            signal: signal as i32,
+                        });
+                        (pid, ChildExit::Signaled(signal as i32))
+                    }
+                    _ => unreachable!(),
+                };
+                // Threads exit too, but only whole processes are reported
+                if children.get(&pid).is_some_and(|tracee| tracee.tgid == pid) {
+                    trackers.stats.exits.push(ProcessExit {
+                        pid: pid.as_raw(),
+                        exit: exit.clone(),
+                        backtrace: crashes.remove(&pid).unwrap_or_default(),
                     });
-                    continue;
                 }
+                // Past its hard RLIMIT_CPU, which it got to without ever stopping at the SIGXCPU
+                let usage = Usage::new(tracing_since.elapsed(), &rusage);
+                if !timed_out
+                    && exit == ChildExit::Signaled(libc::SIGKILL)
+                    && children.get(&pid).is_some_and(|tracee| tracee.tgid == pid)
+                    && config.time_limits.cpu_seconds.is_some_and(|seconds| {
+                        usage.user_time + usage.system_time
+                            >= Duration::from_secs(limits::cpu_hard_limit(seconds))
+                    })
+                {
+                    timed_out = true;
+                    violation = Some(time_out(pid, TimeLimit::Cpu, &children, &mut trackers));
+                }
+                if pid == child {
+                    child_exit = Some(exit);
+                    trackers.stats.usage = usage;
+                }
+                stopped.remove(&pid);
 
-                syscall(pid, signal).unwrap_or_else(|e| {
-                    panic!("failed to restart child {pid} after signal {signal}: {e}")
-                });
+                // Anything this process forked that's still around has been orphaned, which is how daemons detach
+                // from whatever started them.
+                parents.remove(&pid);
+                let orphans: Vec<Pid> = parents
+                    .iter()
+                    .filter(|(_, &parent)| parent == pid)
+                    .map(|(&orphan, _)| orphan)
+                    .collect();
+                for orphan in orphans {
+                    parents.remove(&orphan);
+                    info!("Child {orphan} was orphaned when {pid} exited");
+                    let decision = daemonized(orphan, &config, &mut trackers);
+                    match trackers.overrule(orphan, decision) {
+                        Decision::Detach => {
+                            detaching.insert(orphan);
+                        }
+                        Decision::Exit(exit) => {
+                            stop_violator(orphan, false, &config, &mut children);
+                            trackers.emit(events::Event::Violation {
+                                pid: orphan.as_raw(),
+                                rule: exit.rule().map(String::from),
+                                exit: exit.clone(),
+                            });
+                            break 'supervise exit;
+                        }
+                        _ => {}
+                    }
+                }
             }
-            Ok(WaitStatus::PtraceEvent(pid, _, event))
-                if event == Event::PTRACE_EVENT_EXEC as c_int =>
-            {
-                syscall(pid, None).unwrap_or_else(|e| {
-                    panic!(
-                        "failed to restart child {pid} after event {:?}: {e}",
-                        event_from_int(event)
-                    );
-                });
+            // With the prefilter, a seccomp stop takes the place of the entry stop
+            Ok(
+                status @ (WaitStatus::PtraceSyscall(pid)
+                | WaitStatus::PtraceEvent(pid, _, libc::PTRACE_EVENT_SECCOMP)),
+            ) => {
+                if !children.contains_key(&pid) {
+                    let tracee = Tracee::new(pid, &config, &children);
+                    children.insert(pid, tracee);
+                }
+                let tracee = children.get_mut(&pid).unwrap();
+                // A seccomp stop is always a new syscall, so anything still pending never saw its exit stop
+                if matches!(status, WaitStatus::PtraceEvent(..)) && tracee.pending.take().is_some()
+                {
+                    debug!("Child {pid} started a syscall without finishing the last one");
+                }
+
+                let decision = if tracee.ending {
+                    end_thread(pid, tracee);
+                    Decision::Continue
+                } else if (paused || detached) && tracee.pending.is_none() {
+                    // A stop from before the pause, or one the prefilter makes anyway
+                    Decision::Continue
+                } else {
+                    handle_syscall_stop(pid, &config, tracee, &mut trackers)
+                };
+                match decision {
+                    Decision::Continue => restarts.schedule(pid, None, Instant::now()),
+                    Decision::Delay(delay) => restarts.schedule(pid, None, Instant::now() + delay),
+                    Decision::Hold => {}
+                    Decision::Detach => {
+                        detaching.insert(pid);
+                        restarts.schedule(pid, None, Instant::now());
+                    }
+                    Decision::Exit(exit) => {
+                        let over = stop_violator(pid, true, &config, &mut children);
+                        trackers.emit(events::Event::Violation {
+                            pid: pid.as_raw(),
+                            rule: exit.rule().map(String::from),
+                            exit: exit.clone(),
+                        });
+                        if over {
+                            break exit;
+                        }
+                        violation.get_or_insert(exit);
+                        restarts.schedule(pid, None, Instant::now());
+                    }
+                }
             }
-            Ok(WaitStatus::PtraceEvent(pid, _, event))
-                if event == Event::PTRACE_EVENT_FORK as c_int
-                    || event == Event::PTRACE_EVENT_VFORK as c_int
-                    || event == Event::PTRACE_EVENT_CLONE as c_int =>
+            // A signal about to be delivered, which is passed on as it is. A stopping signal then puts the process in a
+            // group-stop, which shows up as a PTRACE_EVENT_STOP.
+            // Past its soft RLIMIT_CPU, unless someone sent it one by hand
+            Ok(WaitStatus::Stopped(pid, Signal::SIGXCPU))
+                if !timed_out
+                    && config.time_limits.cpu_seconds.is_some_and(|seconds| {
+                        limits::cpu_time(config.proc_root(), pid)
+                            .is_some_and(|used| used >= Duration::from_secs(seconds))
+                    }) =>
             {
-                let new_child_pid = Pid::from_raw(
-                    getevent(pid)
-                        .unwrap_or_else(|e| panic!("failed to get new child of {pid}: {e}"))
-                        .try_into()
-                        .unwrap(),
-                );
-                if !ignore_next_stop.insert(new_child_pid) {
-                    panic!("new child {new_child_pid} already in list to ignore next SIGSTOP");
+                timed_out = true;
+                violation = Some(time_out(pid, TimeLimit::Cpu, &children, &mut trackers));
+            }
+            Ok(WaitStatus::Stopped(pid, signal)) => {
+                if let Some(tracee) = children.get_mut(&pid) {
+                    tracee.signal = Some(signal);
                 }
-                syscall(pid, None).unwrap_or_else(|e| {
-                    panic!(
-                        "failed to restart child {pid} after event {:?}: {e}",
-                        event_from_int(event)
-                    );
-                });
+                restarts.schedule(pid, Some(signal), Instant::now());
             }
-            Ok(status) => panic!("unexpected child process status {status:?}"),
+            // A SIGCONT reaching a process we're the real parent of. The tracee carries on by itself, since PTRACE_LISTEN
+            // left it to, and may already have been seen continuing through its PTRACE_EVENT_STOP.
+            Ok(WaitStatus::Continued(pid)) => {
+                if stopped.remove(&pid) {
+                    trackers.emit(events::Event::Continued { pid: pid.as_raw() });
+                }
+            }
+            Ok(WaitStatus::PtraceEvent(pid, signal, event)) => match event_from_int(event) {
+                Event::PTRACE_EVENT_STOP => {
+                    let group_stop = matches!(
+                        signal,
+                        Signal::SIGSTOP | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU
+                    );
+                    if started.insert(pid) || !group_stop {
+                        // A new tracee starting, or a stopped one being continued
+                        if stopped.remove(&pid) {
+                            trackers.emit(events::Event::Continued { pid: pid.as_raw() });
+                        }
+                        restarts.schedule(pid, None, Instant::now());
+                    } else {
+                        if stopped.insert(pid) {
+                            trackers.stats.job_control_stops += 1;
+                            trackers.emit(events::Event::Stopped {
+                                pid: pid.as_raw(),
+                                signal: signal as i32,
+                            });
+                        }
+                        // Stay stopped until something continues it, without holding up the rest of the tree
+                        listen(pid)
+                            .unwrap_or_else(|e| panic!("failed to listen to child {pid}: {e}"));
+                    }
+                }
+                Event::PTRACE_EVENT_EXEC => {
+                    // The new program can be built for the other ABI than the one that exec'd it
+                    if let Some(tracee) = children.get_mut(&pid) {
+                        tracee.abi = arch::detect(config.proc_root(), pid);
+                        tracee.starting = true;
+                        tracee.binary = binary(pid, &config);
+                    }
+                    trackers.emit(events::Event::Exec { pid: pid.as_raw() });
+                    restarts.schedule(pid, None, Instant::now());
+                }
+                // The last stop before the tracee is gone, when its registers and map can still be read
+                Event::PTRACE_EVENT_EXIT => {
+                    let status = getevent(pid).map_or(0, |status| status as i32);
+                    // Every thread is killed along with the one the signal was delivered to, which is the one that
+                    // was doing whatever it was about
+                    if let Some(tracee) = children.get(&pid).filter(|tracee| {
+                        libc::WIFSIGNALED(status)
+                            && tracee
+                                .signal
+                                .is_some_and(|signal| signal as i32 == libc::WTERMSIG(status))
+                    }) {
+                        let crash = crash_stack(pid, &config, tracee, &mut trackers);
+                        crashes.entry(tracee.tgid).or_insert(crash);
+                    }
+                    restarts.schedule(pid, None, Instant::now());
+                }
+                Event::PTRACE_EVENT_FORK
+                | Event::PTRACE_EVENT_VFORK
+                | Event::PTRACE_EVENT_CLONE => {
+                    let new_child_pid = Pid::from_raw(
+                        getevent(pid)
+                            .unwrap_or_else(|e| panic!("failed to get new child of {pid}: {e}"))
+                            .try_into()
+                            .unwrap(),
+                    );
+                    trackers.emit(events::Event::Forked {
+                        parent: pid.as_raw(),
+                        child: new_child_pid.as_raw(),
+                    });
+                    // Threads show up as clone events, and can't be orphaned
+                    if event != Event::PTRACE_EVENT_CLONE as i32 {
+                        parents.insert(new_child_pid, pid);
+                    }
+                    restarts.schedule(pid, None, Instant::now());
+                }
+                event => panic!("unexpected ptrace event {event:?} from child {pid}"),
+            },
+            // A SIGHUP or a control request, to be dealt with at the top of the loop
+            Err(Errno::EINTR) => {}
             Err(errno) => panic!("error from waitpid: {errno}"),
         }
+    };
+
+    if let Some(path) = &config.decision_cache {
+        if let Err(e) = trackers.decisions.save(path) {
+            warning!("Failed to save decision cache to {}: {e}", path.display());
+        }
     }
+    trackers.stats.processes = children.len() as u64;
+    trackers.stats.cache_hits = trackers.decisions.hits;
+    trackers.stats.cache_misses = trackers.decisions.misses;
+    trackers.stats.peak_tracer_rss = peak_rss();
+    (exit, trackers.stats)
+}
+
+/// wait_with_usage is waitpid for any child, but also returns the resources whichever one it reports on used, when
+/// that's an exit. The usage includes whatever that process had itself waited for, so for the child we started it
+/// covers everything the run left behind.
+///
+/// Only this thread's own children and tracees count, so a run doesn't reap the children of other threads, including
+/// other runs' tracers.
+fn wait_with_usage(flags: WaitPidFlag) -> (nix::Result<WaitStatus>, libc::rusage) {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let flags = flags | WaitPidFlag::__WNOTHREAD;
+    let result = match unsafe { libc::wait4(-1, &mut status, flags.bits(), &mut usage) } {
+        -1 => Err(Errno::last()),
+        0 => Ok(WaitStatus::StillAlive),
+        pid => WaitStatus::from_raw(Pid::from_raw(pid), status),
+    };
+    (result, usage)
+}
+
+/// peak_rss returns the most memory this process has had resident at once, in bytes.
+fn peak_rss() -> u64 {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    match unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } {
+        // Linux counts it in kilobytes
+        0 => usage.ru_maxrss as u64 * 1024,
+        _ => 0,
+    }
+}
+
+/// environment returns the environment the target is started with, given the one it was asked for.
+pub fn environment(env: &[&CStr], config: &Config) -> Vec<CString> {
+    let env = match &config.deterministic {
+        Some(deterministic) => deterministic::environment(deterministic, env),
+        None => env.iter().map(|&var| var.into()).collect(),
+    };
+    scrub::apply(&config.environment, env)
 }
 
 pub fn execute(path: &CStr, args: &[&CStr], env: &[&CStr], config: &Config) -> ChildExit {
-    match unsafe { fork() } {
-        Ok(ForkResult::Child) => child(path, args, env),
-        Ok(ForkResult::Parent { child, .. }) => parent(child, config),
+    execute_with_stats(path, args, env, config).0
+}
+
+/// execute_with_stats is like execute, but also returns counters describing the run.
+///
+/// The run is traced on a thread of its own, which has no children but the target, so it's safe to call from a
+/// thread that has children of its own to wait for, and from several threads at once.
+pub fn execute_with_stats(
+    path: &CStr,
+    args: &[&CStr],
+    env: &[&CStr],
+    config: &Config,
+) -> (ChildExit, RunStats) {
+    thread::scope(|scope| {
+        scope
+            .spawn(|| execute_with_hooks(path, args, env, config, Hooks::default()))
+            .join()
+            .unwrap_or_else(|panic| panic::resume_unwind(panic))
+    })
+}
+
+/// execute_with_events is like execute_with_stats, but also calls `on_event` with each event as it happens.
+pub fn execute_with_events<F: FnMut(events::Event)>(
+    path: &CStr,
+    args: &[&CStr],
+    env: &[&CStr],
+    config: &Config,
+    on_event: F,
+) -> (ChildExit, RunStats) {
+    execute_with_hooks(path, args, env, config, Hooks::default().on_event(on_event))
+}
+
+/// execute_with_hooks is like execute_with_stats, but calls the hooks as the run goes, see Hooks.
+///
+/// The run is traced on the calling thread, so that the hooks are called there. The tracer waits for any child of
+/// that thread, so other threads' children are left alone, but any the calling thread started itself may be reaped.
+pub fn execute_with_hooks(
+    path: &CStr,
+    args: &[&CStr],
+    env: &[&CStr],
+    config: &Config,
+    hooks: Hooks,
+) -> (ChildExit, RunStats) {
+    let env = environment(env, config);
+    let env: Vec<&CStr> = env.iter().map(CString::as_c_str).collect();
+
+    let stdio = match Redirections::open(&config.stdio) {
+        Ok(stdio) => stdio,
+        Err(e) => return (setup_failed(SetupStage::Stdio, e), RunStats::default()),
+    };
+    let cgroup = match config.cgroup.as_ref().map(Cgroup::create).transpose() {
+        Ok(cgroup) => cgroup,
+        Err(e) => return (setup_failed(SetupStage::Cgroup, e), RunStats::default()),
+    };
+    if let Some(cgroup) = &cgroup {
+        info!("Running in cgroup {}", cgroup.path().display());
+    }
+    let namespaces = match namespace::Setup::new(&config.namespaces) {
+        Ok(namespaces) => namespaces,
+        Err(errno) => {
+            return (
+                setup_failed(SetupStage::Namespaces, errno.into()),
+                RunStats::default(),
+            )
+        }
+    };
+    // The syscalls the prefilter stops at, if there's one
+    let filtered = config.prefilter_syscalls();
+    let mut restrictions = Restrictions {
+        stdio,
+        cgroup,
+        namespaces,
+        landlock: config.landlock.as_ref().map(Ruleset::new),
+        filter: filtered.as_ref().map(seccomp::filter),
+    };
+    let prefilter = filtered.is_some();
+    let (report_reader, report) =
+        unistd::pipe2(OFlag::O_CLOEXEC).unwrap_or_else(|e| panic!("failed to create pipe: {e}"));
+    let (attached, attached_writer) =
+        unistd::pipe2(OFlag::O_CLOEXEC).unwrap_or_else(|e| panic!("failed to create pipe: {e}"));
+    if config.subreaper {
+        set_subreaper();
+    }
+    match unsafe { namespace::fork(&config.namespaces) } {
+        Ok(ForkResult::Child) => {
+            drop(report_reader);
+            drop(attached_writer);
+            child(path, args, &env, config, report, attached, &restrictions)
+        }
+        Ok(ForkResult::Parent { child, .. }) => {
+            drop(report);
+            drop(attached);
+            if let Err(e) = seize(child, ptrace_options(prefilter)) {
+                // Don't let it carry on untraced
+                let _ = signal::kill(child, Signal::SIGKILL);
+                panic!("failed to attach to child {child}: {e}");
+            }
+            drop(attached_writer);
+            let captured = match restrictions.stdio.capture() {
+                Ok(captured) => captured,
+                Err(e) => {
+                    let _ = signal::kill(child, Signal::SIGKILL);
+                    panic!("failed to start reading the target's output: {e}");
+                }
+            };
+            let (exit, mut stats) = match start(child, report_reader) {
+                Some(exit) => (exit, RunStats::default()),
+                None => parent(child, config, hooks, filtered.as_ref()),
+            };
+            [stats.stdout, stats.stderr] = captured.finish();
+            (exit, stats)
+        }
+        Err(errno) if config.namespaces.pid => {
+            let exit = ChildExit::SetupFailed {
+                stage: SetupStage::Namespaces,
+                errno,
+            };
+            (exit, RunStats::default())
+        }
         Err(errno) => panic!("failed to fork: {}", errno),
     }
 }
+
+/// setup_failed is the exit for a run that couldn't be set up because of an error on our side of the fork.
+fn setup_failed(stage: SetupStage, e: io::Error) -> ChildExit {
+    ChildExit::SetupFailed {
+        stage,
+        errno: Errno::from_raw(e.raw_os_error().unwrap_or(libc::EIO)),
+    }
+}
+
+/// set_subreaper has orphans in the traced tree reparented to us rather than init.
+fn set_subreaper() {
+    if let Err(errno) = Errno::result(unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) }) {
+        warning!("Failed to become the subreaper for the tree: {errno}");
+    }
+}
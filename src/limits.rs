@@ -0,0 +1,139 @@
+use crate::config::ResourceLimits;
+use nix::{errno::Errno, libc, unistd::Pid};
+use std::{
+    fs,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// set_cpu_limit caps the CPU time of the calling process, and of each process it goes on to start, at `seconds`.
+/// Past it the kernel sends SIGXCPU, which the tracer ends the run at, and SIGKILL a second later in case that never
+/// gets delivered, because the process blocked it or raised its soft limit. Safe to call between fork and exec.
+pub fn set_cpu_limit(seconds: u64) -> nix::Result<()> {
+    set_limit(libc::RLIMIT_CPU, seconds, cpu_hard_limit(seconds))
+}
+
+/// cpu_hard_limit returns the CPU time, in seconds, past which the kernel kills a process with a limit of `seconds`.
+pub fn cpu_hard_limit(seconds: u64) -> u64 {
+    seconds.saturating_add(1)
+}
+
+/// cpu_time returns the CPU time process `pid` has used so far, counting all its threads but none of its children,
+/// which is what RLIMIT_CPU limits.
+pub fn cpu_time(proc_root: &Path, pid: Pid) -> Option<Duration> {
+    let stat = fs::read_to_string(proc_root.join(pid.to_string()).join("stat")).ok()?;
+    // The command name can have anything in it, spaces and parentheses included, but it's the last thing in brackets
+    let mut fields = stat.get(stat.rfind(')')? + 2..)?.split(' ');
+    // utime and stime are the 14th and 15th fields, and the state after the command name is the 3rd
+    let ticks: u64 = fields.nth(11)?.parse::<u64>().ok()? + fields.next()?.parse::<u64>().ok()?;
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (per_second > 0).then(|| Duration::from_secs_f64(ticks as f64 / per_second as f64))
+}
+
+/// set_resource_limits applies the configured limits to the calling process, which the processes it starts inherit.
+/// Each is set as both the soft and the hard limit, so the target can't raise them again. Safe to call between fork
+/// and exec.
+pub fn set_resource_limits(limits: &ResourceLimits) -> nix::Result<()> {
+    for (resource, limit) in limits.resources() {
+        if let Some(limit) = limit {
+            set_limit(resource, limit, limit)?;
+        }
+    }
+    Ok(())
+}
+
+/// set_limit lowers a resource limit. It won't raise a hard limit that's already lower, since only root could.
+fn set_limit(resource: libc::__rlimit_resource_t, soft: u64, hard: u64) -> nix::Result<()> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    Errno::result(unsafe { libc::getrlimit(resource, &mut limit) })?;
+    limit.rlim_max = limit.rlim_max.min(hard);
+    limit.rlim_cur = limit.rlim_max.min(soft);
+    Errno::result(unsafe { libc::setrlimit(resource, &limit) }).map(drop)
+}
+
+/// Watchdog: kills the started process once the run has gone on longer than the timeout. It runs on a thread of its
+/// own so that the tracer can carry on blocking in waitpid, and the exit it causes is what wakes the tracer up to
+/// kill the rest of the tree.
+pub struct Watchdog {
+    fired: Arc<AtomicBool>,
+    // Dropping this wakes the thread up early, so it's gone once the run is over
+    _cancel: mpsc::Sender<()>,
+}
+
+impl Watchdog {
+    /// start starts the timer for `child`, which has to be a process that hasn't been reaped yet.
+    pub fn start(child: Pid, timeout: Duration) -> nix::Result<Watchdog> {
+        // Unlike the pid, the pidfd can't end up referring to some other process if the child's already been reaped
+        // by the time the timeout's up
+        let fd = Errno::result(unsafe { libc::syscall(libc::SYS_pidfd_open, child.as_raw(), 0) })?;
+        let pidfd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let flag = fired.clone();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = cancelled.recv_timeout(timeout) {
+                flag.store(true, Ordering::SeqCst);
+                // It may well have exited in the meantime
+                let _ = unsafe {
+                    libc::syscall(
+                        libc::SYS_pidfd_send_signal,
+                        pidfd.as_raw_fd(),
+                        libc::SIGKILL,
+                        ptr::null::<libc::siginfo_t>(),
+                        0,
+                    )
+                };
+            }
+        });
+        Ok(Watchdog {
+            fired,
+            _cancel: cancel,
+        })
+    }
+
+    /// fired returns whether the timeout is up.
+    pub fn fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::process::ExitStatusExt, process::Command};
+
+    #[test]
+    fn test_watchdog() {
+        let mut sleep = Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = Pid::from_raw(sleep.id() as i32);
+        let watchdog = Watchdog::start(pid, Duration::from_millis(10)).unwrap();
+        assert_eq!(sleep.wait().unwrap().signal(), Some(libc::SIGKILL));
+        assert!(watchdog.fired());
+
+        let watchdog = Watchdog::start(nix::unistd::getpid(), Duration::from_secs(10)).unwrap();
+        assert!(!watchdog.fired());
+    }
+
+    #[test]
+    fn test_cpu_time() {
+        let start = cpu_time(Path::new("/proc"), nix::unistd::getpid()).unwrap();
+        let mut spun = 0u64;
+        let begun = std::time::Instant::now();
+        while begun.elapsed() < Duration::from_millis(100) {
+            spun = std::hint::black_box(spun.wrapping_add(1));
+        }
+        assert!(cpu_time(Path::new("/proc"), nix::unistd::getpid()).unwrap() > start);
+    }
+}
@@ -0,0 +1,202 @@
+pub use crate::elf::ElfError;
+use crate::{
+    elf::Elf,
+    identity, map,
+    profile::{ANONYMOUS_EXEC, LOADER_STARTUP, UNATTRIBUTED},
+    Config,
+};
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Where the loader looks once the search path, LD_LIBRARY_PATH and ld.so.conf have come up empty
+const DEFAULT_DIRS: &[&str] = &["/lib", "/usr/lib", "/lib64", "/usr/lib64"];
+
+/// Lint: what a config is missing or has too much of for a target, found without running it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// Files the target loads at startup that the config has no rules for
+    pub unruled: Vec<String>,
+    /// Libraries the config has rules for that the target doesn't link. They only apply if they're loaded with
+    /// dlopen, or the config is meant for another target too.
+    pub unlinked: Vec<String>,
+    /// DT_NEEDED libraries that couldn't be found, so the target probably won't start
+    pub unresolved: Vec<String>,
+}
+
+impl Lint {
+    pub fn is_clean(&self) -> bool {
+        self.unruled.is_empty() && self.unlinked.is_empty() && self.unresolved.is_empty()
+    }
+}
+
+/// lint checks `config` against the libraries `target` is linked with, following its dynamic section the way the
+/// loader would.
+pub fn lint(config: &Config, target: &Path) -> Result<Lint, ElfError> {
+    let (loaded, unresolved) = dependencies(target)?;
+    let loaded: BTreeSet<String> = loaded
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    Ok(Lint {
+        unruled: loaded
+            .iter()
+            .filter(|path| config.entry(path).is_none())
+            .cloned()
+            .collect(),
+        unlinked: config
+            .shared_objects
+            .keys()
+            .filter(|library| {
+                ![LOADER_STARTUP, UNATTRIBUTED, ANONYMOUS_EXEC].contains(&library.as_str())
+                    && !map::KERNEL_CODE.contains(&library.as_str())
+                    && !identity::is_identity(library)
+            })
+            .filter(|library| {
+                !loaded
+                    .iter()
+                    .any(|path| config.globs.matches(library, path))
+            })
+            .cloned()
+            .collect(),
+        unresolved,
+    })
+}
+
+/// dependencies returns the files mapped when `target` starts, by the paths the memory map would show, along with
+/// the needed libraries that couldn't be found.
+fn dependencies(target: &Path) -> Result<(BTreeSet<PathBuf>, Vec<String>), ElfError> {
+    let executable = Elf::read(target)?;
+    let mut loaded = BTreeSet::from([canonical(target)]);
+    if let Some(interpreter) = &executable.interpreter {
+        loaded.insert(canonical(Path::new(interpreter)));
+    }
+
+    let system_dirs = system_dirs();
+    let mut unresolved = Vec::new();
+    let mut queue = vec![(target.to_path_buf(), executable.clone())];
+    while let Some((path, elf)) = queue.pop() {
+        let origin = path.parent().unwrap_or(Path::new("/")).to_string_lossy();
+        let dirs: Vec<PathBuf> = elf
+            .search_path
+            .iter()
+            .map(|dir| PathBuf::from(dir.replace("$ORIGIN", &origin)))
+            .chain(system_dirs.iter().cloned())
+            .collect();
+        for needed in &elf.needed {
+            let found = dirs
+                .iter()
+                .map(|dir| dir.join(needed))
+                .find_map(|candidate| {
+                    // Libraries for another architecture are skipped over, as the loader would
+                    Elf::read(&candidate)
+                        .ok()
+                        .filter(|library| library.machine == executable.machine)
+                        .map(|library| (candidate, library))
+                });
+            let Some((candidate, library)) = found else {
+                if !unresolved.contains(needed) {
+                    unresolved.push(needed.clone());
+                }
+                continue;
+            };
+            // Each library's own dependencies only need following once
+            if loaded.insert(canonical(&candidate)) {
+                queue.push((candidate, library));
+            }
+        }
+    }
+    Ok((loaded, unresolved))
+}
+
+/// canonical resolves symlinks, like the memory map does.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// system_dirs returns the directories the loader searches for every library: LD_LIBRARY_PATH, then ld.so.conf,
+/// then the defaults.
+fn system_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = env::var("LD_LIBRARY_PATH")
+        .unwrap_or_default()
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .collect();
+    read_ld_so_conf(Path::new("/etc/ld.so.conf"), &mut dirs);
+    dirs.extend(DEFAULT_DIRS.iter().map(PathBuf::from));
+    dirs
+}
+
+/// read_ld_so_conf adds the directories listed in an ld.so.conf, following `include`s of the form
+/// `include /etc/ld.so.conf.d/*.conf`.
+fn read_ld_so_conf(path: &Path, dirs: &mut Vec<PathBuf>) {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return;
+    };
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        match line.strip_prefix("include") {
+            Some(pattern) => {
+                let pattern = Path::new(pattern.trim());
+                let (Some(dir), Some(name)) = (pattern.parent(), pattern.file_name()) else {
+                    continue;
+                };
+                let name = name.to_string_lossy();
+                let suffix = name.strip_prefix('*').unwrap_or(&name);
+                let Ok(entries) = fs::read_dir(dir) else {
+                    continue;
+                };
+                let mut included: Vec<PathBuf> = entries
+                    .flatten()
+                    .map(|entry| entry.path())
+                    .filter(|path| path.to_string_lossy().ends_with(suffix))
+                    .collect();
+                included.sort();
+                for path in included {
+                    read_ld_so_conf(&path, dirs);
+                }
+            }
+            None if !line.is_empty() => dirs.push(PathBuf::from(line)),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_ld_so_conf() {
+        let dir = env::temp_dir().join(format!("crabtrap_ld_so_conf_{}", std::process::id()));
+        fs::create_dir_all(dir.join("ld.so.conf.d")).unwrap();
+        fs::write(
+            dir.join("ld.so.conf"),
+            format!(
+                "# comment\n/opt/lib\ninclude {}/ld.so.conf.d/*.conf\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("ld.so.conf.d/aarch64.conf"),
+            "/usr/lib/aarch64-linux-gnu # multiarch\n",
+        )
+        .unwrap();
+        fs::write(dir.join("ld.so.conf.d/ignored.txt"), "/nope\n").unwrap();
+
+        let mut dirs = Vec::new();
+        read_ld_so_conf(&dir.join("ld.so.conf"), &mut dirs);
+        assert_eq!(
+            dirs,
+            vec![
+                PathBuf::from("/opt/lib"),
+                PathBuf::from("/usr/lib/aarch64-linux-gnu")
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
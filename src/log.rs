@@ -0,0 +1,31 @@
+//! What the tracer has to say about the run. With the `tracing` feature these are `tracing` events, so an embedder can
+//! filter them or send them anywhere with a subscriber. Without it they're printed to stdout.
+
+macro_rules! warning {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        println!($($arg)*);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::info!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        println!($($arg)*);
+    }};
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        println!($($arg)*);
+    }};
+}
+
+pub(crate) use {debug, info, warning};
@@ -1,38 +1,1174 @@
-use clap::Parser;
-use crabtrap::Config;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use crabtrap::{
+    batch::{self, Job, JobResult},
+    control, explain, lint, oci,
+    profile::{self, Profile},
+    replay,
+    scenario::Scenario,
+    seccomp, validate, Asker, Check, ChildExit, Config, Deterministic, Enforcement, Output, Rule,
+    RuleList, RunAs, RunStats,
+};
+use nix::{libc, unistd::dup2};
+use std::cell::RefCell;
 use std::env;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command as Process, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use syscalls::Sysno;
 
 #[derive(Parser)]
 struct Cli {
-    /// The path to the config file
+    /// The most detailed messages to print, from off, error, warn, info, debug and trace. At trace there's one for
+    /// every syscall.
+    #[cfg(feature = "tracing")]
+    #[arg(long, global = true, default_value = "info")]
+    log_level: tracing_subscriber::filter::LevelFilter,
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Run: the options for `crabtrap run`
+#[derive(clap::Args)]
+struct Run {
+    /// The path to the config file. It's read as YAML unless it ends in `.json` or `.toml`. Can be given more than
+    /// once, with each file laid over the ones before it. Sending crabtrap SIGHUP reads them again, and enforces their
+    /// rules from then on.
+    #[arg(long)]
+    config: Vec<PathBuf>,
+    /// Use a built-in profile instead of a config file
+    #[arg(
+        long,
+        conflicts_with = "config",
+        value_parser = clap::builder::PossibleValuesParser::new(Config::profile_names()),
+    )]
+    profile: Option<String>,
+    /// Write a signed receipt for the run to this path
+    #[cfg(feature = "receipts")]
+    #[arg(long, requires = "signing_key")]
+    receipt: Option<PathBuf>,
+    /// The ed25519 key to sign the receipt with, as 32 raw bytes or 64 hex characters
+    #[cfg(feature = "receipts")]
+    #[arg(long, requires = "receipt")]
+    signing_key: Option<PathBuf>,
+    /// Where procfs is mounted, if not /proc. Overrides the config file.
+    #[arg(long)]
+    proc_root: Option<PathBuf>,
+    /// Take some of the nondeterminism out of the run. Uses the config file's `deterministic` settings if it has
+    /// any, and otherwise just fixes the locale and umask.
+    #[arg(long)]
+    deterministic: bool,
+    /// Write a JSON line for each syscall to this path. Overrides the config file.
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+    /// Write a JSON line for each event (syscall, fork, exec, violation, exit) to this path. Overrides the config file.
+    #[arg(long)]
+    log_json: Option<PathBuf>,
+    /// Record what the target prints to this path in asciinema's format, with a marker at each policy decision.
+    /// Overrides the config file.
+    #[arg(long)]
+    record_session: Option<PathBuf>,
+    /// Record each syscall checked, with the registers, memory map and stack it was made with, to this path for
+    /// `crabtrap replay`. Overrides the config file.
+    #[arg(long)]
+    record_trace: Option<PathBuf>,
+    /// A YAML scenario of syscall failures and delays to inject. Overrides the config file.
+    #[arg(long)]
+    scenario: Option<PathBuf>,
+    /// Where to keep rule decisions between runs of the same config. Overrides the config file.
+    #[arg(long)]
+    decision_cache: Option<PathBuf>,
+    /// Listen on a Unix socket at this path for `crabtrap ctl`. Overrides the config file.
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+    /// Only stop at the syscalls the config has rules for, using a seccomp filter. Overrides the config file.
+    #[arg(long)]
+    prefilter: bool,
+    /// Let the target run past violations, and list them once the run is over. Overrides the config file.
+    #[arg(long)]
+    audit_mode: bool,
+    /// Print each syscall to stderr as it's made, with the library it came from and its arguments, and let the target
+    /// run past violations as in audit mode
+    #[arg(long)]
+    trace: bool,
+    /// Pause and ask on the terminal about each syscall the config blocks or has no rule for
+    #[arg(long)]
+    ask: bool,
+    /// Add the `always` and `never` answers to the config file
+    #[arg(long, requires_all = ["ask", "config"])]
+    save_answers: bool,
+    /// Print how long each library spent in syscalls once the run is over
+    #[arg(long)]
+    syscall_times: bool,
+    /// Once the run is over, print a table of how many times each library made each syscall, or write it to the path
+    /// if one is given
+    #[arg(long, num_args = 0..=1, default_missing_value = "-")]
+    summary: Option<PathBuf>,
+    /// What to pass the target as argv[0], if not its path
+    #[arg(long)]
+    argv0: Option<String>,
+    /// Start the target as a login shell, by putting a `-` in front of argv[0]
+    #[arg(long)]
+    login: bool,
+    /// Kill the target and everything it started if the run takes longer than this many seconds. Overrides the
+    /// config file.
+    #[arg(long, value_parser = parse_timeout)]
+    timeout: Option<Duration>,
+    /// Kill the target and everything it started once any one process has used this many seconds of CPU time.
+    /// Overrides the config file.
+    #[arg(long)]
+    cpu_limit: Option<u64>,
+    /// Read the target's stdin from this file. Overrides the config file.
+    #[arg(long)]
+    stdin: Option<PathBuf>,
+    /// Write the target's stdout to this file rather than ours. Overrides the config file.
+    #[arg(long)]
+    stdout: Option<PathBuf>,
+    /// Write the target's stderr to this file rather than ours. Overrides the config file.
+    #[arg(long)]
+    stderr: Option<PathBuf>,
+    /// Drop the variables glibc ignores for setuid programs, like LD_PRELOAD and GCONV_PATH, from the target's
+    /// environment
+    #[arg(long)]
+    scrub_env: bool,
+    /// Set an environment variable for the target, as KEY=VALUE, whatever it was started with. Can be given more
+    /// than once.
+    #[arg(long, value_parser = parse_pin)]
+    pin_env: Vec<(String, String)>,
+    /// Start the target in a network namespace of its own, cut off from any network. Needs CAP_SYS_ADMIN.
+    #[arg(long)]
+    unshare_net: bool,
+    /// Bring up the loopback interface in the target's network namespace
+    #[arg(long, requires = "unshare_net")]
+    loopback: bool,
+    /// Start the target in a user namespace of its own, which lets the other namespaces be made without
+    /// CAP_SYS_ADMIN
+    #[arg(long)]
+    unshare_user: bool,
+    /// Switch the target to this uid before it starts. Needs root.
+    #[arg(long, requires = "gid")]
+    uid: Option<u32>,
+    /// Switch the target to this gid, with no supplementary groups, before it starts. Needs root.
+    #[arg(long, requires = "uid")]
+    gid: Option<u32>,
+    /// Keep the target from gaining privileges through setuid executables or file capabilities
+    #[arg(long)]
+    no_new_privs: bool,
+    /// Start the target as init of a PID namespace of its own. Needs CAP_SYS_ADMIN.
+    #[arg(long)]
+    unshare_pid: bool,
+    /// Have processes the target orphans reparented to crabtrap rather than init
     #[arg(long)]
-    config: Option<std::path::PathBuf>,
-    /// The target executable
+    subreaper: bool,
+    /// Start the target in a mount namespace with this directory as its root. Needs CAP_SYS_ADMIN.
+    #[arg(long)]
+    root: Option<PathBuf>,
+    /// Bind this directory read-only into the new root at the same path. Can be given more than once.
+    #[arg(long, requires = "root")]
+    bind_ro: Vec<PathBuf>,
+    /// Start the target in this directory, which with --root is a path inside the new root
+    #[arg(long)]
+    chdir: Option<PathBuf>,
+    /// Close every file descriptor above 2 before starting the target, rather than letting it inherit ours
+    #[arg(long)]
+    close_fds: bool,
+    /// Pass this file descriptor on to the target even so. Can be given more than once.
+    #[arg(long)]
+    keep_fd: Vec<i32>,
+    /// How to print the result of the run. With `json`, the target's own stdout goes to stderr.
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    output: Format,
+    /// Print what would be run and how it would be sandboxed, without running anything
+    #[arg(long)]
+    dry_run: bool,
+    /// The target executable, looked up in the target's PATH if it's just a name
     target: String,
-    // Additional arguments
+    /// Arguments for the target
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     args: Vec<String>,
 }
 
+/// parse_timeout reads a `--timeout`, which has to come to at least a millisecond.
+fn parse_timeout(timeout: &str) -> Result<Duration, String> {
+    let seconds: f64 = timeout.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|timeout| timeout.as_millis() > 0)
+        .ok_or_else(|| format!("expected a number of seconds no less than 0.001, got {timeout}"))
+}
+
+/// parse_syscall reads a syscall's name.
+fn parse_syscall(name: &str) -> Result<Sysno, String> {
+    Sysno::from_str(name).map_err(|_| format!("unknown syscall {name}"))
+}
+
+/// parse_pin splits a `--pin-env` at its first `=`.
+fn parse_pin(pin: &str) -> Result<(String, String), String> {
+    pin.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {pin}"))
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a target, checking each syscall it makes against the config
+    Run(Box<Run>),
+    /// Merge the audit logs from several runs and print a config allowing everything they saw
+    Aggregate {
+        /// Also write the combined per-library syscall counts to this path
+        #[arg(long)]
+        profile: Option<PathBuf>,
+        /// JSON-lines audit logs
+        #[arg(required = true)]
+        logs: Vec<PathBuf>,
+    },
+    /// Run newline-delimited JSON jobs from stdin, writing one JSON result per line to stdout
+    ///
+    /// Each job looks like
+    /// `{"id": 1, "target": "/bin/ls", "args": ["/"], "env": {"PATH": "/bin"}, "config": "config.yaml"}`, where
+    /// everything but `target` is optional. The targets' own output goes to stderr.
+    Batch {
+        /// How many jobs to run at once. Results come out in job order only if this is 1.
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+        jobs: u64,
+    },
+    /// Control a run started with --control-socket while it goes on
+    Ctl {
+        /// The run's control socket
+        #[arg(long)]
+        socket: PathBuf,
+        #[command(subcommand)]
+        command: Ctl,
+    },
+    /// Show which rules decide a syscall made from a library, without running anything
+    Explain {
+        /// The config file to read
+        #[arg(long)]
+        config: PathBuf,
+        /// The executable making the syscall, for configs with rules for particular binaries
+        #[arg(long)]
+        binary: Option<String>,
+        /// The path of the library making the syscall
+        library: String,
+        /// The syscall's name
+        #[arg(value_parser = parse_syscall)]
+        syscall: Sysno,
+    },
+    /// Compile a config's blocked syscalls into a seccomp filter that can be loaded without crabtrap
+    ///
+    /// The filter is written as raw struct sock_filter instructions, the format `bwrap --seccomp` loads. It can't tell
+    /// libraries apart, so syscalls that any library allows aren't blocked.
+    ExportSeccomp {
+        /// Where to write the filter, if not stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// The config file to compile
+        config: PathBuf,
+    },
+    /// Check a config against the libraries a target links, without running it
+    ///
+    /// Warns about libraries the target loads at startup that have no rules, and rules for libraries it doesn't link.
+    /// Exits with 1 if there's anything to warn about.
+    Lint {
+        /// The config file to check
+        #[arg(long)]
+        config: PathBuf,
+        /// The target executable, whose dependencies are read from its dynamic section
+        #[arg(long)]
+        ldd: PathBuf,
+    },
+    /// Supervise a container from outside, run as its OCI runtime's createRuntime hook
+    ///
+    /// Reads the container's state from stdin, and enforces the config file named by the `crabtrap.config` annotation
+    /// in its config.json, or the built-in profile named by `crabtrap.profile`, on the process the container runs.
+    /// With `crabtrap.result`, how that process exited is written to a new file of that name as JSON. Containers
+    /// without either annotation are left alone. Exits with 1 if the container asks for a policy that can't be
+    /// enforced, which stops the runtime from starting it.
+    OciHook {
+        /// The directory `crabtrap.config` names a config in. Without it, the annotation is refused.
+        #[arg(long)]
+        config_dir: Option<PathBuf>,
+        /// The directory `crabtrap.result` names a file to create in. Without it, the annotation is refused.
+        #[arg(long)]
+        result_dir: Option<PathBuf>,
+    },
+    /// Run a target with nothing enforced and print a config allowing each library exactly the syscalls it made
+    Record {
+        /// Also write the per-library syscall counts to this path
+        #[arg(long)]
+        profile: Option<PathBuf>,
+        /// The target executable
+        target: String,
+        /// Arguments for the target
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Check a config against a trace recorded with `run --record-trace`, without running anything
+    ///
+    /// Prints how the config decides each syscall in the trace. Only the rules are checked, and the stacks are walked
+    /// with the files mapped at the time, which have to still be where they were. Exits with 1 if the config would
+    /// have ended the run.
+    Replay {
+        /// The config file to check
+        #[arg(long)]
+        config: PathBuf,
+        /// The recorded trace
+        trace: PathBuf,
+    },
+    /// Check a config for mistakes, like unknown syscalls or rules for libraries that aren't there, without running
+    /// anything. Exits with 1 if there are any.
+    Validate {
+        /// The config file to check
+        config: PathBuf,
+    },
+    /// Run a single job read from stdin. Used by `batch`, so each job is traced from its own process.
+    #[command(hide = true)]
+    BatchJob,
+}
+
+/// Ctl: what `crabtrap ctl` can ask a run to do
+#[derive(Subcommand)]
+enum Ctl {
+    /// Stop checking syscalls, and let the target run at full speed until told to resume
+    Pause,
+    /// Go back to checking syscalls after a pause
+    Resume,
+    /// Enforce the rules in this config file from now on, in place of the ones the run started with. Logs, limits
+    /// and anything else set up when the run started stay as they were, so it's refused if the prefilter wouldn't
+    /// stop at the syscalls it has rules for.
+    Reload {
+        /// The config file to load
+        config: PathBuf,
+    },
+    /// Print the stats for the run so far as JSON
+    Stats,
+    /// Let go of the target and everything it started, leaving them to run untraced. Refused if the config has the
+    /// prefilter on, a cgroup or captured output.
+    Detach,
+}
+
+fn ctl(socket: &Path, command: Ctl) {
+    let request = match command {
+        Ctl::Pause => control::Request::Pause,
+        Ctl::Resume => control::Request::Resume,
+        // The run is probably going on somewhere else
+        Ctl::Reload { config } => control::Request::Reload {
+            path: config
+                .canonicalize()
+                .unwrap_or_else(|e| panic!("failed to find {}: {e}", config.display())),
+        },
+        Ctl::Stats => control::Request::Stats,
+        Ctl::Detach => control::Request::Detach,
+    };
+    match control::request(socket, &request)
+        .unwrap_or_else(|e| panic!("failed to reach {}: {e}", socket.display()))
+    {
+        control::Response::Ok => {}
+        control::Response::Stats(stats) => {
+            println!("{}", serde_json::to_string_pretty(&stats).unwrap())
+        }
+        control::Response::Error(e) => {
+            eprintln!("crabtrap: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn aggregate(logs: &[PathBuf], profile_path: Option<PathBuf>) {
+    let mut profile = Profile::default();
+    for log in logs {
+        let file =
+            File::open(log).unwrap_or_else(|e| panic!("failed to open {}: {e}", log.display()));
+        profile.merge(
+            &Profile::from_log(BufReader::new(file))
+                .unwrap_or_else(|e| panic!("failed to read {}: {e}", log.display())),
+        );
+    }
+
+    print_profile(&profile, profile_path);
+}
+
+fn record(target: &str, args: &[String], profile_path: Option<PathBuf>) {
+    let c_args = batch::argv(target, None, false, args);
+    let c_env = env::vars()
+        .map(|(key, val)| CString::new(format!("{key}={val}")).unwrap())
+        .collect::<Vec<_>>();
+    let c_env: Vec<&CStr> = c_env.iter().map(|s| s.as_c_str()).collect();
+    let config = Config::new();
+    let path = crabtrap::resolve(target, &c_env, &config)
+        .and_then(crabtrap::c_path)
+        .unwrap_or_else(|e| panic!("failed to find {target}: {e}"));
+    let (exit, _, profile) = Profile::trace(
+        &path,
+        &c_args.iter().map(|s| s.as_c_str()).collect::<Vec<_>>(),
+        &c_env,
+        &config,
+    );
+    // The config goes to stdout, so anything else has to go elsewhere
+    eprintln!("{exit:?}");
+    if let Some(syscalls) = profile.libraries.get(crabtrap::profile::UNATTRIBUTED) {
+        eprintln!(
+            "warning: {} different syscalls couldn't be attributed to any library, so the config has no rules for them",
+            syscalls.len()
+        );
+    }
+    print_profile(&profile, profile_path);
+}
+
+/// print_profile prints the config a profile suggests, and writes the profile itself to `profile_path` if given.
+fn print_profile(profile: &Profile, profile_path: Option<PathBuf>) {
+    if let Some(path) = profile_path {
+        let file = File::create(&path)
+            .unwrap_or_else(|e| panic!("failed to create {}: {e}", path.display()));
+        serde_yaml::to_writer(file, profile).expect("failed to write profile");
+    }
+
+    print!(
+        "{}",
+        serde_yaml::to_string(&profile.suggested_config()).expect("failed to serialize config")
+    );
+}
+
+/// run_job runs one line of batch input in a `batch-job` process and returns the result.
+fn run_job(line: &str) -> JobResult {
+    let job: Job = match serde_json::from_str(line) {
+        Ok(job) => job,
+        Err(e) => return JobResult::failed(None, format!("invalid job: {e}")),
+    };
+
+    let output = Process::new(env::current_exe().expect("failed to find our own executable"))
+        .arg("batch-job")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .and_then(|mut worker| {
+            // Serializing the job again rather than passing `line` on drops anything we didn't parse
+            serde_json::to_writer(worker.stdin.take().unwrap(), &job)?;
+            worker.wait_with_output()
+        });
+
+    match output {
+        Ok(output) if output.status.success() => serde_json::from_slice(&output.stdout)
+            .unwrap_or_else(|e| JobResult::failed(job.id, format!("invalid result: {e}"))),
+        Ok(output) => JobResult::failed(job.id, format!("job failed: {}", output.status)),
+        Err(e) => JobResult::failed(job.id, format!("failed to run job: {e}")),
+    }
+}
+
+fn batch(jobs: u64) {
+    // Each job gets a `batch-job` process of its own, so that process-wide settings like the subreaper and the SIGHUP
+    // handler can't leak from one job to another, and these threads just hand out jobs and wait for them.
+    let stdin = Mutex::new(io::stdin());
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let mut line = String::new();
+                let read = stdin.lock().unwrap().read_line(&mut line);
+                if read.expect("failed to read job") == 0 {
+                    break;
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let result =
+                    serde_json::to_string(&run_job(&line)).expect("failed to serialize result");
+                println!("{result}");
+            });
+        }
+    });
+}
+
+fn explain(config: &Path, binary: Option<&str>, library: &str, syscall: Sysno) {
+    let config = Config::from_file(config);
+    let explanation = explain::explain(&config, binary, library, syscall);
+    if let Some(binary) = &explanation.binary {
+        println!("Binary: {binary}");
+    }
+    if explanation.name != library {
+        println!("Goes by: {}", explanation.name);
+    }
+    match &explanation.rules {
+        Some((key, true)) => println!("Rules: {key}, in the binary's section"),
+        Some((key, false)) => println!("Rules: {key}"),
+        None => println!("Rules: none"),
+    }
+    let rule = |rule: &Rule| {
+        let list = match rule.list {
+            RuleList::Default => String::from("library's default"),
+            list => format!("{list:?} list"),
+        };
+        match &rule.id {
+            Some(id) => format!("{list} of rule {id}"),
+            None => list,
+        }
+    };
+    match &explanation.check {
+        Check::Allowed(allowed) => println!("Allowed by the {}", rule(allowed)),
+        Check::Blocked(action, blocked) => {
+            println!("Blocked by the {}, with {action:?}", rule(blocked))
+        }
+        Check::Unknown => println!(
+            "No rule of the library's covers it, so it's up to the libraries further up the stack, and then the \
+             default policy: {:?}",
+            explanation.default
+        ),
+    }
+    if let Some(on_failure) = explanation.on_failure {
+        println!(
+            "If the walk up the stack is cut short before any rule decides it: {on_failure:?}"
+        );
+    }
+    if explanation.argument_rules {
+        println!("Rules for its arguments may decide otherwise");
+    }
+}
+
+fn replay(config: &Path, trace: &Path) {
+    let config = Config::from_file(config);
+    let file =
+        File::open(trace).unwrap_or_else(|e| panic!("failed to open {}: {e}", trace.display()));
+    let replayed = replay::replay(&config, BufReader::new(file))
+        .unwrap_or_else(|e| panic!("failed to replay {}: {e}", trace.display()));
+    let mut killed = false;
+    for syscall in &replayed {
+        let rule = syscall
+            .rule
+            .as_ref()
+            .map_or(String::new(), |rule| format!(", by rule {rule}"));
+        println!(
+            "[{}] {} from {}: {:?}{rule}",
+            syscall.pid,
+            syscall.syscall,
+            syscall.library.as_deref().unwrap_or(profile::UNATTRIBUTED),
+            syscall.outcome
+        );
+        killed |= syscall.outcome == replay::Outcome::Killed;
+    }
+    if killed {
+        process::exit(1);
+    }
+}
+
+fn export_seccomp(config: &PathBuf, output: Option<PathBuf>) {
+    let filter = seccomp::to_bytes(&Config::from_file(config).to_seccomp_bpf());
+    match output {
+        Some(path) => std::fs::write(&path, filter)
+            .unwrap_or_else(|e| panic!("failed to write {}: {e}", path.display())),
+        None => io::stdout()
+            .write_all(&filter)
+            .expect("failed to write filter"),
+    }
+}
+
+fn lint(config: &PathBuf, target: &Path) {
+    let lint = lint::lint(&Config::from_file(config), target)
+        .unwrap_or_else(|e| panic!("failed to read {}: {e}", target.display()));
+    for library in &lint.unresolved {
+        eprintln!("warning: {library} is needed but couldn't be found");
+    }
+    for library in &lint.unruled {
+        eprintln!("warning: {library} is loaded but has no rules");
+    }
+    for library in &lint.unlinked {
+        eprintln!("warning: {library} has rules but isn't linked, so they only apply if it's loaded with dlopen");
+    }
+    if !lint.is_clean() {
+        process::exit(1);
+    }
+}
+
+fn oci_hook(directories: &oci::Directories) {
+    if let Err(e) = oci::hook(io::stdin().lock(), directories) {
+        eprintln!("crabtrap: {e}");
+        process::exit(1);
+    }
+}
+
+fn validate(config: &Path) {
+    let problems = validate::validate(config);
+    for problem in &problems {
+        match problem.line {
+            Some(line) => eprintln!("{}:{line}: {}", config.display(), problem.message),
+            None => eprintln!("{}: {}", config.display(), problem.message),
+        }
+    }
+    if !problems.is_empty() {
+        process::exit(1);
+    }
+}
+
+/// The limits worth knowing about that the target inherits from us
+const RESOURCE_LIMITS: &[(&str, libc::__rlimit_resource_t)] = &[
+    ("open files", libc::RLIMIT_NOFILE),
+    ("processes", libc::RLIMIT_NPROC),
+    ("address space", libc::RLIMIT_AS),
+    ("data size", libc::RLIMIT_DATA),
+    ("stack size", libc::RLIMIT_STACK),
+    ("file size", libc::RLIMIT_FSIZE),
+    ("cpu time", libc::RLIMIT_CPU),
+    ("core size", libc::RLIMIT_CORE),
+];
+
+fn dry_run(
+    target: &str,
+    path: &io::Result<PathBuf>,
+    args: &[CString],
+    env: &[CString],
+    config: &Config,
+    profile: Option<&str>,
+    config_paths: &[PathBuf],
+) {
+    match path {
+        Ok(path) => println!("Target: {}", path.display()),
+        Err(e) => println!("Target: {target} (can't be run: {e})"),
+    }
+    if let Some(dir) = &config.working_dir {
+        println!("Working directory: {}", dir.display());
+    }
+    println!("argv:");
+    for arg in args {
+        println!("  {arg:?}");
+    }
+    println!("envp:");
+    let env: Vec<&CStr> = env.iter().map(CString::as_c_str).collect();
+    for var in crabtrap::environment(&env, config) {
+        println!("  {var:?}");
+    }
+    if let Some(path) = &config.stdio.stdin {
+        println!("stdin: {}", path.display());
+    }
+    for (name, output) in [
+        ("stdout", &config.stdio.stdout),
+        ("stderr", &config.stdio.stderr),
+    ] {
+        match output {
+            Output::Inherit => {}
+            Output::Null => println!("{name}: discarded"),
+            Output::Capture => println!("{name}: captured"),
+            Output::File(path) => println!("{name}: {}", path.display()),
+        }
+    }
+
+    println!("Config layers:");
+    match profile {
+        Some(profile) => println!("  the built-in {profile} profile"),
+        None if config_paths.is_empty() => println!("  none, so everything is allowed"),
+        None => {}
+    }
+    for path in config_paths {
+        for layer in Config::layers(path).unwrap_or_else(|e| panic!("{e}")) {
+            println!("  {}", layer.display());
+        }
+    }
+
+    println!("Backends:");
+    println!("  ptrace");
+    if let Some(syscalls) = config.prefilter_syscalls() {
+        println!(
+            "  seccomp prefilter, stopping at {} syscalls",
+            syscalls.len()
+        );
+    }
+    if let Some(landlock) = &config.landlock {
+        println!(
+            "  landlock, with {} read-only and {} writable paths",
+            landlock.read_only.len(),
+            landlock.writable.len()
+        );
+    }
+    if config.namespaces.network {
+        match config.namespaces.loopback {
+            true => println!("  network namespace, with loopback"),
+            false => println!("  network namespace"),
+        }
+    }
+    if config.namespaces.user {
+        println!("  user namespace");
+    }
+    if config.namespaces.pid {
+        println!("  pid namespace");
+    }
+    if let Some(run_as) = &config.run_as {
+        println!("  running as uid {} and gid {}", run_as.uid, run_as.gid);
+    }
+    if config.no_new_privs {
+        println!("  no_new_privs");
+    }
+    if let Some(root) = &config.namespaces.root {
+        println!(
+            "  mount namespace rooted at {}, with {} read-only binds",
+            root.display(),
+            config.namespaces.read_only_binds.len()
+        );
+    }
+    if config.inherited_fds.close {
+        println!(
+            "  closing inherited file descriptors, but for {:?}",
+            config.inherited_fds.keep
+        );
+    }
+
+    println!("Resource limits:");
+    let limit = |value: libc::rlim_t| match value {
+        libc::RLIM_INFINITY => "unlimited".to_string(),
+        value => value.to_string(),
+    };
+    for &(name, resource) in RESOURCE_LIMITS {
+        let mut rlimit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        let configured = config
+            .resource_limits
+            .resources()
+            .into_iter()
+            .chain([(libc::RLIMIT_CPU, config.time_limits.cpu_seconds)])
+            .find_map(|(configured, limit)| limit.filter(|_| configured == resource));
+        if let Some(configured) = configured {
+            println!("  {name}: {configured} (from the config)");
+        } else if unsafe { libc::getrlimit(resource, &mut rlimit) } == 0 {
+            println!(
+                "  {name}: {} (hard limit {})",
+                limit(rlimit.rlim_cur),
+                limit(rlimit.rlim_max)
+            );
+        }
+    }
+    if let Some(timeout) = config.time_limits.timeout_ms {
+        println!("  wall-clock time for the run: {timeout}ms");
+    }
+    if let Some(cgroup) = &config.cgroup {
+        if let Some(max) = cgroup.memory_max {
+            println!("  memory for the whole tree: {max}");
+        }
+        if let Some(max) = cgroup.pids_max {
+            println!("  processes for the whole tree: {max}");
+        }
+        if let Some(percent) = cgroup.cpu_percent {
+            println!("  cpu for the whole tree: {percent}% of one core");
+        }
+    }
+    if let Some(per_file) = config.write_quota.per_file {
+        println!("  bytes written per file: {per_file}");
+    }
+    if let Some(total) = config.write_quota.total {
+        println!("  bytes written in total: {total}");
+    }
+}
+
+fn batch_job() {
+    let mut input = String::new();
+    io::stdin()
+        .read_to_string(&mut input)
+        .expect("failed to read job");
+    let job: Job = serde_json::from_str(&input).expect("failed to parse job");
+
+    // Our stdout is for the result, so point the target's at stderr
+    let mut results = File::from(
+        io::stdout()
+            .as_fd()
+            .try_clone_to_owned()
+            .expect("failed to duplicate stdout"),
+    );
+    dup2(io::stderr().as_raw_fd(), io::stdout().as_raw_fd()).expect("failed to redirect stdout");
+
+    serde_json::to_writer(&mut results, &job.run()).expect("failed to write result");
+    results.flush().expect("failed to write result");
+}
+
 fn main() {
     let args = Cli::parse();
-    let c_args = args
-        .args
-        .into_iter()
-        .map(|arg| CString::new(arg).unwrap())
-        .collect::<Vec<_>>();
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_max_level(args.log_level)
+        .with_writer(io::stderr)
+        .init();
+    match args.command {
+        Command::Run(args) => process::exit(run(*args)),
+        Command::Aggregate { profile, logs } => aggregate(&logs, profile),
+        Command::Batch { jobs } => batch(jobs),
+        Command::BatchJob => batch_job(),
+        Command::Ctl { socket, command } => ctl(&socket, command),
+        Command::Explain {
+            config,
+            binary,
+            library,
+            syscall,
+        } => explain(&config, binary.as_deref(), &library, syscall),
+        Command::ExportSeccomp { output, config } => export_seccomp(&config, output),
+        Command::Lint { config, ldd } => lint(&config, &ldd),
+        Command::OciHook {
+            config_dir,
+            result_dir,
+        } => oci_hook(&oci::Directories {
+            configs: config_dir,
+            results: result_dir,
+        }),
+        Command::Record {
+            profile,
+            target,
+            args,
+        } => record(&target, &args, profile),
+        Command::Replay { config, trace } => replay(&config, &trace),
+        Command::Validate { config } => validate(&config),
+    }
+}
+
+/// Format: how `crabtrap run` prints the result of the run
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Format {
+    Text,
+    /// A single JSON object with the exit, the exit code, the rule broken if any, and the stats
+    Json,
+}
+
+/// What `crabtrap run` exits with when the target broke the config
+const EXIT_VIOLATION: i32 = 77;
+/// What `crabtrap run` exits with when it couldn't do its own part, like setting the target up
+const EXIT_SUPERVISOR_ERROR: i32 = 78;
+
+/// exit_code returns what `crabtrap run` exits with after the target exited with `exit`. The target's own exit code
+/// is passed on, and a signal that killed it is reported the way shells do.
+fn exit_code(exit: &ChildExit) -> i32 {
+    match exit {
+        ChildExit::Exited(code) => *code,
+        ChildExit::Signaled(signal) => 128 + signal,
+        ChildExit::SetupFailed { .. } => EXIT_SUPERVISOR_ERROR,
+        // Letting go of it was asked for
+        ChildExit::Detached => 0,
+        ChildExit::IllegalSyscall { .. }
+        | ChildExit::WriteQuotaExceeded(..)
+        | ChildExit::SyscallStorm(..)
+        | ChildExit::Daemonized(_)
+        | ChildExit::ReadOnlyFilesystem(..)
+        | ChildExit::TimedOut(_) => EXIT_VIOLATION,
+    }
+}
+
+/// run runs the target and returns what to exit with.
+fn run(args: Run) -> i32 {
+    if args.output == Format::Json
+        && (args.syscall_times
+            || args
+                .summary
+                .as_ref()
+                .is_some_and(|path| path.as_os_str() == "-"))
+    {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--output json prints nothing but the result, so --syscall-times and --summary without a path can't \
+                 be used with it",
+            )
+            .exit();
+    }
+    // Our stdout is for the JSON result, so the target's is pointed at stderr once it's about to start
+    let results = (args.output == Format::Json).then(|| {
+        Arc::new(File::from(
+            io::stdout()
+                .as_fd()
+                .try_clone_to_owned()
+                .expect("failed to duplicate stdout"),
+        ))
+    });
+
+    // Anything that goes wrong on our side panics, and shouldn't look like something the target did. A panic on any
+    // other thread is left to whoever joins it.
+    let default_hook = panic::take_hook();
+    let main_thread = thread::current().id();
+    let hook_results = results.clone();
+    panic::set_hook(Box::new(move |info| {
+        match &hook_results {
+            Some(results) if thread::current().id() == main_thread => {
+                let _ = writeln!(
+                    results.as_ref(),
+                    "{}",
+                    serde_json::json!({ "error": panic_message(info) })
+                );
+            }
+            _ => default_hook(info),
+        }
+        if thread::current().id() == main_thread {
+            process::exit(EXIT_SUPERVISOR_ERROR);
+        }
+    }));
+
+    let target = args.target;
+    let c_args = batch::argv(&target, args.argv0.as_deref(), args.login, &args.args);
     let c_env = env::vars()
         .map(|(key, val)| CString::new(format!("{key}={val}")).unwrap())
         .collect::<Vec<_>>();
-    let config = args.config.map_or_else(Config::new, Config::from_file);
+    let mut config = match (&args.profile, args.config.as_slice()) {
+        (Some(profile), _) => Config::profile(profile).expect("clap checks the profile exists"),
+        (None, []) => Config::new(),
+        (None, paths) => Config::from_files(paths),
+    };
+    if args.proc_root.is_some() {
+        config.proc_root = args.proc_root;
+    }
+    if args.audit_log.is_some() {
+        config.audit_log = args.audit_log;
+    }
+    if args.log_json.is_some() {
+        config.event_log = args.log_json;
+    }
+    if args.record_session.is_some() {
+        config.session_recording = args.record_session;
+    }
+    if args.record_trace.is_some() {
+        config.trace_recording = args.record_trace;
+    }
+    if let Some(path) = args.scenario {
+        config.scenario = Some(
+            Scenario::from_file(&path)
+                .unwrap_or_else(|e| panic!("failed to load {}: {e}", path.display())),
+        );
+    }
+    if args.decision_cache.is_some() {
+        config.decision_cache = args.decision_cache;
+    }
+    if args.control_socket.is_some() {
+        config.control_socket = args.control_socket;
+    }
+    if args.prefilter {
+        config.prefilter = true;
+    }
+    if args.audit_mode || args.trace {
+        config.enforcement = Enforcement::Audit;
+    }
+    if args.trace {
+        config.trace = true;
+    }
+    if let Some(timeout) = args.timeout {
+        config.time_limits.timeout_ms = Some(timeout.as_millis().try_into().unwrap_or(u64::MAX));
+    }
+    if args.cpu_limit.is_some() {
+        config.time_limits.cpu_seconds = args.cpu_limit;
+    }
+    if args.stdin.is_some() {
+        config.stdio.stdin = args.stdin;
+    }
+    if let Some(path) = args.stdout {
+        config.stdio.stdout = Output::File(path);
+    }
+    if let Some(path) = args.stderr {
+        config.stdio.stderr = Output::File(path);
+    }
+    if args.scrub_env {
+        config.environment.scrub = true;
+    }
+    config.environment.pin.extend(args.pin_env);
+    if args.unshare_net {
+        config.namespaces.network = true;
+    }
+    if args.loopback {
+        config.namespaces.loopback = true;
+    }
+    if args.unshare_user {
+        config.namespaces.user = true;
+    }
+    if let (Some(uid), Some(gid)) = (args.uid, args.gid) {
+        config.run_as = Some(RunAs {
+            uid,
+            gid,
+            groups: Vec::new(),
+        });
+    }
+    if args.no_new_privs {
+        config.no_new_privs = true;
+    }
+    if args.unshare_pid {
+        config.namespaces.pid = true;
+    }
+    if args.subreaper {
+        config.subreaper = true;
+    }
+    if args.root.is_some() {
+        config.namespaces.root = args.root;
+    }
+    config.namespaces.read_only_binds.extend(args.bind_ro);
+    if args.chdir.is_some() {
+        config.working_dir = args.chdir;
+    }
+    if args.close_fds {
+        config.inherited_fds.close = true;
+    }
+    config.inherited_fds.keep.extend(args.keep_fd);
+    if args.deterministic && config.deterministic.is_none() {
+        config.deterministic = Some(Deterministic::default());
+    }
 
-    println!(
-        "{:?}",
-        crabtrap::execute(
-            &CString::new(args.target).unwrap(),
-            &c_args.iter().map(|s| s.as_c_str()).collect::<Vec<_>>(),
-            &c_env.iter().map(|s| s.as_c_str()).collect::<Vec<_>>(),
+    let path = crabtrap::resolve(
+        &target,
+        &c_env.iter().map(|s| s.as_c_str()).collect::<Vec<_>>(),
+        &config,
+    );
+    if args.dry_run {
+        dry_run(
+            &target,
+            &path,
+            &c_args,
+            &c_env,
             &config,
+            args.profile.as_deref(),
+            &args.config,
+        );
+        return 0;
+    }
+
+    let asker = args.ask.then(|| {
+        RefCell::new(
+            // Answers go in the last config, which is the most specific
+            Asker::new(
+                args.save_answers
+                    .then(|| args.config.last().cloned().unwrap()),
+            )
+            .unwrap_or_else(|e| panic!("failed to open the terminal: {e}")),
         )
+    });
+    let mut hooks = asker.as_ref().map(Asker::hooks).unwrap_or_default();
+    if !args.config.is_empty() {
+        // A SIGHUP reads the files again, which the flags still override
+        let (paths, audit) = (args.config.clone(), args.audit_mode || args.trace);
+        hooks = hooks.on_reload(move || {
+            let mut config = Config::load_files(&paths)?;
+            if audit {
+                config.enforcement = Enforcement::Audit;
+            }
+            Ok(config)
+        });
+    }
+    let path = path.unwrap_or_else(|e| panic!("failed to find {target}: {e}"));
+    // Hashed before it runs, since it could rewrite itself
+    #[cfg(feature = "receipts")]
+    let target_hash = args.receipt.is_some().then(|| {
+        crabtrap::receipt::TargetHash::read(&path)
+            .unwrap_or_else(|e| panic!("failed to hash {target}: {e}"))
+    });
+    let path = crabtrap::c_path(path).unwrap_or_else(|e| panic!("failed to find {target}: {e}"));
+    if results.is_some() {
+        dup2(io::stderr().as_raw_fd(), io::stdout().as_raw_fd())
+            .expect("failed to redirect stdout");
+    }
+    let (exit, stats) = crabtrap::execute_with_hooks(
+        &path,
+        &c_args.iter().map(|s| s.as_c_str()).collect::<Vec<_>>(),
+        &c_env.iter().map(|s| s.as_c_str()).collect::<Vec<_>>(),
+        &config,
+        hooks,
+    );
+    match &results {
+        None => print_result(&exit, &stats),
+        Some(results) => writeln!(
+            results.as_ref(),
+            "{}",
+            serde_json::json!({
+                "exit": exit,
+                "exit_code": exit_code(&exit),
+                "rule": exit.rule(),
+                "stats": stats,
+            })
+        )
+        .expect("failed to write the result"),
+    }
+
+    if args.syscall_times {
+        println!("{:>12} {:>12}  library", "syscalls", "time");
+        for (library, library_stats) in stats.by_time() {
+            println!(
+                "{:>12} {:>12.3?}  {library}",
+                library_stats.syscalls, library_stats.time
+            );
+        }
+    }
+
+    match args.summary {
+        Some(path) if path.as_os_str() == "-" => print!("{}", stats.summary()),
+        Some(path) => std::fs::write(&path, stats.summary())
+            .unwrap_or_else(|e| panic!("failed to write {}: {e}", path.display())),
+        None => {}
+    }
+
+    let code = exit_code(&exit);
+    #[cfg(feature = "receipts")]
+    if let (Some(path), Some(key), Some(target_hash)) =
+        (args.receipt, args.signing_key, target_hash)
+    {
+        use crabtrap::receipt::{load_signing_key, Receipt, ReceiptBody};
+
+        let key = load_signing_key(key).unwrap_or_else(|e| panic!("{e}"));
+        let body = ReceiptBody::new(&config, target_hash, stats, exit);
+        let receipt = serde_json::to_string_pretty(&Receipt::sign(body, &key))
+            .expect("failed to serialize receipt");
+        std::fs::write(&path, receipt)
+            .unwrap_or_else(|e| panic!("failed to write receipt to {}: {e}", path.display()));
+    }
+    code
+}
+
+/// print_result prints the result of the run for people to read.
+fn print_result(exit: &ChildExit, stats: &RunStats) {
+    println!("{exit:?}");
+    println!(
+        "Took {:.3?} ({:.3?} user, {:.3?} system) and {} syscalls, using at most {} KiB",
+        stats.usage.wall_time,
+        stats.usage.user_time,
+        stats.usage.system_time,
+        stats.syscalls,
+        stats.usage.max_rss / 1024
     );
+    if let Some(rule) = exit.rule() {
+        println!("Broke rule {rule}");
+    }
+    for (rule, count) in &stats.rules {
+        println!("Rule {rule} decided {count} syscalls");
+    }
+    for (virtualization, count) in &stats.virtualized {
+        println!("Virtualized {virtualization:?} {count} times");
+    }
+    for (syscall, count) in &stats.unattributed {
+        println!("Couldn't attribute {syscall} to any library {count} times");
+    }
+    for (violation, count) in &stats.violations {
+        println!("Would have stopped the run {count} times for {violation:?}");
+    }
+    // Only the ones that didn't succeed, or a big tree would bury the rest
+    for process in stats
+        .exits
+        .iter()
+        .filter(|process| process.exit != ChildExit::Exited(0))
+    {
+        println!("Process {} ended with {:?}", process.pid, process.exit);
+        for frame in &process.backtrace {
+            println!(
+                "    {:#x} {}",
+                frame.address,
+                frame
+                    .library
+                    .as_deref()
+                    .unwrap_or(crabtrap::profile::UNATTRIBUTED)
+            );
+        }
+    }
+}
+
+/// panic_message returns what a panic was raised with.
+fn panic_message(info: &panic::PanicHookInfo) -> String {
+    match info.payload().downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => info
+            .payload()
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_default(),
+    }
 }
@@ -1,15 +1,132 @@
-use nix::unistd::Pid;
+use nix::{libc, unistd::Pid};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{fs, num::ParseIntError, str::FromStr};
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::Read,
+    num::ParseIntError,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use syscalls::Sysno;
 use thiserror::Error;
 
+/// The syscalls that can change a process's memory map when they succeed
+pub const CHANGES_MAP: &[Sysno] = &[
+    Sysno::execve,
+    Sysno::execveat,
+    Sysno::mmap,
+    Sysno::munmap,
+    Sysno::mremap,
+    Sysno::mprotect,
+    Sysno::pkey_mprotect,
+    Sysno::brk,
+    Sysno::shmat,
+    Sysno::shmdt,
+    Sysno::remap_file_pages,
+];
+
+/// changes_code returns whether a syscall from CHANGES_MAP might have changed which code is mapped where. Lookups go by
+/// what's executable, so anything else can wait until a lookup misses, but a lookup that still finds code that's
+/// since been replaced would never miss.
+pub fn changes_code(syscall: Sysno, args: &[u64; 6]) -> bool {
+    match syscall {
+        Sysno::mmap => args[2] as i32 & libc::PROT_EXEC != 0,
+        Sysno::shmat => args[2] as i32 & libc::SHM_EXEC != 0,
+        // The heap only ever grows or shrinks, and anything that makes it executable is an mprotect
+        Sysno::brk => false,
+        _ => true,
+    }
+}
+
+/// What the kernel adds to the path of a file that's been deleted or replaced since it was mapped
+const DELETED: &str = " (deleted)";
+
+/// The names of the regions the kernel maps its own code into, which can be given rules like any library
+pub const KERNEL_CODE: &[&str] = &["[vdso]"];
+
+/// is_loader returns whether a mapped file is the dynamic loader, e.g. /lib/ld-linux-aarch64.so.1.
+pub fn is_loader(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.starts_with("ld.so") || (name.starts_with("ld-") && name.contains(".so"))
+}
+
+/// Permissions: what a region can be accessed for, from the `r-xp` column of the map
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// Whether writes are seen by other mappings of the same file, rather than being private to this one
+    pub shared: bool,
+}
+
+impl FromStr for Permissions {
+    type Err = MemoryMapError;
+
+    fn from_str(s: &str) -> Result<Permissions, MemoryMapError> {
+        match s.as_bytes() {
+            &[read, write, execute, shared] => Ok(Permissions {
+                read: read == b'r',
+                write: write == b'w',
+                execute: execute == b'x',
+                shared: shared == b's',
+            }),
+            _ => Err(MemoryMapError::RegexError(String::from(s))),
+        }
+    }
+}
+
+impl std::fmt::Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x'),
+            if self.shared { 's' } else { 'p' }
+        )
+    }
+}
+
+/// FileId: the device and inode of a mapped file, which stay the same whatever path it's mapped by
+pub type FileId = (u64, u64);
+
 /// Region: one memory region in the process
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
 pub struct Region {
     pub start: u64,
     pub end: u64,
+    pub permissions: Permissions,
     path: String,
+    /// Whether the file has been deleted or replaced since it was mapped, e.g. by upgrading the library
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    /// Which file is mapped, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileId>,
+}
+
+impl Region {
+    /// path is the file mapped, or for an anonymous region, its name if it has one, like `[stack]`
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// strip_deleted strips the marker the kernel adds to a deleted file's path. The rest of the path is left as the
+    /// kernel gave it, which has no symlinks in it to resolve, and resolving it again would go by our mount namespace
+    /// and root rather than the tracee's.
+    fn strip_deleted(&mut self) {
+        if let Some(path) = self.path.strip_suffix(DELETED) {
+            self.path.truncate(path.len());
+            self.deleted = true;
+        }
+    }
 }
 
 #[derive(Debug, Error, PartialEq, Eq)]
@@ -18,15 +135,26 @@ pub enum MemoryMapError {
     RegexError(String),
     #[error("Failed to parse start of region as u64 from {0}: {1}")]
     ParseIntError(String, ParseIntError),
+    #[error("Failed to read memory map: {0}")]
+    ReadFailed(String),
+}
+
+/// file_id reads the device and inode columns of the map, which are all zeroes for anonymous regions.
+fn file_id(major: &str, minor: &str, inode: &str) -> Option<FileId> {
+    let major = u32::from_str_radix(major, 16).ok()?;
+    let minor = u32::from_str_radix(minor, 16).ok()?;
+    let inode = inode.parse().ok().filter(|&inode| inode != 0)?;
+    Some((libc::makedev(major, minor), inode))
 }
 
 impl FromStr for Region {
     type Err = MemoryMapError;
 
     fn from_str(s: &str) -> Result<Region, MemoryMapError> {
-        let re =
-            Regex::new(r"^(?<start>[[:xdigit:]]{12})-(?<end>[[:xdigit:]]{12})[^/\[]*(?<path>.*)$")
-                .unwrap();
+        let re = Regex::new(
+            r"^(?<start>[[:xdigit:]]+)-(?<end>[[:xdigit:]]+) (?<permissions>[r-][w-][x-][ps]) [[:xdigit:]]+ (?<major>[[:xdigit:]]+):(?<minor>[[:xdigit:]]+) (?<inode>\d+)\s*(?<path>.*)$",
+        )
+            .unwrap();
 
         let caps = match re.captures(s) {
             Some(caps) => caps,
@@ -42,7 +170,10 @@ impl FromStr for Region {
                 Ok(start) => start,
                 Err(err) => return Err(MemoryMapError::ParseIntError(String::from(s), err)),
             },
+            permissions: caps["permissions"].parse()?,
             path: String::from(&caps["path"]),
+            file: file_id(&caps["major"], &caps["minor"], &caps["inode"]),
+            ..Default::default()
         })
     }
 }
@@ -52,7 +183,10 @@ impl std::fmt::Debug for Region {
         f.debug_struct("Region")
             .field("start", &format_args!("{0:x}", &self.start))
             .field("end", &format_args!("{0:x}", &self.end))
+            .field("permissions", &format_args!("{}", &self.permissions))
             .field("path", &self.path)
+            .field("deleted", &self.deleted)
+            .field("file", &self.file)
             .finish()
     }
 }
@@ -61,118 +195,402 @@ impl std::fmt::Debug for Region {
 /// See https://www.man7.org/linux/man-pages/man5/proc.5.html
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct MemoryMap {
+    /// Sorted by start, which lookup relies on
     pub files: Vec<Region>,
+    /// The regions not backed by a file, like the stacks and the heap
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub anonymous: Vec<Region>,
 }
 
 impl FromStr for MemoryMap {
     type Err = MemoryMapError;
 
     fn from_str(s: &str) -> Result<MemoryMap, MemoryMapError> {
-        let mut files: Vec<Region> = match s
+        let (mut files, mut anonymous): (Vec<Region>, Vec<Region>) = match s
             .lines()
             .map(Region::from_str)
             .collect::<Result<Vec<Region>, MemoryMapError>>()
         {
             Ok(files) => files
                 .into_iter()
-                .filter(|region| region.path.starts_with('/'))
-                .collect(),
+                .partition(|region| region.path.starts_with('/')),
             Err(err) => return Err(err),
         };
 
-        files.sort_by(|a, b| a.start.cmp(&b.start));
+        files.sort_by_key(|region| region.start);
+        anonymous.sort_by_key(|region| region.start);
 
-        Ok(MemoryMap { files })
+        Ok(MemoryMap { files, anonymous })
     }
 }
 
 impl MemoryMap {
     pub fn from_pid(pid: Pid) -> Result<MemoryMap, MemoryMapError> {
-        let contents =
-            fs::read_to_string(format!("/proc/{pid}/maps")).expect("failed to read maps");
+        MemoryMap::from_proc("/proc", pid)
+    }
+
+    /// from_proc reads the map of `pid` from a procfs mounted at `proc_root`, e.g. /host/proc when running in a
+    /// sidecar container.
+    pub fn from_proc<P: AsRef<Path>>(proc_root: P, pid: Pid) -> Result<MemoryMap, MemoryMapError> {
+        let path = proc_root.as_ref().join(pid.to_string()).join("maps");
+        let file = File::open(&path)
+            .map_err(|e| MemoryMapError::ReadFailed(format!("{}: {e}", path.display())))?;
+
+        let mut map = MemoryMap::from_reader(file)?;
+        map.strip_deleted();
+        Ok(map)
+    }
+
+    /// strip_deleted strips the marker from deleted files' paths, so that they're named the same way as the files
+    /// were before. See Region::strip_deleted.
+    fn strip_deleted(&mut self) {
+        for region in &mut self.files {
+            region.strip_deleted();
+        }
+    }
+
+    /// from_proc_in_root is from_proc with the paths made relative to the process's root directory, for when it's in
+    /// a chroot or a container whose root we can see. Files from outside the root keep the paths we see them by.
+    pub fn from_proc_in_root<P: AsRef<Path>>(
+        proc_root: P,
+        pid: Pid,
+    ) -> Result<MemoryMap, MemoryMapError> {
+        let mut map = MemoryMap::from_proc(&proc_root, pid)?;
+        let link = proc_root.as_ref().join(pid.to_string()).join("root");
+        let root = fs::read_link(&link)
+            .map_err(|e| MemoryMapError::ReadFailed(format!("{}: {e}", link.display())))?;
+        map.strip_root(&root);
+        Ok(map)
+    }
+
+    /// strip_root rewrites the paths under `root` to be relative to it.
+    fn strip_root(&mut self, root: &Path) {
+        let strip = |path: &mut String| {
+            if let Ok(relative) = Path::new(path.as_str()).strip_prefix(root) {
+                *path = PathBuf::from("/")
+                    .join(relative)
+                    .to_string_lossy()
+                    .into_owned();
+            }
+        };
+        for region in &mut self.files {
+            strip(&mut region.path);
+        }
+    }
+
+    /// from_reader parses a map in the format of /proc/{pid}/maps from any reader.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<MemoryMap, MemoryMapError> {
+        let mut contents = String::new();
+        reader
+            .read_to_string(&mut contents)
+            .map_err(|e| MemoryMapError::ReadFailed(e.to_string()))?;
 
         MemoryMap::from_str(&contents)
     }
 
+    /// region returns the file-backed region `addr` is in. The regions are kept sorted by start, and don't overlap, so
+    /// the only one it can be in is the last to start at or before it.
+    pub fn region(&self, addr: u64) -> Option<&Region> {
+        self.region_index(addr).map(|index| &self.files[index])
+    }
+
+    /// region_index returns where in `files` the region `addr` is in is, see region.
+    pub fn region_index(&self, addr: u64) -> Option<usize> {
+        let index = self
+            .files
+            .partition_point(|file| file.start <= addr)
+            .checked_sub(1)?;
+        (addr < self.files[index].end).then_some(index)
+    }
+
+    /// lookup returns the file whose code is at `addr`. Only executable regions count, since a code address that
+    /// lands in a file's data is a sign the stack walk has gone wrong.
     pub fn lookup(&self, addr: u64) -> Option<&str> {
-        // If we cared about perf, we could take advantage of this being sorted to exit early
-        // But let's not worry about maintaining that invariant for now.
-        // It's mostly helpful for the equality check in the unit tests.
+        self.region(addr)
+            .filter(|file| file.permissions.execute)
+            .map(Region::path)
+    }
+
+    /// anonymous_region returns the region not backed by a file that `addr` is in.
+    pub fn anonymous_region(&self, addr: u64) -> Option<&Region> {
+        let region = &self.anonymous[self
+            .anonymous
+            .partition_point(|region| region.start <= addr)
+            .checked_sub(1)?];
+        (addr < region.end).then_some(region)
+    }
+
+    /// is_anonymous_code returns whether `addr` is in executable memory that isn't backed by a file, like a JIT's or
+    /// injected shellcode. The vDSO doesn't count, since the kernel provides it.
+    pub fn is_anonymous_code(&self, addr: u64) -> bool {
+        self.anonymous_region(addr).is_some_and(|region| {
+            region.permissions.execute && !KERNEL_CODE.contains(&region.path.as_str())
+        })
+    }
+
+    /// kernel_code returns the name of the region of the kernel's code `addr` is in, like `[vdso]`.
+    pub fn kernel_code(&self, addr: u64) -> Option<&str> {
+        self.anonymous_region(addr)
+            .map(Region::path)
+            .filter(|path| KERNEL_CODE.contains(path))
+    }
+
+    /// stack returns the bounds of the anonymous region holding the stack pointer `sp`, which is the stack of the
+    /// thread it belongs to.
+    pub fn stack(&self, sp: u64) -> Option<Range<u64>> {
+        self.anonymous_region(sp)
+            .map(|region| region.start..region.end)
+    }
+
+    /// file_id returns the device and inode of a mapped file.
+    pub fn file_id(&self, path: &str) -> Option<FileId> {
+        self.files
+            .iter()
+            .find(|file| file.path == path)
+            .and_then(|file| file.file)
+    }
+
+    /// is_deleted returns whether a file has been deleted or replaced since it was mapped, so that what's at its path
+    /// now isn't what the tracee is running.
+    pub fn is_deleted(&self, path: &str) -> bool {
+        self.files
+            .iter()
+            .any(|file| file.path == path && file.deleted)
+    }
+
+    /// base returns where a file's first mapping starts, which is where it was loaded.
+    pub fn base(&self, path: &str) -> Option<u64> {
+        self.files
+            .iter()
+            .filter(|file| file.path == path)
+            .map(|file| file.start)
+            .min()
+    }
+
+    /// unmap removes everything in [start, end) from the map, splitting the regions that are only partly in it, and
+    /// returns whether any of the files' regions changed.
+    pub fn unmap(&mut self, start: u64, end: u64) -> bool {
+        let files = self
+            .files
+            .iter()
+            .any(|region| region.start < end && region.end > start);
+        for regions in [&mut self.files, &mut self.anonymous] {
+            let mut kept = Vec::with_capacity(regions.len() + 1);
+            for region in regions.drain(..) {
+                if region.end <= start || region.start >= end {
+                    kept.push(region);
+                    continue;
+                }
+                if region.start < start {
+                    kept.push(Region {
+                        end: start,
+                        ..region.clone()
+                    });
+                }
+                if region.end > end {
+                    kept.push(Region {
+                        start: end,
+                        ..region
+                    });
+                }
+            }
+            *regions = kept;
+        }
+        files
+    }
+
+    /// insert adds a new mapping of `path` over [start, end), in place of whatever was there. Anonymous mappings have
+    /// an empty path. A file's path is resolved like the ones read from the map, and `file` is its device and inode,
+    /// which have to come from what was mapped rather than whatever's at the path by now. Returns whether any of the
+    /// files' regions changed, as unmap does.
+    pub fn insert(
+        &mut self,
+        start: u64,
+        end: u64,
+        permissions: Permissions,
+        path: String,
+        file: Option<FileId>,
+    ) -> bool {
+        let unmapped = self.unmap(start, end);
+        let mut region = Region {
+            start,
+            end,
+            permissions,
+            path,
+            file,
+            ..Default::default()
+        };
+        let path_is_file = region.path.starts_with('/');
+        let regions = if path_is_file {
+            region.strip_deleted();
+            &mut self.files
+        } else {
+            &mut self.anonymous
+        };
+        let at = regions.partition_point(|region| region.start < start);
+        regions.insert(at, region);
+        unmapped || path_is_file
+    }
+
+    /// added returns the files mapped in this map that weren't mapped at all in `previous`, in address order.
+    pub fn added(&self, previous: &MemoryMap) -> Vec<&str> {
+        let before: BTreeSet<&str> = previous.files.iter().map(|f| f.path.as_str()).collect();
+        let mut seen = BTreeSet::new();
         self.files
             .iter()
-            .find(|file| file.start <= addr && addr <= file.end)
-            .map(|file| file.path.as_ref())
+            .map(|file| file.path.as_str())
+            .filter(|path| !before.contains(path) && seen.insert(*path))
+            .collect()
+    }
+}
+
+/// ProcessMap: the memory map of a process, which all its threads share
+#[derive(Debug)]
+pub struct ProcessMap {
+    pub map: MemoryMap,
+    /// Whether one of the threads has made a syscall that might have changed the map since it was read. Rather than
+    /// rereading it right away, it's reread the next time it's missing an address.
+    pub dirty: bool,
+    /// Tells this version of the files' regions apart from every other, in this process or any other, so that what's
+    /// been worked out about a region can be kept by its index until they change. See ProcessMap::changed.
+    pub generation: u64,
+}
+
+impl ProcessMap {
+    pub fn new(map: MemoryMap) -> ProcessMap {
+        ProcessMap {
+            map,
+            dirty: false,
+            generation: next_generation(),
+        }
+    }
+
+    /// changed gives the map a new generation, once the files' regions have been changed or reread.
+    pub fn changed(&mut self) {
+        self.generation = next_generation();
     }
 }
 
+/// next_generation returns a generation no map has had yet.
+fn next_generation() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_region() {
         assert_eq!(Region::from_str(&"ffff9f390000-ffff9f517000 r-xp 00000000 fe:01 319964                     /usr/lib/aarch64-linux-gnu/libc.so.6"), Ok(Region {
             start: 0xffff9f390000,
             end: 0xffff9f517000,
+            permissions: Permissions {
+                read: true,
+                write: false,
+                execute: true,
+                shared: false,
+            },
             path: String::from("/usr/lib/aarch64-linux-gnu/libc.so.6"),
+            file: Some((libc::makedev(0xfe, 1), 319964)),
+            ..Default::default()
         }));
+        // riscv64 with Sv39 paging has shorter addresses
+        assert_eq!(Region::from_str("3f8a6c2000-3f8a7f5000 r-xp 00000000 fe:01 1048601                    /usr/lib/riscv64-linux-gnu/libc.so.6"), Ok(Region {
+            start: 0x3f8a6c2000,
+            end: 0x3f8a7f5000,
+            permissions: "r-xp".parse().unwrap(),
+            path: String::from("/usr/lib/riscv64-linux-gnu/libc.so.6"),
+            file: Some((libc::makedev(0xfe, 1), 1048601)),
+            ..Default::default()
+        }));
+        assert_eq!("rw-s".parse::<Permissions>().unwrap().to_string(), "rw-s");
+        assert!("rwx".parse::<Permissions>().is_err());
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_map() {
+        let region = |(start, end, permissions, path): (u64, u64, &str, &str)| Region {
+            start,
+            end,
+            permissions: permissions.parse().unwrap(),
+            path: String::from(path),
+            file: match path {
+                "/usr/bin/cat" => Some(188725),
+                "/usr/lib/aarch64-linux-gnu/libc.so.6" => Some(319964),
+                "/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1" => Some(319946),
+                _ => None,
+            }
+            .map(|inode| (libc::makedev(0xfe, 1), inode)),
+            ..Default::default()
+        };
         let expected_map = MemoryMap {
-            files: vec![
-                Region {
-                    start: 0xaaaae8e20000,
-                    end: 0xaaaae8e29000,
-                    path: String::from("/usr/bin/cat"),
-                },
-                Region {
-                    start: 0xaaaae8e3f000,
-                    end: 0xaaaae8e40000,
-                    path: String::from("/usr/bin/cat"),
-                },
-                Region {
-                    start: 0xaaaae8e40000,
-                    end: 0xaaaae8e41000,
-                    path: String::from("/usr/bin/cat"),
-                },
-                Region {
-                    start: 0xffff9f390000,
-                    end: 0xffff9f517000,
-                    path: String::from("/usr/lib/aarch64-linux-gnu/libc.so.6"),
-                },
-                Region {
-                    start: 0xffff9f517000,
-                    end: 0xffff9f52c000,
-                    path: String::from("/usr/lib/aarch64-linux-gnu/libc.so.6"),
-                },
-                Region {
-                    start: 0xffff9f52c000,
-                    end: 0xffff9f530000,
-                    path: String::from("/usr/lib/aarch64-linux-gnu/libc.so.6"),
-                },
-                Region {
-                    start: 0xffff9f530000,
-                    end: 0xffff9f532000,
-                    path: String::from("/usr/lib/aarch64-linux-gnu/libc.so.6"),
-                },
-                Region {
-                    start: 0xffff9f544000,
-                    end: 0xffff9f56a000,
-                    path: String::from("/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1"),
-                },
-                Region {
-                    start: 0xffff9f582000,
-                    end: 0xffff9f584000,
-                    path: String::from("/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1"),
-                },
-                Region {
-                    start: 0xffff9f584000,
-                    end: 0xffff9f586000,
-                    path: String::from("/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1"),
-                },
-            ],
+            files: [
+                (0xaaaae8e20000, 0xaaaae8e29000, "r-xp", "/usr/bin/cat"),
+                (0xaaaae8e3f000, 0xaaaae8e40000, "r--p", "/usr/bin/cat"),
+                (0xaaaae8e40000, 0xaaaae8e41000, "rw-p", "/usr/bin/cat"),
+                (
+                    0xffff9f390000,
+                    0xffff9f517000,
+                    "r-xp",
+                    "/usr/lib/aarch64-linux-gnu/libc.so.6",
+                ),
+                (
+                    0xffff9f517000,
+                    0xffff9f52c000,
+                    "---p",
+                    "/usr/lib/aarch64-linux-gnu/libc.so.6",
+                ),
+                (
+                    0xffff9f52c000,
+                    0xffff9f530000,
+                    "r--p",
+                    "/usr/lib/aarch64-linux-gnu/libc.so.6",
+                ),
+                (
+                    0xffff9f530000,
+                    0xffff9f532000,
+                    "rw-p",
+                    "/usr/lib/aarch64-linux-gnu/libc.so.6",
+                ),
+                (
+                    0xffff9f544000,
+                    0xffff9f56a000,
+                    "r-xp",
+                    "/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1",
+                ),
+                (
+                    0xffff9f582000,
+                    0xffff9f584000,
+                    "r--p",
+                    "/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1",
+                ),
+                (
+                    0xffff9f584000,
+                    0xffff9f586000,
+                    "rw-p",
+                    "/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1",
+                ),
+            ]
+            .into_iter()
+            .map(region)
+            .collect(),
+            anonymous: [
+                (0xaaaaf9cc3000, 0xaaaaf9ce4000, "rw-p", "[heap]"),
+                (0xffff9f36e000, 0xffff9f390000, "rw-p", ""),
+                (0xffff9f532000, 0xffff9f53f000, "rw-p", ""),
+                (0xffff9f575000, 0xffff9f577000, "rw-p", ""),
+                (0xffff9f57d000, 0xffff9f57f000, "rw-p", ""),
+                (0xffff9f57f000, 0xffff9f581000, "r--p", "[vvar]"),
+                (0xffff9f581000, 0xffff9f582000, "r-xp", "[vdso]"),
+                (0xfffff69fe000, 0xfffff6a1f000, "rw-p", "[stack]"),
+            ]
+            .into_iter()
+            .map(region)
+            .collect(),
         };
 
         // Not sure if these are guaranteed to be ordered by start, so I've purposefully moved one around
@@ -196,9 +614,216 @@ ffff9f584000-ffff9f586000 rw-p 00030000 fe:01 319946                     /usr/li
 fffff69fe000-fffff6a1f000 rw-p 00000000 00:00 0                          [stack]"), Ok(expected_map.clone()));
 
         assert_eq!(
-            expected_map.lookup(0xffff9f582004),
+            expected_map.lookup(0xffff9f544004),
             Some("/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1"),
         );
+        // Code is only ever in executable regions, so a return address in the loader's data isn't attributed to it
+        assert_eq!(
+            expected_map
+                .region(0xffff9f582004)
+                .map(|region| region.path()),
+            Some("/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1"),
+        );
+        assert_eq!(expected_map.lookup(0xffff9f582004), None);
         assert_eq!(expected_map.lookup(0x1234), None);
+        assert_eq!(expected_map.lookup(0xaaaae8e20000), Some("/usr/bin/cat"));
+        // Between two mappings of cat
+        assert_eq!(expected_map.lookup(0xaaaae8e30000), None);
+        assert_eq!(expected_map.lookup(0xfffff0000000), None);
+        assert_eq!(
+            expected_map.stack(0xfffff6a1e010),
+            Some(0xfffff69fe000..0xfffff6a1f000)
+        );
+        assert_eq!(expected_map.stack(0xffff9f390000), None);
+        assert!(!expected_map.is_anonymous_code(0xffff9f581004));
+        assert_eq!(expected_map.kernel_code(0xffff9f581004), Some("[vdso]"));
+        assert_eq!(expected_map.kernel_code(0xfffff6a1e010), None);
+        assert!(!expected_map.is_anonymous_code(0xffff9f36e004));
+    }
+
+    #[test]
+    fn test_is_loader() {
+        assert!(is_loader(
+            "/usr/lib/aarch64-linux-gnu/ld-linux-aarch64.so.1"
+        ));
+        assert!(is_loader("/lib/ld-linux-riscv64-lp64d.so.1"));
+        assert!(is_loader("/lib/ld-musl-aarch64.so.1"));
+        assert!(!is_loader("/usr/lib/aarch64-linux-gnu/libc.so.6"));
+        assert!(!is_loader("/usr/bin/ld-wrapper"));
+    }
+
+    #[test]
+    fn test_changes_code() {
+        let mmap = |prot: i32| [0, 4096, prot as u64, libc::MAP_PRIVATE as u64, 3, 0];
+        assert!(changes_code(
+            Sysno::mmap,
+            &mmap(libc::PROT_READ | libc::PROT_EXEC)
+        ));
+        assert!(!changes_code(
+            Sysno::mmap,
+            &mmap(libc::PROT_READ | libc::PROT_WRITE)
+        ));
+        // Taking away PROT_EXEC changes the code as much as adding it
+        assert!(changes_code(
+            Sysno::mprotect,
+            &[0, 4096, libc::PROT_READ as u64, 0, 0, 0]
+        ));
+        assert!(changes_code(Sysno::munmap, &[0, 4096, 0, 0, 0, 0]));
+        assert!(changes_code(Sysno::mremap, &[0; 6]));
+        assert!(!changes_code(Sysno::brk, &[0; 6]));
+    }
+
+    #[test]
+    fn test_added() {
+        let before = MemoryMap::from_str(
+            "aaaae8e20000-aaaae8e29000 r-xp 00000000 fe:01 188725                     /usr/bin/cat",
+        )
+        .unwrap();
+        let after = MemoryMap::from_str("aaaae8e20000-aaaae8e29000 r-xp 00000000 fe:01 188725                     /usr/bin/cat
+ffff9f390000-ffff9f517000 r-xp 00000000 fe:01 319964                     /usr/lib/aarch64-linux-gnu/libc.so.6
+ffff9f52c000-ffff9f530000 r--p 0018c000 fe:01 319964                     /usr/lib/aarch64-linux-gnu/libc.so.6").unwrap();
+        assert_eq!(
+            after.added(&before),
+            vec!["/usr/lib/aarch64-linux-gnu/libc.so.6"]
+        );
+        assert!(before.added(&after).is_empty());
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut map = MemoryMap::from_str(
+            "aaaae8e20000-aaaae8e29000 r-xp 00000000 fe:01 188725                     /usr/bin/cat
+ffff9f36e000-ffff9f390000 rw-p 00000000 00:00 0",
+        )
+        .unwrap();
+        let code = "r-xp".parse().unwrap();
+        assert!(map.insert(
+            0xffff9f390000,
+            0xffff9f517000,
+            code,
+            String::from("/usr/lib/aarch64-linux-gnu/libc.so.6"),
+            Some((libc::makedev(0xfe, 1), 319964)),
+        ));
+        assert_eq!(
+            map.lookup(0xffff9f390010),
+            Some("/usr/lib/aarch64-linux-gnu/libc.so.6")
+        );
+
+        // Unmapping the middle of a region leaves the two ends
+        assert!(map.unmap(0xaaaae8e22000, 0xaaaae8e24000));
+        assert_eq!(map.lookup(0xaaaae8e21000), Some("/usr/bin/cat"));
+        assert_eq!(map.region_index(0xaaaae8e25000), Some(1));
+        assert_eq!(map.region_index(0xaaaae8e23000), None);
+        assert_eq!(map.lookup(0xaaaae8e23000), None);
+        assert_eq!(map.lookup(0xaaaae8e25000), Some("/usr/bin/cat"));
+        assert_eq!(map.files.len(), 3);
+
+        // A new mapping replaces what it's mapped over
+        map.insert(
+            0xffff9f370000,
+            0xffff9f380000,
+            code,
+            String::from("/tmp/jit"),
+            None,
+        );
+        assert_eq!(map.lookup(0xffff9f372000), Some("/tmp/jit"));
+        // Anonymous memory comes and goes without the files' regions changing
+        assert!(!map.insert(0xffff9f380000, 0xffff9f390000, code, String::new(), None));
+        assert!(map.is_anonymous_code(0xffff9f381000));
+        assert_eq!(map.lookup(0xffff9f381000), None);
+        assert_eq!(
+            map.stack(0xffff9f36f000),
+            Some(0xffff9f36e000..0xffff9f370000)
+        );
+        assert_eq!(map.stack(0xffff9f372000), None);
+        assert_eq!(map.anonymous.len(), 2);
+        assert!(map.files.is_sorted_by_key(|region| region.start));
+    }
+
+    #[test]
+    fn test_from_reader() {
+        assert_eq!(
+            MemoryMap::from_reader(
+                "aaaae8e20000-aaaae8e29000 r-xp 00000000 fe:01 188725                     /usr/bin/cat\n"
+                    .as_bytes()
+            ),
+            Ok(MemoryMap {
+                files: vec![Region {
+                    start: 0xaaaae8e20000,
+                    end: 0xaaaae8e29000,
+                    permissions: "r-xp".parse().unwrap(),
+                    path: String::from("/usr/bin/cat"),
+                    file: Some((libc::makedev(0xfe, 1), 188725)),
+                    ..Default::default()
+                }],
+                anonymous: Vec::new(),
+            })
+        );
+
+        assert!(matches!(
+            MemoryMap::from_proc("/nonexistent", Pid::from_raw(1)),
+            Err(MemoryMapError::ReadFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_strip_deleted() {
+        let dir =
+            std::env::temp_dir().join(format!("crabtrap_strip_deleted_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("libfoo.so.1.2");
+        let link = dir.join("libfoo.so.1");
+        fs::write(&file, "").unwrap();
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+        let link = link.to_string_lossy().into_owned();
+
+        let mut map = MemoryMap::from_str(&format!(
+            "aaaae8e20000-aaaae8e29000 r-xp 00000000 fe:01 188725                     {link}
+ffff9f390000-ffff9f517000 r-xp 00000000 fe:01 319964                     /usr/lib/libbar.so (deleted)"
+        ))
+        .unwrap();
+        map.strip_deleted();
+        // Whatever the path leads to here may not be what it leads to for the tracee
+        assert_eq!(map.lookup(0xaaaae8e20000), Some(link.as_str()));
+        assert!(!map.is_deleted(&link));
+        assert_eq!(map.lookup(0xffff9f390000), Some("/usr/lib/libbar.so"));
+        assert!(map.is_deleted("/usr/lib/libbar.so"));
+
+        // Mappings patched in from mmap get the same treatment
+        map.insert(
+            0xffff9f600000,
+            0xffff9f610000,
+            "r-xp".parse().unwrap(),
+            format!("{link}{DELETED}"),
+            None,
+        );
+        assert_eq!(map.lookup(0xffff9f600000), Some(link.as_str()));
+        assert!(map.is_deleted(&link));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_strip_root() {
+        let mut map = MemoryMap::from_str("aaaae8e20000-aaaae8e29000 r-xp 00000000 fe:01 188725                     /var/lib/containers/abc/usr/bin/cat
+ffff9f390000-ffff9f517000 r-xp 00000000 fe:01 319964                     /var/lib/containers/abcdef/libc.so.6
+ffff9f52c000-ffff9f530000 r--p 0018c000 fe:01 319964                     /opt/shared/libfoo.so").unwrap();
+        map.strip_root(Path::new("/var/lib/containers/abc"));
+        assert_eq!(
+            map.files
+                .iter()
+                .map(|file| file.path.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "/usr/bin/cat",
+                "/var/lib/containers/abcdef/libc.so.6",
+                "/opt/shared/libfoo.so"
+            ]
+        );
+
+        // Outside of any chroot the root is /, which changes nothing
+        let before = map.clone();
+        map.strip_root(Path::new("/"));
+        assert_eq!(map, before);
     }
 }
@@ -0,0 +1,148 @@
+use nix::{
+    sys::{
+        ptrace::{read, write, AddressType},
+        uio::{process_vm_readv, RemoteIoVec},
+    },
+    unistd::Pid,
+};
+use std::{collections::HashMap, io::IoSliceMut, mem};
+
+const WORD: usize = mem::size_of::<i64>();
+
+/// How much MemoryReader reads at once. Chunks are aligned to it, so as a page they never cross the edge of a mapping.
+const CHUNK: usize = 4096;
+
+/// Longest string we'll read out of a tracee, which is enough for any path the kernel would accept
+const MAX_STRING: usize = 4096;
+
+/// read_string reads a nul-terminated string out of the tracee's memory, a word at a time.
+pub fn read_string(pid: Pid, addr: u64) -> Option<String> {
+    if addr == 0 {
+        return None;
+    }
+
+    let mut bytes = Vec::new();
+    while bytes.len() < MAX_STRING {
+        let word = read(pid, (addr + bytes.len() as u64) as AddressType)
+            .ok()?
+            .to_ne_bytes();
+        match word.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&word[..end]);
+                return Some(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            None => bytes.extend_from_slice(&word),
+        }
+    }
+    None
+}
+
+/// read_remote reads all of `buf` out of the tracee's memory in one process_vm_readv call, which fails if the kernel
+/// doesn't have it, or if any of it isn't mapped.
+fn read_remote(pid: Pid, addr: u64, buf: &mut [u8]) -> bool {
+    let len = buf.len();
+    let remote = [RemoteIoVec {
+        base: addr as usize,
+        len,
+    }];
+    process_vm_readv(pid, &mut [IoSliceMut::new(buf)], &remote) == Ok(len)
+}
+
+/// read_bytes reads `len` bytes out of the tracee's memory, all at once if it can, or else a word at a time. The last
+/// word is read so it ends where the buffer does, in case the buffer ends at the edge of a mapping.
+pub fn read_bytes(pid: Pid, addr: u64, len: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    if read_remote(pid, addr, &mut bytes) {
+        return Some(bytes);
+    }
+
+    bytes.clear();
+    while bytes.len() < len {
+        let offset = bytes.len();
+        let start = if len - offset < WORD && len >= WORD {
+            len - WORD
+        } else {
+            offset
+        };
+        let word = read(pid, (addr + start as u64) as AddressType)
+            .ok()?
+            .to_ne_bytes();
+        bytes.extend_from_slice(&word[offset - start..]);
+    }
+    bytes.truncate(len);
+    Some(bytes)
+}
+
+/// write_bytes copies `bytes` into the tracee's memory at `addr`, a word at a time.
+pub fn write_bytes(pid: Pid, addr: u64, bytes: &[u8]) {
+    for (i, chunk) in bytes.chunks(WORD).enumerate() {
+        let addr = (addr + (i * WORD) as u64) as AddressType;
+        let mut word = [0; WORD];
+        if chunk.len() < WORD {
+            // Keep whatever follows the buffer
+            word = read(pid, addr)
+                .expect("failed to read tracee memory")
+                .to_ne_bytes();
+        }
+        word[..chunk.len()].copy_from_slice(chunk);
+        write(pid, addr, i64::from_ne_bytes(word)).expect("failed to write tracee memory");
+    }
+}
+
+/// MemoryReader: reads words out of a tracee's memory a chunk at a time, for walking its stack, where each frame is
+/// near the last. Each chunk takes one process_vm_readv call, and where that doesn't work it falls back to reading a
+/// word at a time with ptrace. Only good for one stop, since the chunks are kept.
+pub struct MemoryReader {
+    pid: Pid,
+    /// By their start, or None if they couldn't be read in one go
+    chunks: HashMap<u64, Option<Vec<u8>>>,
+}
+
+impl MemoryReader {
+    pub fn new(pid: Pid) -> MemoryReader {
+        MemoryReader {
+            pid,
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn word(&mut self, addr: u64) -> Option<u64> {
+        let start = addr & !(CHUNK as u64 - 1);
+        let offset = (addr - start) as usize;
+        let chunk = self.chunks.entry(start).or_insert_with(|| {
+            let mut chunk = vec![0; CHUNK];
+            read_remote(self.pid, start, &mut chunk).then_some(chunk)
+        });
+        match chunk
+            .as_ref()
+            .and_then(|chunk| chunk.get(offset..offset + WORD))
+        {
+            Some(word) => Some(u64::from_ne_bytes(word.try_into().unwrap())),
+            None => read(self.pid, addr as AddressType)
+                .ok()
+                .map(|word| word as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::getpid;
+
+    #[test]
+    fn test_memory_reader() {
+        // A process can read its own memory without tracing itself
+        let words: Vec<u64> = (0..2048).collect();
+        let mut reader = MemoryReader::new(getpid());
+        for i in [0, 1, 1000, 2047] {
+            assert_eq!(reader.word(&words[i] as *const u64 as u64), Some(i as u64));
+        }
+
+        let bytes = b"crabtrap";
+        assert_eq!(
+            read_bytes(getpid(), bytes.as_ptr() as u64, bytes.len()),
+            Some(bytes.to_vec())
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use nix::libc;
+use serde::{de, Deserialize, Deserializer, Serializer};
+use std::{collections::BTreeSet, ffi::CStr, str::FromStr, sync::OnceLock};
+use syscalls::Sysno;
+
+/// SyscallName: a syscall as written in a file, either by name or by its number on this architecture
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SyscallName {
+    Number(u32),
+    Name(String),
+}
+
+/// The built-in syscall groups, which can be written in a set of syscalls as e.g. `@network`
+const GROUPS: &[(&str, &[Sysno])] = &[
+    (
+        "network",
+        &[
+            Sysno::socket,
+            Sysno::socketpair,
+            Sysno::bind,
+            Sysno::listen,
+            Sysno::accept,
+            Sysno::accept4,
+            Sysno::connect,
+            Sysno::getsockname,
+            Sysno::getpeername,
+            Sysno::sendto,
+            Sysno::recvfrom,
+            Sysno::setsockopt,
+            Sysno::getsockopt,
+            Sysno::shutdown,
+            Sysno::sendmsg,
+            Sysno::recvmsg,
+            Sysno::sendmmsg,
+            Sysno::recvmmsg,
+        ],
+    ),
+    (
+        "file-read",
+        &[
+            Sysno::openat,
+            Sysno::openat2,
+            Sysno::read,
+            Sysno::readv,
+            Sysno::pread64,
+            Sysno::preadv,
+            Sysno::preadv2,
+            Sysno::lseek,
+            Sysno::getdents64,
+            Sysno::readlinkat,
+            Sysno::fstatat,
+            Sysno::fstat,
+            Sysno::statx,
+            Sysno::faccessat,
+            Sysno::faccessat2,
+        ],
+    ),
+    (
+        "file-write",
+        &[
+            Sysno::write,
+            Sysno::writev,
+            Sysno::pwrite64,
+            Sysno::pwritev,
+            Sysno::pwritev2,
+            Sysno::truncate,
+            Sysno::ftruncate,
+            Sysno::fallocate,
+            Sysno::copy_file_range,
+            Sysno::sendfile,
+            Sysno::mkdirat,
+            Sysno::mknodat,
+            Sysno::unlinkat,
+            Sysno::renameat,
+            Sysno::renameat2,
+            Sysno::symlinkat,
+            Sysno::linkat,
+            Sysno::fchmod,
+            Sysno::fchmodat,
+            Sysno::fchown,
+            Sysno::fchownat,
+            Sysno::utimensat,
+        ],
+    ),
+    (
+        "process",
+        &[
+            Sysno::clone,
+            Sysno::clone3,
+            Sysno::execve,
+            Sysno::execveat,
+            Sysno::exit,
+            Sysno::exit_group,
+            Sysno::wait4,
+            Sysno::waitid,
+            Sysno::kill,
+            Sysno::tkill,
+            Sysno::tgkill,
+            Sysno::setsid,
+            Sysno::setpgid,
+            Sysno::prctl,
+        ],
+    ),
+    (
+        "memory",
+        &[
+            Sysno::brk,
+            Sysno::mmap,
+            Sysno::munmap,
+            Sysno::mremap,
+            Sysno::mprotect,
+            Sysno::madvise,
+            Sysno::msync,
+            Sysno::mlock,
+            Sysno::munlock,
+        ],
+    ),
+];
+
+/// Syscall names from other architectures, and what this one does the same with. aarch64 and riscv64 have no
+/// `open`, for instance, so libc opens files with `openat`.
+const ALIASES: &[(&str, &[&str])] = &[
+    ("open", &["openat", "openat2"]),
+    ("creat", &["openat", "openat2"]),
+    ("stat", &["fstatat", "statx"]),
+    ("lstat", &["fstatat", "statx"]),
+    ("stat64", &["fstatat", "statx"]),
+    ("lstat64", &["fstatat", "statx"]),
+    ("newfstatat", &["fstatat"]),
+    ("fstatat64", &["fstatat"]),
+    ("fstat64", &["fstat"]),
+    ("access", &["faccessat", "faccessat2"]),
+    ("fork", &["clone", "clone3"]),
+    ("vfork", &["clone", "clone3"]),
+    ("pipe", &["pipe2"]),
+    ("dup2", &["dup3"]),
+    ("poll", &["ppoll"]),
+    ("select", &["pselect6"]),
+    ("epoll_wait", &["epoll_pwait", "epoll_pwait2"]),
+    ("epoll_create", &["epoll_create1"]),
+    ("eventfd", &["eventfd2"]),
+    ("signalfd", &["signalfd4"]),
+    ("inotify_init", &["inotify_init1"]),
+    ("unlink", &["unlinkat"]),
+    ("rmdir", &["unlinkat"]),
+    ("mkdir", &["mkdirat"]),
+    ("rename", &["renameat", "renameat2"]),
+    ("link", &["linkat"]),
+    ("symlink", &["symlinkat"]),
+    ("readlink", &["readlinkat"]),
+    ("chmod", &["fchmodat", "fchmodat2"]),
+    ("chown", &["fchownat"]),
+    ("lchown", &["fchownat"]),
+    ("getdents", &["getdents64"]),
+    ("utimes", &["utimensat"]),
+    ("futimesat", &["utimensat"]),
+];
+
+/// The kernel versions that added syscalls aliases can stand for, which older kernels leave out
+const INTRODUCED: &[(&str, (u32, u32))] = &[
+    ("renameat2", (3, 15)),
+    ("statx", (4, 11)),
+    ("clone3", (5, 3)),
+    ("openat2", (5, 6)),
+    ("faccessat2", (5, 8)),
+    ("epoll_pwait2", (5, 11)),
+    ("fchmodat2", (6, 6)),
+];
+
+/// kernel_version returns the major and minor version of the running kernel, or (0, 0) if it can't be told.
+fn kernel_version() -> (u32, u32) {
+    static VERSION: OnceLock<(u32, u32)> = OnceLock::new();
+    *VERSION.get_or_init(|| {
+        let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+        if unsafe { libc::uname(&mut uts) } != 0 {
+            return (0, 0);
+        }
+        let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy();
+        parse_version(&release)
+    })
+}
+
+/// parse_version reads the start of a kernel release like `6.1.0-18-arm64`.
+fn parse_version(release: &str) -> (u32, u32) {
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// alias returns the syscalls an alias stands for on this architecture and kernel, or None if it isn't one.
+pub fn alias(name: &str) -> Option<Vec<Sysno>> {
+    let (_, targets) = ALIASES.iter().find(|(alias, _)| *alias == name)?;
+    let kernel = kernel_version();
+    Some(
+        targets
+            .iter()
+            .filter(|target| {
+                INTRODUCED
+                    .iter()
+                    .find(|(introduced, _)| introduced == *target)
+                    .is_none_or(|(_, version)| kernel >= *version)
+            })
+            .filter_map(|target| Sysno::from_str(target).ok())
+            .collect(),
+    )
+}
+
+/// group returns the syscalls in the built-in group with this name, which doesn't include the `@`.
+pub fn group(name: &str) -> Option<&'static [Sysno]> {
+    GROUPS
+        .iter()
+        .find(|(group, _)| *group == name)
+        .map(|(_, syscalls)| *syscalls)
+}
+
+impl SyscallName {
+    fn parse<E: de::Error>(self) -> Result<Sysno, E> {
+        match self {
+            SyscallName::Number(id) => Sysno::new(id as usize)
+                .ok_or_else(|| E::custom(format!("unknown syscall number {id}"))),
+            SyscallName::Name(name) => {
+                Sysno::from_str(&name).map_err(|_| E::custom(format!("unknown syscall {name}")))
+            }
+        }
+    }
+
+    /// expand is parse, but also takes the name of a built-in group, or an alias for a syscall this architecture
+    /// doesn't have.
+    fn expand<E: de::Error>(self) -> Result<Vec<Sysno>, E> {
+        match &self {
+            SyscallName::Name(name) if name.starts_with('@') => group(&name[1..])
+                .map(<[Sysno]>::to_vec)
+                .ok_or_else(|| E::custom(format!("unknown syscall group {name}"))),
+            SyscallName::Name(name) if Sysno::from_str(name).is_err() => {
+                alias(name).ok_or_else(|| E::custom(format!("unknown syscall {name}")))
+            }
+            _ => Ok(vec![self.parse()?]),
+        }
+    }
+}
+
+/// Deserializes a syscall from either its name or its number.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Sysno, D::Error> {
+    SyscallName::deserialize(deserializer)?.parse()
+}
+
+/// Serializes optional sets of syscalls by name, and deserializes them from names, numbers or built-in groups.
+pub mod set {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        syscalls: &Option<BTreeSet<Sysno>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match syscalls {
+            Some(syscalls) => serializer.collect_seq(syscalls.iter().map(Sysno::name)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<BTreeSet<Sysno>>, D::Error> {
+        let Some(names) = Option::<Vec<SyscallName>>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        let mut syscalls = BTreeSet::new();
+        for name in names {
+            syscalls.extend(name.expand()?);
+        }
+        Ok(Some(syscalls))
+    }
+}
+
+/// Serializes maps keyed by syscall with names as the keys, and deserializes them from names or built-in groups, with
+/// everything in a group getting the same value.
+pub mod map {
+    use super::*;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer, V: Serialize>(
+        map: &BTreeMap<Sysno, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(syscall, value)| (syscall.name(), value)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de> + Clone>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Sysno, V>, D::Error> {
+        let mut map = BTreeMap::new();
+        for (name, value) in BTreeMap::<String, V>::deserialize(deserializer)? {
+            for syscall in SyscallName::Name(name).expand()? {
+                map.insert(syscall, value.clone());
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// Deserializes named groups of syscalls, keeping each member as it was written. Numbers become strings too.
+pub mod groups {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, Vec<String>>, D::Error> {
+        let groups = BTreeMap::<String, Vec<SyscallName>>::deserialize(deserializer)?;
+        Ok(groups
+            .into_iter()
+            .map(|(group, members)| {
+                let members = members.into_iter().map(|member| match member {
+                    SyscallName::Number(id) => id.to_string(),
+                    SyscallName::Name(name) => name,
+                });
+                (group, members.collect())
+            })
+            .collect())
+    }
+}
+
+/// Serializes errors by name, e.g. `ECONNREFUSED`.
+pub mod errno {
+    use super::*;
+    use nix::errno::Errno;
+
+    pub fn serialize<S: Serializer>(errno: &Errno, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{errno:?}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Errno, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        // Errno has no FromStr, but its Debug output is the name
+        (1..4096)
+            .map(Errno::from_raw)
+            .find(|errno| format!("{errno:?}") == name)
+            .ok_or_else(|| de::Error::custom(format!("unknown errno {name}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct Entry {
+        #[serde(deserialize_with = "super::deserialize", skip_serializing)]
+        syscall: Sysno,
+        #[serde(default, with = "set")]
+        syscalls: Option<BTreeSet<Sysno>>,
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(
+            serde_yaml::from_str::<Entry>("syscall: write\nsyscalls: [read, 63]").unwrap(),
+            Entry {
+                syscall: Sysno::write,
+                syscalls: Some(BTreeSet::from([Sysno::read])),
+            }
+        );
+        assert_eq!(
+            serde_yaml::from_str::<Entry>("syscall: 64").unwrap(),
+            Entry {
+                syscall: Sysno::write,
+                syscalls: None,
+            }
+        );
+        assert!(serde_yaml::from_str::<Entry>("syscall: not_a_syscall").is_err());
+        assert!(serde_yaml::from_str::<Entry>("syscall: '@network'").is_err());
+
+        let grouped =
+            serde_yaml::from_str::<Entry>("syscall: write\nsyscalls: ['@memory', read]").unwrap();
+        assert_eq!(grouped.syscalls.as_ref().map(BTreeSet::len), Some(10));
+        assert!(grouped.syscalls.unwrap().contains(&Sysno::mmap));
+        assert!(serde_yaml::from_str::<Entry>("syscall: write\nsyscalls: ['@nope']").is_err());
+
+        // Neither architecture we trace has open or newfstatat
+        let aliased =
+            serde_yaml::from_str::<Entry>("syscall: write\nsyscalls: [open, newfstatat]").unwrap();
+        let aliased = aliased.syscalls.unwrap();
+        assert!(aliased.contains(&Sysno::openat));
+        assert!(aliased.contains(&Sysno::fstatat));
+        assert!(!aliased.contains(&Sysno::statx));
+        // Aliases are only for syscall sets
+        assert!(serde_yaml::from_str::<Entry>("syscall: open").is_err());
+        assert_eq!(parse_version("6.1.0-18-arm64"), (6, 1));
+        assert_eq!(parse_version("5.15.0"), (5, 15));
+
+        assert_eq!(
+            serde_yaml::to_string(&Entry {
+                syscall: Sysno::write,
+                syscalls: Some(BTreeSet::from([Sysno::read])),
+            })
+            .unwrap(),
+            "syscalls:\n- read\n"
+        );
+    }
+}
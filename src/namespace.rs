@@ -0,0 +1,257 @@
+use crate::config::Namespaces;
+use nix::{
+    errno::Errno,
+    libc,
+    sched::{unshare, CloneFlags},
+    unistd::{self, ForkResult, Pid},
+};
+use std::{
+    ffi::{CStr, CString},
+    mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+/// Setup: what it takes to move into the new namespaces, worked out before forking since the child mustn't allocate
+pub struct Setup {
+    network: bool,
+    loopback: bool,
+    /// Whether the user namespace still has to be made, rather than having been made along with the PID namespace
+    user: bool,
+    ids: Option<IdMaps>,
+    mounts: Option<Mounts>,
+}
+
+/// IdMaps: what to write to a new user namespace's uid_map and gid_map
+struct IdMaps {
+    uid: Vec<u8>,
+    gid: Vec<u8>,
+}
+
+/// Mounts: the new root and what to bind into it
+struct Mounts {
+    root: CString,
+    /// Each directory to bind read-only, and where it goes under the new root
+    binds: Vec<(CString, CString)>,
+}
+
+impl Setup {
+    /// new fails with EINVAL if a path has a NUL byte in it, which a config that's been loaded can't have.
+    pub fn new(namespaces: &Namespaces) -> nix::Result<Setup> {
+        // Each id maps to itself, since a process can map its own ids without any privileges
+        let ids = namespaces.user.then(|| {
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            IdMaps {
+                uid: format!("{uid} {uid} 1").into_bytes(),
+                gid: format!("{gid} {gid} 1").into_bytes(),
+            }
+        });
+        Ok(Setup {
+            network: namespaces.network,
+            loopback: namespaces.loopback,
+            user: namespaces.user && !namespaces.pid,
+            ids,
+            mounts: Mounts::new(namespaces)?,
+        })
+    }
+
+    /// apply moves the calling process into the new namespaces, which the processes it starts share, and into the
+    /// new root if there is one. The user namespace comes first, since it's what lets the rest be made without
+    /// privileges. Safe to call between fork and exec.
+    pub fn apply(&self) -> nix::Result<()> {
+        if self.user {
+            unshare(CloneFlags::CLONE_NEWUSER)?;
+        }
+        if let Some(ids) = &self.ids {
+            ids.apply()?;
+        }
+        let mut flags = CloneFlags::empty();
+        if self.network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+        if self.mounts.is_some() {
+            flags |= CloneFlags::CLONE_NEWNS;
+        }
+        if flags.is_empty() {
+            return Ok(());
+        }
+        unshare(flags)?;
+        if self.network && self.loopback {
+            loopback_up()?;
+        }
+        if let Some(mounts) = &self.mounts {
+            mounts.apply()?;
+        }
+        Ok(())
+    }
+}
+
+impl IdMaps {
+    fn apply(&self) -> nix::Result<()> {
+        // Without privileges in the parent namespace, setgroups has to be turned off before gid_map can be written
+        write(c"/proc/self/setgroups", b"deny")?;
+        write(c"/proc/self/uid_map", &self.uid)?;
+        write(c"/proc/self/gid_map", &self.gid)
+    }
+}
+
+/// write writes `contents` to the file at `path` in a single write, as procfs's control files want.
+fn write(path: &CStr, contents: &[u8]) -> nix::Result<()> {
+    let fd = Errno::result(unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) })?;
+    let written =
+        Errno::result(unsafe { libc::write(fd, contents.as_ptr().cast(), contents.len()) });
+    unsafe { libc::close(fd) };
+    written.map(drop)
+}
+
+impl Mounts {
+    /// new returns the mounts for the configured root, if there is one.
+    fn new(namespaces: &Namespaces) -> nix::Result<Option<Mounts>> {
+        let Some(root) = namespaces.root.as_deref() else {
+            return Ok(None);
+        };
+        let path =
+            |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL);
+        let binds = namespaces
+            .read_only_binds
+            .iter()
+            .map(|bind| {
+                let target: PathBuf = root.join(bind.strip_prefix("/").unwrap_or(bind));
+                Ok((path(bind)?, path(&target)?))
+            })
+            .collect::<nix::Result<_>>()?;
+        Ok(Some(Mounts {
+            root: path(root)?,
+            binds,
+        }))
+    }
+
+    /// apply binds the directories into the new root and switches to it. The calling process has to be in a mount
+    /// namespace of its own already.
+    fn apply(&self) -> nix::Result<()> {
+        // Keep what happens here from spreading back to the namespace it came from
+        mount(None, c"/", libc::MS_REC | libc::MS_PRIVATE)?;
+        // pivot_root needs the new root to be a mount point
+        mount(Some(&self.root), &self.root, libc::MS_BIND | libc::MS_REC)?;
+        for (source, target) in &self.binds {
+            match Errno::result(unsafe { libc::mkdir(target.as_ptr(), 0o755) }) {
+                Ok(_) | Err(Errno::EEXIST) => {}
+                Err(errno) => return Err(errno),
+            }
+            mount(Some(source), target, libc::MS_BIND | libc::MS_REC)?;
+            read_only(source, target)?;
+        }
+        // Pivoting onto the directory we're in stacks the old root on top of the new one, where it can be detached
+        // without needing anywhere inside the new root to put it
+        Errno::result(unsafe { libc::chdir(self.root.as_ptr()) })?;
+        Errno::result(unsafe {
+            libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr())
+        })?;
+        Errno::result(unsafe { libc::umount2(c".".as_ptr(), libc::MNT_DETACH) })?;
+        Errno::result(unsafe { libc::chdir(c"/".as_ptr()) }).map(drop)
+    }
+}
+
+/// read_only makes a bind mount read-only, along with everything mounted under it, leaving its other flags like nosuid
+/// and noexec as they are. Kernels before 5.12 have no mount_setattr to do that with, and finding what's mounted
+/// under it would take reading the mount table, which the child can't allocate for, so there it's bound again
+/// without the mounts under it.
+fn read_only(source: &CStr, target: &CStr) -> nix::Result<()> {
+    let attr = libc::mount_attr {
+        attr_set: libc::MOUNT_ATTR_RDONLY,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    match Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            target.as_ptr(),
+            libc::AT_RECURSIVE,
+            &attr,
+            mem::size_of::<libc::mount_attr>(),
+        )
+    }) {
+        Err(Errno::ENOSYS) => {}
+        result => return result.map(drop),
+    }
+    Errno::result(unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) })?;
+    mount(Some(source), target, libc::MS_BIND)?;
+    // The read-only flag is ignored on the bind itself, so it takes a remount. That sets every flag afresh, so the
+    // ones the source has have to be passed along, or they'd be cleared, which a user namespace isn't allowed to do.
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    Errno::result(unsafe { libc::statvfs(target.as_ptr(), &mut stat) })?;
+    let kept = [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ]
+    .into_iter()
+    .filter(|&(st, _)| stat.f_flag & st != 0)
+    .fold(0, |flags, (_, ms)| flags | ms);
+    mount(
+        None,
+        target,
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | kept,
+    )
+}
+
+/// mount mounts `source` at `target` with no filesystem type or data, which covers binds and changes to mounts that
+/// are already there.
+fn mount(source: Option<&CStr>, target: &CStr, flags: libc::c_ulong) -> nix::Result<()> {
+    let source = source.map_or(ptr::null(), CStr::as_ptr);
+    Errno::result(unsafe { libc::mount(source, target.as_ptr(), ptr::null(), flags, ptr::null()) })
+        .map(drop)
+}
+
+/// fork forks, and starts the child off as init of a new PID namespace if there's to be one. Like `unistd::fork` the
+/// child mustn't allocate.
+pub unsafe fn fork(namespaces: &Namespaces) -> nix::Result<ForkResult> {
+    if !namespaces.pid {
+        return unistd::fork();
+    }
+    // unshare would only put the caller's children in the namespace, not the caller, so it takes a clone. Without a
+    // stack it carries on from here like a fork. The user namespace has to come along with it, so that it owns the
+    // PID namespace and can be what lets it be made.
+    let mut flags = libc::CLONE_NEWPID | libc::SIGCHLD;
+    if namespaces.user {
+        flags |= libc::CLONE_NEWUSER;
+    }
+    match Errno::result(libc::syscall(
+        libc::SYS_clone,
+        flags as libc::c_ulong,
+        0,
+        0,
+        0,
+        0,
+    ))? {
+        0 => Ok(ForkResult::Child),
+        child => Ok(ForkResult::Parent {
+            child: Pid::from_raw(child as i32),
+        }),
+    }
+}
+
+/// loopback_up brings up the loopback interface, which starts off down in a new network namespace.
+fn loopback_up() -> nix::Result<()> {
+    let socket = Errno::result(unsafe {
+        libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0)
+    })?;
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (to, &from) in request.ifr_name.iter_mut().zip(b"lo") {
+        *to = from as libc::c_char;
+    }
+    let result =
+        Errno::result(unsafe { libc::ioctl(socket, libc::SIOCGIFFLAGS as _, &mut request) })
+            .and_then(|_| {
+                unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+                Errno::result(unsafe { libc::ioctl(socket, libc::SIOCSIFFLAGS as _, &request) })
+            });
+    unsafe { libc::close(socket) };
+    result.map(drop)
+}
@@ -0,0 +1,499 @@
+use crate::{
+    fd::FdTable,
+    filesystem,
+    glob::{self, Globs},
+    memory::read_bytes,
+};
+use nix::{libc, unistd::Pid};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, mem,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+    str::FromStr,
+};
+use syscalls::Sysno;
+
+/// The most messages sendmmsg sends in one call
+const UIO_MAXIOV: u32 = 1024;
+
+/// Destination: where a socket is being connected or sent to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Ip(SocketAddr),
+    /// A unix socket's path, or its name starting with `@` if it's abstract
+    Unix(String),
+    /// An address family there are no rules for
+    Other(i32),
+}
+
+/// destinations reads the addresses a connect, sendto, sendmsg or sendmmsg entry is sending to, one for each message
+/// that names one. Messages that don't, like sendto on a connected socket, are left out, and a None is for an address
+/// that couldn't be read.
+///
+/// Unix socket paths are resolved in the tracee's root the way the kernel will find them, so `..` and symlinks can't
+/// take one out from under an allowed prefix, and a socket that can't be found is a None.
+pub fn destinations(
+    pid: Pid,
+    syscall: Sysno,
+    args: &[u64; 6],
+    fds: &mut FdTable,
+    proc_root: &Path,
+) -> Vec<Option<Destination>> {
+    let resolve = |destination| match destination {
+        Some(Destination::Unix(path)) if !path.starts_with('@') => {
+            filesystem::socket_path(pid, Path::new(&path), fds, proc_root)
+                .map(|path| Destination::Unix(path.to_string_lossy().into_owned()))
+        }
+        destination => destination,
+    };
+    let destinations: Vec<_> = match syscall {
+        Sysno::connect => address(pid, args[1], args[2]).into_iter().collect(),
+        Sysno::sendto => address(pid, args[4], args[5]).into_iter().collect(),
+        Sysno::sendmsg => message(pid, args[1]).into_iter().collect(),
+        Sysno::sendmmsg => {
+            // The kernel sends at most UIO_MAXIOV of them, and each is a msghdr followed by the length sent
+            let count = (args[2] as u32).min(UIO_MAXIOV) as u64;
+            (0..count)
+                .filter_map(|i| {
+                    let offset = i * mem::size_of::<libc::mmsghdr>() as u64;
+                    match args[1].checked_add(offset) {
+                        Some(addr) => message(pid, addr),
+                        None => Some(None),
+                    }
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+    destinations.into_iter().map(resolve).collect()
+}
+
+/// message reads the address a struct msghdr at `addr` sends to, like address.
+fn message(pid: Pid, addr: u64) -> Option<Option<Destination>> {
+    // msg_name and msg_namelen are the first fields of struct msghdr
+    let Some(header) = read_bytes(pid, addr, 12) else {
+        return Some(None);
+    };
+    address(
+        pid,
+        u64::from_ne_bytes(header[..8].try_into().unwrap()),
+        u32::from_ne_bytes(header[8..].try_into().unwrap()) as u64,
+    )
+}
+
+/// address reads a sockaddr of `len` bytes at `addr`. It's None if there isn't one, and Some(None) if it couldn't be
+/// read.
+fn address(pid: Pid, addr: u64, len: u64) -> Option<Option<Destination>> {
+    if addr == 0 {
+        return None;
+    }
+    let len = (len as usize).min(mem::size_of::<libc::sockaddr_storage>());
+    Some(read_bytes(pid, addr, len).and_then(|bytes| parse_sockaddr(&bytes)))
+}
+
+/// parse_sockaddr decodes a sockaddr as the tracee laid it out.
+fn parse_sockaddr(bytes: &[u8]) -> Option<Destination> {
+    let family = u16::from_ne_bytes(bytes.get(..2)?.try_into().ok()?) as i32;
+    let port = || Some(u16::from_be_bytes(bytes.get(2..4)?.try_into().ok()?));
+    match family {
+        libc::AF_INET => {
+            let ip: [u8; 4] = bytes.get(4..8)?.try_into().ok()?;
+            Some(Destination::Ip(SocketAddr::from((ip, port()?))))
+        }
+        libc::AF_INET6 => {
+            let ip: [u8; 16] = bytes.get(8..24)?.try_into().ok()?;
+            Some(Destination::Ip(SocketAddr::from((
+                Ipv6Addr::from(ip),
+                port()?,
+            ))))
+        }
+        libc::AF_UNIX => {
+            let path = &bytes[2..];
+            Some(Destination::Unix(match path.split_first() {
+                // Abstract names go by the length given, and can hold nuls
+                Some((0, name)) => format!("@{}", String::from_utf8_lossy(name)),
+                _ => {
+                    let end = path
+                        .iter()
+                        .position(|&byte| byte == 0)
+                        .unwrap_or(path.len());
+                    String::from_utf8_lossy(&path[..end]).into_owned()
+                }
+            }))
+        }
+        family => Some(Destination::Other(family)),
+    }
+}
+
+/// SocketDomain: the address families rules can name
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketDomain {
+    Unix,
+    Inet,
+    Inet6,
+    Netlink,
+    Packet,
+}
+
+impl SocketDomain {
+    const NAMES: [(SocketDomain, &'static str); 5] = [
+        (SocketDomain::Unix, "unix"),
+        (SocketDomain::Inet, "inet"),
+        (SocketDomain::Inet6, "inet6"),
+        (SocketDomain::Netlink, "netlink"),
+        (SocketDomain::Packet, "packet"),
+    ];
+
+    pub fn from_raw(domain: i32) -> Option<SocketDomain> {
+        match domain {
+            libc::AF_UNIX => Some(SocketDomain::Unix),
+            libc::AF_INET => Some(SocketDomain::Inet),
+            libc::AF_INET6 => Some(SocketDomain::Inet6),
+            libc::AF_NETLINK => Some(SocketDomain::Netlink),
+            libc::AF_PACKET => Some(SocketDomain::Packet),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for SocketDomain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SocketDomain, String> {
+        SocketDomain::NAMES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(domain, _)| *domain)
+            .ok_or_else(|| format!("unknown socket domain {s}"))
+    }
+}
+
+impl fmt::Display for SocketDomain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (_, name) = SocketDomain::NAMES
+            .iter()
+            .find(|(domain, _)| domain == self)
+            .unwrap();
+        write!(f, "{name}")
+    }
+}
+
+/// SocketType: the kinds of socket rules can name
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketType {
+    Stream,
+    Dgram,
+    Raw,
+    Seqpacket,
+}
+
+impl SocketType {
+    /// from_raw ignores the flags that can be or'd into the type, like SOCK_CLOEXEC.
+    pub fn from_raw(kind: i32) -> Option<SocketType> {
+        match kind & !(libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK) {
+            libc::SOCK_STREAM => Some(SocketType::Stream),
+            libc::SOCK_DGRAM => Some(SocketType::Dgram),
+            libc::SOCK_RAW => Some(SocketType::Raw),
+            libc::SOCK_SEQPACKET => Some(SocketType::Seqpacket),
+            _ => None,
+        }
+    }
+}
+
+/// DestinationRule: somewhere a library may connect or send to. Written as an address or network with an optional
+/// port, like `10.0.0.0/8:443`, `127.0.0.1` or `[fd00::/8]:53`, or as a unix socket path, prefix or glob, with
+/// abstract names starting with `@`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum DestinationRule {
+    Ip {
+        network: IpAddr,
+        prefix: u8,
+        /// Any port if None
+        port: Option<u16>,
+    },
+    Unix(String),
+}
+
+impl DestinationRule {
+    /// matches checks a destination against the rule, with the config's `globs`.
+    pub fn matches(&self, destination: &Destination, globs: &Globs) -> bool {
+        match (self, destination) {
+            (
+                DestinationRule::Ip {
+                    network,
+                    prefix,
+                    port,
+                },
+                Destination::Ip(addr),
+            ) => {
+                port.is_none_or(|port| port == addr.port())
+                    && in_network(addr.ip().to_canonical(), *network, *prefix)
+            }
+            (DestinationRule::Unix(pattern), Destination::Unix(path)) => {
+                if glob::is_pattern(pattern) {
+                    globs.matches(pattern, path)
+                } else {
+                    Path::new(path).starts_with(pattern)
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// in_network returns whether the first `prefix` bits of `ip` are those of `network`.
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+impl FromStr for DestinationRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DestinationRule, String> {
+        if s.starts_with(['/', '@']) {
+            return Ok(DestinationRule::Unix(s.to_string()));
+        }
+        let (network, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (network, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("missing ] in destination {s}"))?;
+            match rest {
+                "" => (network, None),
+                rest => (
+                    network,
+                    Some(
+                        rest.strip_prefix(':')
+                            .ok_or_else(|| format!("expected :port after ] in destination {s}"))?,
+                    ),
+                ),
+            }
+        } else if s.matches(':').count() > 1 {
+            // An IPv6 address without a port
+            (s, None)
+        } else {
+            match s.split_once(':') {
+                Some((network, port)) => (network, Some(port)),
+                None => (s, None),
+            }
+        };
+
+        let port = port
+            .filter(|port| *port != "*")
+            .map(|port| port.parse())
+            .transpose()
+            .map_err(|e| format!("bad port in destination {s}: {e}"))?;
+        let (address, prefix) = match network.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (network, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|e| format!("bad address in destination {s}: {e}"))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= bits)
+                .ok_or_else(|| format!("bad prefix length in destination {s}"))?,
+            None => bits,
+        };
+        Ok(DestinationRule::Ip {
+            network,
+            prefix,
+            port,
+        })
+    }
+}
+
+impl TryFrom<String> for DestinationRule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<DestinationRule, String> {
+        s.parse()
+    }
+}
+
+impl From<DestinationRule> for String {
+    fn from(rule: DestinationRule) -> String {
+        rule.to_string()
+    }
+}
+
+impl fmt::Display for DestinationRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DestinationRule::Unix(path) => write!(f, "{path}"),
+            DestinationRule::Ip {
+                network,
+                prefix,
+                port,
+            } => {
+                let bits = if network.is_ipv4() { 32 } else { 128 };
+                let network = if *prefix == bits {
+                    network.to_string()
+                } else {
+                    format!("{network}/{prefix}")
+                };
+                match port {
+                    None => write!(f, "{network}"),
+                    Some(port) if network.contains(':') => write!(f, "[{network}]:{port}"),
+                    Some(port) => write!(f, "{network}:{port}"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sockaddr() {
+        let mut inet = vec![0; 16];
+        inet[..2].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+        inet[2..4].copy_from_slice(&443u16.to_be_bytes());
+        inet[4..8].copy_from_slice(&[10, 1, 2, 3]);
+        assert_eq!(
+            parse_sockaddr(&inet),
+            Some(Destination::Ip("10.1.2.3:443".parse().unwrap()))
+        );
+
+        let mut unix = (libc::AF_UNIX as u16).to_ne_bytes().to_vec();
+        unix.extend(b"/run/nscd/socket\0\0\0");
+        assert_eq!(
+            parse_sockaddr(&unix),
+            Some(Destination::Unix("/run/nscd/socket".into()))
+        );
+        let mut abstract_name = (libc::AF_UNIX as u16).to_ne_bytes().to_vec();
+        abstract_name.extend(b"\0dbus");
+        assert_eq!(
+            parse_sockaddr(&abstract_name),
+            Some(Destination::Unix("@dbus".into()))
+        );
+        assert_eq!(parse_sockaddr(&[0]), None);
+    }
+
+    #[test]
+    fn test_destinations() {
+        let mut inet = [0u8; 16];
+        inet[..2].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+        inet[2..4].copy_from_slice(&443u16.to_be_bytes());
+        inet[4..8].copy_from_slice(&[10, 1, 2, 3]);
+        let mut messages: [libc::mmsghdr; 3] = unsafe { mem::zeroed() };
+        messages[0].msg_hdr.msg_name = inet.as_mut_ptr().cast();
+        messages[0].msg_hdr.msg_namelen = inet.len() as u32;
+        // Sent where the socket's connected
+        messages[1].msg_hdr.msg_name = std::ptr::null_mut();
+        messages[2].msg_hdr.msg_name = inet.as_mut_ptr().cast();
+        messages[2].msg_hdr.msg_namelen = inet.len() as u32;
+        let args = [3, messages.as_ptr() as u64, messages.len() as u64, 0, 0, 0];
+
+        let inet = Some(Destination::Ip("10.1.2.3:443".parse().unwrap()));
+        let pid = nix::unistd::getpid();
+        let mut fds = FdTable::new("/proc");
+        let mut destinations = |syscall, args: &[u64; 6]| {
+            destinations(pid, syscall, args, &mut fds, Path::new("/proc"))
+        };
+        assert_eq!(
+            destinations(Sysno::sendmmsg, &args),
+            vec![inet.clone(), inet.clone()]
+        );
+        assert_eq!(destinations(Sysno::sendmsg, &args), vec![inet.clone()]);
+        assert!(destinations(Sysno::sendto, &args).is_empty());
+
+        // Unix socket paths go by where they lead, not how they're written
+        let globs = Globs::default();
+        let dir =
+            std::env::temp_dir().join(format!("crabtrap_destinations_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("allowed")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        std::fs::write(dir.join("evil.sock"), "").unwrap();
+        std::os::unix::fs::symlink("../evil.sock", dir.join("allowed/link.sock")).unwrap();
+        let allowed = format!("{}/allowed/", dir.display())
+            .parse::<DestinationRule>()
+            .unwrap();
+        let evil = Some(Destination::Unix(
+            dir.join("evil.sock").display().to_string(),
+        ));
+        let sockaddr = |path: &str| {
+            let mut unix = (libc::AF_UNIX as u16).to_ne_bytes().to_vec();
+            unix.extend(path.as_bytes());
+            unix.push(0);
+            unix
+        };
+        let mut connect = |unix: &[u8]| {
+            destinations(
+                Sysno::connect,
+                &[3, unix.as_ptr() as u64, unix.len() as u64, 0, 0, 0],
+            )
+        };
+        for path in ["allowed/../evil.sock", "allowed/link.sock"] {
+            let path = format!("{}/{path}", dir.display());
+            assert!(allowed.matches(&Destination::Unix(path.clone()), &globs));
+            let resolved = connect(&sockaddr(&path));
+            assert_eq!(resolved, vec![evil.clone()]);
+            assert!(!allowed.matches(resolved[0].as_ref().unwrap(), &globs));
+        }
+        // Ones that can't be found can't be checked
+        let missing = format!("{}/missing/../allowed/x.sock", dir.display());
+        assert_eq!(connect(&sockaddr(&missing)), vec![None]);
+        // Abstract names aren't files
+        assert_eq!(
+            connect(&sockaddr("\0dbus")[..7]),
+            vec![Some(Destination::Unix("@dbus".into()))]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_destination_rule() {
+        let rule = |s: &str| s.parse::<DestinationRule>().unwrap();
+        let globs = Globs::new(["@dbus*"]);
+        let ip = |s: &str| Destination::Ip(s.parse().unwrap());
+
+        assert!(rule("10.0.0.0/8:443").matches(&ip("10.1.2.3:443"), &globs));
+        assert!(!rule("10.0.0.0/8:443").matches(&ip("10.1.2.3:80"), &globs));
+        assert!(!rule("10.0.0.0/8:443").matches(&ip("11.1.2.3:443"), &globs));
+        assert!(rule("127.0.0.1").matches(&ip("127.0.0.1:53"), &globs));
+        assert!(rule("0.0.0.0/0:*").matches(&ip("8.8.8.8:53"), &globs));
+        // IPv4 in IPv6 is still IPv4
+        assert!(rule("10.0.0.0/8").matches(&ip("[::ffff:10.0.0.1]:80"), &globs));
+        assert!(rule("[fd00::/8]:53").matches(&ip("[fd12::1]:53"), &globs));
+        assert!(rule("::1").matches(&ip("[::1]:8080"), &globs));
+        assert!(rule("/run/dbus/").matches(
+            &Destination::Unix("/run/dbus/system_bus_socket".into()),
+            &globs
+        ));
+        assert!(rule("@dbus*").matches(&Destination::Unix("@dbus-1234".into()), &globs));
+        assert!(!rule("/run/dbus").matches(&ip("127.0.0.1:80"), &globs));
+
+        for written in [
+            "10.0.0.0/8:443",
+            "127.0.0.1",
+            "[fd00::/8]:53",
+            "::1",
+            "/run/dbus",
+        ] {
+            assert_eq!(rule(written).to_string(), written);
+        }
+        assert!("10.0.0.0/33".parse::<DestinationRule>().is_err());
+        assert!("10.0.0.1:http".parse::<DestinationRule>().is_err());
+        assert!("example.com:443".parse::<DestinationRule>().is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use crate::{config::ConfigError, parent, ptrace_options, ChildExit, Config, Hooks};
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc,
+    sys::{
+        ptrace::{cont, seize, setoptions, Options},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{self, ForkResult, Pid},
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Component, Path, PathBuf},
+    process,
+};
+use thiserror::Error;
+
+/// The annotation naming a config file in the hook's config directory to enforce on the container
+pub const CONFIG_ANNOTATION: &str = "crabtrap.config";
+/// The annotation naming a built-in profile to enforce on the container, for one without a config file of its own
+pub const PROFILE_ANNOTATION: &str = "crabtrap.profile";
+/// The annotation naming a new file in the hook's result directory to write how the container's process exited to, as
+/// JSON
+pub const RESULT_ANNOTATION: &str = "crabtrap.result";
+
+#[derive(Debug, Error)]
+pub enum OciError {
+    #[error("Failed to read {0}: {1}")]
+    IoError(String, io::Error),
+    #[error("Malformed {0}: {1}")]
+    JsonError(String, serde_json::Error),
+    #[error("Failed to load config {0}: {1}")]
+    ConfigError(String, ConfigError),
+    #[error("No profile called {0}")]
+    UnknownProfile(String),
+    #[error("Only one of {CONFIG_ANNOTATION} and {PROFILE_ANNOTATION} can be set")]
+    ConflictingAnnotations,
+    #[error("The container has no process to attach to")]
+    NoProcess,
+    #[error("Failed to attach to process {0}: {1}")]
+    AttachError(i32, Errno),
+    #[error("{0} is set, but the hook has no directory for it")]
+    NoDirectory(&'static str),
+    #[error("{0} has to be the name of a file in the hook's directory for it, not {1}")]
+    OutsideDirectory(&'static str, String),
+    #[error("The config sets {0}, which the container's runtime sets up rather than crabtrap")]
+    Unenforceable(&'static str),
+}
+
+/// Directories: where the hook's administrator lets containers' annotations pick files. Whoever can set a container's
+/// annotations needn't be trusted with the host's files, so each annotation naming a file only takes a name in its
+/// directory, and is refused if the hook has none for it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Directories {
+    /// The configs `crabtrap.config` can pick from
+    pub configs: Option<PathBuf>,
+    /// Where `crabtrap.result` can have a result written
+    pub results: Option<PathBuf>,
+}
+
+/// State: the state of a container, which the runtime writes to each hook's stdin
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub id: String,
+    /// The container's init process, as seen from the runtime's namespaces
+    pub pid: Option<i32>,
+    /// The directory holding the container's config.json
+    pub bundle: PathBuf,
+}
+
+/// Spec: the parts of a bundle's config.json that crabtrap reads
+#[derive(Deserialize, Debug, Default)]
+struct Spec {
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+/// Policy: what to enforce on a container, going by its annotations
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub config: Config,
+    /// Where to write how the container's process exited, if anywhere. It's in the result directory.
+    pub result: Option<PathBuf>,
+}
+
+impl State {
+    pub fn read<R: Read>(reader: R) -> Result<State, OciError> {
+        serde_json::from_reader(reader)
+            .map_err(|e| OciError::JsonError(String::from("container state"), e))
+    }
+
+    /// annotations returns the annotations in the container's config.json.
+    pub fn annotations(&self) -> Result<BTreeMap<String, String>, OciError> {
+        let path = self.bundle.join("config.json");
+        let file =
+            File::open(&path).map_err(|e| OciError::IoError(path.display().to_string(), e))?;
+        let spec: Spec = serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|e| OciError::JsonError(path.display().to_string(), e))?;
+        Ok(spec.annotations)
+    }
+}
+
+impl Policy {
+    /// from_annotations returns the policy a container's annotations ask for, or None if they don't ask for one. The
+    /// files they name are looked for in `directories`.
+    pub fn from_annotations(
+        annotations: &BTreeMap<String, String>,
+        directories: &Directories,
+    ) -> Result<Option<Policy>, OciError> {
+        let config = match (
+            annotations.get(CONFIG_ANNOTATION),
+            annotations.get(PROFILE_ANNOTATION),
+        ) {
+            (None, None) => return Ok(None),
+            (Some(name), None) => {
+                let path = in_directory(CONFIG_ANNOTATION, directories.configs.as_deref(), name)?;
+                Config::load(&path)
+                    .map_err(|e| OciError::ConfigError(path.display().to_string(), e))?
+            }
+            (None, Some(name)) => {
+                Config::profile(name).ok_or_else(|| OciError::UnknownProfile(name.clone()))?
+            }
+            (Some(_), Some(_)) => return Err(OciError::ConflictingAnnotations),
+        };
+        if let Some(section) = setup_sections(&config).first() {
+            return Err(OciError::Unenforceable(section));
+        }
+        let result = annotations
+            .get(RESULT_ANNOTATION)
+            .map(|name| in_directory(RESULT_ANNOTATION, directories.results.as_deref(), name))
+            .transpose()?;
+        Ok(Some(Policy { config, result }))
+    }
+}
+
+/// in_directory returns the path of the file an annotation names, which has to be just a name, in `directory`.
+fn in_directory(
+    annotation: &'static str,
+    directory: Option<&Path>,
+    name: &str,
+) -> Result<PathBuf, OciError> {
+    let directory = directory.ok_or(OciError::NoDirectory(annotation))?;
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(file)), None) => Ok(directory.join(file)),
+        _ => Err(OciError::OutsideDirectory(annotation, name.to_string())),
+    }
+}
+
+/// setup_sections returns the sections of a config that set things up before the exec. For a container that's the
+/// runtime's job, so none of them can be enforced.
+fn setup_sections(config: &Config) -> Vec<&'static str> {
+    [
+        ("namespaces", !config.namespaces.is_default()),
+        ("resource_limits", !config.resource_limits.is_unlimited()),
+        (
+            "time_limits.cpu_seconds",
+            config.time_limits.cpu_seconds.is_some(),
+        ),
+        ("landlock", config.landlock.is_some()),
+        ("cgroup", config.cgroup.is_some()),
+        ("run_as", config.run_as.is_some()),
+        ("working_dir", config.working_dir.is_some()),
+        ("no_new_privs", config.no_new_privs),
+        ("environment", !config.environment.is_default()),
+        ("stdio", !config.stdio.is_default()),
+        ("inherited_fds", !config.inherited_fds.is_default()),
+    ]
+    .into_iter()
+    .filter(|(_, set)| *set)
+    .map(|(section, _)| section)
+    .collect()
+}
+
+/// create_result creates the file a container's result goes in. It has to be new, so that it can't be made to
+/// overwrite anything, even through a link.
+fn create_result(path: &Path) -> Result<File, OciError> {
+    File::options()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| OciError::IoError(path.display().to_string(), e))
+}
+
+/// hook is what to run as an OCI createRuntime hook. It reads the container's state from `state`, and if the
+/// container's annotations ask for a policy, starts a supervisor in the background that seizes the container's init
+/// process and enforces the policy on whatever it execs, and everything that starts in turn. It returns once the
+/// supervisor has the process, so the runtime can't start the container before then, and nothing is enforced if the
+/// supervisor can't get it.
+///
+/// Everything a config would set up before the exec, like namespaces, limits and landlock, is the runtime's job here,
+/// so a config asking for any of it is refused, as is one naming a file outside `directories`.
+pub fn hook<R: Read>(state: R, directories: &Directories) -> Result<(), OciError> {
+    let state = State::read(state)?;
+    let Some(policy) = Policy::from_annotations(&state.annotations()?, directories)? else {
+        return Ok(());
+    };
+    let pid = Pid::from_raw(state.pid.ok_or(OciError::NoProcess)?);
+    let result = policy.result.as_deref().map(create_result).transpose()?;
+
+    let (reader, writer) =
+        unistd::pipe2(OFlag::O_CLOEXEC).map_err(|e| OciError::AttachError(pid.as_raw(), e))?;
+    match unsafe { unistd::fork() }.map_err(|e| OciError::AttachError(pid.as_raw(), e))? {
+        ForkResult::Child => {
+            drop(reader);
+            supervise(pid, &policy.config, result, writer)
+        }
+        ForkResult::Parent { .. } => {
+            drop(writer);
+            // The supervisor sends the errno from seizing the process, or 0 if it has it
+            let mut errno = [0; 4];
+            match File::from(reader).read_exact(&mut errno) {
+                Ok(()) if i32::from_ne_bytes(errno) == 0 => Ok(()),
+                Ok(()) => Err(OciError::AttachError(
+                    pid.as_raw(),
+                    Errno::from_raw(i32::from_ne_bytes(errno)),
+                )),
+                Err(e) => Err(OciError::IoError(String::from("the supervisor"), e)),
+            }
+        }
+    }
+}
+
+/// supervise seizes the container's init process and traces it until it exits, in a session of its own so it
+/// outlives the hook, and writes how it exited to `result`. Whether it got the process goes down `ready`.
+fn supervise(pid: Pid, config: &Config, result: Option<File>, ready: OwnedFd) -> ! {
+    let _ = unistd::setsid();
+    // The runtime may be waiting on the hook's output for as long as anything has it open
+    if let Ok(null) = File::options().read(true).write(true).open("/dev/null") {
+        for fd in 0..3 {
+            unsafe { libc::dup2(null.as_raw_fd(), fd) };
+        }
+    }
+    // Only the exec is worth stopping for until it happens, since up to then it's the runtime running. With the
+    // exit-kill option, the container can't carry on without us.
+    let seized = seize(
+        pid,
+        Options::PTRACE_O_EXITKILL.union(Options::PTRACE_O_TRACEEXEC),
+    );
+    let errno = seized.err().map_or(0, |errno| errno as i32);
+    let _ = File::from(ready).write_all(&errno.to_ne_bytes());
+    if seized.is_err() {
+        process::exit(1);
+    }
+
+    let (exit, stats) = match wait_for_exec(pid) {
+        Ok(()) => {
+            setoptions(pid, ptrace_options(false))
+                .unwrap_or_else(|e| panic!("failed to trace process {pid}: {e}"));
+            parent(pid, config, Hooks::default(), None)
+        }
+        Err(exit) => (exit, Default::default()),
+    };
+    if let Some(mut result) = result {
+        let json = serde_json::json!({ "exit": exit, "stats": stats });
+        result
+            .write_all(json.to_string().as_bytes())
+            .unwrap_or_else(|e| panic!("failed to write the result: {e}"));
+    }
+    process::exit(0)
+}
+
+/// wait_for_exec lets the container's init process run until it execs the container's process, and leaves it stopped
+/// there. Runtimes exec from the init's main thread, which is the one seized. If it exits first, that's the exit.
+fn wait_for_exec(pid: Pid) -> Result<(), ChildExit> {
+    loop {
+        let resumed = match waitpid(pid, Some(WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_EXEC)) => return Ok(()),
+            Ok(WaitStatus::Exited(_, code)) => return Err(ChildExit::Exited(code)),
+            Ok(WaitStatus::Signaled(_, signal, _)) => {
+                return Err(ChildExit::Signaled(signal as i32))
+            }
+            // A group-stop
+            Ok(WaitStatus::PtraceEvent(..)) => cont(pid, None),
+            Ok(WaitStatus::Stopped(_, signal)) => cont(pid, signal),
+            status => panic!("unexpected status {status:?} from process {pid} before its exec"),
+        };
+        resumed.unwrap_or_else(|e| panic!("failed to resume process {pid}: {e}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_policy() {
+        let dir = std::env::temp_dir().join(format!("crabtrap_oci_{}", std::process::id()));
+        let bundle = dir.join("bundle");
+        fs::create_dir_all(&bundle).unwrap();
+        fs::write(
+            bundle.join("config.json"),
+            r#"{"ociVersion": "1.0.2", "annotations": {"crabtrap.profile": "no-network", "crabtrap.result": "web.json"}}"#,
+        )
+        .unwrap();
+        let state = State::read(
+            format!(
+                r#"{{"ociVersion": "1.0.2", "id": "web", "status": "created", "pid": 4242, "bundle": "{}"}}"#,
+                bundle.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(state.pid, Some(4242));
+
+        let directories = Directories {
+            configs: Some(dir.join("configs")),
+            results: Some(dir.join("results")),
+        };
+        let policy = Policy::from_annotations(&state.annotations().unwrap(), &directories)
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.config, Config::profile("no-network").unwrap());
+        assert_eq!(policy.result, Some(dir.join("results/web.json")));
+
+        // A container that doesn't mention crabtrap is left alone
+        assert_eq!(
+            Policy::from_annotations(&BTreeMap::new(), &directories).unwrap(),
+            None
+        );
+
+        let annotations = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert!(matches!(
+            Policy::from_annotations(&annotations(&[(PROFILE_ANNOTATION, "nope")]), &directories),
+            Err(OciError::UnknownProfile(_))
+        ));
+
+        // Files are only picked by name, from the directory for them
+        for name in ["/etc/ld.so.preload", "../web.json", "sub/web.json", ".."] {
+            let annotations = annotations(&[
+                (PROFILE_ANNOTATION, "no-network"),
+                (RESULT_ANNOTATION, name),
+            ]);
+            assert!(
+                matches!(
+                    Policy::from_annotations(&annotations, &directories),
+                    Err(OciError::OutsideDirectory(RESULT_ANNOTATION, _))
+                ),
+                "{name}"
+            );
+        }
+        assert!(matches!(
+            Policy::from_annotations(
+                &annotations(&[(CONFIG_ANNOTATION, "web.yaml")]),
+                &Directories::default()
+            ),
+            Err(OciError::NoDirectory(CONFIG_ANNOTATION))
+        ));
+
+        fs::create_dir_all(dir.join("configs")).unwrap();
+        fs::write(dir.join("configs/web.yaml"), "default: deny\n").unwrap();
+        fs::write(
+            dir.join("configs/userns.yaml"),
+            "namespaces:\n  user: true\n",
+        )
+        .unwrap();
+        let policy = Policy::from_annotations(
+            &annotations(&[(CONFIG_ANNOTATION, "web.yaml")]),
+            &directories,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(policy.config.default, crate::DefaultPolicy::Deny);
+        assert!(matches!(
+            Policy::from_annotations(
+                &annotations(&[(CONFIG_ANNOTATION, "userns.yaml")]),
+                &directories
+            ),
+            Err(OciError::Unenforceable("namespaces"))
+        ));
+
+        // A result is never written over anything
+        fs::create_dir_all(dir.join("results")).unwrap();
+        assert!(create_result(&dir.join("results/web.json")).is_ok());
+        assert!(create_result(&dir.join("results/web.json")).is_err());
+        std::os::unix::fs::symlink("/dev/null", dir.join("results/link.json")).unwrap();
+        assert!(create_result(&dir.join("results/link.json")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{
+    events::{Event, Events},
+    ChildExit, Config, ConfigEntry, RunStats,
+};
+use std::{collections::BTreeMap, ffi::CStr};
+
+/// PluginViolation: a violation by a worker, tagged with the plugin it was attributed to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginViolation {
+    /// The name the plugin was registered under, or None if the violation wasn't made by a plugin
+    pub plugin: Option<String>,
+    pub pid: i32,
+    pub exit: ChildExit,
+}
+
+/// PluginHost: sandboxes worker processes that load plugins, holding each plugin to a policy of its own
+///
+/// ```no_run
+/// use crabtrap::{plugins::PluginHost, Config, ConfigEntry};
+/// use std::collections::BTreeSet;
+/// use syscalls::Sysno;
+///
+/// let host = PluginHost::new(Config::default()).register(
+///     "thumbnailer",
+///     "/usr/lib/plugins/libthumbnailer.so",
+///     ConfigEntry {
+///         allow: Some(BTreeSet::from([Sysno::read, Sysno::mmap])),
+///         block: Some(BTreeSet::from([Sysno::socket, Sysno::connect])),
+///         ..Default::default()
+///     },
+/// );
+/// let mut worker = host.spawn(c"/usr/bin/plugin-worker", &[c"plugin-worker"], &[]);
+/// for violation in worker.by_ref() {
+///     println!("{:?} broke its policy: {:?}", violation.plugin, violation.exit);
+/// }
+/// let (exit, stats) = worker.wait();
+/// ```
+pub struct PluginHost {
+    config: Config,
+    /// Plugin names by the path of their shared object
+    plugins: BTreeMap<String, String>,
+}
+
+impl PluginHost {
+    /// new starts from `base` for everything that isn't a plugin, such as the worker itself and the libraries it uses.
+    pub fn new(base: Config) -> PluginHost {
+        PluginHost {
+            config: base,
+            plugins: BTreeMap::new(),
+        }
+    }
+
+    /// register adds the plugin at `path`, to be held to `policy`. This replaces any rules the base config has for
+    /// the same path, and registering a path again replaces its name and policy.
+    pub fn register(mut self, name: &str, path: &str, policy: ConfigEntry) -> PluginHost {
+        self.config.shared_objects.insert(path.to_string(), policy);
+        self.plugins.insert(path.to_string(), name.to_string());
+        self
+    }
+
+    /// config returns the base config merged with every plugin's policy, which is what workers run under.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// plugin returns the name of the plugin a violation was attributed to, if it was attributed to one.
+    pub fn plugin(&self, exit: &ChildExit) -> Option<&str> {
+        plugin(&self.plugins, exit)
+    }
+
+    /// spawn starts a worker under the merged config, tracing it on a thread of its own. Any number of workers can run
+    /// at once.
+    pub fn spawn(&self, path: &CStr, args: &[&CStr], env: &[&CStr]) -> PluginWorker {
+        PluginWorker {
+            events: Events::spawn(path, args, env, self.config.clone()),
+            plugins: self.plugins.clone(),
+        }
+    }
+}
+
+fn plugin<'p>(plugins: &'p BTreeMap<String, String>, exit: &ChildExit) -> Option<&'p str> {
+    match exit {
+        ChildExit::IllegalSyscall { library, .. } | ChildExit::SyscallStorm(_, library) => {
+            plugins.get(library).map(String::as_str)
+        }
+        _ => None,
+    }
+}
+
+/// PluginWorker: a worker that's being traced, as an iterator over its violations
+pub struct PluginWorker {
+    events: Events,
+    plugins: BTreeMap<String, String>,
+}
+
+impl PluginWorker {
+    /// wait waits for the worker to finish, dropping any violations that haven't been seen.
+    pub fn wait(self) -> (ChildExit, RunStats) {
+        self.events.wait()
+    }
+}
+
+impl Iterator for PluginWorker {
+    type Item = PluginViolation;
+
+    /// next blocks until the next violation, and returns None once the worker has finished.
+    fn next(&mut self) -> Option<PluginViolation> {
+        self.events.find_map(|event| match event {
+            Event::Violation { pid, exit, .. } => Some(PluginViolation {
+                plugin: plugin(&self.plugins, &exit).map(String::from),
+                pid,
+                exit,
+            }),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use syscalls::Sysno;
+
+    #[test]
+    fn test_plugin_host() {
+        let policy = ConfigEntry {
+            block: Some(BTreeSet::from([Sysno::socket])),
+            ..Default::default()
+        };
+        let host = PluginHost::new(Config::default()).register(
+            "foo",
+            "/usr/lib/plugins/libfoo.so",
+            policy.clone(),
+        );
+        assert_eq!(
+            host.config().shared_objects,
+            BTreeMap::from([(String::from("/usr/lib/plugins/libfoo.so"), policy)])
+        );
+
+        assert_eq!(
+            host.plugin(&ChildExit::IllegalSyscall {
+                syscall: Sysno::socket,
+                library: String::from("/usr/lib/plugins/libfoo.so"),
+                rule: None,
+                call_site: None,
+                backtrace: Vec::new()
+            }),
+            Some("foo")
+        );
+        assert_eq!(
+            host.plugin(&ChildExit::IllegalSyscall {
+                syscall: Sysno::socket,
+                library: String::from("/usr/lib/libc.so.6"),
+                rule: None,
+                call_site: None,
+                backtrace: Vec::new()
+            }),
+            None
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{
+    audit::SyscallRecord,
+    config::{Config, ConfigEntry},
+    events::Event,
+    execute_with_events, ChildExit, RunStats,
+};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::CStr,
+    io::{self, BufRead},
+};
+use syscalls::Sysno;
+use thiserror::Error;
+
+/// The library name used for syscalls that couldn't be attributed to any mapped file
+pub const UNATTRIBUTED: &str = "[unattributed]";
+/// The library name used for the dynamic loader while it's starting a program up, before the program's own code runs.
+/// Rules for the loader itself then only cover what it does later, like dlopen.
+pub const LOADER_STARTUP: &str = "ld.so (startup)";
+/// The library name used for code in executable memory that isn't backed by a file, like a JIT's, which can be given
+/// rules under this name like any library
+pub const ANONYMOUS_EXEC: &str = "[anonymous-exec]";
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error("Failed to read log: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Failed to parse line {0} of log: {1}")]
+    ParseError(usize, serde_json::Error),
+}
+
+/// Profile: how many times each library made each syscall, across any number of runs
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub libraries: BTreeMap<String, BTreeMap<Sysno, u64>>,
+}
+
+impl Profile {
+    pub fn record(&mut self, library: &str, syscall: Sysno, count: u64) {
+        *self
+            .libraries
+            .entry(library.to_string())
+            .or_default()
+            .entry(syscall)
+            .or_insert(0) += count;
+    }
+
+    pub fn merge(&mut self, other: &Profile) {
+        for (library, syscalls) in &other.libraries {
+            for (&syscall, &count) in syscalls {
+                self.record(library, syscall, count);
+            }
+        }
+    }
+
+    /// trace runs a program under `config` and returns a profile of every syscall it made. Only syscalls the config
+    /// lets through are seen, so this is usually done with an empty config.
+    pub fn trace(
+        path: &CStr,
+        args: &[&CStr],
+        env: &[&CStr],
+        config: &Config,
+    ) -> (ChildExit, RunStats, Profile) {
+        let mut profile = Profile::default();
+        let (exit, stats) = execute_with_events(path, args, env, config, |event| {
+            if let Event::Syscall {
+                syscall, library, ..
+            } = event
+            {
+                profile.record(library.as_deref().unwrap_or(UNATTRIBUTED), syscall, 1);
+            }
+        });
+        (exit, stats, profile)
+    }
+
+    /// from_log reads an audit log, see AuditLog. Lines without a `syscall` field, like those for other events, are
+    /// skipped.
+    pub fn from_log<R: BufRead>(reader: R) -> Result<Profile, ProfileError> {
+        let mut profile = Profile::default();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let value: serde_json::Value =
+                serde_json::from_str(&line).map_err(|e| ProfileError::ParseError(i + 1, e))?;
+            if value.get("syscall").is_none() {
+                continue;
+            }
+
+            let record: SyscallRecord =
+                serde_json::from_value(value).map_err(|e| ProfileError::ParseError(i + 1, e))?;
+            profile.record(
+                record.library.as_deref().unwrap_or(UNATTRIBUTED),
+                record.syscall,
+                record.count,
+            );
+        }
+
+        Ok(profile)
+    }
+
+    /// suggested_config returns a config allowing each library exactly the syscalls it was seen making.
+    pub fn suggested_config(&self) -> Config {
+        Config {
+            shared_objects: self
+                .libraries
+                .iter()
+                .filter(|(library, _)| library.as_str() != UNATTRIBUTED)
+                .map(|(library, syscalls)| {
+                    (
+                        library.clone(),
+                        ConfigEntry {
+                            allow: Some(syscalls.keys().copied().collect::<BTreeSet<_>>()),
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Serialize for Profile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.libraries.len()))?;
+        for (library, syscalls) in &self.libraries {
+            let syscalls: BTreeMap<&str, u64> = syscalls
+                .iter()
+                .map(|(syscall, &count)| (syscall.name(), count))
+                .collect();
+            map.serialize_entry(library, &syscalls)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate() {
+        let mut profile = Profile::from_log(
+            r#"{"event": "syscall", "pid": 10, "syscall": "write", "library": "/usr/lib/libc.so.6"}
+{"event": "fork", "pid": 10, "child": 11}
+
+{"event": "syscall", "pid": 11, "syscall": 63, "library": "/usr/lib/libc.so.6", "count": 3}
+{"event": "syscall", "pid": 11, "syscall": "getpid", "library": null}
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        profile.merge(
+            &Profile::from_log(
+                r#"{"syscall": "write", "library": "/usr/lib/libc.so.6"}
+{"syscall": "openat", "library": "/usr/local/lib/libprintf_wrapper.so"}"#
+                    .as_bytes(),
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(
+            profile.libraries,
+            BTreeMap::from([
+                (
+                    String::from("/usr/lib/libc.so.6"),
+                    BTreeMap::from([(Sysno::read, 3), (Sysno::write, 2)])
+                ),
+                (
+                    String::from("/usr/local/lib/libprintf_wrapper.so"),
+                    BTreeMap::from([(Sysno::openat, 1)])
+                ),
+                (
+                    String::from(UNATTRIBUTED),
+                    BTreeMap::from([(Sysno::getpid, 1)])
+                ),
+            ])
+        );
+
+        assert_eq!(
+            serde_yaml::to_string(&profile.suggested_config()).unwrap(),
+            r#"shared_objects:
+  /usr/lib/libc.so.6:
+    allow:
+    - read
+    - write
+  /usr/local/lib/libprintf_wrapper.so:
+    allow:
+    - openat
+"#
+        );
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let path =
+            std::env::temp_dir().join(format!("crabtrap-audit-{}.jsonl", std::process::id()));
+        let mut log = crate::audit::AuditLog::create(&path).unwrap();
+        let pid = nix::unistd::Pid::from_raw(10);
+        for _ in 0..3 {
+            log.syscall(
+                pid,
+                Sysno::write,
+                Some("/usr/lib/libc.so.6"),
+                None,
+                Default::default(),
+            );
+        }
+        log.syscall(pid, Sysno::getpid, None, None, Default::default());
+        drop(log);
+
+        let profile = Profile::from_log(std::fs::read(&path).unwrap().as_slice()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            profile.libraries,
+            BTreeMap::from([
+                (
+                    String::from("/usr/lib/libc.so.6"),
+                    BTreeMap::from([(Sysno::write, 3)])
+                ),
+                (
+                    String::from(UNATTRIBUTED),
+                    BTreeMap::from([(Sysno::getpid, 1)])
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_bad_line() {
+        assert!(matches!(
+            Profile::from_log("{}\nnot json\n".as_bytes()),
+            Err(ProfileError::ParseError(2, _))
+        ));
+    }
+}
@@ -0,0 +1,323 @@
+use crate::{c_path, execute_with_stats, resolve, ChildExit, Config, Output, RunStats};
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyValueError},
+    prelude::*,
+    types::{PyBytes, PyTuple},
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{CStr, CString},
+    path::PathBuf,
+};
+
+create_exception!(
+    crabtrap,
+    Error,
+    PyException,
+    "Something went wrong running a sandboxed target"
+);
+create_exception!(
+    crabtrap,
+    Violation,
+    Error,
+    "The target broke the config. args holds a message and the Result."
+);
+create_exception!(
+    crabtrap,
+    SetupError,
+    Error,
+    "The target couldn't be set up to run. args holds a message and the Result."
+);
+
+/// Sandbox: a target and how to run it, set up a step at a time like `Sandbox("ls", "-l").timeout(5).run()`
+#[pyclass(name = "Sandbox", module = "crabtrap")]
+struct PySandbox {
+    target: String,
+    args: Vec<String>,
+    /// The target's environment, if not ours
+    env: Option<BTreeMap<String, String>>,
+    config: Config,
+}
+
+/// RunResult: how a run ended, as Python sees it
+#[pyclass(name = "Result", module = "crabtrap", get_all, frozen)]
+struct RunResult {
+    /// The target's exit code, if it exited by itself
+    exit_code: Option<i32>,
+    /// The signal that killed it, if one did
+    signal: Option<i32>,
+    /// The exit as a dict, e.g. `{"IllegalSyscall": {"syscall": "write", "library": "/usr/lib/libc.so.6"}}`, if it was
+    /// a violation or a setup failure
+    violation: Option<PyObject>,
+    /// The id of the rule that was broken, if it has one
+    rule: Option<String>,
+    /// The run's stats as a dict
+    stats: PyObject,
+    stdout: Option<Py<PyBytes>>,
+    stderr: Option<Py<PyBytes>>,
+}
+
+#[pymethods]
+impl PySandbox {
+    #[new]
+    #[pyo3(signature = (target, *args))]
+    fn new(target: String, args: &Bound<'_, PyTuple>) -> PyResult<PySandbox> {
+        Ok(PySandbox {
+            target,
+            args: args.extract()?,
+            env: None,
+            config: Config::default(),
+        })
+    }
+
+    /// config_file loads the config from a file, along with any it extends, in place of the one so far.
+    fn config_file(mut slf: PyRefMut<'_, Self>, path: PathBuf) -> PyResult<PyRefMut<'_, Self>> {
+        slf.config = Config::load(&path).map_err(|e| {
+            PyValueError::new_err(format!("failed to load config {}: {e}", path.display()))
+        })?;
+        Ok(slf)
+    }
+
+    /// profile uses one of the built-in profiles in place of the config so far.
+    fn profile<'a>(mut slf: PyRefMut<'a, Self>, name: &str) -> PyResult<PyRefMut<'a, Self>> {
+        slf.config = Config::profile(name)
+            .ok_or_else(|| PyValueError::new_err(format!("no profile called {name}")))?;
+        Ok(slf)
+    }
+
+    /// env sets the target's whole environment, rather than it getting ours.
+    fn env(mut slf: PyRefMut<'_, Self>, env: BTreeMap<String, String>) -> PyRefMut<'_, Self> {
+        slf.env = Some(env);
+        slf
+    }
+
+    /// working_dir sets the directory the target starts in.
+    fn working_dir(mut slf: PyRefMut<'_, Self>, path: PathBuf) -> PyRefMut<'_, Self> {
+        slf.config.working_dir = Some(path);
+        slf
+    }
+
+    /// timeout kills the target and everything it started once the run has taken this many seconds.
+    fn timeout(mut slf: PyRefMut<'_, Self>, seconds: f64) -> PyRefMut<'_, Self> {
+        slf.config.time_limits.timeout_ms = Some((seconds * 1000.0) as u64);
+        slf
+    }
+
+    /// stdin has the target read stdin from a file.
+    fn stdin(mut slf: PyRefMut<'_, Self>, path: PathBuf) -> PyRefMut<'_, Self> {
+        slf.config.stdio.stdin = Some(path);
+        slf
+    }
+
+    /// capture_output captures the target's stdout and stderr into the result.
+    fn capture_output(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.config.stdio.stdout = Output::Capture;
+        slf.config.stdio.stderr = Output::Capture;
+        slf
+    }
+
+    /// audit lets the target run past violations, which end up in the result's stats instead.
+    fn audit(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.config.enforcement = crate::Enforcement::Audit;
+        slf
+    }
+
+    /// run runs the target to completion and returns a Result. With `check`, a violation raises Violation and a
+    /// failure to set the target up raises SetupError, each with the Result as its second argument.
+    #[pyo3(signature = (check = false))]
+    fn run(&self, py: Python<'_>, check: bool) -> PyResult<Py<RunResult>> {
+        let env: Vec<CString> = match &self.env {
+            Some(env) => env
+                .iter()
+                .map(|(key, val)| CString::new(format!("{key}={val}")))
+                .collect::<Result<_, _>>(),
+            None => env::vars()
+                .map(|(key, val)| CString::new(format!("{key}={val}")))
+                .collect::<Result<_, _>>(),
+        }
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let env: Vec<&CStr> = env.iter().map(CString::as_c_str).collect();
+        let path = resolve(&self.target, &env, &self.config)
+            .and_then(c_path)
+            .map_err(|e| Error::new_err(format!("failed to find {}: {e}", self.target)))?;
+        let args: Vec<CString> = [&self.target]
+            .into_iter()
+            .chain(&self.args)
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<_, _>>()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let args: Vec<&CStr> = args.iter().map(CString::as_c_str).collect();
+
+        // Other Python threads can carry on while the target runs, and their children are left alone
+        let (exit, stats) =
+            py.allow_threads(|| execute_with_stats(&path, &args, &env, &self.config));
+        let result = Py::new(py, RunResult::new(py, &exit, stats)?)?;
+        if check {
+            match &exit {
+                ChildExit::Exited(_) | ChildExit::Signaled(_) | ChildExit::Detached => {}
+                ChildExit::SetupFailed { stage, errno } => {
+                    let message = format!("failed to set the target up at {stage:?}: {errno}");
+                    return Err(SetupError::new_err((message, result)));
+                }
+                exit => return Err(Violation::new_err((format!("{exit:?}"), result))),
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[pymethods]
+impl RunResult {
+    /// ok is whether the target exited with 0.
+    #[getter]
+    fn ok(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    fn __repr__(&self) -> String {
+        match (self.exit_code, self.signal) {
+            (Some(code), _) => format!("Result(exit_code={code})"),
+            (_, Some(signal)) => format!("Result(signal={signal})"),
+            _ => format!(
+                "Result(violation={})",
+                self.violation
+                    .as_ref()
+                    .map_or("None".to_string(), |v| v.to_string())
+            ),
+        }
+    }
+}
+
+impl RunResult {
+    fn new(py: Python<'_>, exit: &ChildExit, stats: RunStats) -> PyResult<RunResult> {
+        let bytes = |output: &Option<Vec<u8>>| {
+            output
+                .as_ref()
+                .map(|output| PyBytes::new(py, output).unbind())
+        };
+        Ok(RunResult {
+            exit_code: match exit {
+                ChildExit::Exited(code) => Some(*code),
+                _ => None,
+            },
+            signal: match exit {
+                ChildExit::Signaled(signal) => Some(*signal),
+                _ => None,
+            },
+            violation: match exit {
+                ChildExit::Exited(_) | ChildExit::Signaled(_) => None,
+                exit => Some(to_python(py, exit)?),
+            },
+            rule: exit.rule().map(String::from),
+            stdout: bytes(&stats.stdout),
+            stderr: bytes(&stats.stderr),
+            stats: to_python(py, &stats)?,
+        })
+    }
+}
+
+/// to_python converts `value` to the Python objects its JSON would load as.
+fn to_python<T: Serialize>(py: Python<'_>, value: &T) -> PyResult<PyObject> {
+    let json = serde_json::to_string(value).map_err(|e| Error::new_err(e.to_string()))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+/// The `crabtrap` Python module
+#[pymodule]
+fn crabtrap(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySandbox>()?;
+    m.add_class::<RunResult>()?;
+    m.add("Error", m.py().get_type::<Error>())?;
+    m.add("Violation", m.py().get_type::<Violation>())?;
+    m.add("SetupError", m.py().get_type::<SetupError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    fn sandbox(script: &str, config: Config) -> PySandbox {
+        PySandbox {
+            target: String::from("sh"),
+            args: vec![String::from("-c"), String::from(script)],
+            env: None,
+            config,
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let result = sandbox("exit 3", Config::default()).run(py, false).unwrap();
+            assert_eq!(result.get().exit_code, Some(3));
+            assert!(!result.get().ok());
+
+            let config: Config = r#"shared_objects:
+  "**/libc.so.*":
+    id: no-mkdir
+    block: [mkdirat]
+"#
+            .parse()
+            .unwrap();
+            let dir = env::temp_dir().join(format!("crabtrap_python_{}", std::process::id()));
+            let script = format!("mkdir {}", dir.display());
+            let error = sandbox(&script, config).run(py, true).unwrap_err();
+            assert!(error.is_instance_of::<Violation>(py));
+            let result: Py<RunResult> = error
+                .value(py)
+                .getattr("args")
+                .unwrap()
+                .get_item(1)
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(result.get().rule.as_deref(), Some("no-mkdir"));
+            assert!(!dir.exists());
+        });
+    }
+
+    #[test]
+    fn test_other_threads_children() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            // A Python thread waiting for a child of its own while the target runs
+            let globals = PyDict::new(py);
+            py.run(
+                cr#"
+import os, threading
+pid = os.posix_spawn("/bin/sh", ["sh", "-c", "sleep 0.2; exit 7"], os.environ)
+codes = []
+def wait():
+    try:
+        codes.append(os.waitstatus_to_exitcode(os.waitpid(pid, 0)[1]))
+    except ChildProcessError:
+        codes.append("reaped by the tracer")
+waiter = threading.Thread(target=wait)
+waiter.start()
+"#,
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let result = sandbox("sleep 0.4", Config::default())
+                .run(py, false)
+                .unwrap();
+            assert_eq!(result.get().exit_code, Some(0));
+            py.run(c"waiter.join()", Some(&globals), None).unwrap();
+            let codes: Vec<PyObject> = globals
+                .get_item("codes")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(codes.len(), 1);
+            assert_eq!(codes[0].extract::<i32>(py).ok(), Some(7));
+        });
+    }
+}
@@ -0,0 +1,375 @@
+use crate::{config::WriteQuota, fd::FdTable, filesystem, memory::read_bytes};
+use nix::{libc, unistd::Pid};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use syscalls::Sysno;
+
+/// The most a single read or write moves, which the kernel cuts longer ones down to
+const MAX_RW_COUNT: u64 = (i32::MAX as u64) & !4095;
+
+/// Quota: which of the limits in a [`WriteQuota`] was exceeded
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    PerFile,
+    Total,
+}
+
+/// WriteTracker: bytes written to each file by the whole traced tree
+#[derive(Debug, Default)]
+pub struct WriteTracker {
+    per_file: BTreeMap<String, u64>,
+    total: u64,
+}
+
+/// Write: a syscall about to write to or grow a file, as it was at the syscall's entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Write {
+    /// The file, as we see it
+    pub path: String,
+    /// Where to find its size, which for a descriptor is its link in /proc/<pid>/fd, in case it's been deleted
+    file: PathBuf,
+    /// Its size before the syscall
+    size: u64,
+    /// How many bytes the syscall asked to write, or to set aside for fallocate
+    requested: u64,
+    /// Whether the syscall's return value is how many bytes it wrote
+    returns_written: bool,
+    /// The most it can count against the quota, which is the bytes it asked to write or how far it can grow the
+    /// file, whichever is more
+    pub len: u64,
+}
+
+impl Write {
+    /// written returns how much the finished syscall counts against the quota: the bytes it wrote or set aside, or
+    /// how much it grew the file by, whichever is more. A file that's gone by then counts for what was asked.
+    pub fn written(&self, ret: i64) -> u64 {
+        let requested = if self.returns_written {
+            ret as u64
+        } else {
+            self.requested
+        };
+        match fs::metadata(&self.file) {
+            Ok(metadata) => requested.max(metadata.len().saturating_sub(self.size)),
+            Err(_) => self.len,
+        }
+    }
+}
+
+/// is_write returns whether the syscall writes to or grows a file, given a descriptor for it or, for truncate, its
+/// path.
+pub fn is_write(syscall: Sysno) -> bool {
+    matches!(
+        syscall,
+        Sysno::write
+            | Sysno::pwrite64
+            | Sysno::writev
+            | Sysno::pwritev
+            | Sysno::pwritev2
+            | Sysno::sendfile
+            | Sysno::copy_file_range
+            | Sysno::splice
+            | Sysno::fallocate
+            | Sysno::ftruncate
+            | Sysno::truncate
+    )
+}
+
+/// write returns what a syscall entry is about to write to a file, for those that write to or grow one: the write
+/// family, the ones that copy between descriptors, fallocate and the truncates. None if what it acts on isn't a file
+/// that counts against the quota.
+pub fn write(
+    pid: Pid,
+    syscall: Sysno,
+    args: &[u64; 6],
+    fds: &mut FdTable,
+    proc_root: &Path,
+) -> Option<Write> {
+    if !is_write(syscall) {
+        return None;
+    }
+    let proc = proc_root.join(pid.to_string());
+    let (path, file) = if syscall == Sysno::truncate {
+        let path = filesystem::path_arguments(pid, syscall, args, fds, proc_root)?.pop()?;
+        (path.to_string_lossy().into_owned(), path)
+    } else {
+        let fd = match syscall {
+            Sysno::copy_file_range | Sysno::splice => args[2],
+            _ => args[0],
+        } as i32;
+        let path = fds.lookup(pid, fd)?.to_string();
+        (path, proc.join("fd").join(fd.to_string()))
+    };
+    if !is_file(&path) {
+        return None;
+    }
+    let metadata = fs::metadata(&file).ok()?;
+    let size = metadata.len();
+    // Where in the file a write at the descriptor's own offset starts, which for one opened to append is the end
+    let position = || {
+        let fd = file.file_name()?.to_str()?;
+        let fdinfo = fs::read_to_string(proc.join("fdinfo").join(fd)).ok()?;
+        let field = |name| {
+            fdinfo
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(str::trim)
+        };
+        let flags = u64::from_str_radix(field("flags:")?, 8).ok()?;
+        if flags & libc::O_APPEND as u64 != 0 {
+            return Some(size);
+        }
+        field("pos:")?.parse().ok()
+    };
+    // An offset passed by pointer, or the descriptor's own if there isn't one
+    let offset_at = |addr: u64| match addr {
+        0 => position(),
+        addr => read_bytes(pid, addr, 8).map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap())),
+    };
+
+    // The kernel cuts anything but fallocate down to MAX_RW_COUNT
+    let rw = |count: u64| count.min(MAX_RW_COUNT);
+
+    // What it asks to write, and where in the file it'll end up, if that could be past the end
+    let (requested, end) = match syscall {
+        Sysno::write | Sysno::sendfile => {
+            let count = rw(if syscall == Sysno::write {
+                args[2]
+            } else {
+                args[3]
+            });
+            (count, position().map(|offset| offset.saturating_add(count)))
+        }
+        Sysno::pwrite64 => (rw(args[2]), Some(args[3].saturating_add(rw(args[2])))),
+        Sysno::writev | Sysno::pwritev | Sysno::pwritev2 => {
+            let count = rw(iovecs_len(pid, args[1], args[2]));
+            let offset = match syscall {
+                // An offset of -1 means the descriptor's own
+                Sysno::pwritev | Sysno::pwritev2 if args[3] as i64 != -1 => Some(args[3]),
+                _ => position(),
+            };
+            (count, offset.map(|offset| offset.saturating_add(count)))
+        }
+        Sysno::copy_file_range | Sysno::splice => (
+            rw(args[4]),
+            offset_at(args[3]).map(|offset| offset.saturating_add(rw(args[4]))),
+        ),
+        Sysno::fallocate => {
+            let mode = args[1] as i32;
+            if mode & (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_COLLAPSE_RANGE) != 0 {
+                return None;
+            }
+            let end = if mode & libc::FALLOC_FL_KEEP_SIZE != 0 {
+                None
+            } else if mode & libc::FALLOC_FL_INSERT_RANGE != 0 {
+                Some(size.saturating_add(args[3]))
+            } else {
+                Some(args[2].saturating_add(args[3]))
+            };
+            (args[3], end)
+        }
+        Sysno::ftruncate | Sysno::truncate => (0, Some(args[1])),
+        _ => return None,
+    };
+    // There's no end for fallocate keeping the size, or if the descriptor's offset is missing, which it only is if
+    // another thread has closed it, and then the syscall will fail
+    let grown = end.map_or(0, |end| end.saturating_sub(size));
+    Some(Write {
+        path,
+        file,
+        size,
+        requested,
+        returns_written: !matches!(
+            syscall,
+            Sysno::fallocate | Sysno::ftruncate | Sysno::truncate
+        ),
+        len: requested.max(grown),
+    })
+}
+
+/// iovecs_len adds up the lengths of the `count` struct iovecs at `addr`.
+fn iovecs_len(pid: Pid, addr: u64, count: u64) -> u64 {
+    let count = count.min(libc::UIO_MAXIOV as u64) as usize;
+    let Some(iovecs) = read_bytes(pid, addr, count * 16) else {
+        return 0;
+    };
+    iovecs
+        .chunks_exact(16)
+        .map(|iovec| u64::from_ne_bytes(iovec[8..].try_into().unwrap()))
+        .fold(0, u64::saturating_add)
+}
+
+/// is_file returns whether an fd target from /proc/{pid}/fd is a file that counts against the quota.
+///
+/// Pipes, sockets and the like don't start with a '/'. Devices do, but writing to a terminal or
+/// /dev/null doesn't fill up the disk.
+pub fn is_file(target: &str) -> bool {
+    target.starts_with('/') && !target.starts_with("/dev/")
+}
+
+impl WriteTracker {
+    /// check returns the quota that would be exceeded by writing `len` more bytes to `path`, if any.
+    pub fn check(&self, quota: &WriteQuota, path: &str, len: u64) -> Option<Quota> {
+        let written = self.per_file.get(path).copied().unwrap_or(0);
+        // len is what the tracee asked for, which can be anything
+        if quota
+            .per_file
+            .is_some_and(|limit| written.saturating_add(len) > limit)
+        {
+            Some(Quota::PerFile)
+        } else if quota
+            .total
+            .is_some_and(|limit| self.total.saturating_add(len) > limit)
+        {
+            Some(Quota::Total)
+        } else {
+            None
+        }
+    }
+
+    /// record adds `len` bytes written to `path`.
+    pub fn record(&mut self, path: &str, len: u64) {
+        let written = self.per_file.entry(path.to_string()).or_insert(0);
+        *written = written.saturating_add(len);
+        self.total = self.total.saturating_add(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_tracker() {
+        let quota = WriteQuota {
+            per_file: Some(10),
+            total: Some(15),
+        };
+        let mut tracker = WriteTracker::default();
+
+        assert_eq!(tracker.check(&quota, "/tmp/a", 10), None);
+        assert_eq!(tracker.check(&quota, "/tmp/a", 11), Some(Quota::PerFile));
+
+        tracker.record("/tmp/a", 8);
+        assert_eq!(tracker.check(&quota, "/tmp/a", 2), None);
+        assert_eq!(tracker.check(&quota, "/tmp/a", 3), Some(Quota::PerFile));
+        assert_eq!(tracker.check(&quota, "/tmp/b", 7), None);
+        assert_eq!(tracker.check(&quota, "/tmp/b", 8), Some(Quota::Total));
+        assert_eq!(
+            tracker.check(&quota, "/tmp/a", u64::MAX),
+            Some(Quota::PerFile)
+        );
+        let total = WriteQuota {
+            per_file: None,
+            total: Some(15),
+        };
+        assert_eq!(
+            tracker.check(&total, "/tmp/b", u64::MAX),
+            Some(Quota::Total)
+        );
+
+        assert_eq!(
+            tracker.check(&WriteQuota::default(), "/tmp/a", u32::MAX.into()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        use std::{
+            io::{Seek, SeekFrom, Write as _},
+            os::fd::AsRawFd,
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("crabtrap_quota_write_{}", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        let fd = file.as_raw_fd() as u64;
+        let pid = nix::unistd::getpid();
+        let mut fds = FdTable::new("/proc");
+        let mut write = |syscall, args| write(pid, syscall, &args, &mut fds, Path::new("/proc"));
+        let len = |write: Option<Write>| write.unwrap().len;
+
+        assert_eq!(len(write(Sysno::write, [fd, 0, 100, 0, 0, 0])), 100);
+        assert_eq!(
+            len(write(Sysno::write, [fd, 0, u64::MAX, 0, 0, 0])),
+            MAX_RW_COUNT
+        );
+        // Growing the file counts as much as writing to it
+        assert_eq!(
+            len(write(Sysno::pwrite64, [fd, 0, 10, 1 << 30, 0, 0])),
+            (1 << 30) + 10
+        );
+        assert_eq!(
+            len(write(Sysno::pwrite64, [fd, 0, 10, u64::MAX, 0, 0])),
+            u64::MAX
+        );
+        assert_eq!(
+            len(write(Sysno::ftruncate, [fd, 1 << 20, 0, 0, 0, 0])),
+            1 << 20
+        );
+        assert_eq!(len(write(Sysno::fallocate, [fd, 0, 0, 4096, 0, 0])), 4096);
+        let keep_size = libc::FALLOC_FL_KEEP_SIZE as u64;
+        assert_eq!(
+            len(write(
+                Sysno::fallocate,
+                [fd, keep_size, 1 << 30, 4096, 0, 0]
+            )),
+            4096
+        );
+        let punch_hole = (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u64;
+        assert_eq!(
+            write(Sysno::fallocate, [fd, punch_hole, 0, 4096, 0, 0]),
+            None
+        );
+        assert_eq!(len(write(Sysno::copy_file_range, [0, 0, fd, 0, 50, 0])), 50);
+        let offset = 1u64 << 20;
+        let at = &offset as *const u64 as u64;
+        assert_eq!(
+            len(write(Sysno::copy_file_range, [0, 0, fd, at, 50, 0])),
+            offset + 50
+        );
+        let iovecs: [u64; 4] = [0, 3, 0, 4];
+        assert_eq!(
+            len(write(
+                Sysno::writev,
+                [fd, iovecs.as_ptr() as u64, 2, 0, 0, 0]
+            )),
+            7
+        );
+        // Only files count
+        let (reader, _writer) = nix::unistd::pipe().unwrap();
+        assert_eq!(
+            write(Sysno::splice, [0, 0, reader.as_raw_fd() as u64, 0, 50, 0]),
+            None
+        );
+        assert_eq!(write(Sysno::read, [fd, 0, 100, 0, 0, 0]), None);
+
+        // Once it's done it counts for what it did
+        let appended = write(Sysno::write, [fd, 0, 100, 0, 0, 0]).unwrap();
+        file.write_all(&[0; 5]).unwrap();
+        assert_eq!(appended.written(5), 5);
+        let truncated = write(Sysno::ftruncate, [fd, 1 << 20, 0, 0, 0, 0]).unwrap();
+        file.set_len(1 << 20).unwrap();
+        assert_eq!(truncated.written(0), (1 << 20) - 5);
+        // Writes go where the descriptor's offset is
+        assert_eq!(len(write(Sysno::write, [fd, 0, 10, 0, 0, 0])), 10);
+        file.seek(SeekFrom::Start(2 << 20)).unwrap();
+        assert_eq!(
+            len(write(Sysno::write, [fd, 0, 10, 0, 0, 0])),
+            (1 << 20) + 10
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_is_file() {
+        assert!(is_file("/tmp/out.txt"));
+        assert!(!is_file("/dev/null"));
+        assert!(!is_file("pipe:[1234]"));
+        assert!(!is_file("socket:[5678]"));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{ChildExit, Config, RunStats};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ReceiptError {
+    #[error("Failed to read {0}: {1}")]
+    IoError(String, std::io::Error),
+    #[error("Signing key must be 32 raw bytes or 64 hex characters")]
+    KeyError,
+    #[error("Receipt has a malformed signature")]
+    MalformedSignature,
+    #[error("Receipt signature doesn't match its contents")]
+    BadSignature,
+}
+
+/// ReceiptBody: the part of a receipt covered by the signature
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReceiptBody {
+    /// SHA-256 of the config, serialized as YAML
+    pub config_sha256: String,
+    pub target: String,
+    /// SHA-256 of the target executable
+    pub target_sha256: String,
+    pub stats: RunStats,
+    pub exit: ChildExit,
+}
+
+/// Receipt: a signed record of a sandboxed run, so whoever holds the public key can check it later
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    pub body: ReceiptBody,
+    /// Hex-encoded ed25519 public key of the signer
+    pub public_key: String,
+    /// Hex-encoded ed25519 signature over the JSON encoding of `body`
+    pub signature: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+/// TargetHash: the target executable as it was before the run, which it could have rewritten by the end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetHash {
+    path: String,
+    sha256: String,
+}
+
+impl TargetHash {
+    /// read hashes the target, which has to happen before it's run.
+    pub fn read<P: AsRef<Path>>(target: P) -> Result<TargetHash, ReceiptError> {
+        let target = target.as_ref();
+        let executable =
+            fs::read(target).map_err(|e| ReceiptError::IoError(target.display().to_string(), e))?;
+        Ok(TargetHash {
+            path: target.display().to_string(),
+            sha256: sha256(&executable),
+        })
+    }
+}
+
+impl ReceiptBody {
+    pub fn new(
+        config: &Config,
+        target: TargetHash,
+        stats: RunStats,
+        exit: ChildExit,
+    ) -> ReceiptBody {
+        ReceiptBody {
+            config_sha256: sha256(
+                serde_yaml::to_string(config)
+                    .expect("failed to serialize config")
+                    .as_bytes(),
+            ),
+            target: target.path,
+            target_sha256: target.sha256,
+            stats,
+            exit,
+        }
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("failed to serialize receipt")
+    }
+}
+
+impl Receipt {
+    pub fn sign(body: ReceiptBody, key: &SigningKey) -> Receipt {
+        let signature = key.sign(&body.signed_bytes());
+        Receipt {
+            body,
+            public_key: to_hex(key.verifying_key().as_bytes()),
+            signature: to_hex(&signature.to_bytes()),
+        }
+    }
+
+    /// verify checks that the receipt was signed by `key` and hasn't been changed since.
+    pub fn verify(&self, key: &VerifyingKey) -> Result<(), ReceiptError> {
+        let signature: [u8; 64] = from_hex(&self.signature)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ReceiptError::MalformedSignature)?;
+
+        key.verify(
+            &self.body.signed_bytes(),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| ReceiptError::BadSignature)
+    }
+}
+
+/// load_signing_key reads an ed25519 secret key, stored either as 32 raw bytes or as 64 hex characters.
+pub fn load_signing_key<P: AsRef<Path>>(path: P) -> Result<SigningKey, ReceiptError> {
+    let path = path.as_ref();
+    let contents =
+        fs::read(path).map_err(|e| ReceiptError::IoError(path.display().to_string(), e))?;
+
+    let secret: [u8; 32] = match contents.try_into() {
+        Ok(raw) => raw,
+        Err(contents) => std::str::from_utf8(&contents)
+            .ok()
+            .and_then(|s| from_hex(s.trim()))
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ReceiptError::KeyError)?,
+    };
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let body = ReceiptBody {
+            config_sha256: sha256(b"shared_objects: {}"),
+            target: String::from("/usr/local/bin/static"),
+            target_sha256: sha256(b"not really an executable"),
+            stats: RunStats::default(),
+            exit: ChildExit::Exited(0),
+        };
+
+        let receipt = Receipt::sign(body, &key);
+        assert_eq!(receipt.public_key, to_hex(key.verifying_key().as_bytes()));
+        assert!(receipt.verify(&key.verifying_key()).is_ok());
+
+        let mut tampered = receipt.clone();
+        tampered.body.exit = ChildExit::Exited(1);
+        assert!(matches!(
+            tampered.verify(&key.verifying_key()),
+            Err(ReceiptError::BadSignature)
+        ));
+
+        let other = SigningKey::from_bytes(&[8; 32]);
+        assert!(matches!(
+            receipt.verify(&other.verifying_key()),
+            Err(ReceiptError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
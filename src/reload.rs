@@ -0,0 +1,201 @@
+use crate::{wake::Wake, Config};
+use nix::{
+    errno::Errno,
+    fcntl::{self, FcntlArg, OFlag},
+    libc,
+    sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    unistd,
+};
+use serde_json::Value;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    os::fd::{AsRawFd, IntoRawFd},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+};
+
+/// Whether there's been a SIGHUP that hasn't been dealt with yet
+static HANGUP: AtomicBool = AtomicBool::new(false);
+/// Where the handler tells the waker there's been a SIGHUP, once the waker's been started
+static NOTIFY: AtomicI32 = AtomicI32::new(-1);
+/// The tracer the waker wakes, while it's catching SIGHUP
+static TRACER: Mutex<Option<Wake>> = Mutex::new(None);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+    let notify = NOTIFY.load(Ordering::SeqCst);
+    if notify >= 0 {
+        let _ = unsafe { libc::write(notify, [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// start_waker starts the thread that wakes the tracer after each SIGHUP, which is left running for good once it's
+/// started. The signal can be delivered to any of our threads, and even on the tracer's it's lost if it comes just
+/// before waitpid, so the tracer is woken again and again until it's dealt with it.
+fn start_waker() -> nix::Result<()> {
+    static STARTED: OnceLock<nix::Result<()>> = OnceLock::new();
+    *STARTED.get_or_init(|| {
+        let (reader, writer) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        // A handler mustn't block, and a full pipe has said enough already
+        fcntl::fcntl(writer.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        thread::spawn(move || {
+            let mut buffer = [0; 64];
+            while let Ok(_) | Err(Errno::EINTR) = unistd::read(reader.as_raw_fd(), &mut buffer) {
+                while HANGUP.load(Ordering::SeqCst) {
+                    match *TRACER.lock().unwrap_or_else(|e| e.into_inner()) {
+                        Some(tracer) => tracer.wake(),
+                        None => break,
+                    }
+                    thread::sleep(Wake::INTERVAL);
+                }
+            }
+        });
+        NOTIFY.store(writer.into_raw_fd(), Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+/// Hangups: catches SIGHUP for as long as it's around, and puts back whatever handled it before once it's dropped.
+/// Whichever thread it's delivered to, the tracer that caught it is woken from waitpid to deal with it.
+pub(crate) struct Hangups {
+    previous: SigAction,
+}
+
+impl Hangups {
+    /// catch has to be called on the tracer's thread.
+    pub fn catch() -> nix::Result<Hangups> {
+        start_waker()?;
+        HANGUP.store(false, Ordering::SeqCst);
+        *TRACER.lock().unwrap_or_else(|e| e.into_inner()) = Some(Wake::current()?);
+        let action = SigAction::new(
+            SigHandler::Handler(on_hangup),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        let previous = unsafe { signal::sigaction(Signal::SIGHUP, &action) }?;
+        Ok(Hangups { previous })
+    }
+
+    /// take returns whether there's been a hangup since the last call.
+    pub fn take(&self) -> bool {
+        HANGUP.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Drop for Hangups {
+    fn drop(&mut self) {
+        let _ = unsafe { signal::sigaction(Signal::SIGHUP, &self.previous) };
+        *TRACER.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Change: a setting that differs between two configs, found by its path through them like
+/// `shared_objects["/usr/lib/libc.so.6"].allow`, with what it was and is now, or None where it's left at its default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+impl fmt::Display for Change {
+    /// Settings that aren't there are at their defaults.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<Value>| {
+            value
+                .as_ref()
+                .map_or(String::from("default"), Value::to_string)
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.path,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
+/// diff returns the settings that differ between two configs. Lists, like a rule's syscalls, are compared as a whole.
+pub fn diff(old: &Config, new: &Config) -> Vec<Change> {
+    let mut changes = Vec::new();
+    compare(
+        String::new(),
+        Some(&serde_json::to_value(old).expect("failed to serialize config")),
+        Some(&serde_json::to_value(new).expect("failed to serialize config")),
+        &mut changes,
+    );
+    changes
+}
+
+fn compare(path: String, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                compare(join(&path, key), old.get(key), new.get(key), changes);
+            }
+        }
+        (old, new) if old != new => changes.push(Change {
+            path,
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+/// join extends a path through a config with one more key, quoting keys that aren't plain names, like paths.
+fn join(path: &str, key: &str) -> String {
+    let plain = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    match (path.is_empty(), plain) {
+        (true, true) => key.to_string(),
+        (false, true) => format!("{path}.{key}"),
+        (_, false) => format!("{path}[{}]", Value::from(key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let old: Config = r#"
+shared_objects:
+  /usr/lib/libc.so.6:
+    allow: [read, write]
+  /usr/lib/libssl.so.3:
+    block: [connect]
+enforcement: audit
+"#
+        .parse()
+        .unwrap();
+        let new: Config = r#"
+shared_objects:
+  /usr/lib/libc.so.6:
+    allow: [read, write, openat]
+trace: true
+"#
+        .parse()
+        .unwrap();
+        let changes: Vec<String> = diff(&old, &new).iter().map(Change::to_string).collect();
+        assert_eq!(
+            changes,
+            [
+                r#"enforcement: "audit" -> default"#,
+                r#"shared_objects["/usr/lib/libc.so.6"].allow: ["read","write"] -> ["openat","read","write"]"#,
+                r#"shared_objects["/usr/lib/libssl.so.3"]: {"block":["connect"]} -> default"#,
+                "trace: default -> true",
+            ]
+        );
+        assert!(diff(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    config::{Fallback, RemotePolicyConfig},
+    log::warning,
+};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+use syscalls::Sysno;
+
+/// Query: what we ask the remote policy service about a syscall
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Query {
+    pub syscall: Sysno,
+    /// The mapped files seen while walking the stack, innermost first
+    pub stack: Vec<String>,
+}
+
+/// Answer: what the remote policy service replies with
+#[cfg(feature = "remote")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Answer {
+    pub allow: bool,
+}
+
+/// RemotePolicy: asks a policy service about syscalls the config has no rule for
+///
+/// Questions are sent from a separate thread, so only the tracee waiting on an answer is held up.
+/// Answers are cached for the rest of the run, and tracees asking a question that's already in
+/// flight wait on the same answer.
+pub struct RemotePolicy {
+    config: RemotePolicyConfig,
+    answered: BTreeMap<Query, bool>,
+    waiting: BTreeMap<Query, Vec<Pid>>,
+    sender: Sender<(Query, bool)>,
+    receiver: Receiver<(Query, bool)>,
+}
+
+impl RemotePolicy {
+    pub fn new(config: &RemotePolicyConfig) -> RemotePolicy {
+        let (sender, receiver) = channel();
+        RemotePolicy {
+            config: config.clone(),
+            answered: BTreeMap::new(),
+            waiting: BTreeMap::new(),
+            sender,
+            receiver,
+        }
+    }
+
+    /// ask returns whether the syscall is allowed if we already know, or sends the query off and returns None.
+    /// In that case `pid` is reported along with the answer once it comes in.
+    pub fn ask(&mut self, pid: Pid, query: Query) -> Option<bool> {
+        if let Some(&allow) = self.answered.get(&query) {
+            return Some(allow);
+        }
+
+        if let Some(pids) = self.waiting.get_mut(&query) {
+            pids.push(pid);
+            return None;
+        }
+
+        self.waiting.insert(query.clone(), vec![pid]);
+        let config = self.config.clone();
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let allow = request(&config, &query).unwrap_or_else(|e| {
+                warning!(
+                    "Remote policy service failed, falling back to {:?}: {e}",
+                    config.fallback
+                );
+                config.fallback == Fallback::Allow
+            });
+            // The receiver only goes away once the supervisor is done, at which point nobody needs the answer.
+            let _ = sender.send((query, allow));
+        });
+        None
+    }
+
+    /// is_waiting returns whether any tracee is waiting on an answer.
+    pub fn is_waiting(&self) -> bool {
+        !self.waiting.is_empty()
+    }
+
+    /// answers returns the answers that have come in, along with the tracees that were waiting on each.
+    pub fn answers(&mut self) -> Vec<(Query, bool, Vec<Pid>)> {
+        let mut answers = Vec::new();
+        while let Ok((query, allow)) = self.receiver.try_recv() {
+            let pids = self.waiting.remove(&query).unwrap_or_default();
+            self.answered.insert(query.clone(), allow);
+            answers.push((query, allow, pids));
+        }
+        answers
+    }
+}
+
+#[cfg(feature = "remote")]
+fn request(config: &RemotePolicyConfig, query: &Query) -> Result<bool, String> {
+    let answer: Answer = ureq::AgentBuilder::new()
+        .timeout(std::time::Duration::from_millis(config.timeout_ms))
+        .build()
+        .post(&config.url)
+        .send_json(query)
+        .map_err(|e| e.to_string())?
+        .into_json()
+        .map_err(|e| e.to_string())?;
+    Ok(answer.allow)
+}
+
+#[cfg(not(feature = "remote"))]
+fn request(_config: &RemotePolicyConfig, _query: &Query) -> Result<bool, String> {
+    Err(String::from(
+        "crabtrap was built without the `remote` feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback() {
+        let mut remote = RemotePolicy::new(&RemotePolicyConfig {
+            // Nothing should be listening here, so we fall back
+            url: String::from("http://127.0.0.1:1/check"),
+            timeout_ms: 100,
+            fallback: Fallback::Block,
+        });
+        let query = Query {
+            syscall: Sysno::write,
+            stack: vec![String::from("/usr/lib/libfoo.so")],
+        };
+
+        assert_eq!(remote.ask(Pid::from_raw(1), query.clone()), None);
+        assert_eq!(remote.ask(Pid::from_raw(2), query.clone()), None);
+        assert!(remote.is_waiting());
+
+        let mut answers = Vec::new();
+        while answers.is_empty() {
+            thread::sleep(std::time::Duration::from_millis(10));
+            answers = remote.answers();
+        }
+        assert_eq!(
+            answers,
+            vec![(
+                query.clone(),
+                false,
+                vec![Pid::from_raw(1), Pid::from_raw(2)]
+            )]
+        );
+        assert!(!remote.is_waiting());
+        assert_eq!(remote.ask(Pid::from_raw(3), query), Some(false));
+    }
+}
@@ -0,0 +1,422 @@
+use crate::{
+    cache::DecisionCache,
+    config::{DefaultPolicy, Enforcement, RuleAction, UnattributedPolicy},
+    executable, handle_syscall,
+    identity::Identities,
+    library_name,
+    map::ProcessMap,
+    mapped_file,
+    memory::MemoryReader,
+    unwind::Unwinder,
+    Config, MemoryMap, Stop, Tracee, Verdict,
+};
+use nix::{libc::user_regs_struct, unistd::Pid};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, BufRead, BufWriter, Write},
+    mem,
+    path::{Path, PathBuf},
+    ptr, slice,
+};
+use syscalls::Sysno;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("Failed to read the trace: {0}")]
+    IoError(io::Error),
+    #[error("Malformed trace at line {0}: {1}")]
+    JsonError(usize, serde_json::Error),
+    #[error("Process {0} made a syscall before its map was recorded")]
+    NoMap(i32),
+}
+
+/// TraceEvent: a line of a recorded trace
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum TraceEvent {
+    /// The memory map a process's syscalls from here on were made with
+    Map {
+        pid: i32,
+        map: MemoryMap,
+    },
+    Syscall(Box<RecordedSyscall>),
+}
+
+/// RecordedSyscall: everything a syscall's entry stop needs to be checked again, without the process that made it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordedSyscall {
+    pub pid: i32,
+    pub syscall: Sysno,
+    pub args: [u64; 6],
+    pub pc: u64,
+    /// The whole register set as words, in user_regs_struct's order. None for compat processes, whose stacks aren't
+    /// walked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regs: Option<Vec<u64>>,
+    /// The executable the process was running, for configs with rules for particular binaries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executable: Option<String>,
+    /// Whether the dynamic loader was still starting the program up
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub starting: bool,
+    /// Each word of the tracee's memory that walking its whole stack read, by address
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub memory: BTreeMap<u64, u64>,
+}
+
+/// TraceRecording: a JSON line for each syscall entry the rules are checked at, with what it takes to walk the stack
+/// again, and each process's map whenever it's changed since its last syscall
+pub(crate) struct TraceRecording {
+    writer: BufWriter<File>,
+    /// The last map recorded for each tracee
+    maps: HashMap<Pid, MemoryMap>,
+}
+
+impl TraceRecording {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<TraceRecording> {
+        Ok(TraceRecording {
+            writer: BufWriter::new(File::create(path)?),
+            maps: HashMap::new(),
+        })
+    }
+
+    /// syscall records a syscall entry, walking the whole stack to see which of the tracee's memory it takes.
+    pub fn syscall(
+        &mut self,
+        pid: Pid,
+        config: &Config,
+        syscall: Sysno,
+        stop: &Stop,
+        tracee: &Tracee,
+        unwinder: &mut Unwinder,
+    ) -> io::Result<()> {
+        let map = tracee.map();
+        if self.maps.get(&pid) != Some(&map) {
+            let event = TraceEvent::Map {
+                pid: pid.as_raw(),
+                map: map.clone(),
+            };
+            self.write(&event)?;
+            self.maps.insert(pid, map.clone());
+        }
+
+        let mut reader = MemoryReader::new(pid);
+        let mut memory = BTreeMap::new();
+        unwinder.walk(
+            stop.pc,
+            stop.regs.as_ref(),
+            &map,
+            config.stack_walk.max_depth,
+            |path| mapped_file(pid, config, &map, path),
+            |addr| {
+                let word = reader.word(addr);
+                if let Some(word) = word {
+                    memory.insert(addr, word);
+                }
+                word
+            },
+            |_| true,
+        );
+        let event = TraceEvent::Syscall(Box::new(RecordedSyscall {
+            pid: pid.as_raw(),
+            syscall,
+            args: stop.args,
+            pc: stop.pc,
+            regs: stop.regs.as_ref().map(words),
+            executable: executable(pid, config).map(|path| path.to_string_lossy().into_owned()),
+            starting: tracee.starting,
+            memory,
+        }));
+        self.write(&event)
+    }
+
+    fn write(&mut self, event: &TraceEvent) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")
+    }
+}
+
+/// words flattens a register set, which on every architecture is made up of u64s.
+fn words(regs: &user_regs_struct) -> Vec<u64> {
+    let len = mem::size_of::<user_regs_struct>() / mem::size_of::<u64>();
+    unsafe { slice::from_raw_parts(regs as *const user_regs_struct as *const u64, len) }.to_vec()
+}
+
+/// registers is the reverse of words, or None if there are the wrong number of them, like in a trace recorded on
+/// another architecture.
+fn registers(words: &[u64]) -> Option<user_regs_struct> {
+    (mem::size_of_val(words) == mem::size_of::<user_regs_struct>())
+        .then(|| unsafe { ptr::read_unaligned(words.as_ptr() as *const user_regs_struct) })
+}
+
+/// Outcome: what a config would have done about a syscall
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Allowed,
+    /// Let through, and logged
+    Logged,
+    /// Skipped, failing with an error
+    Denied,
+    /// Skipped, with SIGSYS sent
+    Trapped,
+    /// The end of the run
+    Killed,
+}
+
+/// Replayed: a recorded syscall, as a config decides it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Replayed {
+    pub pid: i32,
+    pub syscall: Sysno,
+    /// The file the syscall was attributed to, if any
+    pub library: Option<String>,
+    /// The id of the rule that decided it, if it has one
+    pub rule: Option<String>,
+    pub outcome: Outcome,
+}
+
+/// replay checks each syscall in a recorded trace against `config`, walking the stack from the recorded registers and
+/// memory, without running anything. Only the rules are checked: argument rules, quotas, the filesystem settings and
+/// any hooks are left out, and syscalls the recording didn't stop at aren't there.
+///
+/// The unwind tables are read from the mapped files, so a trace replays where it was recorded, or anywhere else with
+/// the same files. A config that walks further than the recording's did runs out of memory, as if the walk was cut
+/// short.
+pub fn replay<R: BufRead>(config: &Config, trace: R) -> Result<Vec<Replayed>, ReplayError> {
+    let mut maps: HashMap<i32, ProcessMap> = HashMap::new();
+    let mut decisions = DecisionCache::new(config);
+    let mut identities = config.uses_identities().then(Identities::default);
+    let mut unwinder = Unwinder::default();
+    let mut replayed = Vec::new();
+    for (number, line) in trace.lines().enumerate() {
+        let line = line.map_err(ReplayError::IoError)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let recorded =
+            match serde_json::from_str(&line).map_err(|e| ReplayError::JsonError(number + 1, e))? {
+                TraceEvent::Map { pid, map } => {
+                    maps.insert(pid, ProcessMap::new(map));
+                    continue;
+                }
+                TraceEvent::Syscall(recorded) => recorded,
+            };
+        let shared = maps
+            .get(&recorded.pid)
+            .ok_or(ReplayError::NoMap(recorded.pid))?;
+        let map = &shared.map;
+        let regs = recorded.regs.as_deref().and_then(registers);
+        let binary = recorded
+            .executable
+            .as_deref()
+            .and_then(|executable| config.binary(executable));
+        let (verdict, _) = handle_syscall(
+            config,
+            recorded.syscall,
+            recorded.starting,
+            map,
+            |visit| {
+                unwinder.walk(
+                    recorded.pc,
+                    regs.as_ref(),
+                    map,
+                    config.stack_walk.max_depth,
+                    |path| PathBuf::from(path),
+                    |addr| recorded.memory.get(&addr).copied(),
+                    visit,
+                )
+            },
+            |region, loc, syscall| {
+                let mut name = || {
+                    library_name(config, binary, map, identities.as_mut(), loc, || {
+                        PathBuf::from(loc)
+                    })
+                };
+                match region {
+                    Some(region) => decisions.check_region(
+                        config,
+                        binary,
+                        (shared.generation, region),
+                        syscall,
+                        name,
+                    ),
+                    None => decisions.check(config, binary, &name(), syscall),
+                }
+            },
+        );
+        replayed.push(decide(config, &recorded, verdict));
+    }
+    Ok(replayed)
+}
+
+/// decide turns a verdict into what the config does about it, the way the tracer would without any hooks. Under
+/// `enforcement: audit` nothing is stopped, so whatever would have been is only logged.
+fn decide(config: &Config, recorded: &RecordedSyscall, verdict: Verdict) -> Replayed {
+    let action = |action: &RuleAction| match action {
+        RuleAction::Kill => Outcome::Killed,
+        RuleAction::Deny(_) => Outcome::Denied,
+        RuleAction::Log => Outcome::Logged,
+        RuleAction::Trap => Outcome::Trapped,
+    };
+    let default = |policy: DefaultPolicy| match policy {
+        DefaultPolicy::Allow => Outcome::Allowed,
+        DefaultPolicy::Kill => Outcome::Killed,
+        DefaultPolicy::Deny => Outcome::Denied,
+    };
+    let (library, rule, outcome) = match verdict {
+        Verdict::Allowed(library, rule) => (Some(library), rule.id, Outcome::Allowed),
+        Verdict::Blocked(library, blocked, rule, _) => (Some(library), rule.id, action(&blocked)),
+        Verdict::Unknown(stack)
+            if stack.is_empty() && config.unattributed == UnattributedPolicy::Block =>
+        {
+            (None, None, Outcome::Killed)
+        }
+        Verdict::Unknown(stack) => (stack.first().cloned(), None, default(config.default)),
+        Verdict::Truncated(stack) => (
+            stack.first().cloned(),
+            None,
+            default(config.stack_walk.on_failure.unwrap_or_default()),
+        ),
+    };
+    let outcome = match outcome {
+        Outcome::Killed | Outcome::Denied | Outcome::Trapped
+            if config.enforcement == Enforcement::Audit =>
+        {
+            Outcome::Logged
+        }
+        outcome => outcome,
+    };
+    Replayed {
+        pid: recorded.pid,
+        syscall: recorded.syscall,
+        library,
+        rule,
+        outcome,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_replay() {
+        let map: MemoryMap = "\
+1000-2000 r-xp 00000000 08:01 11 /opt/crabtrap-test/libfoo.so
+5000-6000 r-xp 00000000 08:01 12 /opt/crabtrap-test/app
+"
+        .parse()
+        .unwrap();
+        let syscall = |pc| {
+            TraceEvent::Syscall(Box::new(RecordedSyscall {
+                pid: 7,
+                syscall: Sysno::openat,
+                args: [0; 6],
+                pc,
+                regs: None,
+                executable: Some(String::from("/opt/crabtrap-test/app")),
+                starting: false,
+                memory: BTreeMap::new(),
+            }))
+        };
+        let trace: String = [
+            TraceEvent::Map { pid: 7, map },
+            syscall(0x1100),
+            syscall(0x5100),
+            syscall(0x9000),
+        ]
+        .iter()
+        .map(|event| serde_json::to_string(event).unwrap() + "\n")
+        .collect();
+
+        let config: Config = r#"
+shared_objects:
+  /opt/crabtrap-test/libfoo.so:
+    id: foo
+    allow: [openat]
+  /opt/crabtrap-test/app:
+    block: [openat]
+"#
+        .parse()
+        .unwrap();
+        let outcomes = |config: &Config| -> Vec<(Option<String>, Option<String>, Outcome)> {
+            replay(config, trace.as_bytes())
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.library, r.rule, r.outcome))
+                .collect()
+        };
+        let libfoo = Some(String::from("/opt/crabtrap-test/libfoo.so"));
+        let app = Some(String::from("/opt/crabtrap-test/app"));
+        assert_eq!(
+            outcomes(&config),
+            [
+                (libfoo.clone(), Some(String::from("foo")), Outcome::Allowed),
+                (app.clone(), None, Outcome::Killed),
+                // Outside anything mapped, so the default decides
+                (None, None, Outcome::Allowed),
+            ]
+        );
+
+        // The recorded executable's section overrides the top-level entry, and an entry's own default covers what
+        // its lists don't
+        let config: Config = r#"
+shared_objects:
+  /opt/crabtrap-test/libfoo.so:
+    id: foo
+    allow: [openat]
+  /opt/crabtrap-test/app:
+    default: deny
+binaries:
+  /opt/crabtrap-test/app:
+    shared_objects:
+      /opt/crabtrap-test/libfoo.so:
+        id: foo-in-app
+        block: [openat]
+        action: trap
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            outcomes(&config),
+            [
+                (
+                    libfoo.clone(),
+                    Some(String::from("foo-in-app")),
+                    Outcome::Trapped
+                ),
+                (app.clone(), None, Outcome::Denied),
+                (None, None, Outcome::Allowed),
+            ]
+        );
+
+        // Under audit, nothing is stopped
+        let config = Config {
+            enforcement: Enforcement::Audit,
+            ..config
+        };
+        assert_eq!(
+            outcomes(&config),
+            [
+                (libfoo, Some(String::from("foo-in-app")), Outcome::Logged),
+                (app, None, Outcome::Logged),
+                (None, None, Outcome::Allowed),
+            ]
+        );
+
+        let regs = words(&unsafe { mem::zeroed() });
+        assert!(registers(&regs).is_some());
+        assert!(registers(&regs[1..]).is_none());
+
+        assert!(matches!(
+            replay(
+                &config,
+                serde_json::to_string(&syscall(0x1100)).unwrap().as_bytes()
+            ),
+            Err(ReplayError::NoMap(7))
+        ));
+    }
+}
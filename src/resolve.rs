@@ -0,0 +1,178 @@
+use crate::{environment, Config, RunAs};
+use nix::{errno::Errno, libc};
+use std::{
+    ffi::{CStr, CString, OsStr},
+    fs, io,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+};
+
+/// Where to look when the environment has no PATH, as glibc's execvp does
+const DEFAULT_PATH: &[u8] = b"/bin:/usr/bin";
+
+/// resolve finds the executable `target` names the way execvp does. A name with a slash in it is a path already, and
+/// is left alone, so a relative one is relative to the directory the target starts in. Anything else is looked up in
+/// each directory in the PATH the target is started with, going by `env` and the config, and inside the new root if
+/// there is one. A relative directory, including an empty one, which means the current directory, is relative to
+/// the directory the target starts in. A script counts as long as the user the target runs as can execute it, since
+/// the kernel runs it with the interpreter its `#!` line names. The path returned is the one the target would see.
+pub fn resolve(target: &str, env: &[&CStr], config: &Config) -> io::Result<PathBuf> {
+    if target.contains('/') {
+        return Ok(PathBuf::from(target));
+    }
+    if target.is_empty() {
+        return Err(Errno::ENOENT.into());
+    }
+    let env = environment(env, config);
+    let search_path = env
+        .iter()
+        .find_map(|var| var.to_bytes().strip_prefix(b"PATH="))
+        .unwrap_or(DEFAULT_PATH);
+    let root = config.namespaces.root.as_deref();
+    // Where the target starts, as the target sees it. A new root is entered at its top, and otherwise it starts where
+    // we are.
+    let cwd = match (&config.working_dir, root) {
+        (Some(dir), _) => Some(dir.as_path()),
+        (None, Some(_)) => Some(Path::new("/")),
+        (None, None) => None,
+    };
+
+    // Like execvp, remember a candidate we couldn't run in case nothing else turns up
+    let mut error = Errno::ENOENT;
+    for dir in search_path.split(|&b| b == b':') {
+        let dir = match dir {
+            b"" => Path::new("."),
+            dir => Path::new(OsStr::from_bytes(dir)),
+        };
+        let candidate = dir.join(target);
+        let absolute = match cwd {
+            Some(cwd) => cwd.join(&candidate),
+            None => candidate.clone(),
+        };
+        let host = match root {
+            Some(root) => root.join(absolute.strip_prefix("/").unwrap_or(&absolute)),
+            None => absolute,
+        };
+        match executable(&host, config.run_as.as_ref()) {
+            Ok(()) => return Ok(candidate),
+            Err(Errno::EACCES) => error = Errno::EACCES,
+            Err(_) => {}
+        }
+    }
+    Err(error.into())
+}
+
+/// executable checks that `path` is a file the target could execute, running as `run_as` if it's given, and as us
+/// otherwise.
+fn executable(path: &Path, run_as: Option<&RunAs>) -> nix::Result<()> {
+    if !path.is_file() {
+        return Err(Errno::ENOENT);
+    }
+    let Some(run_as) = run_as else {
+        let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+        return Errno::result(unsafe {
+            libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::X_OK, libc::AT_EACCESS)
+        })
+        .map(drop);
+    };
+    let metadata = fs::metadata(path).map_err(|_| Errno::ENOENT)?;
+    let mode = metadata.mode();
+    // Root can execute anything that anyone can
+    let bits = if run_as.uid == 0 {
+        libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH
+    } else if metadata.uid() == run_as.uid {
+        libc::S_IXUSR
+    } else if metadata.gid() == run_as.gid || run_as.groups.contains(&metadata.gid()) {
+        libc::S_IXGRP
+    } else {
+        libc::S_IXOTH
+    };
+    if mode & bits == 0 {
+        return Err(Errno::EACCES);
+    }
+    Ok(())
+}
+
+/// c_path returns a path resolve found as the C string to execute it by.
+pub fn c_path(path: PathBuf) -> io::Result<CString> {
+    CString::new(path.into_os_string().into_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    #[test]
+    fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("crabtrap_path_lookup_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("script");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let data = dir.join("data");
+        fs::write(&data, "").unwrap();
+        fs::set_permissions(&data, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let path = CString::new(format!("PATH=/nonexistent:{}", dir.display())).unwrap();
+        let env = [path.as_c_str()];
+        let config = Config::default();
+        assert_eq!(resolve("script", &env, &config).unwrap(), script);
+        assert_eq!(
+            resolve("./script", &env, &config).unwrap(),
+            Path::new("./script")
+        );
+        assert_eq!(
+            resolve("missing", &env, &config).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // Pinned variables are what the target would see
+        let mut config = Config::default();
+        config
+            .environment
+            .pin
+            .insert(String::from("PATH"), String::from("/nonexistent"));
+        assert!(resolve("script", &env, &config).is_err());
+
+        // A file no one can execute is skipped, but still what the error's about
+        assert_eq!(
+            resolve("data", &env, &Config::default())
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EACCES)
+        );
+
+        // An empty entry is the directory the target starts in, not ours
+        let path = c"PATH=/nonexistent:";
+        let config = Config {
+            working_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve("script", &[path], &config).unwrap(),
+            Path::new("./script")
+        );
+        assert!(resolve("script", &[path], &Config::default()).is_err());
+
+        // Whether it can be executed depends on who it's run as
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o744)).unwrap();
+        let config = Config {
+            run_as: Some(RunAs {
+                uid: 12345,
+                gid: 12345,
+                groups: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve("script", &env, &config).unwrap_err().raw_os_error(),
+            Some(libc::EACCES)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use nix::{sys::signal::Signal, unistd::Pid};
+use std::time::Instant;
+
+/// Restart: a stopped tracee waiting to be resumed
+#[derive(Debug, PartialEq, Eq)]
+pub struct Restart {
+    pub pid: Pid,
+    /// The signal to deliver when resuming, if the stop was for a signal we're passing on
+    pub signal: Option<Signal>,
+    pub at: Instant,
+}
+
+/// RestartQueue: tracees that have been dealt with and are waiting to be resumed
+///
+/// Deciding what to do about a stop and resuming the tracee are separate steps, so a single tracee
+/// can be held back without holding up the rest of the tree.
+#[derive(Debug, Default)]
+pub struct RestartQueue {
+    restarts: Vec<Restart>,
+}
+
+impl RestartQueue {
+    /// schedule queues `pid` to be resumed at `at`.
+    pub fn schedule(&mut self, pid: Pid, signal: Option<Signal>, at: Instant) {
+        self.restarts.push(Restart { pid, signal, at });
+    }
+
+    /// is_empty returns whether any tracee is waiting to be resumed.
+    pub fn is_empty(&self) -> bool {
+        self.restarts.is_empty()
+    }
+
+    /// next_due returns when the earliest queued restart is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.restarts.iter().map(|restart| restart.at).min()
+    }
+
+    /// take_due removes and returns every restart due by `now`, in the order they were scheduled.
+    pub fn take_due(&mut self, now: Instant) -> Vec<Restart> {
+        let (due, later) = self
+            .restarts
+            .drain(..)
+            .partition(|restart| restart.at <= now);
+        self.restarts = later;
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_restart_queue() {
+        let now = Instant::now();
+        let later = now + Duration::from_millis(5);
+        let mut queue = RestartQueue::default();
+        assert!(queue.is_empty());
+        assert_eq!(queue.next_due(), None);
+
+        queue.schedule(Pid::from_raw(2), None, later);
+        queue.schedule(Pid::from_raw(1), Some(Signal::SIGUSR1), now);
+        queue.schedule(Pid::from_raw(3), None, now);
+        assert_eq!(queue.next_due(), Some(now));
+
+        assert_eq!(
+            queue.take_due(now),
+            vec![
+                Restart {
+                    pid: Pid::from_raw(1),
+                    signal: Some(Signal::SIGUSR1),
+                    at: now,
+                },
+                Restart {
+                    pid: Pid::from_raw(3),
+                    signal: None,
+                    at: now,
+                },
+            ]
+        );
+        assert_eq!(queue.next_due(), Some(later));
+        assert!(queue.take_due(now).is_empty());
+        assert_eq!(queue.take_due(later).len(), 1);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{events::Event, execute_with_events, ChildExit, Config, RunStats};
+use std::{
+    ffi::{CStr, CString},
+    thread,
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// Sandbox: an async front end for tracing a program, for embedding in async servers
+///
+/// The tracer runs on a thread of its own, since ptrace only lets the thread that started a tracee wait on it. It only
+/// waits for that thread's children, so any number of sandboxes can run at once alongside the server's own children.
+///
+/// ```no_run
+/// # async fn run() {
+/// use crabtrap::{sandbox::Sandbox, Config};
+///
+/// let mut running = Sandbox::new(c"/bin/ls", &[c"ls"], &[], Config::default()).spawn().await;
+/// while let Some(event) = running.next_event().await {
+///     println!("{event:?}");
+/// }
+/// let (exit, stats) = running.wait().await;
+/// # }
+/// ```
+pub struct Sandbox {
+    path: CString,
+    args: Vec<CString>,
+    env: Vec<CString>,
+    config: Config,
+}
+
+/// RunningSandbox: a program that's being traced, and the events it's produced so far
+pub struct RunningSandbox {
+    pid: i32,
+    events: mpsc::UnboundedReceiver<Event>,
+    exit: oneshot::Receiver<(ChildExit, RunStats)>,
+}
+
+impl Sandbox {
+    pub fn new(path: &CStr, args: &[&CStr], env: &[&CStr], config: Config) -> Sandbox {
+        Sandbox {
+            path: path.into(),
+            args: args.iter().map(|&arg| arg.into()).collect(),
+            env: env.iter().map(|&var| var.into()).collect(),
+            config,
+        }
+    }
+
+    /// spawn starts the program, and returns once it's running under the tracer.
+    pub async fn spawn(self) -> RunningSandbox {
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let (exit_sender, exit) = oneshot::channel();
+
+        thread::spawn(move || {
+            let args: Vec<&CStr> = self.args.iter().map(CString::as_c_str).collect();
+            let env: Vec<&CStr> = self.env.iter().map(CString::as_c_str).collect();
+            let result = execute_with_events(&self.path, &args, &env, &self.config, |event| {
+                // Nobody listening just means nobody cares about the events any more
+                let _ = event_sender.send(event);
+            });
+            let _ = exit_sender.send(result);
+        });
+
+        let pid = match events.recv().await {
+            Some(Event::Started { pid }) => pid,
+            event => panic!("expected the tracer to start with the child's pid, got {event:?}"),
+        };
+        RunningSandbox { pid, events, exit }
+    }
+}
+
+impl RunningSandbox {
+    /// pid returns the pid of the program that was started. Processes it starts in turn are traced too.
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// next_event waits for the next event, and returns None once the run is over and every event has been seen.
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// wait waits for the run to finish, dropping any events that haven't been seen.
+    pub async fn wait(self) -> (ChildExit, RunStats) {
+        self.exit.await.expect("tracer thread panicked")
+    }
+}
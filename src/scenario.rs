@@ -0,0 +1,159 @@
+use nix::errno::Errno;
+use serde::{Deserialize, Serialize, Serializer};
+use std::{fs, io, path::Path};
+use syscalls::Sysno;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ScenarioError {
+    #[error("Failed to read scenario: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Failed to parse scenario: {0}")]
+    ParseError(#[from] serde_yaml::Error),
+}
+
+/// Action: what to do to the syscall that triggers a step
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Skip the syscall and have it fail with this error, e.g. `fail: ECONNREFUSED`
+    #[serde(with = "crate::names::errno")]
+    Fail(Errno),
+    /// Hold the calling process back this long before letting the syscall run
+    DelayMs(u64),
+}
+
+/// Step: one step of a scenario, which triggers on the `nth` matching syscall after the previous step
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    #[serde(
+        serialize_with = "serialize_syscall",
+        deserialize_with = "crate::names::deserialize"
+    )]
+    pub syscall: Sysno,
+    #[serde(default = "default_nth")]
+    pub nth: u64,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+fn default_nth() -> u64 {
+    1
+}
+
+fn serialize_syscall<S: Serializer>(syscall: &Sysno, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(syscall.name())
+}
+
+/// Scenario: a script of failures to inject into the traced tree, one step after another
+///
+/// ```yaml
+/// steps:
+///   - syscall: connect
+///     nth: 3
+///     fail: ECONNREFUSED
+///   - syscall: read
+///     delay_ms: 100
+/// ```
+///
+/// fails the third `connect`, then delays the next `read` after that by 100ms.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+}
+
+impl Scenario {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Scenario, ScenarioError> {
+        Ok(serde_yaml::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// ScenarioRunner: how far through a scenario the traced tree has got
+pub struct ScenarioRunner {
+    steps: Vec<Step>,
+    /// The step we're waiting on
+    current: usize,
+    /// How many syscalls have matched the current step so far
+    seen: u64,
+}
+
+impl ScenarioRunner {
+    pub fn new(scenario: &Scenario) -> ScenarioRunner {
+        ScenarioRunner {
+            steps: scenario.steps.clone(),
+            current: 0,
+            seen: 0,
+        }
+    }
+
+    /// next counts a syscall entry, and returns the action to take if it triggers the current step.
+    pub fn next(&mut self, syscall: Sysno) -> Option<Action> {
+        let step = self.steps.get(self.current)?;
+        if step.syscall != syscall {
+            return None;
+        }
+
+        self.seen += 1;
+        if self.seen < step.nth {
+            return None;
+        }
+        self.current += 1;
+        self.seen = 0;
+        Some(step.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scenario() {
+        let scenario: Scenario = serde_yaml::from_str(
+            r#"steps:
+- syscall: connect
+  nth: 3
+  fail: ECONNREFUSED
+- syscall: read
+  delay_ms: 100
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            scenario.steps,
+            vec![
+                Step {
+                    syscall: Sysno::connect,
+                    nth: 3,
+                    action: Action::Fail(Errno::ECONNREFUSED),
+                },
+                Step {
+                    syscall: Sysno::read,
+                    nth: 1,
+                    action: Action::DelayMs(100),
+                },
+            ]
+        );
+
+        let mut runner = ScenarioRunner::new(&scenario);
+        assert_eq!(runner.next(Sysno::read), None);
+        assert_eq!(runner.next(Sysno::connect), None);
+        assert_eq!(runner.next(Sysno::connect), None);
+        assert_eq!(runner.next(Sysno::write), None);
+        assert_eq!(
+            runner.next(Sysno::connect),
+            Some(Action::Fail(Errno::ECONNREFUSED))
+        );
+        assert_eq!(runner.next(Sysno::connect), None);
+        assert_eq!(runner.next(Sysno::read), Some(Action::DelayMs(100)));
+        assert_eq!(runner.next(Sysno::read), None);
+    }
+
+    #[test]
+    fn test_unknown_errno() {
+        assert!(serde_yaml::from_str::<Scenario>(
+            "steps:\n- syscall: connect\n  fail: ENOTANERROR\n"
+        )
+        .is_err());
+    }
+}
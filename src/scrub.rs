@@ -0,0 +1,88 @@
+use crate::config::EnvironmentConfig;
+use std::ffi::CString;
+
+/// The variables glibc drops from a setuid program's environment (its UNSECURE_ENVVARS), since they have it load code
+/// or read files from wherever they say. A library loaded through LD_PRELOAD, LD_AUDIT or LD_LIBRARY_PATH is
+/// attributed like any other, so whoever sets them can have syscalls made from a path the config allows, and
+/// GCONV_PATH has iconv load modules from a directory of their choosing.
+pub const UNSECURE_VARIABLES: [&str; 25] = [
+    "GCONV_PATH",
+    "GETCONF_DIR",
+    "GLIBC_TUNABLES",
+    "HOSTALIASES",
+    "LD_AUDIT",
+    "LD_BIND_NOT",
+    "LD_BIND_NOW",
+    "LD_DEBUG",
+    "LD_DEBUG_OUTPUT",
+    "LD_DYNAMIC_WEAK",
+    "LD_HWCAP_MASK",
+    "LD_LIBRARY_PATH",
+    "LD_ORIGIN_PATH",
+    "LD_PRELOAD",
+    "LD_PROFILE",
+    "LD_SHOW_AUXV",
+    "LOCALDOMAIN",
+    "LOCPATH",
+    "MALLOC_TRACE",
+    "NIS_PATH",
+    "NLSPATH",
+    "RESOLV_HOST_CONF",
+    "RES_OPTIONS",
+    "TMPDIR",
+    "TZDIR",
+];
+
+/// apply drops the unsecure variables from `env` if the config says to scrub them, and sets the pinned ones to their
+/// configured values whatever they were before.
+pub fn apply(config: &EnvironmentConfig, env: Vec<CString>) -> Vec<CString> {
+    let name = |var: &CString| -> String {
+        let var = var.to_bytes();
+        let name = var.split(|&b| b == b'=').next().unwrap_or(var);
+        String::from_utf8_lossy(name).into_owned()
+    };
+    env.into_iter()
+        .filter(|var| {
+            let name = name(var);
+            let scrubbed = config.scrub && UNSECURE_VARIABLES.contains(&name.as_str());
+            !scrubbed && !config.pin.contains_key(&name)
+        })
+        .chain(config.pin.iter().map(|(key, value)| {
+            // Loading a config rejects these, so only one that's been built in code can have them
+            CString::new(format!("{key}={value}")).expect("pinned variable contains a nul byte")
+        }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_apply() {
+        let env = || {
+            [
+                c"PATH=/bin",
+                c"LD_PRELOAD=/tmp/evil.so",
+                c"LD_LIBRARY_PATH=/tmp",
+                c"GCONV_PATH=/tmp",
+            ]
+            .map(CString::from)
+            .to_vec()
+        };
+        assert_eq!(apply(&EnvironmentConfig::default(), env()), env());
+
+        let config = EnvironmentConfig {
+            scrub: true,
+            pin: BTreeMap::from([("LD_LIBRARY_PATH".into(), "/opt/app/lib".into())]),
+        };
+        assert_eq!(
+            apply(&config, env()),
+            vec![
+                CString::from(c"PATH=/bin"),
+                CString::from(c"LD_LIBRARY_PATH=/opt/app/lib")
+            ]
+        );
+    }
+}
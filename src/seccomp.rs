@@ -0,0 +1,185 @@
+use crate::arch;
+use nix::{
+    errno::Errno,
+    libc::{self, sock_filter, sock_fprog},
+};
+use std::collections::{BTreeMap, BTreeSet};
+use syscalls::Sysno;
+
+/// BPF opcodes, from linux/bpf_common.h
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets into struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+fn statement(code: u16, k: u32) -> sock_filter {
+    sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code, jt, jf, k }
+}
+
+/// filter builds a seccomp filter that stops at the given syscalls, and at every syscall from a compat process,
+/// whose numbers come from another table. Everything else runs without the tracer hearing about it.
+pub(crate) fn filter(syscalls: &BTreeSet<Sysno>) -> Vec<sock_filter> {
+    let trace = libc::SECCOMP_RET_TRACE;
+    program(
+        trace,
+        syscalls.iter().map(|&syscall| (syscall, trace)),
+        libc::SECCOMP_RET_ALLOW,
+    )
+}
+
+/// standalone builds a seccomp filter that enforces without a tracer, returning each syscall's value from `rules`
+/// and `default` for the rest. Compat processes are killed, since there's no telling which syscalls they're making.
+pub fn standalone(rules: &BTreeMap<Sysno, u32>, default: u32) -> Vec<sock_filter> {
+    program(
+        libc::SECCOMP_RET_KILL_PROCESS,
+        rules.iter().map(|(&syscall, &ret)| (syscall, ret)),
+        default,
+    )
+}
+
+/// program returns `compat` for a syscall from another architecture, the value paired with the syscall if it's one
+/// of `rules`, and `default` otherwise.
+///
+/// Each syscall gets a comparison of its own followed by a return, so the program is longer than a jump table would
+/// be but never needs a jump further than the 255 instructions BPF allows.
+fn program(
+    compat: u32,
+    rules: impl Iterator<Item = (Sysno, u32)>,
+    default: u32,
+) -> Vec<sock_filter> {
+    let mut program = vec![
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JEQ_K, arch::AUDIT_ARCH, 1, 0),
+        statement(BPF_RET_K, compat),
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    for (syscall, ret) in rules {
+        program.push(jump(BPF_JEQ_K, syscall.id() as u32, 0, 1));
+        program.push(statement(BPF_RET_K, ret));
+    }
+    program.push(statement(BPF_RET_K, default));
+    program
+}
+
+/// to_bytes lays the filter out as an array of struct sock_filter, which is what tools that load a filter from a
+/// file, like `bwrap --seccomp`, expect.
+pub fn to_bytes(filter: &[sock_filter]) -> Vec<u8> {
+    filter
+        .iter()
+        .flat_map(|instruction| {
+            let mut bytes = [0; 8];
+            bytes[..2].copy_from_slice(&instruction.code.to_ne_bytes());
+            bytes[2] = instruction.jt;
+            bytes[3] = instruction.jf;
+            bytes[4..].copy_from_slice(&instruction.k.to_ne_bytes());
+            bytes
+        })
+        .collect()
+}
+
+/// install applies the filter to the calling process and everything it goes on to start. Called in the forked child,
+/// so it mustn't allocate.
+pub(crate) fn install(filter: &[sock_filter]) -> nix::Result<()> {
+    let program = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut sock_filter,
+    };
+    // Without this only root could install a filter
+    Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            0,
+            &program as *const sock_fprog,
+        )
+    })
+    .map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// run interprets the handful of instructions filter uses.
+    fn run(program: &[sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            match instruction.code {
+                BPF_LD_W_ABS if instruction.k == SECCOMP_DATA_ARCH => accumulator = arch,
+                BPF_LD_W_ABS => accumulator = nr,
+                BPF_JEQ_K if accumulator == instruction.k => pc += instruction.jt as usize,
+                BPF_JEQ_K => pc += instruction.jf as usize,
+                BPF_RET_K => return instruction.k,
+                code => panic!("unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let program = filter(&BTreeSet::from([Sysno::openat, Sysno::write]));
+        let trace = libc::SECCOMP_RET_TRACE;
+        let allow = libc::SECCOMP_RET_ALLOW;
+        assert_eq!(
+            run(&program, arch::AUDIT_ARCH, Sysno::write.id() as u32),
+            trace
+        );
+        assert_eq!(
+            run(&program, arch::AUDIT_ARCH, Sysno::openat.id() as u32),
+            trace
+        );
+        assert_eq!(
+            run(&program, arch::AUDIT_ARCH, Sysno::read.id() as u32),
+            allow
+        );
+        assert_eq!(
+            run(&program, arch::COMPAT_AUDIT_ARCH, Sysno::read.id() as u32),
+            trace
+        );
+    }
+
+    #[test]
+    fn test_standalone() {
+        let deny = libc::SECCOMP_RET_ERRNO | Errno::EACCES as u32;
+        let program = standalone(
+            &BTreeMap::from([
+                (Sysno::socket, libc::SECCOMP_RET_KILL_PROCESS),
+                (Sysno::openat, deny),
+            ]),
+            libc::SECCOMP_RET_ALLOW,
+        );
+        assert_eq!(
+            run(&program, arch::AUDIT_ARCH, Sysno::socket.id() as u32),
+            libc::SECCOMP_RET_KILL_PROCESS
+        );
+        assert_eq!(
+            run(&program, arch::AUDIT_ARCH, Sysno::openat.id() as u32),
+            deny
+        );
+        assert_eq!(
+            run(&program, arch::AUDIT_ARCH, Sysno::read.id() as u32),
+            libc::SECCOMP_RET_ALLOW
+        );
+        assert_eq!(
+            run(&program, arch::COMPAT_AUDIT_ARCH, Sysno::read.id() as u32),
+            libc::SECCOMP_RET_KILL_PROCESS
+        );
+        assert_eq!(to_bytes(&program).len(), program.len() * 8);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::memory::read_bytes;
+use nix::{libc, unistd::Pid};
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use syscalls::Sysno;
+
+/// Most of a single write we'll record, so a huge write doesn't stall the tracer
+const MAX_WRITE: usize = 64 * 1024;
+
+/// SessionRecording: what the traced tree printed, in asciinema's v2 format, with a marker at each policy decision
+///
+/// The output is read out of writes to stdout and stderr as they're made, so it goes wherever it was going anyway
+/// and the recording plays back with `asciinema play`.
+pub struct SessionRecording {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+impl SessionRecording {
+    /// create writes the header, sized to our own terminal if we have one.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<SessionRecording> {
+        let mut size = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let (width, height) =
+            match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+                0 if size.ws_col > 0 && size.ws_row > 0 => (size.ws_col, size.ws_row),
+                _ => (80, 24),
+            };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+        });
+        writeln!(writer, "{header}")?;
+        Ok(SessionRecording {
+            writer,
+            started: Instant::now(),
+        })
+    }
+
+    /// syscall records what a syscall entry writes to stdout or stderr, if it's a write to either.
+    pub fn syscall(&mut self, pid: Pid, syscall: Sysno, args: &[u64; 6]) {
+        if !matches!(args[0], 1 | 2) {
+            return;
+        }
+        let output = match syscall {
+            Sysno::write => read_bytes(pid, args[1], (args[2] as usize).min(MAX_WRITE)),
+            Sysno::writev => read_iovecs(pid, args[1], args[2] as usize),
+            _ => return,
+        };
+        if let Some(output) = output.filter(|output| !output.is_empty()) {
+            self.event("o", &String::from_utf8_lossy(&output));
+        }
+    }
+
+    /// marker records a policy decision, which players show as a point to jump to.
+    pub fn marker(&mut self, label: &str) {
+        self.event("m", label);
+    }
+
+    fn event(&mut self, kind: &str, data: &str) {
+        let time = self.started.elapsed().as_secs_f64();
+        writeln!(self.writer, "{}", json!([time, kind, data]))
+            .expect("failed to write session recording");
+    }
+}
+
+/// read_iovecs reads the buffers of a writev, up to MAX_WRITE bytes of them.
+fn read_iovecs(pid: Pid, addr: u64, count: usize) -> Option<Vec<u8>> {
+    let iovecs = read_bytes(pid, addr, count.min(libc::UIO_MAXIOV as usize) * 16)?;
+    let mut output = Vec::new();
+    for iovec in iovecs.chunks_exact(16) {
+        let base = u64::from_ne_bytes(iovec[..8].try_into().unwrap());
+        let len = u64::from_ne_bytes(iovec[8..].try_into().unwrap()) as usize;
+        let len = len.min(MAX_WRITE - output.len());
+        output.extend(read_bytes(pid, base, len)?);
+        if output.len() == MAX_WRITE {
+            break;
+        }
+    }
+    Some(output)
+}
@@ -0,0 +1,249 @@
+use crate::{config::Virtualization, ChildExit, Frame};
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::BTreeMap, time::Duration};
+use syscalls::Sysno;
+
+/// LibraryStats: the syscalls attributed to one library
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct LibraryStats {
+    /// Number of syscalls that ran to completion
+    pub syscalls: u64,
+    /// Total time between their entry and exit stops. This includes our own overhead handling the stops,
+    /// so it's most useful for comparing libraries against each other rather than as an absolute number.
+    pub time: Duration,
+    /// How many of the syscalls were each syscall
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub by_syscall: BTreeMap<Sysno, u64>,
+}
+
+/// ProcessExit: how one process in the traced tree ended
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProcessExit {
+    pub pid: i32,
+    /// Exited or Signaled
+    pub exit: ChildExit,
+    /// If a signal killed it, the stack its main thread was on, innermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backtrace: Vec<Frame>,
+}
+
+/// Usage: the time and memory the traced program took, as the kernel accounted for it when the started process was
+/// reaped. The CPU times and memory cover the processes it waited for as well as itself.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// From when tracing started until the started process exited
+    pub wall_time: Duration,
+    /// CPU time spent in user space and in the kernel
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// The most memory any one of the processes had resident at once, in bytes
+    pub max_rss: u64,
+}
+
+impl Usage {
+    /// new takes the usage wait4 reported for the started process, which ran for `wall_time`.
+    pub fn new(wall_time: Duration, usage: &libc::rusage) -> Self {
+        let time =
+            |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+        Self {
+            wall_time,
+            user_time: time(usage.ru_utime),
+            system_time: time(usage.ru_stime),
+            // Linux counts it in kilobytes
+            max_rss: usage.ru_maxrss as u64 * 1024,
+        }
+    }
+}
+
+/// RunStats: counters describing a traced run
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunStats {
+    /// Number of processes (and threads) that were traced
+    pub processes: u64,
+    /// Number of syscalls made across the whole tree
+    pub syscalls: u64,
+    /// How long the run took and the CPU time and memory it used
+    #[serde(default)]
+    pub usage: Usage,
+    /// Number of times a process was seen daemonizing
+    pub daemonized: u64,
+    /// Number of times a memory map was reread after a syscall changed it
+    #[serde(default)]
+    pub map_rebuilds: u64,
+    /// Number of times a process was stopped by job control
+    #[serde(default)]
+    pub job_control_stops: u64,
+    /// Number of rule checks answered from the decision cache, and the number that had to go to the rules
+    #[serde(default)]
+    pub cache_hits: u64,
+    #[serde(default)]
+    pub cache_misses: u64,
+    /// The most memory our own process had resident at once during the run, in bytes. It covers the whole process,
+    /// so it includes whatever else is running in it besides the tracer.
+    #[serde(default)]
+    pub peak_tracer_rss: u64,
+    /// Per-library syscall counts and time spent in syscalls, keyed by the library each syscall was attributed to
+    pub libraries: BTreeMap<String, LibraryStats>,
+    /// How many syscalls each rule with an id decided
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rules: BTreeMap<String, u64>,
+    /// How many times each syscall was made from somewhere that couldn't be attributed to any mapped file
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unattributed: BTreeMap<Sysno, u64>,
+    /// How many syscall results were replaced by each virtualization in deterministic mode
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub virtualized: BTreeMap<Virtualization, u64>,
+    /// In audit mode, each violation that was let through and how many times it happened, in the order they were
+    /// first seen
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<(ChildExit, u64)>,
+    /// How each process in the tree ended, in the order they did, including the ones besides the one started
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exits: Vec<ProcessExit>,
+    /// What the tree wrote to stdout and stderr, for whichever of them the config captures, up to
+    /// 16MiB of each. They're left out of the JSON result, where they'd be arrays of numbers.
+    #[serde(skip)]
+    pub stdout: Option<Vec<u8>>,
+    #[serde(skip)]
+    pub stderr: Option<Vec<u8>>,
+}
+
+impl RunStats {
+    /// record adds a finished syscall attributed to `library`, which took `time` between its entry and exit stops.
+    pub fn record(&mut self, library: &str, syscall: Sysno, time: Duration) {
+        let stats = self.libraries.entry(library.to_string()).or_default();
+        stats.syscalls += 1;
+        stats.time += time;
+        *stats.by_syscall.entry(syscall).or_insert(0) += 1;
+    }
+
+    /// record_violation counts a violation that was let through, and returns whether it's the first of its kind.
+    pub fn record_violation(&mut self, exit: ChildExit) -> bool {
+        match self.violations.iter_mut().find(|(seen, _)| *seen == exit) {
+            Some((_, count)) => {
+                *count += 1;
+                false
+            }
+            None => {
+                self.violations.push((exit, 1));
+                true
+            }
+        }
+    }
+
+    /// cache_hit_rate returns the fraction of rule checks the decision cache answered, or None if nothing was checked.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let checks = self.cache_hits + self.cache_misses;
+        (checks > 0).then(|| self.cache_hits as f64 / checks as f64)
+    }
+
+    /// by_time returns the per-library stats, the library that spent the most time in syscalls first.
+    pub fn by_time(&self) -> Vec<(&str, &LibraryStats)> {
+        let mut libraries: Vec<_> = self
+            .libraries
+            .iter()
+            .map(|(library, stats)| (library.as_str(), stats))
+            .collect();
+        libraries.sort_by_key(|(_, stats)| Reverse(stats.time));
+        libraries
+    }
+
+    /// summary returns a table of how many times each library made each syscall, the most frequent first within
+    /// each library. Syscalls that couldn't be attributed are listed under [unattributed].
+    pub fn summary(&self) -> String {
+        let mut table = format!("{:>10}  syscall\n", "count");
+        for (library, stats) in &self.libraries {
+            table.push_str(&format!("{library}\n"));
+            let mut syscalls: Vec<_> = stats.by_syscall.iter().collect();
+            syscalls.sort_by_key(|&(_, &count)| Reverse(count));
+            for (syscall, count) in syscalls {
+                table.push_str(&format!("{count:>10}  {syscall}\n"));
+            }
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syscalls::Sysno;
+
+    #[test]
+    fn test_record_violation() {
+        let mut stats = RunStats::default();
+        let write = ChildExit::IllegalSyscall {
+            syscall: Sysno::write,
+            library: String::from("/usr/lib/libfoo.so"),
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new(),
+        };
+        let read = ChildExit::IllegalSyscall {
+            syscall: Sysno::read,
+            library: String::from("/usr/lib/libfoo.so"),
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new(),
+        };
+        assert!(stats.record_violation(write.clone()));
+        assert!(stats.record_violation(read.clone()));
+        assert!(!stats.record_violation(write.clone()));
+        assert_eq!(stats.violations, vec![(write, 2), (read, 1)]);
+    }
+
+    #[test]
+    fn test_by_time() {
+        let mut stats = RunStats::default();
+        stats.record("/usr/lib/libc.so.6", Sysno::read, Duration::from_micros(5));
+        stats.record("/usr/lib/libc.so.6", Sysno::read, Duration::from_micros(5));
+        stats.record(
+            "/usr/lib/libcurl.so.4",
+            Sysno::connect,
+            Duration::from_secs(2),
+        );
+
+        assert_eq!(
+            stats.by_time(),
+            vec![
+                (
+                    "/usr/lib/libcurl.so.4",
+                    &LibraryStats {
+                        syscalls: 1,
+                        time: Duration::from_secs(2),
+                        by_syscall: BTreeMap::from([(Sysno::connect, 1)]),
+                    }
+                ),
+                (
+                    "/usr/lib/libc.so.6",
+                    &LibraryStats {
+                        syscalls: 2,
+                        time: Duration::from_micros(10),
+                        by_syscall: BTreeMap::from([(Sysno::read, 2)]),
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut stats = RunStats::default();
+        stats.record("/usr/lib/libc.so.6", Sysno::write, Duration::ZERO);
+        stats.record("/usr/lib/libc.so.6", Sysno::read, Duration::ZERO);
+        stats.record("/usr/lib/libc.so.6", Sysno::read, Duration::ZERO);
+        stats.record("[unattributed]", Sysno::getpid, Duration::ZERO);
+
+        assert_eq!(
+            stats.summary(),
+            "     count  syscall
+/usr/lib/libc.so.6
+         2  read
+         1  write
+[unattributed]
+         1  getpid
+"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{
+    config::{Output, StdioConfig},
+    log::warning,
+};
+use nix::{
+    errno::Errno,
+    fcntl::{self, FcntlArg, OFlag},
+    libc, unistd,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::fd::{AsRawFd, OwnedFd},
+    path::Path,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+/// Redirections: what to put in place of the target's stdin, stdout and stderr, opened before forking since the child
+/// mustn't allocate. A stream without one is shared with us.
+pub struct Redirections {
+    fds: [Option<OwnedFd>; 3],
+    /// The read ends of the pipes for the captured stdout and stderr
+    captured: [Option<OwnedFd>; 2],
+}
+
+/// Captured: what the target is writing to its captured streams, being read on threads of their own so that it
+/// can't fill a pipe and stall
+pub struct Captured {
+    readers: [Option<JoinHandle<io::Result<Vec<u8>>>>; 2],
+    /// Closed to tell the readers the traced tree is gone
+    stop: OwnedFd,
+}
+
+impl Redirections {
+    pub fn open(config: &StdioConfig) -> io::Result<Redirections> {
+        let stdin = match &config.stdin {
+            Some(path) => Some(File::open(path)?.into()),
+            None => None,
+        };
+        let (stdout, stdout_captured) = output(&config.stdout)?;
+        let (stderr, stderr_captured) = output(&config.stderr)?;
+        Ok(Redirections {
+            fds: [stdin, stdout, stderr],
+            captured: [stdout_captured, stderr_captured],
+        })
+    }
+
+    /// apply puts the redirections in place in the calling process. Safe to call between fork and exec.
+    pub fn apply(&self) -> nix::Result<()> {
+        for (target, fd) in self.fds.iter().enumerate() {
+            if let Some(fd) = fd {
+                // The copy doesn't inherit close-on-exec
+                Errno::result(unsafe { libc::dup2(fd.as_raw_fd(), target as i32) })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// capture closes our copies of the target's ends, and starts reading whatever it writes to the captured streams.
+    pub fn capture(&mut self) -> io::Result<Captured> {
+        self.fds = [None, None, None];
+        let (stopped, stop) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let stopped = Arc::new(stopped);
+        Ok(Captured {
+            readers: self.captured.each_mut().map(|captured| {
+                let stopped = stopped.clone();
+                captured
+                    .take()
+                    .map(|fd| thread::spawn(move || read(File::from(fd), &stopped)))
+            }),
+            stop,
+        })
+    }
+}
+
+impl Captured {
+    /// The most that's kept of each stream. The rest is read and thrown away, so that the target doesn't stall.
+    pub const LIMIT: usize = 16 << 20;
+
+    /// finish returns what was written to the captured streams, once the traced tree is gone. Anything that's still
+    /// got them open then, like a daemon that was let go or a detached process, isn't waited for.
+    pub fn finish(self) -> [Option<Vec<u8>>; 2] {
+        drop(self.stop);
+        self.readers.map(|reader| {
+            reader.map(|reader| {
+                reader
+                    .join()
+                    .expect("output reader panicked")
+                    .unwrap_or_else(|e| panic!("failed to read the target's output: {e}"))
+            })
+        })
+    }
+}
+
+/// read reads `file` until everything that has it open closes it, or until the write end of `stopped` is closed,
+/// after which it takes only what's already been written.
+fn read(mut file: File, stopped: &OwnedFd) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut chunk = [0; 1 << 16];
+    let mut truncated = false;
+    let mut fds = [file.as_raw_fd(), stopped.as_raw_fd()].map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    loop {
+        match Errno::result(unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) })
+        {
+            Err(Errno::EINTR) => continue,
+            Err(errno) => return Err(errno.into()),
+            Ok(_) => {}
+        }
+        if fds[1].revents != 0 {
+            // Whatever's left was written before the tree was gone
+            fcntl::fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        }
+        loop {
+            let read = match file.read(&mut chunk) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(output),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                read => read?,
+            };
+            if read == 0 {
+                return Ok(output);
+            }
+            let kept = read.min(Captured::LIMIT - output.len());
+            if kept < read && !truncated {
+                warning!(
+                    "Keeping only the first {} bytes of the target's output",
+                    Captured::LIMIT
+                );
+                truncated = true;
+            }
+            output.extend_from_slice(&chunk[..kept]);
+            if fds[1].revents == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// output opens what an output stream is redirected to, and for a captured one the end of the pipe to read it from.
+fn output(output: &Output) -> io::Result<(Option<OwnedFd>, Option<OwnedFd>)> {
+    let file = |path: &Path| {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+    };
+    Ok(match output {
+        Output::Inherit => (None, None),
+        Output::Null => (Some(file(Path::new("/dev/null"))?.into()), None),
+        Output::File(path) => (Some(file(path)?.into()), None),
+        Output::Capture => {
+            let (reader, writer) = unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+            (Some(writer), Some(reader))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_read() {
+        let (reader, writer) = unistd::pipe().unwrap();
+        let (stopped, stop) = unistd::pipe().unwrap();
+        let mut writer = File::from(writer);
+        writer.write_all(b"hello").unwrap();
+        // Still open, like a daemon's copy would be
+        let reading = thread::spawn(move || read(File::from(reader), &stopped));
+        drop(stop);
+        assert_eq!(reading.join().unwrap().unwrap(), b"hello");
+        drop(writer);
+    }
+}
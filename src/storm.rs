@@ -0,0 +1,84 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+use syscalls::Sysno;
+
+/// How long to hold up each failing call from a site that's been throttled
+pub const THROTTLE: Duration = Duration::from_millis(1);
+/// How long failures are counted together for
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Window: failures from one call site during the current one-second window
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    failures: u64,
+}
+
+/// StormDetector: counts failing syscalls per call site to spot programs stuck retrying in a tight loop
+///
+/// A call site is the syscall together with the address of the instruction that made it. Sites whose window has run
+/// out are forgotten once a window, so only the ones failing lately are kept.
+#[derive(Debug, Default)]
+pub struct StormDetector {
+    sites: BTreeMap<(Sysno, u64), Window>,
+    /// When the sites were last swept for ones whose window has run out
+    swept: Option<Instant>,
+}
+
+impl StormDetector {
+    /// record_failure counts a failed syscall made at `pc`, and returns how many times that site has failed in the
+    /// current window.
+    pub fn record_failure(&mut self, syscall: Sysno, pc: u64, now: Instant) -> u64 {
+        if self
+            .swept
+            .is_none_or(|swept| now.duration_since(swept) >= WINDOW)
+        {
+            self.sites
+                .retain(|_, window| now.duration_since(window.start) < WINDOW);
+            self.swept = Some(now);
+        }
+        let window = self.sites.entry((syscall, pc)).or_insert(Window {
+            start: now,
+            failures: 0,
+        });
+
+        if now.duration_since(window.start) >= WINDOW {
+            *window = Window {
+                start: now,
+                failures: 0,
+            };
+        }
+
+        window.failures += 1;
+        window.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storm_detector() {
+        let mut storms = StormDetector::default();
+        let start = Instant::now();
+
+        for i in 1..=5 {
+            assert_eq!(storms.record_failure(Sysno::read, 0x1000, start), i);
+        }
+        assert_eq!(storms.record_failure(Sysno::read, 0x2000, start), 1);
+        assert_eq!(storms.record_failure(Sysno::write, 0x1000, start), 1);
+        assert_eq!(
+            storms.record_failure(Sysno::read, 0x1000, start + Duration::from_millis(999)),
+            6
+        );
+        assert_eq!(
+            storms.record_failure(Sysno::read, 0x1000, start + Duration::from_secs(1)),
+            1
+        );
+        // The other sites haven't failed since, so they're gone
+        assert_eq!(storms.sites.len(), 1);
+    }
+}
@@ -0,0 +1,851 @@
+use crate::{
+    arch,
+    elf::{self, ElfError},
+    map::MemoryMap,
+};
+use nix::{
+    libc::{self, user_regs_struct},
+    unistd::getpid,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::Read,
+    ops::Range,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    rc::Rc,
+    slice,
+};
+
+/// How many registers the DWARF numbering has for the integer registers, which are all we track
+const REGISTERS: usize = 32;
+
+/// From the DWARF and LSB specs
+const DW_EH_PE_PCREL: u8 = 0x10;
+
+/// Registers: the integer registers of a frame, by DWARF number, as far as they're known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub pc: u64,
+    values: [Option<u64>; REGISTERS],
+}
+
+impl Registers {
+    pub fn new(regs: &user_regs_struct) -> Registers {
+        Registers {
+            pc: arch::pc(regs),
+            values: arch::dwarf_registers(regs).map(Some),
+        }
+    }
+
+    pub fn get(&self, register: u16) -> Option<u64> {
+        self.values.get(register as usize).copied().flatten()
+    }
+}
+
+/// Rule: where to find a register's value for the caller, from DWARF's call frame information
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    Undefined,
+    SameValue,
+    /// Saved at this offset from the CFA
+    Offset(i64),
+    /// The CFA plus this offset
+    ValOffset(i64),
+    /// In another register
+    Register(u16),
+    /// Worked out by a DWARF expression, which we don't evaluate
+    Expression,
+}
+
+/// Row: the rules for one address in a function
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Row {
+    /// The CFA as a register and an offset from it, or None if it takes an expression to work out
+    cfa: Option<(u16, i64)>,
+    registers: BTreeMap<u16, Rule>,
+}
+
+/// Cie: the parts of a Common Information Entry we use
+#[derive(Debug, Clone)]
+struct Cie {
+    code_alignment: u64,
+    data_alignment: i64,
+    return_address: u16,
+    /// How the addresses in its FDEs are encoded
+    encoding: u8,
+    /// Whether its FDEs have augmentation data to skip
+    augmented: bool,
+    instructions: Range<usize>,
+}
+
+/// Fde: a Frame Description Entry, covering one function
+#[derive(Debug, Clone)]
+struct Fde {
+    start: u64,
+    end: u64,
+    cie: usize,
+    instructions: Range<usize>,
+}
+
+/// Reader: a cursor over the bytes of a section
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.bytes.get(self.pos..self.pos.checked_add(N)?)?;
+        self.pos += N;
+        bytes.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take::<1>().map(|[byte]| byte)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<&[u8]> {
+        let rest = self.bytes.get(self.pos..)?;
+        let len = rest.iter().position(|&byte| byte == 0)?;
+        self.pos += len + 1;
+        Some(&rest[..len])
+    }
+
+    /// encoded reads a pointer in one of the DW_EH_PE_* encodings. `section` is the address the section is loaded
+    /// at, for pc-relative pointers.
+    fn encoded(&mut self, encoding: u8, section: u64) -> Option<u64> {
+        let field = section.wrapping_add(self.pos as u64);
+        let value = match encoding & 0x0f {
+            0x00 | 0x04 => self.u64()?,
+            0x01 => self.uleb()?,
+            0x02 => self.u16()?.into(),
+            0x03 => self.u32()?.into(),
+            0x09 => self.sleb()? as u64,
+            0x0a => self.u16()? as i16 as u64,
+            0x0b => self.u32()? as i32 as u64,
+            0x0c => self.u64()?,
+            _ => return None,
+        };
+        // Only the personality routine is ever indirect, and we only skip over that
+        match encoding & 0x70 {
+            0 => Some(value),
+            DW_EH_PE_PCREL => Some(field.wrapping_add(value)),
+            _ => None,
+        }
+    }
+}
+
+/// UnwindTable: the call frame information of one file, from its .eh_frame, or its .debug_frame if it only has that
+pub struct UnwindTable {
+    section: Vec<u8>,
+    /// Where the section is loaded, as the file lays out addresses
+    addr: u64,
+    /// Whether it's .eh_frame, whose CIE pointers and ids differ from .debug_frame's
+    eh: bool,
+    /// See elf::load_bias
+    bias: u64,
+    cies: HashMap<usize, Cie>,
+    /// Sorted by start
+    fdes: Vec<Fde>,
+}
+
+impl UnwindTable {
+    pub fn parse(bytes: &[u8]) -> Result<Option<UnwindTable>, ElfError> {
+        let (section, eh) = match elf::section(bytes, ".eh_frame")? {
+            Some(section) => (section, true),
+            None => match elf::section(bytes, ".debug_frame")? {
+                Some(section) => (section, false),
+                None => return Ok(None),
+            },
+        };
+        let mut table = UnwindTable {
+            section: section.bytes(bytes)?.to_vec(),
+            addr: if eh { section.addr } else { 0 },
+            eh,
+            bias: elf::load_bias(bytes)?,
+            cies: HashMap::new(),
+            fdes: Vec::new(),
+        };
+        table.index().ok_or(ElfError::Malformed)?;
+        table.fdes.sort_by_key(|fde| fde.start);
+        Ok(Some(table))
+    }
+
+    /// index reads every entry in the section.
+    fn index(&mut self) -> Option<()> {
+        let mut pos = 0;
+        while pos < self.section.len() {
+            let mut reader = Reader {
+                bytes: &self.section,
+                pos,
+            };
+            let (length, wide) = match reader.u32()? {
+                // The terminator at the end of .eh_frame
+                0 => break,
+                0xffff_ffff => (reader.u64()? as usize, true),
+                length => (length as usize, false),
+            };
+            let end = reader.pos.checked_add(length)?;
+            let id_pos = reader.pos;
+            let id = if wide {
+                reader.u64()?
+            } else {
+                reader.u32()?.into()
+            };
+            let is_cie = match (self.eh, wide) {
+                (true, _) => id == 0,
+                (false, true) => id == u64::MAX,
+                (false, false) => id == 0xffff_ffff,
+            };
+
+            if !is_cie {
+                let cie = if self.eh {
+                    id_pos.checked_sub(id as usize)?
+                } else {
+                    id as usize
+                };
+                if !self.cies.contains_key(&cie) {
+                    let parsed = self.cie(cie)?;
+                    self.cies.insert(cie, parsed);
+                }
+                let fde = self.fde(&mut reader, &self.cies[&cie], end)?;
+                self.fdes.push(Fde { cie, ..fde });
+            }
+            pos = end;
+        }
+        Some(())
+    }
+
+    fn cie(&self, pos: usize) -> Option<Cie> {
+        let mut reader = Reader {
+            bytes: &self.section,
+            pos,
+        };
+        let end = match reader.u32()? {
+            0xffff_ffff => {
+                let length = reader.u64()? as usize;
+                reader.u64()?;
+                (reader.pos - 8).checked_add(length)?
+            }
+            length => {
+                reader.u32()?;
+                (reader.pos - 4).checked_add(length as usize)?
+            }
+        };
+        let version = reader.u8()?;
+        let augmentation = reader.string()?.to_vec();
+        if version >= 4 {
+            // Address and segment selector sizes
+            reader.take::<2>()?;
+        }
+        let code_alignment = reader.uleb()?;
+        let data_alignment = reader.sleb()?;
+        let return_address = if version == 1 {
+            reader.u8()?.into()
+        } else {
+            reader.uleb()? as u16
+        };
+
+        let mut cie = Cie {
+            code_alignment,
+            data_alignment,
+            return_address,
+            encoding: 0,
+            augmented: augmentation.first() == Some(&b'z'),
+            instructions: 0..0,
+        };
+        if cie.augmented {
+            let len = reader.uleb()? as usize;
+            let data_end = reader.pos.checked_add(len)?;
+            for &kind in &augmentation[1..] {
+                match kind {
+                    b'R' => cie.encoding = reader.u8()?,
+                    b'P' => {
+                        let encoding = reader.u8()?;
+                        reader.encoded(encoding & 0x7f, self.addr)?;
+                    }
+                    b'L' => {
+                        reader.u8()?;
+                    }
+                    _ => {}
+                }
+            }
+            reader.pos = data_end;
+        }
+        cie.instructions = reader.pos..end;
+        Some(cie)
+    }
+
+    fn fde(&self, reader: &mut Reader, cie: &Cie, end: usize) -> Option<Fde> {
+        let start = reader.encoded(cie.encoding, self.addr)?;
+        // The length is never relative to anything
+        let len = reader.encoded(cie.encoding & 0x0f, self.addr)?;
+        if cie.augmented {
+            let len = reader.uleb()? as usize;
+            reader.pos = reader.pos.checked_add(len)?;
+        }
+        Some(Fde {
+            start,
+            end: start.wrapping_add(len),
+            cie: 0,
+            instructions: reader.pos..end,
+        })
+    }
+
+    /// run carries out call frame instructions from `loc` until they pass `target`, starting from `row`. `initial` is
+    /// the row the CIE's own instructions leave, which DW_CFA_restore goes back to.
+    fn run(
+        &self,
+        cie: &Cie,
+        instructions: Range<usize>,
+        mut row: Row,
+        initial: &Row,
+        mut loc: u64,
+        target: u64,
+    ) -> Option<Row> {
+        let mut reader = Reader {
+            bytes: self.section.get(..instructions.end)?,
+            pos: instructions.start,
+        };
+        let mut remembered = Vec::new();
+        let factored = |offset: u64| (offset as i64).wrapping_mul(cie.data_alignment);
+        while reader.pos < instructions.end {
+            let op = reader.u8()?;
+            let advance: Option<u64> = match (op & 0xc0, op & 0x3f) {
+                (0x40, delta) => Some(delta.into()),
+                (0x80, register) => {
+                    let offset = factored(reader.uleb()?);
+                    row.registers.insert(register.into(), Rule::Offset(offset));
+                    None
+                }
+                (0xc0, register) => {
+                    restore(&mut row, initial, register.into());
+                    None
+                }
+                (_, 0x00) => None,
+                (_, 0x01) => {
+                    let next = reader.encoded(cie.encoding, self.addr)?;
+                    if next > target {
+                        return Some(row);
+                    }
+                    loc = next;
+                    None
+                }
+                (_, 0x02) => Some(reader.u8()?.into()),
+                (_, 0x03) => Some(reader.u16()?.into()),
+                (_, 0x04) => Some(reader.u32()?.into()),
+                (_, 0x05) => {
+                    let register = reader.uleb()? as u16;
+                    let offset = factored(reader.uleb()?);
+                    row.registers.insert(register, Rule::Offset(offset));
+                    None
+                }
+                (_, 0x06) => {
+                    restore(&mut row, initial, reader.uleb()? as u16);
+                    None
+                }
+                (_, 0x07) => {
+                    row.registers.insert(reader.uleb()? as u16, Rule::Undefined);
+                    None
+                }
+                (_, 0x08) => {
+                    row.registers.insert(reader.uleb()? as u16, Rule::SameValue);
+                    None
+                }
+                (_, 0x09) => {
+                    let register = reader.uleb()? as u16;
+                    let other = reader.uleb()? as u16;
+                    row.registers.insert(register, Rule::Register(other));
+                    None
+                }
+                (_, 0x0a) => {
+                    remembered.push(row.clone());
+                    None
+                }
+                (_, 0x0b) => {
+                    row = remembered.pop()?;
+                    None
+                }
+                (_, 0x0c) => {
+                    let register = reader.uleb()? as u16;
+                    row.cfa = Some((register, reader.uleb()? as i64));
+                    None
+                }
+                (_, 0x0d) => {
+                    let register = reader.uleb()? as u16;
+                    row.cfa = Some((register, row.cfa.map_or(0, |(_, offset)| offset)));
+                    None
+                }
+                (_, 0x0e) => {
+                    let offset = reader.uleb()? as i64;
+                    row.cfa = Some((row.cfa?.0, offset));
+                    None
+                }
+                (_, 0x0f) => {
+                    let len = reader.uleb()? as usize;
+                    reader.pos = reader.pos.checked_add(len)?;
+                    row.cfa = None;
+                    None
+                }
+                (_, 0x10 | 0x16) => {
+                    let register = reader.uleb()? as u16;
+                    let len = reader.uleb()? as usize;
+                    reader.pos = reader.pos.checked_add(len)?;
+                    row.registers.insert(register, Rule::Expression);
+                    None
+                }
+                (_, 0x11) => {
+                    let register = reader.uleb()? as u16;
+                    let offset = reader.sleb()?.wrapping_mul(cie.data_alignment);
+                    row.registers.insert(register, Rule::Offset(offset));
+                    None
+                }
+                (_, 0x12) => {
+                    let register = reader.uleb()? as u16;
+                    let offset = reader.sleb()?.wrapping_mul(cie.data_alignment);
+                    row.cfa = Some((register, offset));
+                    None
+                }
+                (_, 0x13) => {
+                    let offset = reader.sleb()?.wrapping_mul(cie.data_alignment);
+                    row.cfa = Some((row.cfa?.0, offset));
+                    None
+                }
+                (_, 0x14) => {
+                    let register = reader.uleb()? as u16;
+                    let offset = factored(reader.uleb()?);
+                    row.registers.insert(register, Rule::ValOffset(offset));
+                    None
+                }
+                (_, 0x15) => {
+                    let register = reader.uleb()? as u16;
+                    let offset = reader.sleb()?.wrapping_mul(cie.data_alignment);
+                    row.registers.insert(register, Rule::ValOffset(offset));
+                    None
+                }
+                // DW_CFA_AARCH64_negate_ra_state, which says whether the return address is signed. Addresses are
+                // stripped of their signatures anyway.
+                (_, 0x2d) => None,
+                // DW_CFA_GNU_args_size
+                (_, 0x2e) => {
+                    reader.uleb()?;
+                    None
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                (_, 0x2f) => {
+                    let register = reader.uleb()? as u16;
+                    let offset = factored(reader.uleb()?).wrapping_neg();
+                    row.registers.insert(register, Rule::Offset(offset));
+                    None
+                }
+                _ => return None,
+            };
+            if let Some(delta) = advance {
+                loc = loc.wrapping_add(delta.wrapping_mul(cie.code_alignment));
+                if loc > target {
+                    break;
+                }
+            }
+        }
+        Some(row)
+    }
+
+    /// row returns the rules for an address as the file lays addresses out, and the CIE they go with.
+    fn row(&self, target: u64) -> Option<(Row, &Cie)> {
+        let fde = &self.fdes[self
+            .fdes
+            .partition_point(|fde| fde.start <= target)
+            .checked_sub(1)?];
+        if target >= fde.end {
+            return None;
+        }
+        let cie = &self.cies[&fde.cie];
+        let initial = self.run(
+            cie,
+            cie.instructions.clone(),
+            Row::default(),
+            &Row::default(),
+            fde.start,
+            u64::MAX,
+        )?;
+        let row = self.run(
+            cie,
+            fde.instructions.clone(),
+            initial.clone(),
+            &initial,
+            fde.start,
+            target,
+        )?;
+        Some((row, cie))
+    }
+
+    /// step works out the registers of the caller of a frame, given how far the frame's pc is past the start of the
+    /// file's first mapping. `innermost` is whether the frame is the one that made the syscall, since in the others
+    /// the pc is a return address, which can be past the end of the call's function. `read` reads a word of the
+    /// tracee's memory.
+    pub fn step(
+        &self,
+        offset: u64,
+        frame: &Registers,
+        innermost: bool,
+        read: &mut impl FnMut(u64) -> Option<u64>,
+    ) -> Option<Registers> {
+        let vaddr = offset.wrapping_add(self.bias);
+        let (row, cie) = self.row(if innermost {
+            vaddr
+        } else {
+            vaddr.wrapping_sub(1)
+        })?;
+        let (register, offset) = row.cfa?;
+        let cfa = frame.get(register)?.wrapping_add_signed(offset);
+
+        let mut caller = Registers {
+            pc: 0,
+            values: [None; REGISTERS],
+        };
+        for register in 0..REGISTERS as u16 {
+            let rule = row
+                .registers
+                .get(&register)
+                .copied()
+                .unwrap_or(Rule::SameValue);
+            caller.values[register as usize] = match rule {
+                Rule::Undefined | Rule::Expression => None,
+                Rule::SameValue => frame.get(register),
+                Rule::Offset(offset) => read(cfa.wrapping_add_signed(offset)),
+                Rule::ValOffset(offset) => Some(cfa.wrapping_add_signed(offset)),
+                Rule::Register(other) => frame.get(other),
+            };
+        }
+        caller.values[arch::DWARF_SP as usize] = Some(cfa);
+        caller.pc = arch::strip_return_address(caller.get(cie.return_address)?);
+        Some(caller)
+    }
+}
+
+/// restore sets a register's rule back to what the CIE left it as.
+fn restore(row: &mut Row, initial: &Row, register: u16) {
+    match initial.registers.get(&register) {
+        Some(&rule) => row.registers.insert(register, rule),
+        None => row.registers.remove(&register),
+    };
+}
+
+/// FileKey: tells apart the files unwind tables were read from. A path alone would go on naming the old file once
+/// it's been replaced, and a device and inode can be reused once it's deleted, so the time it was last modified is
+/// part of it too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FileKey {
+    dev: u64,
+    ino: u64,
+    mtime: (i64, i64),
+}
+
+impl FileKey {
+    fn new(metadata: &fs::Metadata) -> FileKey {
+        FileKey {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+        }
+    }
+}
+
+/// Unwinder: the unwind tables of the files mapped into the traced processes, read the first time each is needed
+#[derive(Default)]
+pub struct Unwinder {
+    tables: HashMap<FileKey, Option<Rc<UnwindTable>>>,
+    vdso: Option<Option<Rc<UnwindTable>>>,
+}
+
+/// vdso_table parses the unwind table of the vDSO. It's the same for every process on the kernel, so it's read from
+/// our own.
+fn vdso_table() -> Option<UnwindTable> {
+    let base = unsafe { libc::getauxval(libc::AT_SYSINFO_EHDR) };
+    if base == 0 {
+        return None;
+    }
+    let map = MemoryMap::from_pid(getpid()).ok()?;
+    let region = map.anonymous_region(base)?;
+    let bytes =
+        unsafe { slice::from_raw_parts(base as *const u8, (region.end - region.start) as usize) };
+    UnwindTable::parse(bytes).ok().flatten()
+}
+
+impl Unwinder {
+    /// table returns the unwind table of a file, or None if it has none or can't be read.
+    pub fn table(&mut self, file: PathBuf) -> Option<Rc<UnwindTable>> {
+        let key = FileKey::new(&fs::metadata(&file).ok()?);
+        self.tables
+            .entry(key)
+            .or_insert_with(|| {
+                // The path may have been replaced since, so the file that's read has to be checked to be the same
+                let mut opened = File::open(&file).ok()?;
+                if FileKey::new(&opened.metadata().ok()?) != key {
+                    return None;
+                }
+                let mut bytes = Vec::new();
+                opened.read_to_end(&mut bytes).ok()?;
+                UnwindTable::parse(&bytes).ok().flatten().map(Rc::new)
+            })
+            .clone()
+    }
+
+    /// frame_table returns the unwind table for the code at `pc`, and how far `pc` is into what it was loaded from.
+    fn frame_table(
+        &mut self,
+        map: &MemoryMap,
+        pc: u64,
+        file: &impl Fn(&str) -> PathBuf,
+    ) -> Option<(Rc<UnwindTable>, u64)> {
+        if map.kernel_code(pc).is_some() {
+            let start = map.anonymous_region(pc)?.start;
+            let table = self.vdso.get_or_insert_with(|| vdso_table().map(Rc::new));
+            return Some((table.clone()?, pc - start));
+        }
+        let path = map.lookup(pc)?;
+        let offset = pc - map.base(path)?;
+        Some((self.table(file(path))?, offset))
+    }
+
+    /// walk goes up the stack of a tracee stopped at a syscall, calling `visit` with the pc and then each return
+    /// address until it returns false or the stack runs out. Returns false if the walk was cut short instead, by going
+    /// `max_depth` frames deep or coming to a frame that doesn't look right.
+    ///
+    /// Each frame is unwound with its file's call frame information if it has any. The first frame without it, or
+    /// whose information can't be followed, is walked from by following the chain of frame pointers instead, see the
+    /// arch module for where each architecture keeps them. Each frame has to be further up the stack than the last,
+    /// and within the region the stack pointer is in. Without registers, only the pc is visited. `file` gives the path
+    /// to read a mapped file from, and `read` reads a word of the tracee's memory.
+    #[allow(clippy::too_many_arguments)]
+    pub fn walk(
+        &mut self,
+        pc: u64,
+        regs: Option<&user_regs_struct>,
+        map: &MemoryMap,
+        max_depth: usize,
+        file: impl Fn(&str) -> PathBuf,
+        mut read: impl FnMut(u64) -> Option<u64>,
+        mut visit: impl FnMut(u64) -> bool,
+    ) -> bool {
+        if !visit(pc) {
+            return true;
+        }
+        let Some(regs) = regs else {
+            return true;
+        };
+
+        let mut frame = Registers::new(regs);
+        frame.pc = pc;
+        let stack = frame.get(arch::DWARF_SP).and_then(|sp| map.stack(sp));
+        // The top of the stack is as far as the outermost frame's CFA can go
+        let on_stack = |addr: u64| {
+            stack
+                .as_ref()
+                .is_none_or(|stack| stack.start <= addr && addr <= stack.end)
+        };
+        let mut frames = 1;
+        let mut innermost = true;
+        loop {
+            if frames >= max_depth {
+                return false;
+            }
+            let Some(caller) = self
+                .frame_table(map, frame.pc, &file)
+                .and_then(|(table, offset)| table.step(offset, &frame, innermost, &mut read))
+            else {
+                break;
+            };
+            // Only a frame that's just been entered can share its caller's stack pointer
+            let (sp, caller_sp) = (frame.get(arch::DWARF_SP), caller.get(arch::DWARF_SP));
+            if let (Some(sp), Some(caller_sp)) = (sp, caller_sp) {
+                if caller_sp < sp || (caller_sp == sp && !innermost) || !on_stack(caller_sp) {
+                    return false;
+                }
+            }
+            // A return address of 0 marks the outermost frame
+            if caller.pc == 0 || !visit(caller.pc) {
+                return true;
+            }
+            frame = caller;
+            frames += 1;
+            innermost = false;
+        }
+
+        let mut frame_pointer = if innermost {
+            if !visit(arch::link_register(regs)) {
+                return true;
+            }
+            frames += 1;
+            arch::frame_pointer(regs)
+        } else {
+            match frame.get(arch::DWARF_FP) {
+                Some(frame_pointer) => frame_pointer,
+                None => return false,
+            }
+        };
+        let mut below = frame.get(arch::DWARF_SP).unwrap_or(0);
+        while frame_pointer != 0 {
+            if frames >= max_depth {
+                return false;
+            }
+            // Frame pointers go up the stack, so one that goes back down or off it means the walk has gone wrong
+            if frame_pointer < below || frame_pointer % 8 != 0 || !on_stack(frame_pointer) {
+                return false;
+            }
+            let Some(saved_lr) =
+                read(frame_pointer.wrapping_add_signed(arch::SAVED_RETURN_ADDRESS))
+            else {
+                return false;
+            };
+            if !visit(saved_lr) {
+                return true;
+            }
+            let Some(next) = read(frame_pointer.wrapping_add_signed(arch::SAVED_FRAME_POINTER))
+            else {
+                return false;
+            };
+            below = frame_pointer + 1;
+            frame_pointer = next;
+            frames += 1;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leb() {
+        let mut reader = Reader {
+            bytes: &[0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f],
+            pos: 0,
+        };
+        assert_eq!(reader.uleb(), Some(624485));
+        assert_eq!(reader.sleb(), Some(-1));
+        assert_eq!(reader.sleb(), Some(-128));
+        assert_eq!(reader.uleb(), None);
+    }
+
+    #[test]
+    fn test_malformed() {
+        // An entry whose length runs past the end of memory is turned down rather than overflowing
+        let mut section = vec![0xff; 4];
+        section.extend(u64::MAX.to_le_bytes());
+        let mut table = UnwindTable {
+            section,
+            addr: 0,
+            eh: true,
+            bias: 0,
+            cies: HashMap::new(),
+            fdes: Vec::new(),
+        };
+        assert_eq!(table.index(), None);
+    }
+
+    #[test]
+    fn test_unwind_table() {
+        let bytes = fs::read("/proc/self/exe").unwrap();
+        let table = UnwindTable::parse(&bytes).unwrap().unwrap();
+        assert!(!table.fdes.is_empty());
+
+        let map = MemoryMap::from_pid(nix::unistd::getpid()).unwrap();
+        let exe = fs::read_link("/proc/self/exe").unwrap();
+        let offset =
+            test_unwind_table as *const () as u64 - map.base(exe.to_str().unwrap()).unwrap();
+        // On entry to a function, nothing's been pushed yet, so the CFA is the stack pointer
+        let (row, cie) = table.row(offset + table.bias).unwrap();
+        assert_eq!(row.cfa, Some((arch::DWARF_SP, 0)));
+
+        // So the caller's registers are the same, and it returns to wherever the link register says
+        let mut values = [Some(0); REGISTERS];
+        values[arch::DWARF_SP as usize] = Some(0x1000);
+        values[cie.return_address as usize] = Some(0x2000);
+        let frame = Registers { pc: offset, values };
+        let caller = table.step(offset, &frame, true, &mut |_| None).unwrap();
+        assert_eq!(caller.pc, 0x2000);
+        assert_eq!(caller.get(arch::DWARF_SP), Some(0x1000));
+    }
+
+    #[test]
+    fn test_vdso_table() {
+        let table = vdso_table().unwrap();
+        assert!(!table.fdes.is_empty());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let map: MemoryMap = "".parse().unwrap();
+        let regs: user_regs_struct = unsafe { std::mem::zeroed() };
+        let mut unwinder = Unwinder::default();
+        let mut walk = |max_depth| {
+            let mut visited = Vec::new();
+            let complete = unwinder.walk(
+                0x1000,
+                Some(&regs),
+                &map,
+                max_depth,
+                |path| PathBuf::from(path),
+                |_| None,
+                |addr| {
+                    visited.push(addr);
+                    true
+                },
+            );
+            (complete, visited)
+        };
+        // Without call frame information or a frame pointer, the link register is as far as it goes
+        assert_eq!(walk(256), (true, vec![0x1000, 0]));
+        // Going max_depth frames deep cuts the walk short
+        assert_eq!(walk(1), (false, vec![0x1000]));
+    }
+}
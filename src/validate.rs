@@ -0,0 +1,348 @@
+use crate::{
+    config::{self, ConfigError, ConfigFormat},
+    glob, identity, map, names,
+    profile::{ANONYMOUS_EXEC, LOADER_STARTUP, UNATTRIBUTED},
+};
+use serde_yaml::{Mapping, Value};
+use std::{collections::BTreeSet, fmt, fs, path::Path, str::FromStr};
+use syscalls::Sysno;
+
+/// Problem: something wrong with a config, and where in the file it is if that can be told
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// 1-based, like an editor's
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// validate checks a config file, and the ones it extends, for anything that would stop it loading or is probably a
+/// mistake: unknown syscalls or groups, libraries that aren't on this host, and syscalls a library both allows and
+/// blocks. Returns every problem it finds rather than stopping at the first.
+pub fn validate(path: &Path) -> Vec<Problem> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            return vec![Problem {
+                line: None,
+                message: format!("Failed to read {}: {e}", path.display()),
+            }]
+        }
+    };
+    // Syntax errors in the file itself get a line number, which errors from the files it extends wouldn't mean much
+    // against
+    if let Err(e) = config::parse_value(&source, ConfigFormat::from_path(path)) {
+        return vec![error(&source, e)];
+    }
+    let merged = match config::read_layers(path, &mut Vec::new(), &mut Vec::new()) {
+        Ok(merged) => merged,
+        Err(e) => {
+            return vec![Problem {
+                line: None,
+                message: e.to_string(),
+            }]
+        }
+    };
+
+    let groups: BTreeSet<&str> = merged
+        .get("groups")
+        .and_then(Value::as_mapping)
+        .map(|groups| groups.keys().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let mut problems = Vec::new();
+    let mut check_names = |names: &Value, context: &[&str], what: &str| {
+        for name in names.as_sequence().into_iter().flatten() {
+            if let Some(message) = unknown_syscall(name, &groups) {
+                let written = match name.as_u64() {
+                    Some(number) => number.to_string(),
+                    None => name.as_str().unwrap_or_default().to_string(),
+                };
+                let mut needles = context.to_vec();
+                needles.push(&written);
+                problems.push(Problem {
+                    line: find_line(&source, &needles),
+                    message: format!("{message} in {what}"),
+                });
+            }
+        }
+    };
+    if let Some(groups) = merged.get("groups").and_then(Value::as_mapping) {
+        for (group, members) in groups {
+            let group = group.as_str().unwrap_or_default();
+            check_names(members, &["groups", group], &format!("group {group}"));
+        }
+    }
+    // Each set of rules, with the keys leading to it and how to describe where it is
+    let mut sections: Vec<(Vec<&str>, String, &Mapping)> = Vec::new();
+    if let Some(shared_objects) = merged.get("shared_objects").and_then(Value::as_mapping) {
+        sections.push((vec!["shared_objects"], String::new(), shared_objects));
+    }
+    let binaries = merged.get("binaries").and_then(Value::as_mapping);
+    for (binary, section) in binaries.into_iter().flatten() {
+        let binary = binary.as_str().unwrap_or_default();
+        if let Some(shared_objects) = section.get("shared_objects").and_then(Value::as_mapping) {
+            sections.push((
+                vec!["binaries", binary, "shared_objects"],
+                format!(" under {binary}"),
+                shared_objects,
+            ));
+        }
+    }
+    for (context, under, shared_objects) in &sections {
+        for (library, entry) in *shared_objects {
+            let library = library.as_str().unwrap_or_default();
+            for list in ["allow", "block"] {
+                if let Some(names) = entry.get(list) {
+                    let mut needles = context.clone();
+                    needles.extend([library, list]);
+                    check_names(
+                        names,
+                        &needles,
+                        &format!("the {list} list for {library}{under}"),
+                    );
+                }
+            }
+        }
+    }
+    // Anything past the paths would only repeat problems with syscall names
+    let bad_names = !problems.is_empty();
+
+    for binary in binaries
+        .into_iter()
+        .flat_map(|binaries| binaries.keys())
+        .filter_map(Value::as_str)
+    {
+        if !glob::is_pattern(binary) && !Path::new(binary).exists() {
+            problems.push(Problem {
+                line: find_line(&source, &["binaries", binary]),
+                message: format!("{binary} doesn't exist on this host"),
+            });
+        }
+    }
+    for (context, _, shared_objects) in &sections {
+        for library in shared_objects.keys().filter_map(Value::as_str) {
+            let special = [LOADER_STARTUP, UNATTRIBUTED, ANONYMOUS_EXEC].contains(&library)
+                || map::KERNEL_CODE.contains(&library)
+                || identity::is_identity(library)
+                || glob::is_pattern(library);
+            if !special && !Path::new(library).exists() {
+                let mut needles = context.clone();
+                needles.push(library);
+                problems.push(Problem {
+                    line: find_line(&source, &needles),
+                    message: format!("{library} doesn't exist on this host"),
+                });
+            }
+        }
+    }
+    if bad_names {
+        return problems;
+    }
+
+    let config = match config::from_value(merged) {
+        Ok(config) => config,
+        Err(e) => {
+            // Deserializing the merged config loses track of lines, but the file on its own may have them
+            let line = serde_yaml::from_str::<config::Config>(&source)
+                .err()
+                .and_then(|e| e.location())
+                .map(|location| location.line());
+            problems.push(Problem {
+                line,
+                message: e.to_string(),
+            });
+            return problems;
+        }
+    };
+    let sections = std::iter::once((
+        vec!["shared_objects"],
+        String::new(),
+        &config.shared_objects,
+    ))
+    .chain(config.binaries.iter().map(|(binary, section)| {
+        (
+            vec!["binaries", binary.as_str(), "shared_objects"],
+            format!(" under {binary}"),
+            &section.shared_objects,
+        )
+    }));
+    for (context, under, shared_objects) in sections {
+        for (library, entry) in shared_objects {
+            let lists = [("allow", &entry.allow), ("block", &entry.block)];
+            for (i, (first, a)) in lists.iter().enumerate() {
+                for (second, b) in &lists[i + 1..] {
+                    let (Some(a), Some(b)) = (a, b) else {
+                        continue;
+                    };
+                    let mut needles = context.clone();
+                    needles.push(library);
+                    for syscall in a.intersection(b) {
+                        problems.push(Problem {
+                            line: find_line(&source, &needles),
+                            message: format!(
+                                "{syscall} is in both the {first} and {second} lists for {library}{under}, so it's {}",
+                                if *first == "allow" { "allowed" } else { "blocked" }
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// unknown_syscall returns what's wrong with a name in a list of syscalls, if anything.
+fn unknown_syscall(name: &Value, groups: &BTreeSet<&str>) -> Option<String> {
+    if let Some(number) = name.as_u64() {
+        return Sysno::new(number as usize)
+            .is_none()
+            .then(|| format!("Unknown syscall number {number}"));
+    }
+    let Some(name) = name.as_str() else {
+        return Some(format!("Expected a syscall name, not {name:?}"));
+    };
+    let known = match name.strip_prefix('@') {
+        Some(group) => names::group(group).is_some() || groups.contains(group),
+        None => Sysno::from_str(name).is_ok() || names::alias(name).is_some(),
+    };
+    match (known, name.starts_with('@')) {
+        (true, _) => None,
+        (false, true) => Some(format!("Unknown syscall group {name}")),
+        (false, false) => Some(format!(
+            "Unknown syscall {name} on {}",
+            std::env::consts::ARCH
+        )),
+    }
+}
+
+/// error gives a problem loading the file its line, if the parser said where it was.
+fn error(source: &str, e: ConfigError) -> Problem {
+    let line = match &e {
+        ConfigError::Yaml(e) => e.location().map(|location| location.line()),
+        ConfigError::Json(e) => Some(e.line()).filter(|&line| line > 0),
+        ConfigError::Toml(e) => e
+            .span()
+            .map(|span| source[..span.start].matches('\n').count() + 1),
+        _ => None,
+    };
+    Problem {
+        line,
+        message: e.to_string(),
+    }
+}
+
+/// find_line finds where something is written in a config by looking for each of `needles` in turn, each on or
+/// after the line the last was found on, and returns the line the last one was found on. Formats don't keep track of
+/// where values came from once they're parsed, so this is the next best thing.
+fn find_line(source: &str, needles: &[&str]) -> Option<usize> {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut start = 0;
+    for needle in needles {
+        start += lines[start..]
+            .iter()
+            .position(|line| contains_word(line, needle))?;
+    }
+    Some(start + 1)
+}
+
+/// contains_word returns whether `needle` is in `line` without being part of a longer name.
+fn contains_word(line: &str, needle: &str) -> bool {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    line.match_indices(needle).any(|(i, _)| {
+        !line[..i].ends_with(is_name) && !line[i + needle.len()..].starts_with(is_name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let path =
+            std::env::temp_dir().join(format!("crabtrap_validate_{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            r#"groups:
+  db: [connect, not_a_syscall]
+shared_objects:
+  /proc/self/exe:
+    allow: [read, write]
+    block: [write, "@nope"]
+  /usr/lib/libmissing.so:
+    allow: [read]
+  "[vdso]":
+    allow: [clock_gettime]
+  soname:libssl.so.3:
+    allow: [read]
+"#,
+        )
+        .unwrap();
+        let messages: Vec<String> = validate(&path).iter().map(Problem::to_string).collect();
+        assert_eq!(
+            messages,
+            vec![
+                format!(
+                    "line 2: Unknown syscall not_a_syscall on {} in group db",
+                    std::env::consts::ARCH
+                ),
+                "line 6: Unknown syscall group @nope in the block list for /proc/self/exe".into(),
+                "line 7: /usr/lib/libmissing.so doesn't exist on this host".into(),
+            ]
+        );
+
+        fs::write(
+            &path,
+            r#"shared_objects:
+  /proc/self/exe:
+    allow: [read, write]
+    block: [write]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            validate(&path),
+            vec![Problem {
+                line: Some(2),
+                message:
+                    "write is in both the allow and block lists for /proc/self/exe, so it's allowed"
+                        .into(),
+            }]
+        );
+
+        fs::write(
+            &path,
+            r#"binaries:
+  /usr/bin/not_a_binary:
+    shared_objects:
+      /proc/self/exe:
+        block: [nope]
+"#,
+        )
+        .unwrap();
+        let messages: Vec<String> = validate(&path).iter().map(Problem::to_string).collect();
+        assert_eq!(
+            messages,
+            vec![
+                format!(
+                    "line 5: Unknown syscall nope on {} in the block list for /proc/self/exe under /usr/bin/not_a_binary",
+                    std::env::consts::ARCH
+                ),
+                "line 2: /usr/bin/not_a_binary doesn't exist on this host".into(),
+            ]
+        );
+
+        fs::write(&path, "shared_objects:\n  - [unclosed\n").unwrap();
+        assert!(validate(&path)[0].line.is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use nix::{
+    errno::Errno,
+    libc,
+    unistd::{self, Pid},
+};
+use std::{mem, ptr, sync::OnceLock, time::Duration};
+
+extern "C" fn on_wake(_: libc::c_int) {}
+
+/// signal returns the signal tracers are woken with. It's a real-time one, which the host process is less likely to
+/// have a use for itself than any of the standard ones.
+fn signal() -> libc::c_int {
+    libc::SIGRTMIN()
+}
+
+/// install handles the wake signal from now on, without SA_RESTART so that it interrupts whatever the thread it's
+/// sent to is blocked in. It's never put back, since tracers on other threads may be counting on it.
+fn install() -> nix::Result<()> {
+    static INSTALLED: OnceLock<nix::Result<()>> = OnceLock::new();
+    *INSTALLED.get_or_init(|| {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = on_wake as *const () as libc::sighandler_t;
+        Errno::result(unsafe { libc::sigaction(signal(), &action, ptr::null_mut()) }).map(drop)
+    })
+}
+
+/// Wake: wakes a tracer thread blocked in waitpid, which then fails with EINTR, so that it gets around to whatever
+/// it's been asked to do without waiting for the next stop. A wake sent just before the tracer starts waiting is
+/// lost, so whoever sends one has to keep sending them until the tracer answers.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Wake {
+    tid: Pid,
+}
+
+impl Wake {
+    /// How long to wait for the tracer to answer before waking it again, in case it missed the last wake
+    pub const INTERVAL: Duration = Duration::from_millis(10);
+
+    /// current returns a Wake for the calling thread.
+    pub fn current() -> nix::Result<Wake> {
+        install()?;
+        Ok(Wake {
+            tid: unistd::gettid(),
+        })
+    }
+
+    pub fn wake(&self) {
+        // The thread may be gone by now, if the run's over
+        let _ = unsafe {
+            libc::syscall(
+                libc::SYS_tgkill,
+                unistd::getpid().as_raw(),
+                self.tid.as_raw(),
+                signal(),
+            )
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::fd::AsRawFd, sync::mpsc, thread};
+
+    #[test]
+    fn test_wake() {
+        let (reader, writer) = unistd::pipe().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let blocked = thread::spawn(move || {
+            sender.send(Wake::current().unwrap()).unwrap();
+            // Unlike std's, nix's read doesn't try again after EINTR
+            unistd::read(reader.as_raw_fd(), &mut [0; 1])
+        });
+        let wake = receiver.recv().unwrap();
+        while !blocked.is_finished() {
+            wake.wake();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(blocked.join().unwrap(), Err(Errno::EINTR));
+        drop(writer);
+    }
+}
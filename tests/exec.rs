@@ -1,8 +1,50 @@
-use crabtrap::{ChildExit, Config, ConfigEntry};
+use crabtrap::{
+    CgroupConfig, ChildExit, CommandExt, Config, ConfigEntry, DaemonPolicy, DefaultPolicy,
+    Enforcement, FilesystemConfig, FilesystemMode, InheritedFds, LandlockConfig, Namespaces,
+    Output, Quota, ReadOnlyAction, ResourceLimits, RuleAction, RunAs, SetupStage, StdioConfig,
+    TimeLimit, TimeLimits, ViolationScope, WriteQuota,
+};
 use std::collections::{BTreeMap, BTreeSet};
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::process::Command;
+use std::time::Duration;
 use syscalls::Sysno;
 
+/// without_stack checks that a write blocked in libprintf_wrapper was traced to the function that made it, and that
+/// the backtrace goes through it, then drops both, since the addresses depend on how the sample was compiled. Other
+/// exits are left as they are.
+fn without_stack(exit: ChildExit) -> ChildExit {
+    match exit {
+        ChildExit::IllegalSyscall {
+            syscall,
+            library,
+            rule,
+            call_site,
+            backtrace,
+        } => {
+            let call_site = call_site.expect("no call site");
+            assert!(
+                call_site.starts_with("libprintf_wrapper.so!printf_wrapper+0x"),
+                "{call_site}"
+            );
+            assert!(
+                backtrace
+                    .iter()
+                    .any(|frame| frame.library.as_ref() == Some(&library)),
+                "{backtrace:?}"
+            );
+            ChildExit::IllegalSyscall {
+                syscall,
+                library,
+                rule,
+                call_site: None,
+                backtrace: Vec::new(),
+            }
+        }
+        exit => exit,
+    }
+}
+
 #[test]
 fn test_ok() {
     for bin in ["static", "dynamic", "all-in-one"] {
@@ -11,20 +53,91 @@ fn test_ok() {
                 &CString::new(format!("/usr/local/bin/{}", bin)).unwrap(),
                 &[],
                 &[&CString::new("LD_LIBRARY_PATH=/usr/local/lib").unwrap()],
-                &Config {
-                    shared_objects: BTreeMap::new(),
-                },
+                &Config::default(),
             ),
             ChildExit::Exited(0),
         );
     }
 }
 
+#[test]
+fn test_setup_failed() {
+    assert_eq!(
+        crabtrap::execute(c"/usr/local/bin/missing", &[], &[], &Config::default()),
+        ChildExit::SetupFailed {
+            stage: SetupStage::Exec,
+            errno: nix::errno::Errno::ENOENT,
+        },
+    );
+}
+
+#[test]
+fn test_cgroup_setup_failed() {
+    let config = Config {
+        cgroup: Some(CgroupConfig {
+            parent: "/sys/fs/cgroup/crabtrap-missing".into(),
+            pids_max: Some(16),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        crabtrap::execute(c"/bin/true", &[c"true"], &[], &config),
+        ChildExit::SetupFailed {
+            stage: SetupStage::Cgroup,
+            errno: nix::errno::Errno::ENOENT,
+        },
+    );
+}
+
+#[test]
+fn test_cgroup() {
+    // The parent can't be a cgroup with processes in it, so it's one of our own
+    let parent = std::path::Path::new("/sys/fs/cgroup")
+        .join(format!("crabtrap-test-{}", std::process::id()));
+    if std::fs::create_dir(&parent).is_err() {
+        // Not somewhere we can manage cgroups
+        return;
+    }
+    let config = Config {
+        cgroup: Some(CgroupConfig {
+            parent: parent.clone(),
+            pids_max: Some(1),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let exit = crabtrap::execute(
+        c"/bin/sh",
+        &[c"sh", c"-c", c"(true) && touch /dev/null"],
+        &[],
+        &config,
+    );
+    let subtree_control = std::fs::read_to_string(parent.join("cgroup.subtree_control")).unwrap();
+    std::fs::remove_dir(&parent).unwrap();
+    match exit {
+        // The hierarchy above doesn't let us have the pids controller
+        ChildExit::SetupFailed {
+            stage: SetupStage::Cgroup,
+            ..
+        } => {}
+        // The shell is the one process the tree may have, so its subshell can't be forked
+        exit => {
+            assert!(
+                matches!(exit, ChildExit::Exited(code) if code != 0),
+                "{exit:?}"
+            );
+            // The controller the run enabled is disabled again
+            assert_eq!(subtree_control.trim(), "");
+        }
+    }
+}
+
 #[test]
 fn test_blocked() {
     for bin in ["static", "dynamic"] {
         assert_eq!(
-            crabtrap::execute(
+            without_stack(crabtrap::execute(
                 &CString::new(format!("/usr/local/bin/{}", bin)).unwrap(),
                 &[],
                 &[&CString::new("LD_LIBRARY_PATH=/usr/local/lib").unwrap()],
@@ -32,18 +145,1024 @@ fn test_blocked() {
                     shared_objects: BTreeMap::from([(
                         "/usr/local/lib/libprintf_wrapper.so".into(),
                         ConfigEntry {
-                            allow: None,
                             block: Some(BTreeSet::from([Sysno::write])),
+                            ..Default::default()
                         }
                     )]),
+                    ..Default::default()
                 },
-            ),
-            ChildExit::IllegalSyscall(Sysno::write, "/usr/local/lib/libprintf_wrapper.so".into()),
+            )),
+            ChildExit::IllegalSyscall {
+                syscall: Sysno::write,
+                library: "/usr/local/lib/libprintf_wrapper.so".into(),
+                rule: None,
+                call_site: None,
+                backtrace: Vec::new()
+            },
+        );
+    }
+}
+
+#[test]
+fn test_blocked_without_frame_pointers() {
+    // Without frame pointers to follow, the wrapper can only be found by unwinding libc with its call frame information
+    let library = "/usr/local/lib/libprintf_wrapper_nofp.so";
+    let exit = crabtrap::execute(
+        c"/usr/local/bin/nofp",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &Config {
+            shared_objects: BTreeMap::from([(
+                library.into(),
+                ConfigEntry {
+                    block: Some(BTreeSet::from([Sysno::write])),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        },
+    );
+    let ChildExit::IllegalSyscall {
+        syscall: Sysno::write,
+        library: blocked,
+        call_site: Some(call_site),
+        ..
+    } = &exit
+    else {
+        panic!("{exit:?}");
+    };
+    assert_eq!(blocked, library);
+    assert!(
+        call_site.starts_with("libprintf_wrapper_nofp.so!printf_wrapper+0x"),
+        "{call_site}"
+    );
+}
+
+#[test]
+fn test_anonymous_code() {
+    let config = Config {
+        forbid_anonymous_code: true,
+        ..Default::default()
+    };
+    // The syscall is made from libc, but called through code in anonymous memory
+    let exit = crabtrap::execute(c"/usr/local/bin/jit", &[], &[], &config);
+    let ChildExit::IllegalSyscall {
+        syscall: Sysno::getpid,
+        library,
+        ..
+    } = &exit
+    else {
+        panic!("{exit:?}");
+    };
+    assert_eq!(library, crabtrap::profile::ANONYMOUS_EXEC);
+}
+
+#[test]
+fn test_violation_scope() {
+    use crabtrap::events::Event;
+
+    let mut events = Vec::new();
+    let (exit, _) = crabtrap::execute_with_events(
+        c"/usr/local/bin/threads",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &Config {
+            shared_objects: BTreeMap::from([(
+                "/usr/local/lib/libprintf_wrapper.so".into(),
+                ConfigEntry {
+                    block: Some(BTreeSet::from([Sysno::write])),
+                    ..Default::default()
+                },
+            )]),
+            violation_scope: ViolationScope::Thread,
+            ..Default::default()
+        },
+        |event| events.push(event),
+    );
+    assert_eq!(
+        without_stack(exit),
+        ChildExit::IllegalSyscall {
+            syscall: Sysno::write,
+            library: "/usr/local/lib/libprintf_wrapper.so".into(),
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new()
+        }
+    );
+    // Only the thread that made the write was stopped, so the main thread got to exit normally
+    let Some(Event::Started { pid }) = events.first().cloned() else {
+        panic!("no start event");
+    };
+    assert!(events.contains(&Event::Exited { pid, status: 0 }));
+}
+
+#[test]
+fn test_prefilter() {
+    let config = |prefilter| Config {
+        shared_objects: BTreeMap::from([(
+            "/usr/local/lib/libprintf_wrapper.so".into(),
+            ConfigEntry {
+                block: Some(BTreeSet::from([Sysno::write])),
+                ..Default::default()
+            },
+        )]),
+        prefilter,
+        ..Default::default()
+    };
+    let run = |prefilter| {
+        crabtrap::execute_with_stats(
+            c"/usr/local/bin/static",
+            &[],
+            &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+            &config(prefilter),
+        )
+    };
+
+    let (exit, stats) = run(false);
+    let (prefiltered_exit, prefiltered_stats) = run(true);
+    assert_eq!(
+        without_stack(exit.clone()),
+        ChildExit::IllegalSyscall {
+            syscall: Sysno::write,
+            library: "/usr/local/lib/libprintf_wrapper.so".into(),
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new()
+        }
+    );
+    assert_eq!(prefiltered_exit, exit);
+    // Startup makes plenty of syscalls the config doesn't mention, which the filter lets run without stopping
+    assert!(prefiltered_stats.syscalls < stats.syscalls);
+
+    assert_eq!(
+        crabtrap::execute(c"/usr/local/bin/missing", &[], &[], &config(true)),
+        ChildExit::SetupFailed {
+            stage: SetupStage::Exec,
+            errno: nix::errno::Errno::ENOENT,
+        },
+    );
+}
+
+#[test]
+fn test_landlock() {
+    std::fs::create_dir_all("/tmp/crabtrap-landlock").unwrap();
+    let config = Config {
+        landlock: Some(LandlockConfig {
+            read_only: ["/bin", "/usr", "/lib", "/etc"].map(Into::into).to_vec(),
+            writable: vec!["/tmp/crabtrap-landlock".into()],
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        crabtrap::execute(
+            c"/bin/sh",
+            &[
+                c"sh",
+                c"-c",
+                c"echo ok > /tmp/crabtrap-landlock/out && ! echo no > /tmp/crabtrap-out"
+            ],
+            &[],
+            &config,
+        ),
+        ChildExit::Exited(0),
+    );
+    assert!(!std::path::Path::new("/tmp/crabtrap-out").exists());
+}
+
+#[test]
+fn test_session_recording() {
+    let path = std::env::temp_dir().join(format!("crabtrap_session_{}.cast", std::process::id()));
+    let config = Config {
+        session_recording: Some(path.clone()),
+        ..Default::default()
+    };
+    assert_eq!(
+        crabtrap::execute(c"/bin/sh", &[c"sh", c"-c", c"echo hello"], &[], &config),
+        ChildExit::Exited(0),
+    );
+
+    let recording = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut lines = recording.lines();
+    let header: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+    assert_eq!(header["version"], 2);
+    assert!(lines.any(|line| {
+        let event: serde_json::Value = serde_json::from_str(line).unwrap();
+        event[1] == "o" && event[2] == "hello\n"
+    }));
+}
+
+#[test]
+fn test_trace_recording() {
+    use crabtrap::replay::{replay, Outcome};
+
+    let path = std::env::temp_dir().join(format!("crabtrap_trace_{}.jsonl", std::process::id()));
+    let config = Config {
+        trace_recording: Some(path.clone()),
+        ..Default::default()
+    };
+    assert_eq!(
+        crabtrap::execute(c"/bin/sh", &[c"sh", c"-c", c"echo hello"], &[], &config),
+        ChildExit::Exited(0),
+    );
+
+    let trace = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let replayed = replay(&Config::default(), trace.as_slice()).unwrap();
+    assert!(replayed
+        .iter()
+        .any(|syscall| syscall.syscall == Sysno::write));
+    assert!(replayed
+        .iter()
+        .all(|syscall| syscall.outcome == Outcome::Allowed));
+
+    // The same run under a config that would have stopped the echo
+    let blocked = Config::parse(
+        "shared_objects:\n  \"**\":\n    block: [write]\n",
+        crabtrap::ConfigFormat::Yaml,
+    )
+    .unwrap();
+    let replayed = replay(&blocked, trace.as_slice()).unwrap();
+    assert!(replayed.iter().any(|syscall| {
+        syscall.syscall == Sysno::write
+            && syscall.outcome == Outcome::Killed
+            && syscall.library.is_some()
+    }));
+}
+
+#[test]
+fn test_time_limits() {
+    let run = |script: &CStr, time_limits| {
+        let config = Config {
+            time_limits,
+            ..Default::default()
+        };
+        crabtrap::execute_with_stats(c"/bin/sh", &[c"sh", c"-c", script], &[], &config)
+    };
+
+    // The sleep is a process of its own, which has to be killed along with the shell
+    let (exit, stats) = run(
+        c"sleep 10; true",
+        TimeLimits {
+            timeout_ms: Some(100),
+            ..Default::default()
+        },
+    );
+    assert_eq!(exit, ChildExit::TimedOut(TimeLimit::Wall));
+    assert!(stats.usage.wall_time < Duration::from_secs(10));
+
+    // One busy making syscalls is likely to be waiting to be restarted when it's killed
+    let (exit, _) = run(
+        c"while :; do echo > /dev/null; done",
+        TimeLimits {
+            timeout_ms: Some(100),
+            ..Default::default()
+        },
+    );
+    assert_eq!(exit, ChildExit::TimedOut(TimeLimit::Wall));
+
+    let (exit, _) = run(
+        c"while :; do :; done",
+        TimeLimits {
+            cpu_seconds: Some(1),
+            ..Default::default()
+        },
+    );
+    assert_eq!(exit, ChildExit::TimedOut(TimeLimit::Cpu));
+
+    // With its soft limit raised to the hard one, it's killed without a SIGXCPU
+    let (exit, _) = run(
+        c"ulimit -S -t 2; while :; do :; done",
+        TimeLimits {
+            cpu_seconds: Some(1),
+            ..Default::default()
+        },
+    );
+    assert_eq!(exit, ChildExit::TimedOut(TimeLimit::Cpu));
+
+    // A SIGXCPU sent by hand is just a signal
+    let (exit, _) = run(
+        c"kill -XCPU $$",
+        TimeLimits {
+            cpu_seconds: Some(10),
+            ..Default::default()
+        },
+    );
+    assert_eq!(exit, ChildExit::Signaled(nix::libc::SIGXCPU));
+}
+
+#[test]
+fn test_resource_limits() {
+    let config = Config {
+        resource_limits: ResourceLimits {
+            open_files: Some(64),
+            file_size: Some(1024),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // ulimit -f counts in 512 byte blocks
+    assert_eq!(
+        crabtrap::execute(
+            c"/bin/sh",
+            &[
+                c"sh",
+                c"-c",
+                c"test $(ulimit -n) = 64 && test $(ulimit -f) = 2"
+            ],
+            &[],
+            &config
+        ),
+        ChildExit::Exited(0),
+    );
+}
+
+#[test]
+fn test_network_namespace() {
+    let config = Config {
+        namespaces: Namespaces {
+            network: true,
+            loopback: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // Two lines of headers, and then just lo
+    match crabtrap::execute(
+        c"/bin/sh",
+        &[c"sh", c"-c", c"test $(wc -l < /proc/net/dev) = 3"],
+        &[],
+        &config,
+    ) {
+        // Without CAP_SYS_ADMIN there's no making one
+        ChildExit::SetupFailed {
+            stage: SetupStage::Namespaces,
+            errno: nix::errno::Errno::EPERM,
+        } => {}
+        exit => assert_eq!(exit, ChildExit::Exited(0)),
+    }
+}
+
+#[test]
+fn test_pid_namespace() {
+    let config = Config {
+        namespaces: Namespaces {
+            pid: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    match crabtrap::execute(c"/bin/sh", &[c"sh", c"-c", c"test $$ = 1"], &[], &config) {
+        ChildExit::SetupFailed {
+            stage: SetupStage::Namespaces,
+            errno: nix::errno::Errno::EPERM,
+        } => {}
+        exit => assert_eq!(exit, ChildExit::Exited(0)),
+    }
+}
+
+#[test]
+fn test_subreaper() {
+    let config = Config {
+        subreaper: true,
+        ..Default::default()
+    };
+    // The inner shell is orphaned as soon as it's started, and still has to be waited for. A traced orphan would be
+    // reported to us anyway, but only the subreaper makes it our child.
+    let parent = std::env::temp_dir().join(format!("crabtrap_subreaper_{}", std::process::id()));
+    let script = CString::new(format!(
+        "sh -c 'sleep 0.1; cut -d\" \" -f4 /proc/$$/stat > {}; exit 3' & exit 0",
+        parent.display()
+    ))
+    .unwrap();
+    let (exit, stats) =
+        crabtrap::execute_with_stats(c"/bin/sh", &[c"sh", c"-c", &script], &[], &config);
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert!(stats
+        .exits
+        .iter()
+        .any(|process| process.exit == ChildExit::Exited(3)));
+    assert_eq!(
+        std::fs::read_to_string(&parent).unwrap().trim(),
+        std::process::id().to_string()
+    );
+    std::fs::remove_file(&parent).unwrap();
+}
+
+#[test]
+fn test_user_namespace() {
+    let config = Config {
+        namespaces: Namespaces {
+            user: true,
+            network: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // The network namespace doesn't need any privileges with the user namespace, which maps just our own uid
+    match crabtrap::execute(
+        c"/bin/sh",
+        &[
+            c"sh",
+            c"-c",
+            c"test $(wc -l < /proc/net/dev) = 3 && test $(wc -l < /proc/self/uid_map) = 1",
+        ],
+        &[],
+        &config,
+    ) {
+        // Some systems turn off unprivileged user namespaces
+        ChildExit::SetupFailed {
+            stage: SetupStage::Namespaces,
+            errno: nix::errno::Errno::EPERM,
+        } => {}
+        exit => assert_eq!(exit, ChildExit::Exited(0)),
+    }
+}
+
+#[test]
+fn test_run_as() {
+    let config = Config {
+        run_as: Some(RunAs {
+            uid: 65534,
+            gid: 65534,
+            groups: Vec::new(),
+        }),
+        no_new_privs: true,
+        ..Default::default()
+    };
+    let exit = crabtrap::execute(
+        c"/bin/sh",
+        &[
+            c"sh",
+            c"-c",
+            c"test $(id -u) = 65534 && test $(id -G) = 65534 && grep -q 'NoNewPrivs:.*1' /proc/self/status",
+        ],
+        &[],
+        &config,
+    );
+    if unsafe { nix::libc::geteuid() } == 0 {
+        assert_eq!(exit, ChildExit::Exited(0));
+    } else {
+        assert_eq!(
+            exit,
+            ChildExit::SetupFailed {
+                stage: SetupStage::Credentials,
+                errno: nix::errno::Errno::EPERM,
+            }
+        );
+    }
+}
+
+#[test]
+fn test_mount_namespace() {
+    let root = std::env::temp_dir().join(format!("crabtrap_root_{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let config = Config {
+        namespaces: Namespaces {
+            root: Some(root.clone()),
+            read_only_binds: ["/usr", "/lib", "/bin"].map(Into::into).to_vec(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    match crabtrap::execute(
+        c"/bin/sh",
+        &[
+            c"sh",
+            c"-c",
+            c"test ! -e /etc/passwd && ! touch /usr/crabtrap",
+        ],
+        &[],
+        &config,
+    ) {
+        ChildExit::SetupFailed {
+            stage: SetupStage::Namespaces,
+            errno: nix::errno::Errno::EPERM,
+        } => {}
+        exit => assert_eq!(exit, ChildExit::Exited(0)),
+    }
+    // The binds are only under the root in the child's mount namespace, so it's empty again
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_working_dir() {
+    let config = Config {
+        working_dir: Some("/tmp".into()),
+        ..Default::default()
+    };
+    let path = crabtrap::resolve("sh", &[c"PATH=/nonexistent:/bin"], &config).unwrap();
+    assert_eq!(path, std::path::Path::new("/bin/sh"));
+    let exit = crabtrap::execute(
+        &CString::new(path.into_os_string().into_encoded_bytes()).unwrap(),
+        &[c"sh", c"-c", c"test \"$(pwd)\" = /tmp"],
+        &[],
+        &config,
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+
+    let config = Config {
+        working_dir: Some("/nonexistent".into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        crabtrap::execute(c"/bin/true", &[c"true"], &[], &config),
+        ChildExit::SetupFailed {
+            stage: SetupStage::Chdir,
+            errno: nix::errno::Errno::ENOENT,
+        }
+    );
+}
+
+#[test]
+fn test_inherited_fds() {
+    // Unlike std's, dup's descriptors aren't close-on-exec
+    let fd = unsafe { nix::libc::dup(2) };
+    assert!(fd > 2);
+    let check = CString::new(format!("test -e /proc/self/fd/{fd}")).unwrap();
+    let run = |config: &Config| crabtrap::execute(c"/bin/sh", &[c"sh", c"-c", &check], &[], config);
+    assert_eq!(run(&Config::default()), ChildExit::Exited(0));
+
+    let mut config = Config {
+        inherited_fds: InheritedFds {
+            close: true,
+            keep: BTreeSet::new(),
+        },
+        ..Default::default()
+    };
+    assert_eq!(run(&config), ChildExit::Exited(1));
+    config.inherited_fds.keep.insert(fd);
+    assert_eq!(run(&config), ChildExit::Exited(0));
+    unsafe { nix::libc::close(fd) };
+}
+
+#[test]
+fn test_stdio() {
+    let stdin = std::env::temp_dir().join(format!("crabtrap_stdin_{}", std::process::id()));
+    std::fs::write(&stdin, "hello\n").unwrap();
+    let config = Config {
+        stdio: StdioConfig {
+            stdin: Some(stdin.clone()),
+            stdout: Output::Capture,
+            stderr: Output::Capture,
+        },
+        ..Default::default()
+    };
+    let (exit, stats) = crabtrap::execute_with_stats(
+        c"/bin/sh",
+        &[c"sh", c"-c", c"cat; echo oops >&2"],
+        &[],
+        &config,
+    );
+    std::fs::remove_file(&stdin).unwrap();
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert_eq!(stats.stdout.as_deref(), Some(&b"hello\n"[..]));
+    assert_eq!(stats.stderr.as_deref(), Some(&b"oops\n"[..]));
+}
+
+#[test]
+fn test_event_log() {
+    let path = std::env::temp_dir().join(format!("crabtrap_events_{}.jsonl", std::process::id()));
+    let config = Config {
+        event_log: Some(path.clone()),
+        ..Default::default()
+    };
+    assert_eq!(
+        crabtrap::execute(
+            c"/bin/sh",
+            &[c"sh", c"-c", c"/bin/true; /bin/true"],
+            &[],
+            &config
+        ),
+        ChildExit::Exited(0),
+    );
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let events: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(events.iter().all(|event| event["timestamp"].is_f64()));
+    assert!(events
+        .iter()
+        .any(|event| event["event"] == "syscall" && event["syscall"] == "execve"));
+    assert!(events.iter().any(|event| event["event"] == "forked"));
+    assert!(events.iter().any(|event| event["event"] == "exec"));
+    assert_eq!(events.last().unwrap()["event"], "exited");
+}
+
+#[test]
+fn test_job_control() {
+    // The shell stops itself, and stays stopped until the background job continues it
+    assert_eq!(
+        crabtrap::execute(
+            c"/bin/sh",
+            &[
+                c"sh",
+                c"-c",
+                c"(sleep 1; kill -CONT $$) & kill -STOP $$; wait; exit 3"
+            ],
+            &[],
+            &Config::default(),
+        ),
+        ChildExit::Exited(3),
+    );
+}
+
+#[test]
+fn test_run_stats() {
+    let (exit, stats) = crabtrap::execute_with_stats(
+        c"/usr/local/bin/dynamic",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &Config::default(),
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert_eq!(stats.processes, 1);
+    assert!(stats.syscalls > 0);
+    // The loader makes the same syscalls over and over
+    assert!(stats.cache_hits > 0 && stats.cache_misses > 0);
+    assert!(stats
+        .cache_hit_rate()
+        .is_some_and(|rate| rate > 0.0 && rate < 1.0));
+    assert!(stats.peak_tracer_rss > 0);
+}
+
+#[test]
+fn test_loader_startup() {
+    let (exit, stats) = crabtrap::execute_with_stats(
+        c"/usr/local/bin/dynamic",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &Config::default(),
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert!(stats
+        .libraries
+        .contains_key(crabtrap::profile::LOADER_STARTUP));
+}
+
+#[test]
+fn test_record() {
+    let env = [c"LD_LIBRARY_PATH=/usr/local/lib"];
+    let (exit, _, profile) =
+        crabtrap::profile::Profile::trace(c"/usr/local/bin/dynamic", &[], &env, &Config::default());
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert!(profile.libraries["/usr/local/lib/libprintf_wrapper.so"].contains_key(&Sysno::write));
+
+    // The recorded config is enough to run the program again
+    let exit = crabtrap::execute(
+        c"/usr/local/bin/dynamic",
+        &[],
+        &env,
+        &profile.suggested_config(),
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+}
+
+#[test]
+fn test_audit_mode() {
+    let (exit, stats) = crabtrap::execute_with_stats(
+        c"/usr/local/bin/dynamic",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &Config {
+            shared_objects: BTreeMap::from([(
+                "/usr/local/lib/libprintf_wrapper.so".into(),
+                ConfigEntry {
+                    block: Some(BTreeSet::from([Sysno::write])),
+                    ..Default::default()
+                },
+            )]),
+            enforcement: Enforcement::Audit,
+            ..Default::default()
+        },
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+    // The rules are only checked at the syscall's entry stop, so its exit stop doesn't count it again
+    assert!(matches!(
+        stats.violations.first(),
+        Some((ChildExit::IllegalSyscall { syscall: Sysno::write, library, .. }, 1))
+            if library == "/usr/local/lib/libprintf_wrapper.so"
+    ));
+}
+
+#[test]
+fn test_overruled() {
+    use crabtrap::hooks::{Hooks, Ruling};
+
+    let mut violations = Vec::new();
+    let (exit, _) = crabtrap::execute_with_hooks(
+        c"/usr/local/bin/dynamic",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &Config {
+            shared_objects: BTreeMap::from([(
+                "/usr/local/lib/libprintf_wrapper.so".into(),
+                ConfigEntry {
+                    block: Some(BTreeSet::from([Sysno::write])),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        },
+        Hooks::default().on_violation(|_, exit| {
+            violations.push(exit.clone());
+            Ruling::Allow
+        }),
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert_eq!(
+        violations.first().cloned().map(without_stack),
+        Some(ChildExit::IllegalSyscall {
+            syscall: Sysno::write,
+            library: "/usr/local/lib/libprintf_wrapper.so".into(),
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new()
+        })
+    );
+}
+
+#[test]
+fn test_on_unknown() {
+    use crabtrap::hooks::{Hooks, Judgement};
+
+    let (exit, _) = crabtrap::execute_with_hooks(
+        c"/usr/local/bin/static",
+        &[],
+        &[],
+        &Config::default(),
+        Hooks::default().on_unknown(|event| match event.syscall {
+            Sysno::write => Judgement::Block,
+            _ => Judgement::Defer,
+        }),
+    );
+    assert!(matches!(
+        exit,
+        ChildExit::IllegalSyscall {
+            syscall: Sysno::write,
+            ..
+        }
+    ));
+
+    // A walk that's cut short, here at every syscall, is asked about too before on_failure's fallen back on
+    let config = Config {
+        stack_walk: crabtrap::StackWalkConfig {
+            max_depth: 1,
+            on_failure: Some(crabtrap::DefaultPolicy::Kill),
+        },
+        ..Default::default()
+    };
+    let (exit, _) = crabtrap::execute_with_hooks(
+        c"/usr/local/bin/static",
+        &[],
+        &[],
+        &config,
+        Hooks::default().on_unknown(|_| Judgement::Allow),
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+}
+
+#[test]
+fn test_default_policy() {
+    // Nothing is allowed, so the very first syscall is a violation
+    assert!(matches!(
+        crabtrap::execute(
+            c"/usr/local/bin/static",
+            &[],
+            &[],
+            &Config {
+                default: DefaultPolicy::Kill,
+                ..Default::default()
+            },
+        ),
+        ChildExit::IllegalSyscall { rule: None, .. }
+    ));
+}
+
+#[test]
+fn test_lint() {
+    let entry = || ConfigEntry {
+        allow: Some(BTreeSet::from([Sysno::write])),
+        ..Default::default()
+    };
+    let config = Config {
+        shared_objects: BTreeMap::from([
+            ("/usr/local/lib/libprintf_wrapper.so".into(), entry()),
+            ("/usr/local/lib/libmissing.so".into(), entry()),
+        ]),
+        ..Default::default()
+    };
+    let lint =
+        crabtrap::lint::lint(&config, std::path::Path::new("/usr/local/bin/static")).unwrap();
+    assert!(lint.unresolved.is_empty());
+    assert_eq!(lint.unlinked, vec!["/usr/local/lib/libmissing.so"]);
+    // The executable, the loader and libc have no rules, but the wrapper does
+    assert!(lint.unruled.contains(&"/usr/local/bin/static".to_string()));
+    assert!(lint
+        .unruled
+        .iter()
+        .any(|library| library.contains("libc.so")));
+    assert!(!lint
+        .unruled
+        .iter()
+        .any(|library| library.contains("printf_wrapper")));
+}
+
+#[test]
+fn test_profile() {
+    let compute_only = Config::profile("compute-only").unwrap();
+    assert_eq!(
+        crabtrap::execute(
+            c"/usr/local/bin/static",
+            &[],
+            &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+            &compute_only,
+        ),
+        ChildExit::Exited(0),
+    );
+    // Opening a file to write to fails, so the shell gives up on the command
+    assert!(matches!(
+        crabtrap::execute(
+            c"/bin/sh",
+            &[c"sh", c"-c", c"echo hi > /tmp/crabtrap_compute_only"],
+            &[],
+            &compute_only,
+        ),
+        ChildExit::Exited(code) if code != 0
+    ));
+}
+
+#[test]
+fn test_paths() {
+    let config: Config = r#"shared_objects:
+  "**/libc.so.*":
+    action: deny
+    paths:
+      openat: [/etc/, /usr/, /lib/]
+"#
+    .parse()
+    .unwrap();
+    let cat = |path: &CStr| crabtrap::execute(c"/bin/cat", &[c"cat", path], &[], &config);
+    assert_eq!(cat(c"/etc/passwd"), ChildExit::Exited(0));
+    // A symlink out of the allowed paths doesn't get around them
+    let link = "/tmp/crabtrap_paths_link";
+    let _ = std::fs::remove_file(link);
+    std::os::unix::fs::symlink("/proc/version", link).unwrap();
+    assert_eq!(cat(c"/tmp/crabtrap_paths_link"), ChildExit::Exited(1));
+    assert_eq!(cat(c"/proc/version"), ChildExit::Exited(1));
+    std::fs::remove_file(link).unwrap();
+}
+
+#[test]
+fn test_network() {
+    let config: Config = r#"shared_objects:
+  "**/libc.so.*":
+    action: deny
+    network:
+      types: [dgram]
+      destinations: ["127.0.0.0/8:53"]
+"#
+    .parse()
+    .unwrap();
+    // Connecting a UDP socket doesn't need anything listening
+    let connect = |kind: &CStr, port: &CStr| {
+        crabtrap::execute(
+            c"/usr/local/bin/connect",
+            &[c"connect", kind, c"127.0.0.1", port],
+            &[],
+            &config,
+        )
+    };
+    assert_eq!(connect(c"udp", c"53"), ChildExit::Exited(0));
+    assert_eq!(connect(c"udp", c"80"), ChildExit::Exited(1));
+    assert_eq!(connect(c"tcp", c"53"), ChildExit::Exited(2));
+}
+
+#[test]
+fn test_fds() {
+    let config: Config = r#"shared_objects:
+  "**/libc.so.*":
+    action: deny
+    fds:
+      write: [stdout, stderr]
+"#
+    .parse()
+    .unwrap();
+    // The file opens fine, but nothing can be written to it
+    assert_eq!(
+        crabtrap::execute(
+            c"/bin/sh",
+            &[
+                c"sh",
+                c"-c",
+                c"echo ok && ! echo no > /tmp/crabtrap_fds_out"
+            ],
+            &[],
+            &config,
+        ),
+        ChildExit::Exited(0),
+    );
+    assert_eq!(
+        std::fs::read_to_string("/tmp/crabtrap_fds_out").unwrap(),
+        ""
+    );
+}
+
+#[test]
+fn test_denied() {
+    // printf's error is ignored, so the program runs to the end instead of being killed at the write
+    assert_eq!(
+        crabtrap::execute(
+            c"/usr/local/bin/static",
+            &[],
+            &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+            &Config {
+                shared_objects: BTreeMap::from([(
+                    "/usr/local/lib/libprintf_wrapper.so".into(),
+                    ConfigEntry {
+                        block: Some(BTreeSet::from([Sysno::write])),
+                        action: RuleAction::Deny(nix::errno::Errno::EPERM),
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
+            },
+        ),
+        ChildExit::Exited(0),
+    );
+}
+
+#[test]
+fn test_denied_without_prefilter() {
+    // Without the prefilter the tracee is first seen at the exit of its execve, and every stop after it has to be
+    // told apart from the next one
+    let config: Config = r#"shared_objects:
+  "**/libc.so.*":
+    block: [mkdirat]
+    action: deny
+"#
+    .parse()
+    .unwrap();
+    assert!(!config.prefilter);
+    let dir = std::env::temp_dir().join(format!("crabtrap_denied_{}", std::process::id()));
+    let script = CString::new(format!(
+        "mkdir {} 2>&1 | grep -q 'Operation not permitted'",
+        dir.display()
+    ))
+    .unwrap();
+    assert_eq!(
+        crabtrap::execute(c"/bin/sh", &[c"sh", c"-c", &script], &[], &config),
+        ChildExit::Exited(0),
+    );
+    assert!(!dir.exists());
+}
+
+#[test]
+fn test_rule_actions() {
+    use nix::{errno::Errno, sys::signal::Signal};
+
+    for (action, expected) in [
+        (
+            RuleAction::Kill,
+            ChildExit::IllegalSyscall {
+                syscall: Sysno::write,
+                library: "/usr/local/lib/libprintf_wrapper.so".into(),
+                rule: None,
+                call_site: None,
+                backtrace: Vec::new(),
+            },
+        ),
+        (RuleAction::Deny(Errno::EACCES), ChildExit::Exited(0)),
+        (RuleAction::Log, ChildExit::Exited(0)),
+        (RuleAction::Trap, ChildExit::Signaled(Signal::SIGSYS as i32)),
+    ] {
+        assert_eq!(
+            without_stack(crabtrap::execute(
+                c"/usr/local/bin/static",
+                &[],
+                &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+                &Config {
+                    shared_objects: BTreeMap::from([(
+                        "/usr/local/lib/libprintf_wrapper.so".into(),
+                        ConfigEntry {
+                            block: Some(BTreeSet::from([Sysno::write])),
+                            action,
+                            ..Default::default()
+                        },
+                    )]),
+                    ..Default::default()
+                },
+            )),
+            expected,
         );
     }
 }
 
 #[test]
+#[allow(clippy::useless_format)]
 fn test_child_ok() {
     assert_eq!(
         crabtrap::execute(
@@ -54,10 +1173,11 @@ fn test_child_ok() {
                 shared_objects: BTreeMap::from([(
                     "/usr/local/lib/libprintf_wrapper.so".into(),
                     ConfigEntry {
-                        allow: None,
                         block: Some(BTreeSet::from([Sysno::write])),
+                        ..Default::default()
                     }
                 )]),
+                ..Default::default()
             },
         ),
         ChildExit::Exited(0),
@@ -65,9 +1185,54 @@ fn test_child_ok() {
 }
 
 #[test]
+fn test_process_exits() {
+    let (exit, stats) = crabtrap::execute_with_stats(
+        c"/usr/local/bin/child",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &Config::default(),
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+    // The child and the three processes under it, each waiting for the one it forked before exiting itself
+    assert_eq!(stats.exits.len(), 4);
+    assert!(stats
+        .exits
+        .iter()
+        .all(|process| process.exit == ChildExit::Exited(0) && process.backtrace.is_empty()));
+    // The started process waited for the others, so their usage is counted in with its own
+    assert!(stats.usage.wall_time > Duration::ZERO);
+    assert!(stats.usage.max_rss > 0);
+    assert!(stats.syscalls > 0);
+}
+
+#[test]
+fn test_crash() {
+    let (exit, stats) = crabtrap::execute_with_stats(
+        c"/usr/local/bin/threads",
+        &[c"threads", c"crash"],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &Config::default(),
+    );
+    assert_eq!(exit, ChildExit::Signaled(nix::libc::SIGSEGV));
+    let [process] = &stats.exits[..] else {
+        panic!("{:?}", stats.exits);
+    };
+    assert_eq!(process.exit, exit);
+    // The worker thread's stack, not the main thread's, which is waiting in libc
+    assert_eq!(
+        process
+            .backtrace
+            .first()
+            .and_then(|frame| frame.library.as_deref()),
+        Some("/usr/local/bin/threads")
+    );
+}
+
+#[test]
+#[allow(clippy::useless_format)]
 fn test_child_blocked() {
     assert_eq!(
-        crabtrap::execute(
+        without_stack(crabtrap::execute(
             &CString::new(format!("/usr/local/bin/child")).unwrap(),
             &[],
             &[&CString::new("LD_LIBRARY_PATH=/usr/local/lib").unwrap()],
@@ -75,12 +1240,668 @@ fn test_child_blocked() {
                 shared_objects: BTreeMap::from([(
                     "/usr/local/lib/libprintf_wrapper.so".into(),
                     ConfigEntry {
-                        allow: None,
                         block: Some(BTreeSet::from([Sysno::write])),
+                        ..Default::default()
                     }
                 )]),
+                ..Default::default()
+            },
+        )),
+        ChildExit::IllegalSyscall {
+            syscall: Sysno::write,
+            library: "/usr/local/lib/libprintf_wrapper.so".into(),
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new()
+        },
+    );
+}
+
+#[test]
+fn test_concurrent_threads() {
+    let run = |block| {
+        crabtrap::execute_with_stats(
+            c"/usr/local/bin/concurrent",
+            &[],
+            &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+            &Config {
+                shared_objects: BTreeMap::from([(
+                    "/usr/local/lib/libprintf_wrapper.so".into(),
+                    ConfigEntry {
+                        block: Some(BTreeSet::from([block])),
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
             },
+        )
+    };
+
+    // getpid is made from the threads' own code, so blocking it in the wrapper leaves them be
+    let (exit, stats) = run(Sysno::getpid);
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert_eq!(stats.processes, 9);
+    assert!(stats.syscalls >= 8 * 50 * 2);
+
+    assert_eq!(
+        without_stack(run(Sysno::write).0),
+        ChildExit::IllegalSyscall {
+            syscall: Sysno::write,
+            library: "/usr/local/lib/libprintf_wrapper.so".into(),
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new()
+        }
+    );
+}
+
+#[test]
+fn test_vfork_and_posix_spawn() {
+    for how in [c"vfork", c"posix_spawn"] {
+        let run = |config: &Config| {
+            crabtrap::execute(
+                c"/usr/local/bin/spawn",
+                &[c"spawn", how],
+                &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+                config,
+            )
+        };
+        assert_eq!(run(&Config::default()), ChildExit::Exited(0), "{how:?}");
+        assert_eq!(
+            without_stack(run(&Config {
+                shared_objects: BTreeMap::from([(
+                    "/usr/local/lib/libprintf_wrapper.so".into(),
+                    ConfigEntry {
+                        block: Some(BTreeSet::from([Sysno::write])),
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
+            })),
+            ChildExit::IllegalSyscall {
+                syscall: Sysno::write,
+                library: "/usr/local/lib/libprintf_wrapper.so".into(),
+                rule: None,
+                call_site: None,
+                backtrace: Vec::new()
+            },
+            "{how:?}"
+        );
+    }
+}
+
+#[test]
+fn test_binaries() {
+    // The rules only apply once spawn has exec'd static
+    let config: Config = r#"binaries:
+  /usr/local/bin/static:
+    shared_objects:
+      /usr/local/lib/libprintf_wrapper.so:
+        block: [write]
+"#
+    .parse()
+    .unwrap();
+    assert_eq!(
+        without_stack(crabtrap::execute(
+            c"/usr/local/bin/spawn",
+            &[c"spawn", c"posix_spawn"],
+            &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+            &config,
+        )),
+        ChildExit::IllegalSyscall {
+            syscall: Sysno::write,
+            library: "/usr/local/lib/libprintf_wrapper.so".into(),
+            rule: None,
+            call_site: None,
+            backtrace: Vec::new()
+        },
+    );
+    assert_eq!(
+        crabtrap::execute(
+            c"/usr/local/bin/dynamic",
+            &[],
+            &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+            &config,
+        ),
+        ChildExit::Exited(0),
+    );
+}
+
+#[test]
+fn test_rule_id() {
+    // The id is that of the rules that blocked the syscall, not whichever the library has at the top level
+    let config: Config = r#"shared_objects:
+  /usr/local/lib/libprintf_wrapper.so:
+    id: everywhere
+    allow: [write]
+binaries:
+  /usr/local/bin/static:
+    shared_objects:
+      /usr/local/lib/libprintf_wrapper.so:
+        id: in-static
+        block: [write]
+"#
+    .parse()
+    .unwrap();
+    let exit = without_stack(crabtrap::execute(
+        c"/usr/local/bin/spawn",
+        &[c"spawn", c"posix_spawn"],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        &config,
+    ));
+    assert!(
+        matches!(
+            exit,
+            ChildExit::IllegalSyscall {
+                syscall: Sysno::write,
+                ..
+            }
+        ),
+        "{exit:?}"
+    );
+    assert_eq!(exit.rule(), Some("in-static"));
+
+    // Rules listed under one of a library's other names count too
+    let config: Config = r#"shared_objects:
+  soname:libc.so.6:
+    id: libc
+    block: [mkdirat]
+"#
+    .parse()
+    .unwrap();
+    let dir = std::env::temp_dir().join(format!("crabtrap_rule_id_{}", std::process::id()));
+    let dir = CString::new(dir.into_os_string().into_encoded_bytes()).unwrap();
+    let exit = crabtrap::execute(c"/bin/mkdir", &[c"mkdir", &dir], &[], &config);
+    assert!(
+        matches!(
+            exit,
+            ChildExit::IllegalSyscall {
+                syscall: Sysno::mkdirat,
+                ..
+            }
+        ),
+        "{exit:?}"
+    );
+    assert_eq!(exit.rule(), Some("libc"));
+}
+
+#[test]
+fn test_write_quota() {
+    assert_eq!(
+        crabtrap::execute(
+            &CString::new("/bin/sh").unwrap(),
+            &[
+                &CString::new("sh").unwrap(),
+                &CString::new("-c").unwrap(),
+                &CString::new("head -c 100 /dev/zero > /tmp/crabtrap_quota").unwrap(),
+            ],
+            &[],
+            &Config {
+                write_quota: WriteQuota {
+                    per_file: Some(10),
+                    total: None,
+                },
+                ..Default::default()
+            },
+        ),
+        ChildExit::WriteQuotaExceeded(Quota::PerFile, "/tmp/crabtrap_quota".into()),
+    );
+
+    // Files grown without writing to them, or copied into, count too
+    let path = std::env::temp_dir().join(format!("crabtrap_quota_{}", std::process::id()));
+    let copied = path.with_extension("copy");
+    let config = Config {
+        write_quota: WriteQuota {
+            per_file: Some(10),
+            total: None,
+        },
+        ..Default::default()
+    };
+    for script in [
+        format!("truncate -s 1G {}", path.display()),
+        format!("cp /bin/sh {}", copied.display()),
+    ] {
+        let script = CString::new(script).unwrap();
+        let exit = crabtrap::execute(c"/bin/sh", &[c"sh", c"-c", &script], &[], &config);
+        assert!(
+            matches!(exit, ChildExit::WriteQuotaExceeded(Quota::PerFile, _)),
+            "{exit:?}"
+        );
+    }
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&copied);
+}
+
+#[test]
+fn test_daemonize_denied() {
+    assert!(matches!(
+        crabtrap::execute(
+            &CString::new("/usr/bin/setsid").unwrap(),
+            &[
+                &CString::new("setsid").unwrap(),
+                &CString::new("true").unwrap(),
+            ],
+            &[],
+            &Config {
+                daemonize: DaemonPolicy::Deny,
+                ..Default::default()
+            },
+        ),
+        ChildExit::Daemonized(_),
+    ));
+}
+
+#[test]
+fn test_daemonize_with_prefilter() {
+    // Let go of, the daemon would be left with a filter nobody answers, and its mkdir would fail with ENOSYS
+    let config: Config = r#"daemonize: allow
+prefilter: true
+shared_objects:
+  "**/libc.so.*":
+    block: [mkdirat]
+    action: deny
+"#
+    .parse()
+    .unwrap();
+    let dir = std::env::temp_dir().join(format!("crabtrap_daemon_{}", std::process::id()));
+    let script = CString::new(format!(
+        "mkdir {} 2>&1 | grep -q 'Operation not permitted'",
+        dir.display()
+    ))
+    .unwrap();
+    let (exit, stats) = crabtrap::execute_with_stats(
+        c"/usr/bin/setsid",
+        &[c"setsid", c"sh", c"-c", &script],
+        &[],
+        &config,
+    );
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert_eq!(stats.daemonized, 1);
+    assert!(!dir.exists());
+}
+
+#[test]
+fn test_read_only_filesystem() {
+    let run = |action| {
+        crabtrap::execute(
+            &CString::new("/bin/sh").unwrap(),
+            &[
+                &CString::new("sh").unwrap(),
+                &CString::new("-c").unwrap(),
+                &CString::new("echo hi > /tmp/writable/ok && echo hi > /tmp/crabtrap_ro").unwrap(),
+            ],
+            &[],
+            &Config {
+                filesystem: FilesystemConfig {
+                    mode: FilesystemMode::ReadOnly,
+                    writable: vec!["/tmp/writable".into()],
+                    action,
+                },
+                ..Default::default()
+            },
+        )
+    };
+
+    std::fs::create_dir_all("/tmp/writable").unwrap();
+    assert_eq!(
+        run(ReadOnlyAction::Kill),
+        ChildExit::ReadOnlyFilesystem(Sysno::openat, "/tmp/crabtrap_ro".into()),
+    );
+    assert!(matches!(run(ReadOnlyAction::Erofs), ChildExit::Exited(code) if code != 0));
+}
+
+#[test]
+fn test_command() {
+    assert_eq!(
+        Command::new("/usr/local/bin/static")
+            .sandbox(&Config::default())
+            .status_traced()
+            .unwrap(),
+        ChildExit::Exited(0),
+    );
+
+    // The locale is the configured one, whatever the command or we had
+    let config = Config {
+        deterministic: Some(Default::default()),
+        ..Default::default()
+    };
+    assert_eq!(
+        Command::new("/bin/sh")
+            .args(["-c", r#"test -z "$LC_TIME" && test "$LC_ALL" = C"#])
+            .env("LC_TIME", "fr_FR")
+            .sandbox(&config)
+            .status_traced()
+            .unwrap(),
+        ChildExit::Exited(0),
+    );
+}
+
+#[test]
+fn test_events() {
+    use crabtrap::events::{Event, Events};
+
+    let mut events = Events::spawn(
+        c"/usr/local/bin/dynamic",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+        Config::default(),
+    );
+    let seen: Vec<Event> = events.by_ref().collect();
+    assert!(seen.iter().any(|event| matches!(
+        event,
+        Event::LibraryLoaded { path, .. } if path == "/usr/local/lib/libprintf_wrapper.so"
+    )));
+    assert!(matches!(seen.last(), Some(Event::Exited { status: 0, .. })));
+    assert_eq!(events.wait().0, ChildExit::Exited(0));
+}
+
+#[test]
+fn test_job_control_events() {
+    use crabtrap::events::{Event, Events};
+
+    // The shell stops itself, and a background subshell continues it
+    let mut events = Events::spawn(
+        c"/bin/sh",
+        &[
+            c"sh",
+            c"-c",
+            c"(sleep 0.2; kill -CONT $$) & kill -STOP $$; wait",
+        ],
+        &[],
+        Config::default(),
+    );
+    let seen: Vec<Event> = events.by_ref().collect();
+    let stopped = seen
+        .iter()
+        .position(
+            |event| matches!(event, Event::Stopped { signal, .. } if *signal == nix::libc::SIGSTOP),
+        )
+        .expect("no stop event");
+    assert!(seen[stopped..]
+        .iter()
+        .any(|event| matches!(event, Event::Continued { .. })));
+    let (exit, stats) = events.wait();
+    assert_eq!(exit, ChildExit::Exited(0));
+    assert_eq!(stats.job_control_stops, 1);
+}
+
+#[test]
+fn test_plugin_host() {
+    use crabtrap::plugins::{PluginHost, PluginViolation};
+
+    let host = PluginHost::new(Config::default()).register(
+        "printf",
+        "/usr/local/lib/libprintf_wrapper.so",
+        ConfigEntry {
+            block: Some(BTreeSet::from([Sysno::write])),
+            ..Default::default()
+        },
+    );
+    let mut worker = host.spawn(
+        c"/usr/local/bin/dynamic",
+        &[],
+        &[c"LD_LIBRARY_PATH=/usr/local/lib"],
+    );
+    let violations: Vec<PluginViolation> = worker.by_ref().collect();
+    let exit = ChildExit::IllegalSyscall {
+        syscall: Sysno::write,
+        library: "/usr/local/lib/libprintf_wrapper.so".into(),
+        rule: None,
+        call_site: None,
+        backtrace: Vec::new(),
+    };
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].plugin.as_deref(), Some("printf"));
+    assert_eq!(without_stack(violations[0].exit.clone()), exit);
+    assert_eq!(worker.wait().0, exit);
+}
+
+#[test]
+fn test_concurrent_runs() {
+    use crabtrap::events::Events;
+
+    // A child of our own that's still running when the runs start, and ends before they do
+    let mut own = Command::new("/bin/sh")
+        .args(["-c", "sleep 0.2; exit 4"])
+        .spawn()
+        .unwrap();
+    let other = Events::spawn(
+        c"/bin/sh",
+        &[c"sh", c"-c", c"sleep 0.4; exit 5"],
+        &[],
+        Config::default(),
+    );
+    assert_eq!(
+        crabtrap::execute(
+            c"/bin/sh",
+            &[c"sh", c"-c", c"sleep 0.4; exit 3"],
+            &[],
+            &Config::default()
+        ),
+        ChildExit::Exited(3)
+    );
+    assert_eq!(other.wait().0, ChildExit::Exited(5));
+    assert_eq!(own.wait().unwrap().code(), Some(4));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_sandbox() {
+    use crabtrap::{events::Event, sandbox::Sandbox};
+
+    let mut running = Sandbox::new(c"/usr/local/bin/static", &[], &[], Config::default())
+        .spawn()
+        .await;
+    let pid = running.pid();
+
+    let mut events = Vec::new();
+    while let Some(event) = running.next_event().await {
+        events.push(event);
+    }
+    assert!(events.contains(&Event::Exited { pid, status: 0 }));
+    assert_eq!(running.wait().await.0, ChildExit::Exited(0));
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi() {
+    use crabtrap::capi::*;
+    use std::{mem::MaybeUninit, ptr};
+
+    let config = unsafe { crabtrap_config_load(c"/nonexistent.yaml".as_ptr()) };
+    assert!(config.is_null());
+    let error = unsafe { CStr::from_ptr(crabtrap_last_error()) };
+    assert!(error.to_str().unwrap().contains("/nonexistent.yaml"));
+
+    let argv = [
+        c"sh".as_ptr(),
+        c"-c".as_ptr(),
+        c"exit 3".as_ptr(),
+        ptr::null(),
+    ];
+    let mut result = MaybeUninit::uninit();
+    let status = unsafe {
+        crabtrap_execute(
+            c"/bin/sh".as_ptr(),
+            argv.as_ptr(),
+            ptr::null(),
+            ptr::null(),
+            result.as_mut_ptr(),
+        )
+    };
+    assert_eq!(status, 0);
+    let mut result = unsafe { result.assume_init() };
+    assert_eq!(result.kind, CrabtrapExitKind::Exited);
+    assert_eq!(result.code, 3);
+    assert!(result.subject.is_null());
+    let json: serde_json::Value =
+        serde_json::from_str(unsafe { CStr::from_ptr(result.json) }.to_str().unwrap()).unwrap();
+    assert_eq!(json["exit"], serde_json::json!({ "Exited": 3 }));
+    unsafe { crabtrap_result_free(&mut result) };
+    assert!(result.json.is_null());
+}
+
+#[test]
+fn test_control_socket() {
+    use crabtrap::control::{request, Request, Response};
+
+    let socket =
+        std::env::temp_dir().join(format!("crabtrap_exec_control_{}.sock", std::process::id()));
+    let config = Config {
+        control_socket: Some(socket.clone()),
+        ..Default::default()
+    };
+    let run = std::thread::spawn(move || {
+        crabtrap::execute(
+            c"/bin/sh",
+            &[c"sh", c"-c", c"sleep 1; exit 3"],
+            &[],
+            &config,
+        )
+    });
+    while !socket.exists() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    match request(&socket, &Request::Stats).unwrap() {
+        Response::Stats(stats) => assert!(stats.processes >= 1, "{stats:?}"),
+        response => panic!("unexpected response {response:?}"),
+    }
+    assert_eq!(request(&socket, &Request::Pause).unwrap(), Response::Ok);
+    assert_eq!(request(&socket, &Request::Resume).unwrap(), Response::Ok);
+    assert!(matches!(
+        request(
+            &socket,
+            &Request::Reload {
+                path: "/nonexistent.yaml".into()
+            }
+        )
+        .unwrap(),
+        Response::Error(_)
+    ));
+    // Letting go of the target doesn't stop us from seeing how it exits, since it's still our child
+    assert_eq!(request(&socket, &Request::Detach).unwrap(), Response::Ok);
+    assert_eq!(run.join().unwrap(), ChildExit::Exited(3));
+    assert!(!socket.exists());
+}
+
+#[test]
+fn test_control_socket_with_prefilter() {
+    use crabtrap::control::{request, Request, Response};
+
+    let dir =
+        std::env::temp_dir().join(format!("crabtrap_prefilter_control_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("control.sock");
+    let rules = "shared_objects:\n  \"**/libc.so.*\":\n    block: [mkdirat]\n    action: deny\n";
+    let mut config: Config = rules.parse().unwrap();
+    config.prefilter = true;
+    config.control_socket = Some(socket.clone());
+    let run = std::thread::spawn(move || {
+        crabtrap::execute(
+            c"/bin/sh",
+            &[c"sh", c"-c", c"sleep 1; exit 3"],
+            &[],
+            &config,
+        )
+    });
+    while !socket.exists() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // The tracer is woken for the request, rather than answering at the next stop, which is after the sleep
+    let asked = std::time::Instant::now();
+    assert!(matches!(
+        request(&socket, &Request::Stats).unwrap(),
+        Response::Stats(_)
+    ));
+    assert!(asked.elapsed() < Duration::from_millis(500));
+    // The prefilter only stops at mkdirat, so a rule for unlinkat could never be enforced
+    let reloaded = dir.join("reloaded.yaml");
+    std::fs::write(&reloaded, rules.replace("mkdirat", "unlinkat")).unwrap();
+    assert!(matches!(
+        request(
+            &socket,
+            &Request::Reload {
+                path: reloaded.clone()
+            }
+        )
+        .unwrap(),
+        Response::Error(_)
+    ));
+    std::fs::write(&reloaded, rules).unwrap();
+    assert_eq!(
+        request(&socket, &Request::Reload { path: reloaded }).unwrap(),
+        Response::Ok
+    );
+    // Without us the filter would make its syscalls fail
+    assert!(matches!(
+        request(&socket, &Request::Detach).unwrap(),
+        Response::Error(_)
+    ));
+    assert_eq!(run.join().unwrap(), ChildExit::Exited(3));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_reload() {
+    use crabtrap::events::Event;
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        time::Instant,
+    };
+
+    let (execs, exec) = mpsc::channel();
+    let violators = Arc::new(Mutex::new(Vec::new()));
+    let reloaded = Arc::new(Mutex::new(None));
+    let run = std::thread::spawn({
+        let (violators, reloaded) = (violators.clone(), reloaded.clone());
+        move || {
+            let mut started = None;
+            let hooks = crabtrap::hooks::Hooks::default()
+                .on_event(move |event| match event {
+                    Event::Started { pid } => started = Some(pid),
+                    // The sleep, which sh forks to run
+                    Event::Exec { pid } if Some(pid) != started => {
+                        let _ = execs.send(pid);
+                    }
+                    Event::Violation { pid, .. } => violators.lock().unwrap().push(pid),
+                    _ => {}
+                })
+                .on_reload(move || {
+                    *reloaded.lock().unwrap() = Some(Instant::now());
+                    Config::parse(
+                        "shared_objects:\n  \"**\":\n    block: [execve]\n",
+                        crabtrap::ConfigFormat::Yaml,
+                    )
+                });
+            crabtrap::execute_with_hooks(
+                c"/bin/sh",
+                &[c"sh", c"-c", c"sleep 1; /bin/true"],
+                &[],
+                &Config::default(),
+                hooks,
+            )
+        }
+    });
+    let sleep = exec.recv().unwrap();
+    let hangup = Instant::now();
+    nix::sys::signal::raise(nix::sys::signal::Signal::SIGHUP).unwrap();
+
+    let (exit, _) = run.join().unwrap();
+    assert!(
+        matches!(
+            exit,
+            ChildExit::IllegalSyscall {
+                syscall: Sysno::execve,
+                ..
+            }
         ),
-        ChildExit::IllegalSyscall(Sysno::write, "/usr/local/lib/libprintf_wrapper.so".into()),
+        "{exit:?}"
     );
+    // The sleep started before the reload, so running true is what broke the new config
+    assert!(!violators.lock().unwrap().contains(&sleep));
+    // The tracer was woken for it while the sleep went on, rather than getting to it at the sleep's next stop
+    let reloaded = reloaded.lock().unwrap().expect("never reloaded");
+    assert!(reloaded.duration_since(hangup) < Duration::from_millis(500));
 }